@@ -0,0 +1,83 @@
+//! Delivering one event to many connections, through the `SocketEventHandler`
+//! of a reader and the `EventWebSocket` actors of the connections, with frames in
+//! memory instead of TCP connections.
+
+use std::time::Duration;
+
+use actix::SystemRunner;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use events_api_websocket_server::harness::{self, ConnectOptions, Connection, Harness, Publisher};
+use futures_util::future::join_all;
+
+const STREAM_KEY: &str = "nft_transfer";
+/// Subscribers of the benchmark.
+const SUBSCRIBERS: &[usize] = &[1, 100, 1_000];
+/// Different filters among the subscribers, like a few presets of a UI. All of
+/// them match the events.
+const DISTINCT_FILTERS: usize = 10;
+
+fn fanout(criterion: &mut Criterion) {
+    let system = actix::System::new();
+    let harness = system.block_on(async { Harness::start() });
+    let publisher = harness.publisher(STREAM_KEY).unwrap();
+    let values = harness::sample(STREAM_KEY);
+    let mut connections = Vec::new();
+    let mut next_id = 1_u64;
+    let mut group = criterion.benchmark_group("fanout");
+    for &count in SUBSCRIBERS {
+        subscribe(&system, &harness, &publisher, &mut connections, count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| {
+                // A new ID every time, since duplicates are delivered differently
+                let id = format!("{next_id}-0");
+                next_id += 1;
+                system.block_on(async {
+                    let (published, frames) = futures_util::join!(
+                        publisher.publish(&id, values.clone()),
+                        join_all(connections.iter_mut().map(Connection::next_text)),
+                    );
+                    published.unwrap();
+                    assert!(frames.iter().all(Option::is_some), "Connection closed");
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Opens connections until there are `count`, and waits until all of them are
+/// subscribed.
+fn subscribe(
+    system: &SystemRunner,
+    harness: &Harness,
+    publisher: &Publisher,
+    connections: &mut Vec<Connection>,
+    count: usize,
+) {
+    system.block_on(async {
+        while connections.len() < count {
+            let mut connection = harness
+                .open(STREAM_KEY, &ConnectOptions::default())
+                .await
+                .unwrap();
+            let filter = serde_json::json!({
+                "min_block_height": connections.len() % DISTINCT_FILTERS,
+            });
+            connection.send(&filter.to_string());
+            connections.push(connection);
+        }
+        while publisher.subscribers() < count {
+            for connection in connections.iter_mut() {
+                connection.settle().await;
+            }
+            actix::clock::sleep(Duration::from_millis(1)).await;
+        }
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = fanout
+}
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+//! Parsing stream entries and matching filters, of every stream.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use events_api_websocket_server::benchmarks;
+
+criterion_group! {
+    name = parsing;
+    config = Criterion::default().sample_size(20);
+    targets = benchmarks::parsing_and_filters
+}
+criterion_main!(parsing);
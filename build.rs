@@ -0,0 +1,55 @@
+//! Build info for `/version` and `/status`: the build time, and the git commit
+//! if `GIT_COMMIT` isn't set, e.g. in Docker builds without `.git`. Also
+//! generates the gRPC service of `proto/events.proto`, with a vendored `protoc`
+//! so that building doesn't need one installed.
+
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={now}");
+    // Printing any rerun-if-changed (tonic_build prints the protos) turns off
+    // rerunning on every change, so the commit has to be watched too
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    if std::env::var("GIT_COMMIT").is_err() {
+        for path in ["HEAD", "logs/HEAD"] {
+            if let Some(path) = git(&["rev-parse", "--git-path", path]) {
+                println!("cargo:rerun-if-changed={path}");
+            }
+        }
+        if let Some(path) = git(&["symbolic-ref", "-q", "HEAD"])
+            .and_then(|head| git(&["rev-parse", "--git-path", &head]))
+        {
+            println!("cargo:rerun-if-changed={path}");
+        }
+        if let Some(commit) = git(&["rev-parse", "HEAD"]) {
+            println!("cargo:rustc-env=GIT_COMMIT={commit}");
+        }
+    }
+
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+    }
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/events.proto"], &["proto"])
+        .expect("Failed to compile proto/events.proto");
+}
+
+/// The trimmed output of a git command, or `None` if it failed.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+}
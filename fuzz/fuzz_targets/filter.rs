@@ -0,0 +1,11 @@
+//! Filter messages and filter commands of clients, for every endpoint.
+
+#![no_main]
+
+use events_api_websocket_server::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (&str, &str)| {
+    let (message, command) = input;
+    fuzzing::filter(message, command);
+});
@@ -0,0 +1,10 @@
+//! Stream entries of the indexer, decoded as the events of every endpoint.
+
+#![no_main]
+
+use events_api_websocket_server::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|fields: Vec<(String, Vec<u8>)>| {
+    fuzzing::from_redis(&fields);
+});
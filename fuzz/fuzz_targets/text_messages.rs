@@ -0,0 +1,47 @@
+//! Text messages of a client on a connection to an endpoint: proof-of-work
+//! answers, acks, and filters with options.
+
+#![no_main]
+
+use actix::{System, SystemRunner};
+use arbitrary::Arbitrary;
+use events_api_websocket_server::{
+    fuzzing,
+    harness::{ConnectOptions, Harness},
+};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    endpoint: u8,
+    v1: bool,
+    /// Whether the client has to solve a challenge first, of 0 or 1 bits so that
+    /// some answers are right.
+    proof_of_work_bits: Option<bool>,
+    messages: Vec<String>,
+}
+
+thread_local! {
+    static SERVER: (SystemRunner, Harness) = {
+        let system = System::new();
+        let harness = system.block_on(async { Harness::start() });
+        (system, harness)
+    };
+}
+
+fuzz_target!(|input: Input| {
+    let stream_keys = fuzzing::stream_keys();
+    let stream_key = stream_keys[usize::from(input.endpoint) % stream_keys.len()];
+    let options = ConnectOptions {
+        v1: input.v1,
+        proof_of_work_bits: input.proof_of_work_bits.map(u8::from),
+    };
+    SERVER.with(|(system, harness)| {
+        system.block_on(fuzzing::text_messages(
+            harness,
+            stream_key,
+            &options,
+            &input.messages,
+        ));
+    });
+});
@@ -0,0 +1,166 @@
+//! Abuse control for connections without an API key, configured with `anonymous`
+//! in the config file. Anonymous clients are told apart by a fingerprint, a hash
+//! of their IP address and `User-Agent`, that their event rate is limited by, and
+//! clients that connect too often in a minute, usually a reconnect loop, are
+//! banned for a while. Bans are kept in Redis, so that all instances refuse them.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use dashmap::DashMap;
+use redis::aio::ConnectionManager;
+use ring::digest;
+
+use crate::{config::AnonymousConfig, connections};
+
+/// Bans that this instance knows of, until when they last.
+static BANNED: LazyLock<DashMap<String, Instant>> = LazyLock::new(DashMap::new);
+/// Connections in the current minute by fingerprint.
+static CONNECTS: LazyLock<DashMap<String, (u64, u32)>> = LazyLock::new(DashMap::new);
+/// The minute of `CONNECTS`, which is cleared when the next one starts.
+static MINUTE: AtomicU64 = AtomicU64::new(0);
+
+pub struct AbuseControl {
+    pub config: AnonymousConfig,
+    connection: ConnectionManager,
+}
+
+fn redis_key(fingerprint: &str) -> String {
+    format!("events_api_ban_{fingerprint}")
+}
+
+/// Hash of the IP address and `User-Agent` of a request, in hex.
+pub fn fingerprint(req: &HttpRequest) -> String {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    fingerprint_of(&connections::client_addr(req), user_agent)
+}
+
+fn fingerprint_of(ip: &str, user_agent: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, format!("{ip}\n{user_agent}").as_bytes());
+    hash.as_ref()[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Counts a connection in the minute, and returns whether the client connected
+/// more often than allowed.
+fn count_connect(fingerprint: &str, max_per_min: u32, minute: u64) -> bool {
+    if MINUTE.swap(minute, Ordering::Relaxed) != minute {
+        CONNECTS.retain(|_, (connects_minute, _)| *connects_minute == minute);
+    }
+    let mut connects = CONNECTS
+        .entry(fingerprint.to_string())
+        .or_insert((minute, 0));
+    if connects.0 != minute {
+        *connects = (minute, 0);
+    }
+    connects.1 += 1;
+    connects.1 > max_per_min
+}
+
+impl AbuseControl {
+    pub fn new(config: AnonymousConfig, connection: ConnectionManager) -> Self {
+        Self { config, connection }
+    }
+
+    /// Seconds until the ban of a client ends, `None` if it isn't banned.
+    async fn ban_remaining(&self, fingerprint: &str) -> Option<u64> {
+        if let Some(until) = BANNED.get(fingerprint).map(|until| *until) {
+            match until.checked_duration_since(Instant::now()) {
+                Some(remaining) => return Some(remaining.as_secs().max(1)),
+                None => {
+                    BANNED.remove(fingerprint);
+                }
+            }
+        }
+        // Banned by another instance
+        let ttl: i64 = match redis::cmd("TTL")
+            .arg(redis_key(fingerprint))
+            .query_async(&mut self.connection.clone())
+            .await
+        {
+            Ok(ttl) => ttl,
+            Err(err) => {
+                tracing::warn!("Failed to check the ban of {fingerprint}: {err}");
+                return None;
+            }
+        };
+        let remaining = u64::try_from(ttl).ok().filter(|ttl| *ttl > 0)?;
+        BANNED.insert(
+            fingerprint.to_string(),
+            Instant::now() + Duration::from_secs(remaining),
+        );
+        Some(remaining)
+    }
+
+    async fn ban(&self, fingerprint: &str) {
+        let ban_sec = self.config.ban_sec;
+        tracing::warn!("Banning anonymous client {fingerprint} for {ban_sec}s");
+        BANNED.insert(
+            fingerprint.to_string(),
+            Instant::now() + Duration::from_secs(ban_sec),
+        );
+        if let Err(err) = redis::cmd("SET")
+            .arg(redis_key(fingerprint))
+            .arg(1)
+            .arg("EX")
+            .arg(ban_sec)
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await
+        {
+            tracing::warn!("Failed to save the ban of {fingerprint}: {err}");
+        }
+    }
+
+    /// The answer to an upgrade request of a banned client, or of one that is
+    /// banned now, or `None` to accept it.
+    pub async fn banned_response(&self, fingerprint: &str, now_ms: u128) -> Option<HttpResponse> {
+        let remaining = match self.ban_remaining(fingerprint).await {
+            Some(remaining) => remaining,
+            None => {
+                let minute = (now_ms / 60_000) as u64;
+                if !count_connect(fingerprint, self.config.max_connections_per_min, minute) {
+                    return None;
+                }
+                self.ban(fingerprint).await;
+                self.config.ban_sec
+            }
+        };
+        Some(
+            HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, remaining))
+                .body("Too many connections, use an API key or reconnect less often"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_and_counts_connects() {
+        let fingerprint = fingerprint_of("203.0.113.7", "bot/1.0");
+        assert_eq!(fingerprint.len(), 32);
+        assert_eq!(fingerprint, fingerprint_of("203.0.113.7", "bot/1.0"));
+        assert_ne!(fingerprint, fingerprint_of("203.0.113.7", "bot/1.1"));
+        assert_ne!(fingerprint, fingerprint_of("203.0.113.8", "bot/1.0"));
+
+        for _ in 0..3 {
+            assert!(!count_connect(&fingerprint, 3, 100));
+        }
+        assert!(count_connect(&fingerprint, 3, 100));
+        assert!(!count_connect(&fingerprint, 3, 101));
+    }
+}
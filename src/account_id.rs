@@ -0,0 +1,129 @@
+//! NEAR account IDs, checked when an event or filter is read, so that a filter
+//! with a typo is rejected when it's set instead of never matching.
+
+use std::{borrow::Borrow, fmt, io, ops::Deref};
+
+use borsh::BorshDeserialize;
+use serde::{Deserialize, Serialize};
+
+const MIN_LENGTH: usize = 2;
+const MAX_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AccountId(String);
+
+impl AccountId {
+    /// Checks the rules of the protocol: 2 to 64 characters, and parts of
+    /// lowercase letters and digits separated by `.`, with single `-` or `_` inside
+    /// of parts.
+    pub fn validate(id: &str) -> Result<(), String> {
+        if !(MIN_LENGTH..=MAX_LENGTH).contains(&id.len()) {
+            return Err(format!(
+                "Invalid account id {id:?}: must be {MIN_LENGTH} to {MAX_LENGTH} characters long"
+            ));
+        }
+        let mut after_separator = true;
+        for c in id.chars() {
+            match c {
+                'a'..='z' | '0'..='9' => after_separator = false,
+                '.' | '-' | '_' if !after_separator => after_separator = true,
+                '.' | '-' | '_' => {
+                    return Err(format!(
+                        "Invalid account id {id:?}: {c:?} at the start or after another separator"
+                    ))
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid account id {id:?}: invalid character {c:?}"
+                    ))
+                }
+            }
+        }
+        if after_separator {
+            return Err(format!("Invalid account id {id:?}: ends with a separator"));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for AccountId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::validate(&value)?;
+        Ok(Self(value))
+    }
+}
+
+impl TryFrom<&str> for AccountId {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::try_from(value.to_string())
+    }
+}
+
+impl From<AccountId> for String {
+    fn from(id: AccountId) -> Self {
+        id.0
+    }
+}
+
+impl Deref for AccountId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for AccountId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for AccountId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl BorshDeserialize for AccountId {
+    fn deserialize_reader<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        Self::try_from(String::deserialize_reader(reader)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_protocol_rules() {
+        for valid in [
+            "alice.near",
+            "a-b_c.tg",
+            "nft.herewallet.near",
+            "17208628f84f5d6ad33f0da3bbbeb27ffcb398eac501a31bd6ad2011e36133a1",
+            "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            "aa",
+        ] {
+            assert!(AccountId::validate(valid).is_ok(), "{valid}");
+        }
+        for invalid in [
+            "a",
+            "Alice.near",
+            "alice..near",
+            ".alice.near",
+            "alice.near.",
+            "alice-_near",
+            "alice near",
+            &"a".repeat(65),
+        ] {
+            assert!(AccountId::validate(invalid).is_err(), "{invalid}");
+        }
+    }
+}
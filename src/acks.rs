@@ -0,0 +1,113 @@
+//! At-least-once delivery of the WebSocket endpoints: with the `ack_timeout_ms`
+//! option, every event frame has an `"ack_id"` that the client replies with as
+//! `{"ack": <ack_id>}` once it processed the event, and events that aren't acked
+//! within `ack_timeout_ms` are sent again with the same `ack_id`. At most
+//! `MAX_UNACKED` events wait for their ack, the oldest are given up beyond that.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+pub const MAX_UNACKED: usize = 1000;
+
+/// The events that were sent and not acked yet.
+pub struct Acks<T> {
+    next_id: u64,
+    /// Events and when they were last sent, by ack ID, which is in the order they
+    /// were first sent.
+    pending: BTreeMap<u64, (T, Instant)>,
+}
+
+impl<T> Default for Acks<T> {
+    fn default() -> Self {
+        Self {
+            next_id: 1,
+            pending: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> Acks<T> {
+    /// Records an event that is sent now and returns its ack ID, and the oldest
+    /// event if it was given up on to make room.
+    pub fn add(&mut self, event: T, now: Instant) -> (u64, Option<T>) {
+        let mut given_up = None;
+        if self.pending.len() >= MAX_UNACKED {
+            if let Some((id, (event, _))) = self.pending.pop_first() {
+                tracing::debug!("Giving up on event {id} that wasn't acked");
+                given_up = Some(event);
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, (event, now));
+        (id, given_up)
+    }
+
+    /// Forgets an acked event. Unknown IDs, e.g. of events that were acked twice,
+    /// are ignored.
+    pub fn ack(&mut self, id: u64) {
+        self.pending.remove(&id);
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &T> {
+        self.pending.values().map(|(event, _)| event)
+    }
+
+    /// The events that were last sent `timeout` or longer ago, to send them again
+    /// now.
+    pub fn due(&mut self, timeout: Duration, now: Instant) -> Vec<(u64, &T)> {
+        self.pending
+            .iter_mut()
+            .filter(|(_, (_, sent))| now.duration_since(*sent) >= timeout)
+            .map(|(id, (event, sent))| {
+                *sent = now;
+                (*id, &*event)
+            })
+            .collect()
+    }
+}
+
+/// The ack ID of a client message, if it's an ack.
+pub fn parse(text: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Ack {
+        ack: u64,
+    }
+    serde_json::from_str::<Ack>(text).ok().map(|ack| ack.ack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redelivers_unacked_events() {
+        let timeout = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut acks = Acks::default();
+        let (first, _) = acks.add("a", start);
+        let (second, _) = acks.add("b", start);
+        assert!(acks.due(timeout, start).is_empty());
+        acks.ack(first);
+        assert_eq!(acks.due(timeout, start + timeout), vec![(second, &"b")]);
+        // Sent again, so it's due a timeout later
+        assert!(acks.due(timeout, start + timeout).is_empty());
+        assert_eq!(acks.due(timeout, start + timeout * 2).len(), 1);
+
+        for _ in 0..MAX_UNACKED - 1 {
+            assert_eq!(acks.add("c", start).1, None);
+        }
+        assert_eq!(acks.add("d", start).1, Some("b"));
+        assert_eq!(acks.pending.len(), MAX_UNACKED);
+        assert!(!acks.pending.contains_key(&second));
+
+        assert_eq!(parse(r#"{"ack": 5}"#), Some(5));
+        assert_eq!(parse(r#"{"ack": 5, "owner_id": "alice.near"}"#), None);
+        assert_eq!(parse(r#"{"owner_id": "alice.near"}"#), None);
+    }
+}
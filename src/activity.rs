@@ -0,0 +1,240 @@
+//! `/v0/me/activity`, the private stream of a NEAR account: the events of all
+//! streams that involve the account that the client signed in with (see
+//! `near_auth`), on one connection. Filters can narrow it down to some streams, but
+//! never to other accounts, so wallet apps can use it without being able to see
+//! the data of other users.
+
+use std::{collections::HashSet, sync::Arc, time::Instant};
+
+use actix::prelude::*;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use bytestring::ByteString;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+
+use crate::{
+    broadcast::{for_each_event_type, Broadcasts, EventTypeVisitor},
+    drain, near_auth,
+    protocol::ControlFrame,
+    types::AccountId,
+    EventFilter, FromRedis, Networks, TaggedEvent, CLIENT_TIMEOUT, DEFAULT_NETWORK,
+    HEARTBEAT_INTERVAL,
+};
+
+#[derive(Serialize)]
+struct ActivityFrame<'a, E> {
+    stream: &'static str,
+    #[serde(flatten)]
+    event: TaggedEvent<'a, E>,
+}
+
+/// A message of the client.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActivityFilter {
+    /// Stream keys to get events of, all if not set.
+    streams: Option<Vec<String>>,
+    /// Can only be the account of the client.
+    involved_account_ids: Option<Vec<AccountId>>,
+}
+
+pub async fn activity(
+    req: HttpRequest,
+    stream: web::Payload,
+    networks: web::Data<Networks>,
+    broadcasts: web::Data<Broadcasts>,
+) -> Result<HttpResponse, Error> {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return Ok(HttpResponse::NotFound().body(format!("Unknown network: {network}")));
+    }
+    let Some(account_id) = near_auth::authenticate(&req, &network).await? else {
+        return Ok(HttpResponse::Unauthorized().body("Sign in with a NEAR wallet first"));
+    };
+    if let Some(response) = drain::draining_response(&req) {
+        return Ok(response);
+    }
+    ws::start(
+        ActivityWebSocket {
+            last_heartbeat: Instant::now(),
+            account_id,
+            network,
+            broadcasts: broadcasts.into_inner(),
+            streams: None,
+            forwarders: Vec::new(),
+        },
+        &req,
+        stream,
+    )
+}
+
+struct ActivityWebSocket {
+    last_heartbeat: Instant,
+    account_id: AccountId,
+    network: String,
+    broadcasts: Arc<Broadcasts>,
+    /// Streams that the client chose, all if `None`.
+    streams: Option<HashSet<String>>,
+    /// Forward the events of the account to this actor, one per stream.
+    forwarders: Vec<JoinHandle<()>>,
+}
+
+/// Starts a forwarder for every stream whose filter has `involved_account_ids`.
+struct Forwarders<'a> {
+    socket: &'a mut ActivityWebSocket,
+    addr: Addr<ActivityWebSocket>,
+}
+
+impl EventTypeVisitor for Forwarders<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        if !F::filter_fields().contains(&"involved_account_ids") {
+            return;
+        }
+        let filter = serde_json::json!({ "involved_account_ids": [self.socket.account_id] });
+        let Ok(filter) = serde_json::from_value::<F>(filter) else {
+            return;
+        };
+        let mut events = self.socket.broadcasts.subscribe::<E>(&self.socket.network);
+        let addr = self.addr.clone();
+        self.socket.forwarders.push(tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(count)) => {
+                        tracing::warn!("Activity client fell behind by {count} events");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if !filter.matches(&event.event) {
+                    continue;
+                }
+                let frame = ActivityFrame {
+                    stream: E::STREAM_KEY,
+                    event: TaggedEvent {
+                        source: &event.source,
+                        stream_id: event.id,
+                        event: &event.event,
+                    },
+                };
+                let frame = serde_json::to_string(&frame).unwrap().into();
+                addr.do_send(Frame(E::STREAM_KEY, frame));
+            }
+        }));
+    }
+}
+
+impl Actor for ActivityWebSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+            }
+            ctx.ping(b"");
+        });
+        let addr = ctx.address();
+        for_each_event_type(&mut Forwarders { socket: self, addr });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        for forwarder in self.forwarders.drain(..) {
+            forwarder.abort();
+        }
+    }
+}
+
+impl ActivityWebSocket {
+    fn configure(&mut self, text: &str) -> Result<(), String> {
+        let filter = serde_json::from_str::<ActivityFilter>(text).map_err(|err| err.to_string())?;
+        if filter
+            .involved_account_ids
+            .is_some_and(|accounts| accounts.iter().any(|account| *account != self.account_id))
+        {
+            return Err(format!(
+                "Only the events of {} are delivered here",
+                self.account_id
+            ));
+        }
+        self.streams = filter.streams.map(|streams| streams.into_iter().collect());
+        Ok(())
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ActivityWebSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+            }
+            Ok(ws::Message::Text(text)) => {
+                if let Err(message) = self.configure(&text) {
+                    let frame = ControlFrame::Error { message: &message };
+                    ctx.text(serde_json::to_string(&frame).unwrap());
+                }
+            }
+            _ => ctx.stop(),
+        }
+    }
+}
+
+/// The frame of an event of a stream.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Frame(&'static str, ByteString);
+
+impl Handler<Frame> for ActivityWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, Frame(stream, frame): Frame, ctx: &mut Self::Context) -> Self::Result {
+        if self
+            .streams
+            .as_ref()
+            .is_none_or(|streams| streams.contains(stream))
+        {
+            ctx.text(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_other_accounts() {
+        let mut socket = ActivityWebSocket {
+            last_heartbeat: Instant::now(),
+            account_id: AccountId::try_from("alice.near").unwrap(),
+            network: DEFAULT_NETWORK.to_string(),
+            broadcasts: Arc::new(Broadcasts::default()),
+            streams: None,
+            forwarders: Vec::new(),
+        };
+        assert!(socket
+            .configure(r#"{"streams": ["nft_transfer"], "involved_account_ids": ["alice.near"]}"#)
+            .is_ok());
+        assert!(socket.streams.as_ref().unwrap().contains("nft_transfer"));
+        assert!(socket
+            .configure(r#"{"involved_account_ids": ["alice.near", "bob.near"]}"#)
+            .is_err());
+        assert!(socket.configure(r#"{"contract_id": "nft.near"}"#).is_err());
+        assert!(socket.configure("{}").is_ok());
+        assert!(socket.streams.is_none());
+    }
+}
@@ -0,0 +1,219 @@
+//! Admin API at `/admin`, enabled by setting `ADMIN_TOKEN`. Requests must have an
+//! `Authorization: Bearer <ADMIN_TOKEN>` header.
+
+use std::sync::Arc;
+
+use actix::prelude::*;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use dashmap::DashSet;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    connections, drain, readers, usage, EventFilter, EventWebSocket, FromRedis, NetworkSockets,
+    Notice, Server, UnsubscribeFromEvents,
+};
+
+pub struct AdminToken(pub String);
+
+impl AdminToken {
+    /// The token in `ADMIN_TOKEN`, or `None` if it's not set. Panics if it's
+    /// empty, since anyone could send an empty token.
+    pub fn from_env() -> Option<Self> {
+        let token = std::env::var("ADMIN_TOKEN").ok()?;
+        assert!(
+            !token.trim().is_empty(),
+            "ADMIN_TOKEN is empty, unset it to disable the admin API"
+        );
+        Some(Self(token))
+    }
+}
+
+/// Compares the digests of the tokens in constant time, so that neither the
+/// token nor its length can be guessed from how long the comparison takes.
+pub fn authorized(req: &HttpRequest, token: &AdminToken) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| {
+            let value = digest::digest(&digest::SHA256, value.as_bytes());
+            let token = digest::digest(&digest::SHA256, token.0.as_bytes());
+            value.as_ref().ct_eq(token.as_ref()).into()
+        })
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/notice").route(web::post().to(notice)))
+        .service(web::resource("/drain").route(web::post().to(drain::drain)))
+        .service(web::resource("/connections").route(web::get().to(connections)))
+        .service(web::resource("/usage").route(web::get().to(usage::export)))
+        .service(web::resource("/readers").route(web::get().to(list_readers)))
+        .service(web::resource("/readers/restart").route(web::post().to(restart_readers)));
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoticeRequest {
+    message: String,
+    /// Only notify clients of this network.
+    network: Option<String>,
+    /// Only notify clients of these endpoints, e.g. `["nft_mint", "trade_swap"]`.
+    endpoints: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+struct NoticeResponse {
+    notified: usize,
+}
+
+/// Sends a notice to all clients that match the request.
+async fn notice(
+    req: HttpRequest,
+    body: web::Json<NoticeRequest>,
+    token: web::Data<AdminToken>,
+    server: web::Data<Addr<Server>>,
+) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let body = body.into_inner();
+    match server
+        .send(Broadcast {
+            notice: Arc::new(Notice::new(&body.message)),
+            network: body.network,
+            endpoints: body.endpoints,
+        })
+        .await
+    {
+        Ok(notified) => HttpResponse::Ok().json(NoticeResponse { notified }),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+/// Lists the connected WebSocket clients of the event endpoints.
+async fn connections(req: HttpRequest, token: web::Data<AdminToken>) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(connections::list())
+}
+
+/// Lists the stream readers and their state.
+async fn list_readers(req: HttpRequest, token: web::Data<AdminToken>) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(readers::list())
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RestartRequest {
+    /// Only restart the readers of this source.
+    source: Option<String>,
+    /// Only restart the readers of this stream key, with the source's prefix.
+    stream_key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RestartResponse {
+    restarted: usize,
+}
+
+/// Restarts stream readers, e.g. one that is stuck, without restarting the server.
+async fn restart_readers(
+    req: HttpRequest,
+    body: Option<web::Json<RestartRequest>>,
+    token: web::Data<AdminToken>,
+) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    let restarted = readers::restart(body.source.as_deref(), body.stream_key.as_deref());
+    HttpResponse::Ok().json(RestartResponse { restarted })
+}
+
+/// Sends a notice to the clients of `network` and `endpoints`, or all if they're
+/// not set. Responds with the number of notified clients.
+#[derive(Message)]
+#[rtype(result = "usize")]
+struct Broadcast {
+    notice: Arc<Notice>,
+    network: Option<String>,
+    endpoints: Option<Vec<String>>,
+}
+
+impl Handler<Broadcast> for Server {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Broadcast, _ctx: &mut Self::Context) -> Self::Result {
+        self.networks
+            .iter()
+            .filter(|(network, _)| msg.network.as_ref().is_none_or(|n| n == *network))
+            .map(|(_, sockets)| sockets.broadcast(&msg))
+            .sum()
+    }
+}
+
+impl NetworkSockets {
+    fn broadcast(&self, msg: &Broadcast) -> usize {
+        notify(&self.nft_mint_sockets, msg)
+            + notify(&self.nft_transfer_sockets, msg)
+            + notify(&self.nft_burn_sockets, msg)
+            + notify(&self.potlock_donation_sockets, msg)
+            + notify(&self.potlock_pot_project_donation_sockets, msg)
+            + notify(&self.potlock_pot_donation_sockets, msg)
+            + notify(&self.trade_pool_sockets, msg)
+            + notify(&self.trade_swap_sockets, msg)
+            + notify(&self.trade_pool_change_sockets, msg)
+    }
+}
+
+fn notify<E: FromRedis + Unpin + 'static, F: EventFilter<E> + Unpin + 'static>(
+    sockets: &DashSet<Addr<EventWebSocket<E, F>>>,
+    msg: &Broadcast,
+) -> usize
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    if !msg
+        .endpoints
+        .as_ref()
+        .is_none_or(|endpoints| endpoints.iter().any(|e| e == E::STREAM_KEY))
+    {
+        return 0;
+    }
+    for socket in sockets.iter() {
+        socket.do_send(Arc::clone(&msg.notice));
+    }
+    sockets.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn checks_the_token() {
+        let token = AdminToken("secret".to_string());
+        let request = |authorization: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, authorization))
+                .to_http_request()
+        };
+        assert!(authorized(&request("Bearer secret"), &token));
+        for authorization in ["Bearer secre", "Bearer secret2", "Bearer ", "secret"] {
+            assert!(
+                !authorized(&request(authorization), &token),
+                "{authorization}"
+            );
+        }
+        assert!(!authorized(
+            &TestRequest::default().to_http_request(),
+            &token
+        ));
+    }
+}
@@ -0,0 +1,51 @@
+//! API keys, configured with `api_keys` in the config file. Clients send their key
+//! as `?api_key=<key>` or an `Authorization: Bearer <key>` header. Connections
+//! without a key are anonymous, connections with an unknown key are rejected.
+
+use std::{collections::HashMap, sync::Arc};
+
+use actix_web::{error::ErrorUnauthorized, http::header, web, Error, HttpRequest};
+use serde::Deserialize;
+
+use crate::config::ApiKeyConfig;
+
+pub type ApiKey = Arc<ApiKeyConfig>;
+
+pub struct ApiKeys(HashMap<String, ApiKey>);
+
+impl ApiKeys {
+    pub fn new(keys: Vec<ApiKeyConfig>) -> Self {
+        Self(
+            keys.into_iter()
+                .map(|key| (key.key.clone(), Arc::new(key)))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyQuery {
+    api_key: Option<String>,
+}
+
+/// Returns the API key of the request, `None` if it has none, or an error for
+/// unknown keys.
+pub fn authenticate(req: &HttpRequest) -> Result<Option<ApiKey>, Error> {
+    let from_query = web::Query::<ApiKeyQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().api_key);
+    let from_header = || {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+    };
+    let Some(key) = from_query.or_else(from_header) else {
+        return Ok(None);
+    };
+    req.app_data::<web::Data<ApiKeys>>()
+        .and_then(|keys| keys.0.get(&key).cloned())
+        .map(Some)
+        .ok_or_else(|| ErrorUnauthorized("Invalid API key"))
+}
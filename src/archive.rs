@@ -0,0 +1,287 @@
+//! Archives events in ClickHouse, configured with `archive` in the config file, so
+//! that `/history` can serve events that are no longer retained in Redis.
+//!
+//! Every archived stream is read from Redis by its own task, with its own checkpoint,
+//! and inserted over the HTTP interface as `JSONEachRow`. Every row has the fields
+//! of the event (the same JSON as the WebSocket endpoints of protocol 1), and:
+//! - `stream_ms`, `stream_seq`: the parts of the stream ID
+//! - `json`: the whole event as a JSON string
+//! - `fields`: the stream entry as a JSON object of strings, read back by `/history`
+//!
+//! Fields that the table has no column for are skipped, so a table can have
+//! columns for the event fields that it should be queried by. Batches are retried
+//! until they're inserted, so rows can be duplicated; the default schema uses a
+//! `ReplacingMergeTree` that removes them eventually, and reads skip them.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use redis::{aio::ConnectionManager, FromRedisValue};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    config::ArchiveConfig,
+    http_client::Endpoint,
+    redis_reader::{load_id, read_page, save_id},
+    replay::StreamId,
+    types::BlockHeight,
+    EventFilter, FromRedis, RedisSource, StreamSource, TaggedEvent,
+};
+
+/// Stream entries inserted at once.
+const BATCH_SIZE: usize = 1000;
+/// How often a stream that is archived up to its end is checked for new entries.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+pub struct Archive {
+    endpoint: Endpoint,
+    config: ArchiveConfig,
+}
+
+/// A stream entry read back from the archive.
+pub type ArchivedEntry = (StreamId, Arc<str>, HashMap<String, redis::Value>);
+
+impl Archive {
+    pub fn new(config: ArchiveConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            endpoint: Endpoint::parse(&config.url)?,
+            config,
+        })
+    }
+
+    /// The archive has the events of this stream and network.
+    pub fn serves(&self, network: &str, stream_key: &str) -> bool {
+        self.config.network == network && self.config.archives(stream_key)
+    }
+
+    async fn query(&self, query: &str) -> anyhow::Result<String> {
+        let mut headers = Vec::new();
+        if let Some(user) = &self.config.user {
+            headers.push(("X-ClickHouse-User", user.as_str()));
+        }
+        if let Some(password) = &self.config.password {
+            headers.push(("X-ClickHouse-Key", password.as_str()));
+        }
+        if let Some(database) = &self.config.database {
+            headers.push(("X-ClickHouse-Database", database.as_str()));
+        }
+        self.endpoint
+            .post(
+                "/?input_format_skip_unknown_fields=1",
+                &headers,
+                "text/plain",
+                query,
+            )
+            .await
+    }
+
+    /// Reads up to `count` archived entries of the stream after `after`, in stream
+    /// order, only those in the block range if it's set.
+    pub async fn read(
+        &self,
+        stream_key: &str,
+        after: Option<StreamId>,
+        from_block: Option<BlockHeight>,
+        to_block: Option<BlockHeight>,
+        count: usize,
+    ) -> anyhow::Result<Vec<ArchivedEntry>> {
+        let StreamId(ms, seq) = after.unwrap_or(StreamId(0, 0));
+        let mut conditions = vec![format!("(stream_ms, stream_seq) > ({ms}, {seq})")];
+        if let Some(from_block) = from_block {
+            conditions.push(format!("block_height >= {from_block}"));
+        }
+        if let Some(to_block) = to_block {
+            conditions.push(format!("block_height <= {to_block}"));
+        }
+        let query = format!(
+            "SELECT stream_ms, stream_seq, source, fields FROM {} WHERE {} \
+             ORDER BY stream_ms, stream_seq LIMIT 1 BY stream_ms, stream_seq, source \
+             LIMIT {count} FORMAT JSONEachRow",
+            self.config.table(stream_key),
+            conditions.join(" AND "),
+        );
+
+        #[derive(Deserialize)]
+        struct Row {
+            #[serde(deserialize_with = "number_or_string")]
+            stream_ms: u64,
+            #[serde(deserialize_with = "number_or_string")]
+            stream_seq: u64,
+            source: String,
+            fields: String,
+        }
+        let mut entries = Vec::new();
+        for line in self.query(&query).await?.lines() {
+            let row = serde_json::from_str::<Row>(line)?;
+            let fields = serde_json::from_str::<HashMap<String, String>>(&row.fields)?
+                .into_iter()
+                .map(|(key, value)| (key, redis::Value::Data(value.into_bytes())))
+                .collect();
+            entries.push((
+                StreamId(row.stream_ms, row.stream_seq),
+                row.source.into(),
+                fields,
+            ));
+        }
+        Ok(entries)
+    }
+}
+
+/// ClickHouse quotes 64-bit integers in JSON by default.
+fn number_or_string<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u64),
+        String(String),
+    }
+    match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(number) => Ok(number),
+        NumberOrString::String(string) => string.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// Starts archiving the configured streams of every source of the archive's network.
+pub fn spawn(archive: &Arc<Archive>, sources: &[RedisSource]) {
+    for source in sources {
+        if source.network == archive.config.network {
+            for_each_event_type(&mut Archivers { archive, source });
+        }
+    }
+}
+
+struct Archivers<'a> {
+    archive: &'a Arc<Archive>,
+    source: &'a RedisSource,
+}
+
+impl EventTypeVisitor for Archivers<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        if !self.source.reads(E::STREAM_KEY) || !self.archive.config.archives(E::STREAM_KEY) {
+            return;
+        }
+        tokio::spawn(archive_stream::<E>(
+            Arc::clone(self.archive),
+            self.source.stream_source(E::STREAM_KEY),
+        ));
+    }
+}
+
+async fn archive_stream<E: Serialize + FromRedis>(archive: Arc<Archive>, source: StreamSource) {
+    let StreamSource {
+        stream_key,
+        connection,
+        ..
+    } = &source;
+    let table = archive.config.table(E::STREAM_KEY);
+    let checkpoint_key = format!("events_api_archive_last_id_{stream_key}");
+    let mut last_id = loop {
+        match start(&archive, &table, &checkpoint_key, connection.clone()).await {
+            Ok(last_id) => break last_id,
+            Err(err) => tracing::warn!("Failed to start archiving {stream_key}: {err}"),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    };
+    tracing::info!("Archiving {stream_key} into {table}");
+    loop {
+        let batch = archive_batch::<E>(&archive, &source, &table, last_id).await;
+        match batch {
+            Ok(Some(id)) => {
+                last_id = Some(id);
+                if let Err(err) =
+                    save_id(connection.clone(), &checkpoint_key, &id.to_string()).await
+                {
+                    tracing::warn!("Failed to save archive checkpoint of {stream_key}: {err}");
+                }
+            }
+            Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+            Err(err) => {
+                tracing::warn!("Failed to archive {stream_key}: {err}");
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Creates the table if configured, and returns the last archived ID.
+async fn start(
+    archive: &Archive,
+    table: &str,
+    checkpoint_key: &str,
+    connection: ConnectionManager,
+) -> anyhow::Result<Option<StreamId>> {
+    if archive.config.create_tables {
+        archive
+            .query(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (\
+                 stream_ms UInt64, stream_seq UInt64, source String, block_height UInt64, \
+                 json String, fields String\
+                 ) ENGINE = ReplacingMergeTree ORDER BY (stream_ms, stream_seq, source)"
+            ))
+            .await?;
+    }
+    match load_id(connection, checkpoint_key).await? {
+        Some(id) => Ok(Some(id.parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Inserts the next batch of entries, returns the ID of the last one, or `None`
+/// if there are no new entries.
+async fn archive_batch<E: Serialize + FromRedis>(
+    archive: &Archive,
+    source: &StreamSource,
+    table: &str,
+    after: Option<StreamId>,
+) -> anyhow::Result<Option<StreamId>> {
+    let entries = read_page(
+        source.connection.clone(),
+        &source.stream_key,
+        after,
+        BATCH_SIZE,
+    )
+    .await?;
+    let Some((last_id, _)) = entries.last() else {
+        return Ok(None);
+    };
+    let last_id = last_id.parse::<StreamId>()?;
+
+    let mut query = format!("INSERT INTO {table} FORMAT JSONEachRow\n");
+    for (id, values) in entries {
+        let stream_id = id.parse::<StreamId>()?;
+        let fields = values
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), String::from_redis_value(value)?.into())))
+            .collect::<redis::RedisResult<serde_json::Map<_, _>>>()?;
+        let mut row = match E::decode(&values, source.encoding) {
+            Ok(event) => serde_json::to_value(TaggedEvent {
+                source: &source.name,
+                stream_id,
+                event: &event,
+            })?,
+            Err(err) => {
+                // Still archived, in case a later version can read it
+                tracing::warn!(%id, "Archiving an event that can't be deserialized: {err}");
+                json!({ "source": &*source.name, "stream_id": stream_id })
+            }
+        };
+        row["json"] = row.to_string().into();
+        row["stream_ms"] = stream_id.0.into();
+        row["stream_seq"] = stream_id.1.into();
+        row["fields"] = serde_json::Value::Object(fields).to_string().into();
+        if row["block_height"].is_null() {
+            row["block_height"] = 0.into();
+        }
+        query.push_str(&row.to_string());
+        query.push('\n');
+    }
+    archive.query(&query).await?;
+    Ok(Some(last_id))
+}
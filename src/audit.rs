@@ -0,0 +1,96 @@
+//! Delivery audit log of API keys with `audit` set: the IDs of all events that
+//! were delivered to a connection, to settle disputes about missed events and for
+//! SLA reports. IDs are collected and written once a second, as a log line or an
+//! entry of the Redis stream `events_api_audit_<key name>`.
+
+use std::time::Duration;
+
+use redis::aio::ConnectionManager;
+
+use crate::{
+    config::{ApiKeyConfig, AuditTarget},
+    replay::StreamId,
+    unix_time_ms,
+};
+
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Approximate length that the Redis streams are trimmed to.
+const MAX_STREAM_LEN: usize = 1_000_000;
+
+pub struct AuditLog {
+    /// Where records are written, `None` for the log.
+    redis: Option<(ConnectionManager, String)>,
+    connection_id: String,
+    network: String,
+    endpoint: &'static str,
+    delivered: Vec<StreamId>,
+}
+
+impl AuditLog {
+    /// Returns `None` if the key isn't audited.
+    pub fn new(
+        api_key: &ApiKeyConfig,
+        connection: &ConnectionManager,
+        connection_id: &str,
+        network: &str,
+        endpoint: &'static str,
+    ) -> Option<Self> {
+        let redis = match api_key.audit? {
+            AuditTarget::Log => None,
+            AuditTarget::Redis => Some((
+                connection.clone(),
+                format!("events_api_audit_{}", api_key.name),
+            )),
+        };
+        Some(Self {
+            redis,
+            connection_id: connection_id.to_string(),
+            network: network.to_string(),
+            endpoint,
+            delivered: Vec::new(),
+        })
+    }
+
+    pub fn record(&mut self, id: StreamId) {
+        self.delivered.push(id);
+    }
+
+    /// Writes the IDs delivered since the last flush, in the background.
+    pub fn flush(&mut self) {
+        if self.delivered.is_empty() {
+            return;
+        }
+        let stream_ids = std::mem::take(&mut self.delivered)
+            .iter()
+            .map(StreamId::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let Some((connection, redis_key)) = &self.redis else {
+            tracing::info!(target: "audit", stream_ids, "Delivered");
+            return;
+        };
+        let mut connection = connection.clone();
+        let command = redis::cmd("XADD")
+            .arg(redis_key)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(MAX_STREAM_LEN)
+            .arg("*")
+            .arg("connection_id")
+            .arg(&self.connection_id)
+            .arg("network")
+            .arg(&self.network)
+            .arg("endpoint")
+            .arg(self.endpoint)
+            .arg("delivered_at_ms")
+            .arg(unix_time_ms().to_string())
+            .arg("stream_ids")
+            .arg(stream_ids)
+            .clone();
+        tokio::spawn(async move {
+            if let Err(err) = command.query_async::<_, ()>(&mut connection).await {
+                tracing::warn!("Failed to write the audit log: {err}");
+            }
+        });
+    }
+}
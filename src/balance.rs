@@ -0,0 +1,120 @@
+//! Amounts in yocto, which are decimal strings in JSON because they don't fit
+//! into the numbers of JavaScript. They're parsed once when an event or filter is
+//! read, so that an invalid amount in a filter is rejected when it's set instead
+//! of never matching.
+
+use std::{fmt, io};
+
+use borsh::BorshDeserialize;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Balance(pub u128);
+
+/// A signed amount, like the balance changes of trades, where sold tokens are
+/// negative.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct BalanceChange(pub i128);
+
+impl Balance {
+    /// The amount in whole tokens, e.g. `12.5` for `12500000` with 6 decimals.
+    pub fn to_decimal(self, decimals: u8) -> String {
+        decimal(false, self.0, decimals)
+    }
+}
+
+impl BalanceChange {
+    /// The amount in whole tokens, e.g. `-12.5` for `-12500000` with 6 decimals.
+    pub fn to_decimal(self, decimals: u8) -> String {
+        decimal(self.0 < 0, self.0.unsigned_abs(), decimals)
+    }
+}
+
+fn decimal(negative: bool, amount: u128, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = usize::from(decimals);
+    let digits = format!("{digits:0>width$}", width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    let sign = if negative { "-" } else { "" };
+    if fraction.is_empty() {
+        format!("{sign}{whole}")
+    } else {
+        format!("{sign}{whole}.{fraction}")
+    }
+}
+
+macro_rules! string_amount {
+    ($type:ident) => {
+        impl TryFrom<String> for $type {
+            type Error = String;
+
+            fn try_from(value: String) -> Result<Self, Self::Error> {
+                value
+                    .parse()
+                    .map(Self)
+                    .map_err(|err| format!("Invalid amount {value:?}: {err}"))
+            }
+        }
+
+        impl From<$type> for String {
+            fn from(amount: $type) -> Self {
+                amount.0.to_string()
+            }
+        }
+
+        impl fmt::Display for $type {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        /// A string in borsh too, like in JSON.
+        impl BorshDeserialize for $type {
+            fn deserialize_reader<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+                Self::try_from(String::deserialize_reader(reader)?)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            }
+        }
+    };
+}
+
+string_amount!(Balance);
+string_amount!(BalanceChange);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_a_string() {
+        let amount =
+            serde_json::from_str::<Balance>(r#""340282366920938463463374607431768211455""#);
+        assert_eq!(amount.unwrap(), Balance(u128::MAX));
+        assert!(serde_json::from_str::<Balance>("1").is_err());
+        assert!(serde_json::from_str::<Balance>(r#""-1""#).is_err());
+        assert_eq!(
+            serde_json::to_string(&BalanceChange(-5)).unwrap(),
+            r#""-5""#
+        );
+    }
+
+    #[test]
+    fn decimal_amounts() {
+        assert_eq!(Balance(12_500_000).to_decimal(6), "12.5");
+        assert_eq!(Balance(5).to_decimal(6), "0.000005");
+        assert_eq!(Balance(3_000_000).to_decimal(6), "3");
+        assert_eq!(Balance(42).to_decimal(0), "42");
+        assert_eq!(BalanceChange(-1_250).to_decimal(3), "-1.25");
+        assert_eq!(
+            Balance(u128::MAX).to_decimal(24),
+            "340282366920938.463463374607431768211455"
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! Benchmarks of parsing stream entries and matching filters, for the
+//! `cargo bench` targets in `benches/`, which can't reach the event types. Only in
+//! builds with `--features bench`, like:
+//!
+//! ```sh
+//! cargo bench --features bench
+//! ```
+
+use criterion::{black_box, Criterion};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    config::Encoding,
+    synthetic, EventFilter, FromRedis,
+};
+
+/// A typical filter of every stream, that some of the synthetic events match.
+const FILTERS: &[(&str, &str)] = &[
+    ("nft_mint", r#"{"contract_id": "nft.herewallet.near"}"#),
+    (
+        "nft_transfer",
+        r#"{"involved_account_ids": ["alice.near", "bob.near"]}"#,
+    ),
+    ("nft_burn", r#"{"owner_id": "alice.near"}"#),
+    ("potlock_donation", r#"{"donor_id": "alice.near"}"#),
+    (
+        "potlock_pot_project_donation",
+        r#"{"min_amount_near": "1000000000000000000000000"}"#,
+    ),
+    ("potlock_pot_donation", r#"{"referrer_id": "bob.near"}"#),
+    ("trade_pool", r#"{"account_id": "alice.near"}"#),
+    (
+        "trade_swap",
+        r#"{"min_balance_changes": {"wrap.near": "1000000000000000000000000"}}"#,
+    ),
+    ("trade_pool_change", r#"{"pool_id": "REF-1"}"#),
+];
+
+struct Bench<'a> {
+    criterion: &'a mut Criterion,
+}
+
+impl EventTypeVisitor for Bench<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        let values = synthetic::sample(E::STREAM_KEY);
+        let (_, filter) = FILTERS
+            .iter()
+            .find(|(stream, _)| *stream == E::STREAM_KEY)
+            .unwrap_or_else(|| panic!("No benchmark filter for {}", E::STREAM_KEY));
+        let filter = serde_json::from_str::<F>(filter).unwrap();
+        let event = E::decode(&values, Encoding::Json).unwrap();
+        self.criterion
+            .bench_function(&format!("from_redis/{}", E::STREAM_KEY), |b| {
+                b.iter(|| E::decode(black_box(&values), Encoding::Json).unwrap())
+            });
+        self.criterion
+            .bench_function(&format!("matches/{}", E::STREAM_KEY), |b| {
+                b.iter(|| filter.matches(black_box(&event)))
+            });
+    }
+}
+
+/// Parses a synthetic entry of every stream, and matches the event against the
+/// typical filter of the stream.
+pub fn parsing_and_filters(criterion: &mut Criterion) {
+    for_each_event_type(&mut Bench { criterion });
+}
@@ -0,0 +1,293 @@
+//! Command line subscriber: connects to an endpoint and prints its events to
+//! stdout, one JSON object per line, to pipe them into `jq` or scripts.
+//! Reconnects when the connection fails and resumes after the last event, with
+//! `?from_stream_id=`, and follows the `reconnect` frames of draining servers.
+//!
+//! ```sh
+//! cargo run --bin subscribe -- wss://ws-events.intear.tech/v0/nft/nft_transfer \
+//!     --field involved_account_ids='["alice.near"]' | jq .token_ids
+//! ```
+
+use std::{
+    io::{ErrorKind, Write},
+    process::ExitCode,
+    time::Duration,
+};
+
+use actix_web::http::Uri;
+use events_api_websocket_server::ws_client::WsClient;
+use serde_json::{Map, Value};
+
+const USAGE: &str = "\
+Usage: subscribe <ws-url> [options]
+
+Prints the events of an endpoint, e.g. wss://ws-events.intear.tech/v0/nft/nft_mint,
+to stdout as NDJSON. Errors and reconnects are logged to stderr.
+
+Options:
+  --filter <json>           Filter message, e.g. '{\"token_account_id\": \"nft.near\"}'
+  --filter-file <path>      Read the filter message from a file
+  --field <name>=<value>    Set a field of the filter, can be repeated. The value
+                            is JSON if it parses, e.g. '[\"a.near\"]', else a string
+  --api-key <key>           API key, sent as `Authorization: Bearer <key>`
+  --from-stream-id <id>     Start after this stream ID instead of with live events
+  --no-reconnect            Exit when the connection closes";
+
+/// The delay before the first reconnect, doubled after every failure in a row.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    url: String,
+    filter: Map<String, Value>,
+    api_key: Option<String>,
+    from_stream_id: Option<String>,
+    reconnect: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut url = None;
+    let mut filter = Map::new();
+    let mut fields = Vec::new();
+    let mut api_key = None;
+    let mut from_stream_id = None;
+    let mut reconnect = true;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+        match arg.as_str() {
+            "--filter" | "--filter-file" => {
+                let text = if arg == "--filter" {
+                    value(&arg)?
+                } else {
+                    let path = value(&arg)?;
+                    std::fs::read_to_string(&path)
+                        .map_err(|err| format!("Failed to read {path}: {err}"))?
+                };
+                filter = serde_json::from_str(&text)
+                    .map_err(|err| format!("The filter isn't a JSON object: {err}"))?;
+            }
+            "--field" => {
+                let field = value(&arg)?;
+                let Some((name, value)) = field.split_once('=') else {
+                    return Err(format!("Expected --field <name>=<value>, got {field}"));
+                };
+                let value = serde_json::from_str(value)
+                    .unwrap_or_else(|_| Value::String(value.to_string()));
+                fields.push((name.to_string(), value));
+            }
+            "--api-key" => api_key = Some(value(&arg)?),
+            "--from-stream-id" => from_stream_id = Some(value(&arg)?),
+            "--no-reconnect" => reconnect = false,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}")),
+            _ if url.is_none() => url = Some(arg),
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+    // Fields override the filter, wherever they are in the arguments
+    filter.extend(fields);
+    Ok(Args {
+        url: url.ok_or("No URL given")?,
+        filter,
+        api_key,
+        from_stream_id,
+        reconnect,
+    })
+}
+
+/// The URL with `from_stream_id`, if there is one.
+fn resume_url(url: &str, from_stream_id: Option<&str>) -> String {
+    match from_stream_id {
+        Some(id) if url.contains('?') => format!("{url}&from_stream_id={id}"),
+        Some(id) => format!("{url}?from_stream_id={id}"),
+        None => url.to_string(),
+    }
+}
+
+/// `reconnect_to` of a draining server is a base URL, like `wss://events-v2.example.com`.
+fn moved_url(url: &str, reconnect_to: &str) -> Option<String> {
+    let path = url
+        .parse::<Uri>()
+        .ok()?
+        .path_and_query()?
+        .as_str()
+        .to_string();
+    Some(format!("{}{path}", reconnect_to.trim_end_matches('/')))
+}
+
+struct Subscriber {
+    args: Args,
+    /// Where to resume after a reconnect, the last event or `from_stream_id`.
+    last_stream_id: Option<String>,
+}
+
+enum Closed {
+    /// By the server, or the connection failed.
+    Connection(anyhow::Error),
+    /// The server is draining, connect to this URL next.
+    Moved(Option<String>),
+    /// Stdout was closed, e.g. by `head`.
+    Stdout,
+}
+
+impl Subscriber {
+    /// Reads events until the connection is closed.
+    async fn receive(&mut self, url: &str, events: &mut bool) -> Closed {
+        let filter = Value::Object(self.args.filter.clone()).to_string();
+        let authorization = self
+            .args
+            .api_key
+            .as_ref()
+            .map(|key| format!("Bearer {key}"));
+        let mut headers = vec![("X-Filter", filter.as_str())];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let resume_url = resume_url(url, self.last_stream_id.as_deref());
+        let mut client = match WsClient::connect(&resume_url, &headers).await {
+            Ok(client) => client,
+            Err(err) => return Closed::Connection(err),
+        };
+        eprintln!("Connected to {resume_url}");
+        let mut stdout = std::io::stdout().lock();
+        loop {
+            let message = match client.next_message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => return Closed::Connection(anyhow::anyhow!("Closed by the server")),
+                Err(err) => return Closed::Connection(err),
+            };
+            let frame = match serde_json::from_slice::<Map<String, Value>>(&message) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    eprintln!("Invalid frame: {err}");
+                    continue;
+                }
+            };
+            // Events have a stream ID, control frames have a type instead
+            if let Some(Value::String(id)) = frame.get("stream_id") {
+                self.last_stream_id = Some(id.clone());
+            } else {
+                match frame.get("type").and_then(Value::as_str) {
+                    Some("reconnect") => {
+                        if let Some(Value::String(id)) = frame.get("resume_from") {
+                            self.last_stream_id = Some(id.clone());
+                        }
+                        let reconnect_to = frame.get("reconnect_to").and_then(Value::as_str);
+                        return Closed::Moved(reconnect_to.and_then(|to| moved_url(url, to)));
+                    }
+                    Some("error" | "warning") => eprintln!("{}", Value::Object(frame)),
+                    _ => {}
+                }
+                continue;
+            }
+            *events = true;
+            if let Err(err) = writeln!(stdout, "{}", Value::Object(frame)) {
+                if err.kind() != ErrorKind::BrokenPipe {
+                    eprintln!("Failed to write to stdout: {err}");
+                }
+                return Closed::Stdout;
+            }
+        }
+    }
+
+    async fn run(mut self) -> ExitCode {
+        let mut url = self.args.url.clone();
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            let mut events = false;
+            match self.receive(&url, &mut events).await {
+                Closed::Stdout => return ExitCode::SUCCESS,
+                Closed::Moved(moved) => {
+                    eprintln!("The server is draining, reconnecting");
+                    url = moved.unwrap_or(url);
+                    delay = RECONNECT_DELAY;
+                    continue;
+                }
+                Closed::Connection(err) => {
+                    eprintln!("Disconnected: {err:#}");
+                    if !self.args.reconnect {
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            if events {
+                delay = RECONNECT_DELAY;
+            }
+            eprintln!("Reconnecting in {}s", delay.as_secs());
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let args = match parse_args(args.into_iter()) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let last_stream_id = args.from_stream_id.clone();
+    Subscriber {
+        args,
+        last_stream_id,
+    }
+    .run()
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parses_args() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+        let parsed = args(&[
+            "ws://localhost:7070/v0/nft/nft_mint",
+            "--field",
+            "account_id=alice.near",
+            "--filter",
+            r#"{"token_account_id": "nft.near", "account_id": "bob.near"}"#,
+            "--field",
+            "contract_ids=[\"a.near\"]",
+            "--from-stream-id",
+            "5-0",
+        ])
+        .unwrap();
+        assert_eq!(
+            Value::Object(parsed.filter),
+            json!({
+                "token_account_id": "nft.near",
+                "account_id": "alice.near",
+                "contract_ids": ["a.near"],
+            })
+        );
+        assert_eq!(parsed.from_stream_id.as_deref(), Some("5-0"));
+        assert!(parsed.reconnect);
+        assert!(args(&["ws://localhost:7070/v0/nft/nft_mint", "--field", "x"]).is_err());
+        assert!(args(&["--no-reconnect"]).is_err());
+
+        assert_eq!(
+            resume_url("ws://localhost/v0/nft/nft_mint?api_key=key", Some("5-0")),
+            "ws://localhost/v0/nft/nft_mint?api_key=key&from_stream_id=5-0"
+        );
+        assert_eq!(
+            moved_url(
+                "ws://localhost/v0/nft/nft_mint?api_key=key",
+                "wss://events-v2.example.com/"
+            )
+            .unwrap(),
+            "wss://events-v2.example.com/v0/nft/nft_mint?api_key=key"
+        );
+    }
+}
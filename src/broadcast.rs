@@ -0,0 +1,100 @@
+use std::{any::Any, sync::Arc};
+
+use dashmap::DashMap;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    firehose::Firehose,
+    nft_events::{
+        FullNftBurnEvent, FullNftMintEvent, FullNftTransferEvent, NftBurnFilter, NftMintFilter,
+        NftTransferFilter,
+    },
+    potlock_events::{
+        FullPotlockDonationEvent, FullPotlockPotDonationEvent, FullPotlockPotProjectDonationEvent,
+        PotlockDonationEventFilter, PotlockPotDonationEventFilter,
+        PotlockPotProjectDonationEventFilter,
+    },
+    trade_events::{
+        FullTradePoolChangeEvent, FullTradePoolEvent, FullTradeSwapEvent,
+        TradePoolChangeEventFilter, TradePoolEventFilter, TradeSwapEventFilter,
+    },
+    Event, EventFilter, FromRedis,
+};
+
+/// Events that a slow consumer can fall behind by before it starts missing them.
+const BROADCAST_CAPACITY: usize = 1024;
+
+/// Events of every stream and network, for consumers that aren't WebSocket
+/// clients, like the gRPC API. Every reader sends its events here after
+/// sending them to the WebSocket clients.
+#[derive(Default)]
+pub struct Broadcasts {
+    /// `broadcast::Sender<Arc<Event<E>>>` by network and `E::STREAM_KEY`.
+    senders: DashMap<(String, &'static str), Box<dyn Any + Send + Sync>>,
+    /// Frames of `/v0/firehose` by network, `None` if no API key can read them.
+    firehose: Option<DashMap<String, Arc<Firehose>>>,
+}
+
+pub type EventSender<E> = broadcast::Sender<Arc<Event<E>>>;
+pub type EventReceiver<E> = broadcast::Receiver<Arc<Event<E>>>;
+
+impl Broadcasts {
+    pub fn sender<E: FromRedis + Send + Sync + 'static>(&self, network: &str) -> EventSender<E> {
+        self.senders
+            .entry((network.to_string(), E::STREAM_KEY))
+            .or_insert_with(|| Box::new(broadcast::channel::<Arc<Event<E>>>(BROADCAST_CAPACITY).0))
+            .downcast_ref::<EventSender<E>>()
+            .expect("Two event types have the same stream key")
+            .clone()
+    }
+
+    pub fn subscribe<E: FromRedis + Send + Sync + 'static>(
+        &self,
+        network: &str,
+    ) -> EventReceiver<E> {
+        self.sender::<E>(network).subscribe()
+    }
+
+    /// Also keeps the frames of `/v0/firehose`.
+    pub fn with_firehose() -> Self {
+        Self {
+            firehose: Some(DashMap::new()),
+            ..Self::default()
+        }
+    }
+
+    pub fn firehose(&self, network: &str) -> Option<Arc<Firehose>> {
+        Some(Arc::clone(
+            &self
+                .firehose
+                .as_ref()?
+                .entry(network.to_string())
+                .or_insert_with(|| Arc::new(Firehose::new())),
+        ))
+    }
+}
+
+/// Called with every event type and its filter, for consumers that handle all streams.
+pub trait EventTypeVisitor {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    );
+}
+
+pub fn for_each_event_type(visitor: &mut impl EventTypeVisitor) {
+    visitor.visit::<FullNftMintEvent, NftMintFilter>();
+    visitor.visit::<FullNftTransferEvent, NftTransferFilter>();
+    visitor.visit::<FullNftBurnEvent, NftBurnFilter>();
+
+    visitor.visit::<FullPotlockDonationEvent, PotlockDonationEventFilter>();
+    visitor.visit::<FullPotlockPotProjectDonationEvent, PotlockPotProjectDonationEventFilter>();
+    visitor.visit::<FullPotlockPotDonationEvent, PotlockPotDonationEventFilter>();
+
+    visitor.visit::<FullTradePoolEvent, TradePoolEventFilter>();
+    visitor.visit::<FullTradeSwapEvent, TradeSwapEventFilter>();
+    visitor.visit::<FullTradePoolChangeEvent, TradePoolChangeEventFilter>();
+}
@@ -0,0 +1,447 @@
+//! `collection_stats` events at `/v0/nft/collection_stats`, derived from NFT sales
+//! (`nft_transfer` events with a price): the floor price, volume and number of
+//! sales of a collection in the last 24 hours, sent after every sale.
+//!
+//! The floor price is the lowest sale price in the window, since listings aren't
+//! indexed. On startup, the last 24 hours are read from the Redis streams.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix::prelude::*;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+
+use crate::{
+    broadcast::{Broadcasts, EventReceiver},
+    drain,
+    nft_events::FullNftTransferEvent,
+    redis_reader::read_page,
+    replay::StreamId,
+    types::{AccountId, Balance, BlockHeight, TransactionId},
+    FromRedis, Networks, StreamSource, CLIENT_TIMEOUT, DEFAULT_NETWORK, HEARTBEAT_INTERVAL,
+};
+
+const WINDOW: Duration = Duration::from_secs(24 * 3600);
+const BACKFILL_PAGE_SIZE: usize = 1000;
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CollectionStatsEvent {
+    pub contract_id: AccountId,
+    /// Lowest sale price in the last 24 hours, in yoctoNEAR.
+    pub floor_price_near: Balance,
+    /// Floor price before this sale, `null` if there were no sales in the window.
+    pub previous_floor_price_near: Option<Balance>,
+    pub volume_24h_near: Balance,
+    pub sales_24h: usize,
+    /// Price of the sale that triggered this event. If one transfer sold several
+    /// tokens, the sum of their prices.
+    pub last_sale_price_near: Balance,
+    pub transaction_id: TransactionId,
+    pub block_height: BlockHeight,
+    pub block_timestamp_nanosec: String,
+}
+
+/// Redis sources of `nft_transfer` that the stats of their network are backfilled from.
+pub struct BackfillSource {
+    pub network: String,
+    pub source: StreamSource,
+}
+
+/// Latest stats of every collection, and the new ones as they're computed.
+pub struct CollectionStats {
+    latest: DashMap<(String, AccountId), Arc<CollectionStatsEvent>>,
+    senders: HashMap<String, broadcast::Sender<Arc<CollectionStatsEvent>>>,
+}
+
+impl CollectionStats {
+    /// Starts computing the stats of every network.
+    pub fn spawn(
+        networks: &Networks,
+        sources: Vec<BackfillSource>,
+        broadcasts: &Broadcasts,
+    ) -> Arc<Self> {
+        let stats = Arc::new(Self {
+            latest: DashMap::new(),
+            senders: networks
+                .iter()
+                .map(|network| (network.clone(), broadcast::channel(CAPACITY).0))
+                .collect(),
+        });
+        for network in networks {
+            let live = broadcasts.subscribe::<FullNftTransferEvent>(network);
+            let sources = sources
+                .iter()
+                .filter(|source| source.network == *network)
+                .map(|source| source.source.clone())
+                .collect();
+            tokio::spawn(compute(Arc::clone(&stats), network.clone(), sources, live));
+        }
+        stats
+    }
+
+    fn subscribe(&self, network: &str) -> broadcast::Receiver<Arc<CollectionStatsEvent>> {
+        self.senders[network].subscribe()
+    }
+
+    fn latest(&self, network: &str, contract_id: &AccountId) -> Option<Arc<CollectionStatsEvent>> {
+        self.latest
+            .get(&(network.to_string(), contract_id.clone()))
+            .map(|stats| Arc::clone(&stats))
+    }
+}
+
+#[derive(Default)]
+struct Collection {
+    /// Time in milliseconds and price of the sales in the window, oldest first.
+    sales: VecDeque<(u64, u128)>,
+}
+
+impl Collection {
+    fn floor(&self) -> Option<u128> {
+        self.sales.iter().map(|(_, price)| *price).min()
+    }
+
+    /// Adds the sale and returns the stats after it.
+    fn add(&mut self, event: &FullNftTransferEvent) -> Option<CollectionStatsEvent> {
+        let price = event
+            .event
+            .token_prices_near
+            .iter()
+            .flatten()
+            .map(|price| price.0)
+            .collect::<Vec<_>>();
+        if price.is_empty() {
+            return None;
+        }
+        let timestamp_ms = event
+            .context
+            .common
+            .block_timestamp_nanosec
+            .parse::<u128>()
+            .map_or(0, |ns| (ns / 1_000_000) as u64);
+        let window_start = timestamp_ms.saturating_sub(WINDOW.as_millis() as u64);
+        while self.sales.front().is_some_and(|(at, _)| *at < window_start) {
+            self.sales.pop_front();
+        }
+        let previous_floor = self.floor();
+        self.sales
+            .extend(price.iter().map(|price| (timestamp_ms, *price)));
+        Some(CollectionStatsEvent {
+            contract_id: event.context.contract_id.clone(),
+            floor_price_near: Balance(self.floor().unwrap_or_default()),
+            previous_floor_price_near: previous_floor.map(Balance),
+            volume_24h_near: Balance(
+                self.sales
+                    .iter()
+                    .map(|(_, price)| *price)
+                    .fold(0u128, u128::saturating_add),
+            ),
+            sales_24h: self.sales.len(),
+            last_sale_price_near: Balance(price.iter().sum()),
+            transaction_id: event.context.common.transaction_id.clone(),
+            block_height: event.context.common.block_height,
+            block_timestamp_nanosec: event.context.common.block_timestamp_nanosec.clone(),
+        })
+    }
+}
+
+async fn compute(
+    stats: Arc<CollectionStats>,
+    network: String,
+    sources: Vec<StreamSource>,
+    mut live: EventReceiver<FullNftTransferEvent>,
+) {
+    let mut collections = HashMap::<AccountId, Collection>::new();
+    // The live events up to these IDs were already read by the backfill
+    let mut backfilled = HashMap::new();
+    for source in sources {
+        let started = Instant::now();
+        let stream_key = &source.stream_key;
+        match backfill(&stats, &network, &mut collections, &source).await {
+            Ok((last_id, count)) => {
+                tracing::info!(
+                    "Read {count} sales of the last 24 hours from {stream_key} in {:?}",
+                    started.elapsed()
+                );
+                backfilled.insert(Arc::clone(&source.name), last_id);
+            }
+            Err(err) => {
+                tracing::warn!("Failed to read collection stats from {stream_key}: {err}")
+            }
+        }
+    }
+
+    loop {
+        let event = match live.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => {
+                tracing::warn!("Collection stats fell behind, {count} transfers were skipped");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if backfilled
+            .get(&event.source)
+            .is_some_and(|last_id: &Option<StreamId>| last_id.is_some_and(|id| event.id <= id))
+        {
+            continue;
+        }
+        let collection = collections
+            .entry(event.event.context.contract_id.clone())
+            .or_default();
+        if let Some(stats_event) = collection.add(&event.event) {
+            let stats_event = Arc::new(stats_event);
+            stats.latest.insert(
+                (network.clone(), stats_event.contract_id.clone()),
+                Arc::clone(&stats_event),
+            );
+            // Fails only if there are no clients
+            let _ = stats.senders[&network].send(stats_event);
+        }
+    }
+}
+
+/// Reads the sales of the last 24 hours, returns the ID of the last entry read
+/// and the number of sales.
+async fn backfill(
+    stats: &CollectionStats,
+    network: &str,
+    collections: &mut HashMap<AccountId, Collection>,
+    source: &StreamSource,
+) -> anyhow::Result<(Option<StreamId>, usize)> {
+    let mut count = 0;
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let mut after = Some(StreamId(
+        now_ms.saturating_sub(WINDOW.as_millis() as u64),
+        0,
+    ));
+    let mut last_id = None;
+    loop {
+        let entries = read_page(
+            source.connection.clone(),
+            &source.stream_key,
+            after,
+            BACKFILL_PAGE_SIZE,
+        )
+        .await?;
+        let is_last_page = entries.len() < BACKFILL_PAGE_SIZE;
+        for (id, values) in entries {
+            let id = id.parse::<StreamId>()?;
+            after = Some(id);
+            last_id = Some(id);
+            match FullNftTransferEvent::decode(&values, source.encoding) {
+                Ok(event) => {
+                    let collection = collections
+                        .entry(event.context.contract_id.clone())
+                        .or_default();
+                    if let Some(stats_event) = collection.add(&event) {
+                        count += 1;
+                        stats.latest.insert(
+                            (network.to_string(), stats_event.contract_id.clone()),
+                            Arc::new(stats_event),
+                        );
+                    }
+                }
+                Err(err) => tracing::warn!(%id, "Failed to read transfer: {err}"),
+            }
+        }
+        if is_last_page {
+            return Ok((last_id, count));
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectionStatsFilter {
+    contract_id: Option<AccountId>,
+}
+
+impl CollectionStatsFilter {
+    fn matches(&self, event: &CollectionStatsEvent) -> bool {
+        self.contract_id
+            .as_ref()
+            .is_none_or(|contract_id| event.contract_id == *contract_id)
+    }
+}
+
+pub async fn collection_stats(
+    req: HttpRequest,
+    stream: web::Payload,
+    networks: web::Data<Networks>,
+    stats: web::Data<CollectionStats>,
+) -> Result<HttpResponse, Error> {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return Ok(HttpResponse::NotFound().body(format!("Unknown network: {network}")));
+    }
+    if let Some(response) = drain::draining_response(&req) {
+        return Ok(response);
+    }
+    ws::start(
+        CollectionStatsWebSocket {
+            last_heartbeat: Instant::now(),
+            network,
+            stats,
+            filter: None,
+            updates: None,
+        },
+        &req,
+        stream,
+    )
+}
+
+struct CollectionStatsWebSocket {
+    last_heartbeat: Instant,
+    network: String,
+    stats: web::Data<CollectionStats>,
+    filter: Option<CollectionStatsFilter>,
+    /// Forwards the stats events of the network to this actor.
+    updates: Option<JoinHandle<()>>,
+}
+
+impl Actor for CollectionStatsWebSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+            }
+            ctx.ping(b"");
+        });
+        let mut events = self.stats.subscribe(&self.network);
+        let addr = ctx.address();
+        self.updates = Some(tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => addr.do_send(StatsUpdate(event)),
+                    Err(RecvError::Lagged(count)) => {
+                        tracing::warn!("Collection stats client fell behind by {count} events")
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        }));
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(updates) = self.updates.take() {
+            updates.abort();
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for CollectionStatsWebSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+            }
+            Ok(ws::Message::Text(text)) => {
+                // Like the other endpoints in protocol 1, invalid filters are ignored
+                let Ok(filter) = serde_json::from_str::<CollectionStatsFilter>(&text) else {
+                    return;
+                };
+                // The current stats of the collection, so that clients don't wait for a sale
+                if let Some(contract_id) = &filter.contract_id {
+                    if let Some(latest) = self.stats.latest(&self.network, contract_id) {
+                        ctx.text(serde_json::to_string(&*latest).unwrap());
+                    }
+                }
+                self.filter = Some(filter);
+            }
+            _ => ctx.stop(),
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct StatsUpdate(Arc<CollectionStatsEvent>);
+
+impl Handler<StatsUpdate> for CollectionStatsWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: StatsUpdate, ctx: &mut Self::Context) -> Self::Result {
+        if self.filter.as_ref().is_none_or(|f| f.matches(&msg.0)) {
+            ctx.text(serde_json::to_string(&*msg.0).unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        nft_events::{NftEventContext, NftTransferEvent},
+        types::EventContext,
+    };
+
+    use super::*;
+
+    fn sale(timestamp_ms: u64, prices: &[Option<u128>]) -> FullNftTransferEvent {
+        FullNftTransferEvent {
+            event: NftTransferEvent {
+                old_owner_id: "a.near".try_into().unwrap(),
+                new_owner_id: "b.near".try_into().unwrap(),
+                token_ids: prices.iter().map(|_| "1".to_string()).collect(),
+                memo: None,
+                token_prices_near: prices.iter().map(|p| p.map(Balance)).collect(),
+                extra: Default::default(),
+            },
+            context: NftEventContext {
+                common: EventContext {
+                    transaction_id: "tx".to_string(),
+                    receipt_id: "receipt".to_string(),
+                    block_height: 1,
+                    block_timestamp_nanosec: (timestamp_ms as u128 * 1_000_000).to_string(),
+                    extra: Default::default(),
+                },
+                contract_id: "nft.near".try_into().unwrap(),
+            },
+        }
+    }
+
+    #[test]
+    fn computes_stats_of_window() {
+        let mut collection = Collection::default();
+        assert!(collection.add(&sale(0, &[None])).is_none());
+        let stats = collection.add(&sale(0, &[Some(5)])).unwrap();
+        assert_eq!(stats.floor_price_near, Balance(5));
+        assert_eq!(stats.previous_floor_price_near, None);
+
+        let stats = collection.add(&sale(1000, &[Some(3), Some(10)])).unwrap();
+        assert_eq!(stats.floor_price_near, Balance(3));
+        assert_eq!(stats.previous_floor_price_near, Some(Balance(5)));
+        assert_eq!(stats.volume_24h_near, Balance(18));
+        assert_eq!(stats.sales_24h, 3);
+        assert_eq!(stats.last_sale_price_near, Balance(13));
+
+        // The first sale leaves the window
+        let stats = collection
+            .add(&sale(WINDOW.as_millis() as u64 + 1, &[Some(20)]))
+            .unwrap();
+        assert_eq!(stats.floor_price_near, Balance(3));
+        assert_eq!(stats.volume_24h_near, Balance(33));
+        assert_eq!(stats.sales_24h, 3);
+    }
+}
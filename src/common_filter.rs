@@ -0,0 +1,69 @@
+//! The fields that the filters of all streams with transactions and accounts
+//! share. Filters flatten [`CommonFilter`] into their own fields, and check it
+//! with the block, IDs and accounts of the event before their own fields.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fields,
+    types::{AccountId, BlockHeight, EventContext, ReceiptId, TransactionId},
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CommonFilter {
+    /// Only events in blocks from this height on.
+    pub min_block_height: Option<BlockHeight>,
+    /// Only events in blocks up to this height.
+    pub max_block_height: Option<BlockHeight>,
+    /// Only events of this transaction, e.g. to wait for a transaction that the
+    /// client submitted.
+    pub transaction_id: Option<TransactionId>,
+    /// Only events of this receipt.
+    pub receipt_id: Option<ReceiptId>,
+    /// Only events where one of the accounts of the event, like the owner of an
+    /// NFT or the trader and the tokens of a trade, is one of these.
+    pub involved_account_ids: Option<Vec<AccountId>>,
+}
+
+impl CommonFilter {
+    /// Whether an event with this context and these accounts matches.
+    pub fn matches<'a>(
+        &self,
+        context: &EventContext,
+        accounts: impl IntoIterator<Item = &'a AccountId>,
+    ) -> bool {
+        in_block_range(
+            self.min_block_height,
+            self.max_block_height,
+            context.block_height,
+        ) && self
+            .transaction_id
+            .as_ref()
+            .is_none_or(|id| context.transaction_id == *id)
+            && self
+                .receipt_id
+                .as_ref()
+                .is_none_or(|id| context.receipt_id == *id)
+            && self.involved_account_ids.as_ref().is_none_or(|involved| {
+                accounts
+                    .into_iter()
+                    .any(|account_id| involved.contains(account_id))
+            })
+    }
+
+    /// The fields of a filter that flattens this, with `own` fields.
+    pub fn with_fields(own: &[&'static str]) -> Vec<&'static str> {
+        [own, fields::field_names::<Self>()].concat()
+    }
+}
+
+/// Whether a block is within the `min_block_height` and `max_block_height` of a
+/// filter.
+pub fn in_block_range(
+    min_block_height: Option<BlockHeight>,
+    max_block_height: Option<BlockHeight>,
+    block_height: BlockHeight,
+) -> bool {
+    min_block_height.is_none_or(|min| block_height >= min)
+        && max_block_height.is_none_or(|max| block_height <= max)
+}
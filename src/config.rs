@@ -0,0 +1,551 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::replay::StreamId;
+
+/// Optional JSON configuration, loaded from the file at `CONFIG_FILE`.
+/// Without it, the server reads everything from environment variables
+/// like it always did.
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub redis_sources: Vec<RedisSourceConfig>,
+    /// Prepended to stream keys of the sources that don't have their own, e.g.
+    /// `mainnet:` for deployments that share one Redis.
+    #[serde(default)]
+    pub stream_prefix: String,
+    /// Reader settings by stream key (without `stream_prefix`).
+    #[serde(default)]
+    pub streams: HashMap<String, StreamConfig>,
+    /// Republish events to NATS.
+    pub nats: Option<NatsConfig>,
+    /// Mirror events into Kafka topics.
+    pub kafka: Option<KafkaConfig>,
+    /// Publish events to an MQTT broker.
+    pub mqtt: Option<MqttConfig>,
+    /// Archive events in ClickHouse.
+    pub archive: Option<ArchiveConfig>,
+    /// Post matching events to HTTP endpoints.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Mail server for `digests`.
+    pub smtp: Option<SmtpConfig>,
+    /// Periodic email digests of matching events.
+    #[serde(default)]
+    pub digests: Vec<DigestConfig>,
+    /// Named filters by stream key, that clients can choose with `{"preset": <name>}`.
+    #[serde(default)]
+    pub presets: HashMap<String, HashMap<String, serde_json::Value>>,
+    /// Keys that identify clients.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    /// Limits of API keys by plan name, e.g. `free`, that keys choose with `plan`.
+    #[serde(default)]
+    pub plans: HashMap<String, PlanConfig>,
+    /// Limits of clients without an API key.
+    pub anonymous: Option<AnonymousConfig>,
+    /// Sign-in with NEAR wallets.
+    pub near_auth: Option<NearAuthConfig>,
+    /// Don't start if a stream that is read doesn't exist.
+    #[serde(default)]
+    pub require_streams: bool,
+    /// Prepended to stream keys for the Redis keys of the readers' checkpoints,
+    /// `events_api_websocket_last_id_` if not set.
+    pub checkpoint_prefix: Option<String>,
+    /// Where clients are told to reconnect to when the server is draining.
+    pub drain_reconnect_url: Option<String>,
+    /// Tuning of the HTTP server.
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// Read the firehose of another instance instead of Redis.
+    pub firehose_upstream: Option<FirehoseUpstreamConfig>,
+    /// Add the tokens of the pool to `trade_pool` events.
+    pub pool_metadata: Option<PoolMetadataConfig>,
+    /// Secret that session tokens are signed with, so that clients can resume
+    /// after a restart.
+    pub session_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NearAuthConfig {
+    /// The `recipient` of the signed messages, usually the domain of the server.
+    pub recipient: String,
+    /// RPC by network, that the keys of accounts are checked on.
+    pub rpc_urls: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PoolMetadataConfig {
+    /// RPC by network, e.g. `{"mainnet": "https://rpc.mainnet.near.org"}`, that
+    /// `ft_metadata` of the tokens is called on. Networks without one get no metadata.
+    pub rpc_urls: HashMap<String, String>,
+    /// Also add the amounts of trades with the decimals of the tokens, e.g. `"12.5"`.
+    #[serde(default)]
+    pub decimal_amounts: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FirehoseUpstreamConfig {
+    /// `wss://<host>/v0/firehose`, or `/v0/<network>/firehose` for other networks.
+    pub url: String,
+    /// A key of the upstream that has `firehose` set.
+    pub api_key: String,
+}
+
+/// Unset fields keep the defaults of actix-web.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HttpConfig {
+    pub workers: Option<usize>,
+    /// Maximum number of pending connections.
+    pub backlog: Option<u32>,
+    /// Maximum number of connections per worker.
+    pub max_connections: Option<usize>,
+    /// How long idle HTTP connections are kept open, 0 to close them.
+    pub keep_alive_sec: Option<u64>,
+    /// How long a client has to send the request headers.
+    pub client_request_timeout_ms: Option<u64>,
+    /// How long a client has to acknowledge that the connection is closed.
+    pub client_disconnect_timeout_ms: Option<u64>,
+    /// Maximum size of WebSocket frames from clients, in bytes.
+    pub max_frame_size: Option<usize>,
+    /// Also listen on this Unix socket, like `BIND_UDS`.
+    pub bind_uds: Option<String>,
+    /// Addresses to listen on if `BIND_ADDRESS` isn't set, e.g. `["[::]:3000", "0.0.0.0:3000"]`.
+    #[serde(default)]
+    pub bind_addresses: Vec<String>,
+    /// Reverse proxies whose `X-Forwarded-For` is trusted for the client address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NatsConfig {
+    /// `nats://[user:password@ or token@]host[:port]`
+    pub url: String,
+    /// Network whose events are republished.
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Streams to republish. If not set, all streams are republished.
+    pub streams: Option<Vec<String>>,
+    /// Subjects by stream key. Streams that aren't here are published to
+    /// `events.<category>.<name>`, e.g. `events.nft.transfer` for `nft_transfer`.
+    #[serde(default)]
+    pub subjects: HashMap<String, String>,
+}
+
+impl NatsConfig {
+    pub fn subject(&self, stream_key: &str) -> String {
+        self.subjects.get(stream_key).cloned().unwrap_or_else(|| {
+            let (category, name) = stream_key.split_once('_').unwrap_or((stream_key, ""));
+            format!("events.{category}.{name}")
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KafkaConfig {
+    /// Bootstrap brokers, `host:port`.
+    pub brokers: Vec<String>,
+    /// Network whose events are mirrored.
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Streams to mirror. If not set, all streams are mirrored.
+    pub streams: Option<Vec<String>>,
+    /// Topics by stream key. Streams that aren't here are produced to a topic
+    /// with the name of the stream key.
+    #[serde(default)]
+    pub topics: HashMap<String, String>,
+    /// Acknowledgements required from replicas: 0, 1, or -1 for all in-sync replicas.
+    #[serde(default = "default_kafka_acks")]
+    pub acks: i16,
+    #[serde(default)]
+    pub format: KafkaFormat,
+    /// Confluent schema registry to register the Avro schemas in, needed for `avro`.
+    pub schema_registry: Option<String>,
+    /// Connect to the brokers with TLS, like most managed Kafka services need.
+    #[serde(default)]
+    pub tls: bool,
+    /// Credentials for SASL/PLAIN, sent after connecting to every broker.
+    pub sasl: Option<KafkaSasl>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KafkaSasl {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KafkaFormat {
+    /// The event as on the WebSocket endpoints of protocol 1.
+    #[default]
+    Json,
+    /// Avro with the schema of the stream in `avro/`, in the wire format of the
+    /// schema registry.
+    Avro,
+}
+
+impl KafkaConfig {
+    pub fn topic(&self, stream_key: &str) -> String {
+        self.topics
+            .get(stream_key)
+            .cloned()
+            .unwrap_or_else(|| stream_key.to_string())
+    }
+}
+
+fn default_kafka_acks() -> i16 {
+    -1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    /// `mqtt://[user:password@]host[:port]`
+    pub url: String,
+    /// Network whose events are published.
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Streams to publish. If not set, all streams are published.
+    pub streams: Option<Vec<String>>,
+    /// Events are published to `<topic_prefix>/<stream key>[/<contract_id>]`.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    /// Publish with the retain flag, so new subscribers get the last event of every topic.
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "events".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArchiveConfig {
+    /// HTTP interface of ClickHouse, e.g. `http://clickhouse:8123`.
+    pub url: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Database of the tables, the user's default database if not set.
+    pub database: Option<String>,
+    /// Network whose events are archived.
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Streams to archive. If not set, all streams are archived.
+    pub streams: Option<Vec<String>>,
+    /// Tables by stream key. Streams that aren't here are archived to a table
+    /// with the name of the stream key.
+    #[serde(default)]
+    pub tables: HashMap<String, String>,
+    /// Create missing tables with the default schema on startup.
+    #[serde(default)]
+    pub create_tables: bool,
+}
+
+impl ArchiveConfig {
+    pub fn archives(&self, stream_key: &str) -> bool {
+        self.streams
+            .as_ref()
+            .is_none_or(|streams| streams.iter().any(|s| s == stream_key))
+    }
+
+    pub fn table(&self, stream_key: &str) -> String {
+        self.tables
+            .get(stream_key)
+            .cloned()
+            .unwrap_or_else(|| stream_key.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Stream key of the events, e.g. `nft_transfer`.
+    pub stream: String,
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Filter in the same format as the WebSocket filter message.
+    pub filter: Option<serde_json::Value>,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Text of Discord and Telegram messages, with `{field}` placeholders.
+    pub template: Option<String>,
+    /// Title of Discord embeds, with `{field}` placeholders.
+    pub title: Option<String>,
+    /// Chat that Telegram messages are sent to.
+    pub chat_id: Option<serde_json::Value>,
+    /// Sign the requests with HMAC-SHA256 and this secret.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// The event as on the WebSocket endpoints of protocol 1.
+    #[default]
+    Json,
+    /// A Discord webhook message with an embed.
+    Discord,
+    /// A Telegram Bot API `sendMessage` request.
+    Telegram,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Sender address of digests.
+    pub from: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Upgrade the connection with STARTTLS, usually on port 587.
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// Plaintext, only for local mail servers.
+    None,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DigestConfig {
+    /// Recipient addresses.
+    pub to: Vec<String>,
+    /// Stream key of the events, e.g. `potlock_donation`.
+    pub stream: String,
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Filter in the same format as the WebSocket filter message.
+    pub filter: Option<serde_json::Value>,
+    /// How often the digest is sent, if there were matching events.
+    pub interval_minutes: u64,
+    /// Subject of the emails, with `{count}` and `{stream}` placeholders.
+    pub subject: Option<String>,
+    /// One line per event, with the same placeholders as webhook templates.
+    pub template: Option<String>,
+    /// Events listed in one digest at most, the rest are only counted.
+    #[serde(default = "default_digest_max_events")]
+    pub max_events: usize,
+}
+
+fn default_digest_max_events() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKeyConfig {
+    /// The secret that clients send.
+    pub key: String,
+    /// Shown in logs, and used in the Redis keys of data kept for this key.
+    pub name: String,
+    /// Keep a record of every event delivered to connections with this key.
+    pub audit: Option<AuditTarget>,
+    /// Allow `/v0/firehose`, for mirrors of this server.
+    #[serde(default)]
+    pub firehose: bool,
+    /// Sign every event delivered to this key with HMAC-SHA256 and this secret.
+    pub signing_secret: Option<String>,
+    /// Name of the plan in `plans` that limits this key.
+    pub plan: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnonymousConfig {
+    /// Connections of a client per minute on this instance, clients that connect
+    /// more often are banned.
+    pub max_connections_per_min: u32,
+    /// How long clients are banned for.
+    #[serde(default = "default_ban_sec")]
+    pub ban_sec: u64,
+    /// Events delivered to all connections of a client per second, the rest is dropped.
+    pub max_events_per_sec: Option<u32>,
+}
+
+fn default_ban_sec() -> u64 {
+    600
+}
+
+/// Limits of the API keys on a plan. Unset limits don't apply.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PlanConfig {
+    /// Stream keys of the endpoints that the plan includes, e.g. `["nft_mint"]`,
+    /// all if not set.
+    pub endpoints: Option<Vec<String>>,
+    /// WebSocket connections of a key at once, on this instance.
+    pub max_connections: Option<usize>,
+    /// Events delivered to all connections of a key per second, the rest is dropped.
+    pub max_events_per_sec: Option<u32>,
+    /// Events of `replay_last`.
+    pub max_replay_events: Option<usize>,
+    /// How old the event that a replay starts after can be.
+    pub max_replay_age_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditTarget {
+    /// Log lines with the `audit` target.
+    Log,
+    /// The Redis stream `events_api_audit_<name>` of the first Redis source.
+    Redis,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedisSourceConfig {
+    /// Added to every event from this source as `source`.
+    pub name: String,
+    pub url: String,
+    /// Streams to read from this source. If not set, all streams are read.
+    pub streams: Option<Vec<String>>,
+    /// Network that this source's events are served on, `/v0/{network}/...`.
+    /// Mainnet is also served without the network segment.
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Prepended to stream keys when reading from this source, e.g. `testnet_`.
+    /// Defaults to the `stream_prefix` of the config.
+    pub stream_prefix: Option<String>,
+    /// Encodings of this source's streams by stream key, if its indexer encodes
+    /// them differently than the `encoding` of the stream's config.
+    #[serde(default)]
+    pub encodings: HashMap<String, Encoding>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StreamConfig {
+    /// Maximum number of entries returned by one XREAD.
+    pub xread_count: usize,
+    /// How long one XREAD waits for new entries. 0 waits until there is a new
+    /// entry, so streams with 0 get their own Redis connection.
+    pub xread_block_ms: u64,
+    /// Save the last read ID after this many events...
+    pub checkpoint_every_events: u64,
+    /// ...or this long after the last save, whichever comes first.
+    pub checkpoint_interval_ms: u64,
+    /// If set, the endpoint is deprecated, and new clients get this message as a notice.
+    pub deprecated: Option<String>,
+    /// Where the reader starts.
+    pub start: StartPosition,
+    /// Don't read the stream, and answer its endpoints with 410.
+    pub disabled: bool,
+    /// How the indexer encodes the fields of the stream's entries.
+    pub encoding: Encoding,
+    /// Send events to WebSocket clients with the fields of their entries as the
+    /// indexer wrote them, instead of the event that the server read from them.
+    /// Only for sources whose entries of the stream are JSON.
+    pub passthrough: bool,
+    /// Subscribe to the pub/sub channel with the stream key instead of reading
+    /// the stream, for indexers that PUBLISH events instead of XADDing them.
+    pub pubsub: bool,
+    /// Clients get no events until they send a filter, and can't send an empty one.
+    pub require_filter: bool,
+    /// Clients without an API key get no events until they solve a proof-of-work
+    /// challenge of this many bits.
+    pub proof_of_work_bits: Option<u8>,
+}
+
+/// Encoding of the fields of stream entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// JSON strings.
+    #[default]
+    Json,
+    /// Base64 of borsh, with the fields of the event structs in their order and
+    /// of the same types as in JSON. Nested JSON without a fixed schema, like the
+    /// `pool` of pool changes, is a borsh string of the JSON.
+    Borsh,
+}
+
+/// Where a reader starts: `checkpoint`, `latest`, `earliest`, or a stream ID.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum StartPosition {
+    /// After the saved ID, or at new entries if there's none.
+    #[default]
+    Checkpoint,
+    /// At new entries, even if there's a saved ID.
+    Latest,
+    /// After the saved ID, or at the oldest entry in the stream if there's none.
+    Earliest,
+    /// After the saved ID, or after this ID if there's none.
+    After(StreamId),
+}
+
+impl TryFrom<String> for StartPosition {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "checkpoint" => Ok(Self::Checkpoint),
+            "latest" => Ok(Self::Latest),
+            "earliest" => Ok(Self::Earliest),
+            id => id.parse().map(Self::After).map_err(|_| {
+                format!("Invalid start {id}, expected checkpoint, latest, earliest, or a stream ID")
+            }),
+        }
+    }
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            xread_count: 100,
+            xread_block_ms: 250,
+            checkpoint_every_events: 1000,
+            checkpoint_interval_ms: 1000,
+            deprecated: None,
+            start: StartPosition::default(),
+            disabled: false,
+            encoding: Encoding::default(),
+            passthrough: false,
+            pubsub: false,
+            require_filter: false,
+            proof_of_work_bits: None,
+        }
+    }
+}
+
+fn default_network() -> String {
+    "mainnet".to_string()
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let mut config = if let Ok(path) = std::env::var("CONFIG_FILE") {
+            let file = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read config file {path}: {e}"));
+            serde_json::from_str(&file)
+                .unwrap_or_else(|e| panic!("Failed to parse config file {path}: {e}"))
+        } else {
+            Config::default()
+        };
+
+        if config.redis_sources.is_empty() {
+            config.redis_sources.push(RedisSourceConfig {
+                name: "mainnet".to_string(),
+                url: std::env::var("REDIS_URL").expect("REDIS_URL enviroment variable not set"),
+                streams: None,
+                network: default_network(),
+                stream_prefix: None,
+                encodings: HashMap::new(),
+            });
+        }
+
+        config
+    }
+}
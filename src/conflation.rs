@@ -0,0 +1,81 @@
+//! Conflation of the WebSocket endpoints: with the `conflate_ms` option, clients
+//! get only the latest event of each value of the `conflate_key` field every
+//! `conflate_ms`, with `"conflated_count"` telling how many events it replaced.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::stats;
+
+/// The events waiting for the end of the window, one per key.
+pub struct Conflation<T> {
+    /// Latest events and the number of events of their key, in the order of the
+    /// first event of each key.
+    pending: Vec<(T, u64)>,
+    positions: HashMap<String, usize>,
+}
+
+impl<T> Default for Conflation<T> {
+    fn default() -> Self {
+        Self {
+            pending: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Conflation<T> {
+    /// Replaces the pending event with the same key. Returns `true` if it's the
+    /// first pending event, so that the window starts.
+    pub fn add(&mut self, key: String, event: T) -> bool {
+        if let Some(&position) = self.positions.get(&key) {
+            let pending = &mut self.pending[position];
+            pending.0 = event;
+            pending.1 += 1;
+            return false;
+        }
+        self.positions.insert(key, self.pending.len());
+        self.pending.push((event, 1));
+        self.pending.len() == 1
+    }
+
+    /// Returns the pending events with their counts and starts the next window.
+    pub fn take(&mut self) -> Vec<(T, u64)> {
+        self.positions.clear();
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// The conflation key of an event, `None` if it doesn't have the field.
+pub fn key(event: &Value, name: &str) -> Option<String> {
+    match stats::field(event, name) {
+        Some(Value::Null) | None => None,
+        Some(value) => Some(stats::to_key(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn keeps_latest_event_per_key() {
+        let mut conflation = Conflation::default();
+        assert!(conflation.add("a".to_string(), 1));
+        assert!(!conflation.add("b".to_string(), 2));
+        assert!(!conflation.add("a".to_string(), 3));
+        assert!(!conflation.add("a".to_string(), 4));
+        assert_eq!(conflation.take(), vec![(4, 3), (2, 1)]);
+        assert!(conflation.add("b".to_string(), 5));
+        assert_eq!(conflation.take(), vec![(5, 1)]);
+
+        let event = json!({"owner_id": "alice.near", "pool": {"id": 7}, "nothing": null});
+        assert_eq!(key(&event, "owner_id"), Some("alice.near".to_string()));
+        assert_eq!(key(&event, "pool/id"), Some("7".to_string()));
+        assert_eq!(key(&event, "nothing"), None);
+        assert_eq!(key(&event, "missing"), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! IDs of WebSocket connections, sent to clients in `Ack` frames and the
+//! `X-Connection-Id` header and included in every log line of the connection, and
+//! the list of connected clients at `GET /admin/connections`. Clients that open
+//! many connections with the same endpoint and filter, usually by a reconnect loop
+//! that doesn't close the old ones, get a warning.
+
+use std::{
+    net::IpAddr,
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{web, HttpRequest};
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{config::HttpConfig, h2_websocket::Bridge};
+
+pub const CONNECTION_ID_HEADER: &str = "x-connection-id";
+/// Identical connections of a client from which on every new one gets a warning.
+pub const DUPLICATE_WARNING_THRESHOLD: usize = 5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub connection_id: String,
+    pub endpoint: &'static str,
+    pub network: String,
+    pub remote_addr: String,
+    pub api_key: Option<String>,
+    pub connected_at_ms: u128,
+}
+
+static CONNECTIONS: LazyLock<DashMap<String, ConnectionInfo>> = LazyLock::new(DashMap::new);
+/// Number of connections by client, endpoint, network and filter.
+static IDENTICAL: LazyLock<DashMap<String, usize>> = LazyLock::new(DashMap::new);
+
+/// A random (version 4) UUID.
+pub fn new_connection_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("Failed to generate a connection ID");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// The IP address of the client of a request. Anyone can send `X-Forwarded-For`,
+/// so it's only used if the connection comes from one of the `trusted_proxies`
+/// of the `http` config, from the Unix socket, which only local processes can
+/// connect to, or from the HTTP/2 bridge.
+pub fn client_addr(req: &HttpRequest) -> String {
+    let trusted = req
+        .app_data::<web::Data<HttpConfig>>()
+        .map_or(&[][..], |config| &config.trusted_proxies);
+    let forwarded_for = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let bridged = req
+        .app_data::<web::Data<Bridge>>()
+        .is_some_and(|bridge| req.app_config().local_addr() == bridge.upstream);
+    client_ip(
+        req.peer_addr().filter(|_| !bridged).map(|addr| addr.ip()),
+        &forwarded_for,
+        trusted,
+    )
+}
+
+/// Proxies append the address they got the request from to `X-Forwarded-For`,
+/// so the client is the last address that isn't a trusted proxy.
+fn client_ip(peer: Option<IpAddr>, forwarded_for: &str, trusted: &[IpAddr]) -> String {
+    let is_trusted = |ip: IpAddr| trusted.contains(&ip.to_canonical());
+    match peer {
+        Some(peer) if !is_trusted(peer) => return peer.to_canonical().to_string(),
+        _ => {}
+    }
+    for addr in forwarded_for.rsplit(',').map(str::trim) {
+        match addr.parse::<IpAddr>() {
+            Ok(ip) if is_trusted(ip) => continue,
+            Ok(ip) => return ip.to_canonical().to_string(),
+            Err(_) => break,
+        }
+    }
+    peer.map_or("unknown".to_string(), |peer| {
+        peer.to_canonical().to_string()
+    })
+}
+
+pub fn register(
+    connection_id: &str,
+    endpoint: &'static str,
+    network: &str,
+    remote_addr: &str,
+    api_key: Option<&str>,
+) {
+    let connected_at_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    CONNECTIONS.insert(
+        connection_id.to_string(),
+        ConnectionInfo {
+            connection_id: connection_id.to_string(),
+            endpoint,
+            network: network.to_string(),
+            remote_addr: remote_addr.to_string(),
+            api_key: api_key.map(str::to_string),
+            connected_at_ms,
+        },
+    );
+}
+
+pub fn unregister(connection_id: &str) {
+    CONNECTIONS.remove(connection_id);
+}
+
+/// The key of identical connections. Clients are API keys, or IP addresses
+/// without one.
+pub fn identity(client: &str, endpoint: &str, network: &str, filter_key: Option<&str>) -> String {
+    format!("{client}/{endpoint}/{network}/{}", filter_key.unwrap_or(""))
+}
+
+/// Counts a connection with this identity, and returns how many there are now.
+pub fn add_identical(identity: &str) -> usize {
+    let mut count = IDENTICAL.entry(identity.to_string()).or_default();
+    *count += 1;
+    *count
+}
+
+pub fn remove_identical(identity: &str) {
+    IDENTICAL.remove_if_mut(identity, |_, count| {
+        *count -= 1;
+        *count == 0
+    });
+}
+
+/// Number of connections with this API key.
+pub fn count_of_key(api_key: &str) -> usize {
+    CONNECTIONS
+        .iter()
+        .filter(|entry| entry.api_key.as_deref() == Some(api_key))
+        .count()
+}
+
+/// Connected clients, oldest first.
+pub fn list() -> Vec<ConnectionInfo> {
+    let mut connections = CONNECTIONS
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect::<Vec<_>>();
+    connections.sort_by_key(|connection| connection.connected_at_ms);
+    connections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_uuids() {
+        let id = new_connection_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!("89ab".contains(&id[19..20]));
+        assert_ne!(id, new_connection_id());
+    }
+
+    #[test]
+    fn trusts_forwarded_for_only_from_proxies() {
+        let proxy = "10.0.0.1".parse().unwrap();
+        let client = Some("203.0.113.7".parse().unwrap());
+        assert_eq!(client_ip(client, "198.51.100.1", &[proxy]), "203.0.113.7");
+        assert_eq!(
+            client_ip(Some(proxy), "198.51.100.1, 203.0.113.7", &[proxy]),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_ip(Some(proxy), "203.0.113.7, 10.0.0.1", &[proxy]),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_ip(Some("::ffff:10.0.0.1".parse().unwrap()), "", &[proxy]),
+            "10.0.0.1"
+        );
+        assert_eq!(client_ip(None, "203.0.113.7", &[]), "203.0.113.7");
+        assert_eq!(client_ip(None, "", &[]), "unknown");
+    }
+
+    #[test]
+    fn counts_identical_connections() {
+        let identity = identity("bot", "nft_mint", "mainnet", Some(r#"{"owner_id":"a"}"#));
+        assert_eq!(add_identical(&identity), 1);
+        assert_eq!(add_identical(&identity), 2);
+        remove_identical(&identity);
+        remove_identical(&identity);
+        assert!(!IDENTICAL.contains_key(&identity));
+    }
+}
@@ -0,0 +1,187 @@
+//! Stream entries that can't be turned into events are skipped instead of
+//! stopping the reader, counted in `events_api_invalid_entries_total`, and kept
+//! in the Redis stream `events_api_dead_letters` of their source to be looked at.
+//! Replays and `/history` skip them too, and only the first time an entry is
+//! seen is recorded, however often it's replayed.
+
+use std::{collections::HashMap, fmt, sync::LazyLock};
+
+use dashmap::DashSet;
+use redis::aio::ConnectionManager;
+
+use crate::metrics;
+
+/// Redis stream of the entries that couldn't be read.
+pub const DEAD_LETTER_STREAM: &str = "events_api_dead_letters";
+/// Approximate length that the dead letter stream is trimmed to.
+const MAX_DEAD_LETTERS: usize = 10_000;
+
+/// Entries that were recorded by this instance, by source, stream and ID.
+static RECORDED: LazyLock<DashSet<String>> = LazyLock::new(DashSet::new);
+
+/// Whether the entry wasn't recorded yet. Forgets all entries when there are as
+/// many as the stream keeps, so it stays small.
+fn first_time(source: &str, stream_key: &str, id: &str) -> bool {
+    if RECORDED.len() >= MAX_DEAD_LETTERS {
+        RECORDED.clear();
+    }
+    RECORDED.insert(format!("{source}\n{stream_key}\n{id}"))
+}
+
+/// Why a stream entry couldn't be turned into an event.
+#[derive(Debug)]
+pub enum FromRedisError {
+    MissingField(&'static str),
+    NotAString {
+        field: &'static str,
+        error: redis::RedisError,
+    },
+    Json {
+        field: &'static str,
+        error: serde_json::Error,
+    },
+    Base64 {
+        field: &'static str,
+        error: base64::DecodeError,
+    },
+    Borsh {
+        field: &'static str,
+        error: std::io::Error,
+    },
+}
+
+impl FromRedisError {
+    /// The `error` label of the metric.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FromRedisError::MissingField(_) => "missing_field",
+            FromRedisError::NotAString { .. } => "not_a_string",
+            FromRedisError::Json { .. } => "json",
+            FromRedisError::Base64 { .. } => "base64",
+            FromRedisError::Borsh { .. } => "borsh",
+        }
+    }
+
+    pub fn field(&self) -> &'static str {
+        match self {
+            FromRedisError::MissingField(field)
+            | FromRedisError::NotAString { field, .. }
+            | FromRedisError::Json { field, .. }
+            | FromRedisError::Base64 { field, .. }
+            | FromRedisError::Borsh { field, .. } => field,
+        }
+    }
+}
+
+impl fmt::Display for FromRedisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FromRedisError::MissingField(field) => write!(f, "Missing field {field}"),
+            FromRedisError::NotAString { field, error } => {
+                write!(f, "Field {field} is not a string: {error}")
+            }
+            FromRedisError::Json { field, error } => {
+                write!(f, "Invalid JSON in field {field}: {error}")
+            }
+            FromRedisError::Base64 { field, error } => {
+                write!(f, "Invalid base64 in field {field}: {error}")
+            }
+            FromRedisError::Borsh { field, error } => {
+                write!(f, "Invalid borsh in field {field}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FromRedisError {}
+
+pub struct DeadLetters {
+    pub connection: ConnectionManager,
+}
+
+impl DeadLetters {
+    /// Counts the entry and saves it with the error in the background, unless it
+    /// was already.
+    pub fn push(
+        &self,
+        source: &str,
+        stream_key: &str,
+        id: &str,
+        error: &FromRedisError,
+        values: &HashMap<String, redis::Value>,
+    ) {
+        if !first_time(source, stream_key, id) {
+            tracing::debug!(%id, "Skipping an entry that can't be read: {error}");
+            return;
+        }
+        tracing::error!(%id, "Skipping an entry that can't be read: {error}");
+        metrics::increment(
+            metrics::INVALID_ENTRIES,
+            &[
+                ("source", source),
+                ("stream", stream_key),
+                ("error", error.kind()),
+                ("field", error.field()),
+            ],
+        );
+        let mut command = redis::cmd("XADD");
+        command
+            .arg(DEAD_LETTER_STREAM)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(MAX_DEAD_LETTERS)
+            .arg("*")
+            .arg("source")
+            .arg(source)
+            .arg("stream")
+            .arg(stream_key)
+            .arg("id")
+            .arg(id)
+            .arg("error")
+            .arg(error.to_string());
+        for (field, value) in values {
+            command.arg(format!("field_{field}"));
+            match value {
+                redis::Value::Data(data) => command.arg(data),
+                value => command.arg(format!("{value:?}")),
+            };
+        }
+        let mut connection = self.connection.clone();
+        tokio::spawn(async move {
+            if let Err(err) = command.query_async::<_, ()>(&mut connection).await {
+                tracing::warn!("Failed to save a dead letter: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Encoding, redis_field};
+
+    #[test]
+    fn records_entries_once() {
+        assert!(first_time("dead-letters-test", "nft_mint", "1-0"));
+        assert!(!first_time("dead-letters-test", "nft_mint", "1-0"));
+        assert!(first_time("dead-letters-test", "nft_burn", "1-0"));
+    }
+
+    #[test]
+    fn names_the_field() {
+        let values = HashMap::from([
+            ("number".to_string(), redis::Value::Int(1)),
+            ("invalid".to_string(), redis::Value::Data(b"{".to_vec())),
+        ]);
+        let errors = ["missing", "number", "invalid"]
+            .map(|field| redis_field::<String>(&values, field, Encoding::Json).unwrap_err());
+        assert_eq!(
+            errors.each_ref().map(|error| (error.kind(), error.field())),
+            [
+                ("missing_field", "missing"),
+                ("not_a_string", "number"),
+                ("json", "invalid"),
+            ]
+        );
+    }
+}
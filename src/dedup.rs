@@ -0,0 +1,86 @@
+use std::collections::{HashSet, VecDeque};
+
+/// A fixed-size window of the most recently seen stream entry IDs.
+pub struct RecentIds {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Adds the ID to the window, evicting the oldest one if it's full.
+    /// Returns `false` if the ID is already in the window.
+    pub fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(id.to_string());
+        self.ids.insert(id.to_string());
+        true
+    }
+
+    /// The IDs in the window, oldest first and separated by spaces, to save them
+    /// with the reader's checkpoint.
+    pub fn to_saved(&self) -> String {
+        self.order
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Adds IDs saved with `to_saved`, e.g. by the server before a restart.
+    pub fn restore(&mut self, saved: &str) {
+        for id in saved.split_whitespace() {
+            self.insert(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_oldest_ids() {
+        let mut recent = RecentIds::new(2);
+        assert!(recent.insert("1-0"));
+        assert!(recent.insert("2-0"));
+        assert!(!recent.insert("1-0"));
+        assert!(recent.insert("3-0"));
+        assert!(recent.insert("1-0"));
+        assert!(!recent.insert("3-0"));
+    }
+
+    #[test]
+    fn restores_saved_ids() {
+        let mut recent = RecentIds::new(3);
+        for id in ["1-0", "2-0", "3-0", "4-0"] {
+            recent.insert(id);
+        }
+        assert_eq!(recent.to_saved(), "2-0 3-0 4-0");
+
+        let mut restored = RecentIds::new(2);
+        restored.restore(&recent.to_saved());
+        assert_eq!(restored.to_saved(), "3-0 4-0");
+        assert!(!restored.insert("4-0"));
+        assert!(restored.insert("2-0"));
+
+        let mut empty = RecentIds::new(2);
+        empty.restore("");
+        assert_eq!(empty.to_saved(), "");
+    }
+}
@@ -0,0 +1,138 @@
+//! Email digests, configured with `digests` and `smtp` in the config file. Every
+//! digest collects the matching events of its stream, and emails them every
+//! `interval_minutes` if there were any. Collected events are lost on restart.
+
+use std::{sync::Arc, time::Duration};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{sync::broadcast::error::RecvError, time::MissedTickBehavior};
+
+use crate::{
+    broadcast::{for_each_event_type, Broadcasts, EventReceiver, EventTypeVisitor},
+    config::{DigestConfig, SmtpConfig},
+    fields,
+    smtp::{self, Email},
+    webhooks::render,
+    EventFilter, FromRedis, TaggedEvent,
+};
+
+pub fn spawn(digests: Vec<DigestConfig>, smtp: SmtpConfig, broadcasts: &Broadcasts) {
+    for_each_event_type(&mut Spawner {
+        digests,
+        smtp: Arc::new(smtp),
+        broadcasts,
+    });
+}
+
+struct Spawner<'a> {
+    digests: Vec<DigestConfig>,
+    smtp: Arc<SmtpConfig>,
+    broadcasts: &'a Broadcasts,
+}
+
+impl EventTypeVisitor for Spawner<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        for digest in self.digests.iter().filter(|d| d.stream == E::STREAM_KEY) {
+            let filter = digest.filter.as_ref().map(|filter| {
+                fields::parse_filter::<F>(filter).unwrap_or_else(|err| {
+                    panic!("Invalid filter of digest to {:?}: {err}", digest.to)
+                })
+            });
+            tokio::spawn(collect(
+                self.broadcasts.subscribe::<E>(&digest.network),
+                filter,
+                digest.clone(),
+                Arc::clone(&self.smtp),
+            ));
+        }
+    }
+}
+
+async fn collect<E: Serialize + FromRedis, F: EventFilter<E>>(
+    mut events: EventReceiver<E>,
+    filter: Option<F>,
+    digest: DigestConfig,
+    smtp: Arc<SmtpConfig>,
+) {
+    let interval = Duration::from_secs(digest.interval_minutes.max(1) * 60);
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut lines = Vec::new();
+    let mut count = 0;
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = ticks.tick() => {
+                if count > 0 {
+                    send(&digest, &smtp, E::STREAM_KEY, &lines, count).await;
+                    lines.clear();
+                    count = 0;
+                }
+                continue;
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    "Digest to {:?} fell behind, {skipped} events were skipped",
+                    digest.to
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !filter.as_ref().is_none_or(|f| f.matches(&event.event)) {
+            continue;
+        }
+        count += 1;
+        if lines.len() < digest.max_events {
+            let event = serde_json::to_value(TaggedEvent {
+                source: &event.source,
+                stream_id: event.id,
+                event: &event.event,
+            })
+            .unwrap();
+            lines.push(match &digest.template {
+                Some(template) => render(template, E::STREAM_KEY, &event),
+                None => event.to_string(),
+            });
+        }
+    }
+}
+
+async fn send(
+    digest: &DigestConfig,
+    smtp: &SmtpConfig,
+    stream_key: &str,
+    lines: &[String],
+    count: usize,
+) {
+    let subject = digest
+        .subject
+        .as_deref()
+        .unwrap_or("{count} new {stream} events")
+        .replace("{count}", &count.to_string())
+        .replace("{stream}", stream_key);
+    let mut body = lines.join("\n");
+    if count > lines.len() {
+        body.push_str(&format!("\n\n...and {} more", count - lines.len()));
+    }
+    let email = Email {
+        to: &digest.to,
+        subject: &subject,
+        body: &body,
+    };
+    match smtp::send(smtp, &email).await {
+        Ok(()) => tracing::info!(
+            "Sent a digest of {count} {stream_key} events to {:?}",
+            digest.to
+        ),
+        Err(err) => tracing::warn!("Failed to send a digest to {:?}: {err}", digest.to),
+    }
+}
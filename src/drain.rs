@@ -0,0 +1,224 @@
+//! Draining for rolling deploys, started with `POST /admin/drain`: new WebSocket
+//! connections are refused with 503, connected clients are told to reconnect
+//! elsewhere, and the server stops when the last client left or at the deadline.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::{dev::ServerHandle, http::header, web, HttpRequest, HttpResponse};
+use dashmap::DashSet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::{self, AdminToken},
+    protocol::ControlFrame,
+    EventFilter, EventWebSocket, FromRedis, NetworkSockets, Server, UnsubscribeFromEvents,
+};
+
+const DEFAULT_DEADLINE_SECS: u64 = 300;
+const DEFAULT_RETRY_AFTER_SECS: u64 = 10;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Drain {
+    draining: AtomicBool,
+    retry_after_secs: AtomicU64,
+    /// Where clients are told to reconnect, if the request doesn't say.
+    reconnect_url: Option<String>,
+    /// Set once the HTTP server runs.
+    handle: OnceLock<ServerHandle>,
+}
+
+impl Drain {
+    pub fn new(reconnect_url: Option<String>) -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+            retry_after_secs: AtomicU64::new(DEFAULT_RETRY_AFTER_SECS),
+            reconnect_url,
+            handle: OnceLock::new(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_handle(&self, handle: ServerHandle) {
+        let _ = self.handle.set(handle);
+    }
+}
+
+/// The answer to upgrade requests while the server is draining, or `None` if it
+/// isn't.
+pub fn draining_response(req: &HttpRequest) -> Option<HttpResponse> {
+    let drain = req.app_data::<web::Data<Drain>>()?;
+    drain.draining.load(Ordering::Relaxed).then(|| {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((
+                header::RETRY_AFTER,
+                drain.retry_after_secs.load(Ordering::Relaxed),
+            ))
+            .body("The server is shutting down")
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DrainRequest {
+    /// Stop after this long even if clients are still connected.
+    deadline_sec: Option<u64>,
+    /// `Retry-After` of refused upgrades.
+    retry_after_sec: Option<u64>,
+    /// Overrides the configured `drain_reconnect_url`.
+    reconnect_to: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DrainResponse {
+    connected: usize,
+}
+
+pub async fn drain(
+    req: HttpRequest,
+    body: web::Json<DrainRequest>,
+    token: web::Data<AdminToken>,
+    server: web::Data<Addr<Server>>,
+    drain: web::Data<Drain>,
+) -> HttpResponse {
+    if !admin::authorized(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let body = body.into_inner();
+    if let Some(retry_after) = body.retry_after_sec {
+        drain.retry_after_secs.store(retry_after, Ordering::Relaxed);
+    }
+    if drain.draining.swap(true, Ordering::Relaxed) {
+        return HttpResponse::Conflict().body("Already draining");
+    }
+
+    let reconnect_to = body.reconnect_to.or_else(|| drain.reconnect_url.clone());
+    let connected = match server.send(ReconnectAll { reconnect_to }).await {
+        Ok(notified) => notified,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    tracing::info!("Draining {connected} clients");
+
+    let deadline =
+        Instant::now() + Duration::from_secs(body.deadline_sec.unwrap_or(DEFAULT_DEADLINE_SECS));
+    let server = server.get_ref().clone();
+    let drain = drain.into_inner();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            let connected = server.send(CountClients).await.unwrap_or(0);
+            if connected == 0 {
+                tracing::info!("All clients left, stopping");
+                break;
+            }
+            if Instant::now() >= deadline {
+                tracing::info!("Drain deadline passed with {connected} clients, stopping");
+                break;
+            }
+        }
+        if let Some(handle) = drain.handle.get() {
+            handle.stop(true).await;
+        }
+    });
+    HttpResponse::Ok().json(DrainResponse { connected })
+}
+
+/// Tells every client where to reconnect to. Responds with the number of clients.
+#[derive(Message)]
+#[rtype(result = "usize")]
+struct ReconnectAll {
+    reconnect_to: Option<String>,
+}
+
+/// Sends the client where to reconnect to, and which event to resume after.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Reconnect {
+    reconnect_to: Option<String>,
+}
+
+impl Handler<ReconnectAll> for Server {
+    type Result = usize;
+
+    fn handle(&mut self, msg: ReconnectAll, _ctx: &mut Self::Context) -> Self::Result {
+        let msg = Arc::new(Reconnect {
+            reconnect_to: msg.reconnect_to,
+        });
+        for sockets in self.networks.values() {
+            reconnect(&sockets.nft_mint_sockets, &msg);
+            reconnect(&sockets.nft_transfer_sockets, &msg);
+            reconnect(&sockets.nft_burn_sockets, &msg);
+            reconnect(&sockets.potlock_donation_sockets, &msg);
+            reconnect(&sockets.potlock_pot_project_donation_sockets, &msg);
+            reconnect(&sockets.potlock_pot_donation_sockets, &msg);
+            reconnect(&sockets.trade_pool_sockets, &msg);
+            reconnect(&sockets.trade_swap_sockets, &msg);
+            reconnect(&sockets.trade_pool_change_sockets, &msg);
+        }
+        self.networks.values().map(|sockets| sockets.len()).sum()
+    }
+}
+
+fn reconnect<E: FromRedis + Unpin + 'static, F: EventFilter<E> + Unpin + 'static>(
+    sockets: &DashSet<Addr<EventWebSocket<E, F>>>,
+    msg: &Arc<Reconnect>,
+) where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    for socket in sockets.iter() {
+        socket.do_send(Arc::clone(msg));
+    }
+}
+
+impl<E: Unpin + 'static, F: EventFilter<E> + Unpin + 'static> Handler<Arc<Reconnect>>
+    for EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Arc<Reconnect>, ctx: &mut Self::Context) -> Self::Result {
+        let frame = ControlFrame::Reconnect {
+            message: "The server is shutting down",
+            connection_id: &self.connection_id,
+            reconnect_to: msg.reconnect_to.as_deref(),
+            resume_from: self.last_id,
+        };
+        ctx.text(serde_json::to_string(&frame).unwrap());
+    }
+}
+
+/// Responds with the number of connected WebSocket clients of event endpoints.
+#[derive(Message)]
+#[rtype(result = "usize")]
+struct CountClients;
+
+impl NetworkSockets {
+    fn len(&self) -> usize {
+        self.nft_mint_sockets.len()
+            + self.nft_transfer_sockets.len()
+            + self.nft_burn_sockets.len()
+            + self.potlock_donation_sockets.len()
+            + self.potlock_pot_project_donation_sockets.len()
+            + self.potlock_pot_donation_sockets.len()
+            + self.trade_pool_sockets.len()
+            + self.trade_swap_sockets.len()
+            + self.trade_pool_change_sockets.len()
+    }
+}
+
+impl Handler<CountClients> for Server {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: CountClients, _ctx: &mut Self::Context) -> Self::Result {
+        self.networks.values().map(|sockets| sockets.len()).sum()
+    }
+}
@@ -0,0 +1,13 @@
+//! Streams whose entries aren't JSON, like high-volume trade streams that the
+//! indexer writes in borsh to keep Redis small. Each source knows the encoding
+//! of its streams, and readers decode entries with it.
+
+use std::io;
+
+use borsh::BorshDeserialize;
+
+/// Reads JSON that is written as a borsh string.
+pub fn json_string<R: io::Read>(reader: &mut R) -> io::Result<serde_json::Value> {
+    let json = String::deserialize_reader(reader)?;
+    serde_json::from_str(&json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
@@ -0,0 +1,81 @@
+//! A small web UI at `/explorer`, with the `explorer` cargo feature, for trying
+//! out the endpoints: pick one, fill in a filter from its fields, connect, and
+//! watch the events. It's one static page that talks to the public API like any
+//! other client.
+
+use actix_web::{web, HttpResponse};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    EventFilter, FromRedis,
+};
+
+const PAGE: &str = include_str!("explorer.html");
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("").route(web::get().to(page)))
+        .service(web::resource("/endpoints").route(web::get().to(endpoints)));
+}
+
+async fn page() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(PAGE)
+}
+
+#[derive(Debug, Serialize)]
+struct EndpointInfo {
+    stream: &'static str,
+    /// Path after `/v0` or `/v0/<network>`, e.g. `/nft/nft_mint`.
+    path: String,
+    filter_fields: Vec<&'static str>,
+}
+
+#[derive(Default)]
+struct Endpoints(Vec<EndpointInfo>);
+
+impl EventTypeVisitor for Endpoints {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        let scope = E::STREAM_KEY.split('_').next().unwrap_or_default();
+        self.0.push(EndpointInfo {
+            stream: E::STREAM_KEY,
+            path: format!("/{scope}/{}", E::STREAM_KEY),
+            filter_fields: F::filter_fields(),
+        });
+    }
+}
+
+/// The event endpoints and the fields of their filters.
+async fn endpoints() -> HttpResponse {
+    let mut endpoints = Endpoints::default();
+    for_each_event_type(&mut endpoints);
+    HttpResponse::Ok().json(endpoints.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_endpoints() {
+        let mut endpoints = Endpoints::default();
+        for_each_event_type(&mut endpoints);
+        let nft_mint = endpoints
+            .0
+            .iter()
+            .find(|endpoint| endpoint.stream == "nft_mint")
+            .unwrap();
+        assert_eq!(nft_mint.path, "/nft/nft_mint");
+        assert!(nft_mint.filter_fields.contains(&"contract_id"));
+        assert!(endpoints
+            .0
+            .iter()
+            .any(|endpoint| endpoint.path == "/potlock/potlock_pot_project_donation"));
+    }
+}
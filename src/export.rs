@@ -0,0 +1,284 @@
+//! Exports at `/v0/.../<endpoint>/export`, for `curl | jq` pipelines and ingestion
+//! jobs that don't speak WebSocket: the stored events after `from_stream_id` (or
+//! the last `replay_last`) that match `filter`, then the live events, as
+//! newline-delimited JSON in one chunked response. Needs an API key.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::Arc,
+};
+
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    broadcast::{Broadcasts, EventReceiver},
+    disabled_response, fields, plans,
+    replay::{ReplayPage, ReplayQuery, ReplayStart, StreamId},
+    unix_time_ms, usage, Event, EventFilter, FromRedis, Networks, ReadReplay, Server, TaggedEvent,
+    DEFAULT_NETWORK,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    /// Filter in the same JSON format as the WebSocket filter message.
+    filter: Option<String>,
+    /// Keep the response open for live events after the stored ones.
+    #[serde(default = "default_live")]
+    live: bool,
+}
+
+fn default_live() -> bool {
+    true
+}
+
+/// Where the next page of stored events is read from, if there is one.
+struct NextPage {
+    server: Addr<Server>,
+    network: String,
+    after: StreamId,
+}
+
+/// What's left to send of an export.
+struct Export<E, F> {
+    stored: VecDeque<Arc<Event<E>>>,
+    next_page: Option<NextPage>,
+    /// `None` once only the stored events are sent.
+    live: Option<EventReceiver<E>>,
+    /// Live events up to these IDs were sent as stored events, by source.
+    last_stored: HashMap<Arc<str>, StreamId>,
+    filter: Option<F>,
+    api_key: String,
+}
+
+impl<E: Serialize + FromRedis + Send + Sync + 'static, F: EventFilter<E>> Export<E, F> {
+    /// The next matching event, or `None` when the export is done.
+    async fn next_event(&mut self) -> Option<io::Result<Arc<Event<E>>>> {
+        loop {
+            if self.stored.is_empty() {
+                if let Some(next_page) = self.next_page.take() {
+                    if let Err(err) = self.read_page(next_page).await {
+                        return Some(Err(err));
+                    }
+                    continue;
+                }
+            }
+            let event = match self.stored.pop_front() {
+                Some(event) => event,
+                None => match self.live.as_mut()?.recv().await {
+                    Ok(event)
+                        if self
+                            .last_stored
+                            .get(&event.source)
+                            .is_some_and(|last_id| event.id <= *last_id) =>
+                    {
+                        continue
+                    }
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(count)) => {
+                        // A gap would go unnoticed, so end the response and let the
+                        // client resume after the last line instead
+                        return Some(Err(io::Error::other(format!(
+                            "The export fell behind by {count} events"
+                        ))));
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+            };
+            if self.filter.as_ref().is_none_or(|f| f.matches(&event.event)) {
+                return Some(Ok(event));
+            }
+        }
+    }
+
+    /// Reads the next page of stored events, before the live events.
+    async fn read_page(&mut self, next_page: NextPage) -> io::Result<()> {
+        let page = next_page
+            .server
+            .send(ReadReplay::<E> {
+                network: next_page.network.clone(),
+                start: ReplayStart::After(next_page.after),
+                _marker: Default::default(),
+            })
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|err| {
+                tracing::error!("Failed to read {} for an export: {err}", E::STREAM_KEY);
+                io::Error::other("Failed to read events")
+            })?;
+        self.add_page(page, next_page.server, next_page.network);
+        Ok(())
+    }
+
+    fn add_page(&mut self, page: ReplayPage<E>, server: Addr<Server>, network: String) {
+        for event in &page.events {
+            self.last_stored.insert(Arc::clone(&event.source), event.id);
+        }
+        self.stored.extend(page.events);
+        self.next_page = page.next.map(|after| NextPage {
+            server,
+            network,
+            after,
+        });
+    }
+
+    /// The next line of the response.
+    async fn next_line(mut self) -> Option<(io::Result<Bytes>, Self)> {
+        let event = match self.next_event().await? {
+            Ok(event) => event,
+            Err(err) => {
+                tracing::warn!("Export of {} failed: {err}", E::STREAM_KEY);
+                self.live = None;
+                self.stored.clear();
+                self.next_page = None;
+                return Some((Err(err), self));
+            }
+        };
+        let mut line = serde_json::to_vec(&TaggedEvent {
+            source: &event.source,
+            stream_id: event.id,
+            event: &event.event,
+        })
+        .unwrap();
+        line.push(b'\n');
+        usage::record(&self.api_key, line.len());
+        Some((Ok(line.into()), self))
+    }
+}
+
+pub async fn export<
+    E: Serialize + FromRedis + Send + Sync + 'static,
+    F: EventFilter<E> + DeserializeOwned + 'static,
+>(
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+    replay: web::Query<ReplayQuery>,
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+    broadcasts: web::Data<Broadcasts>,
+) -> actix_web::Result<HttpResponse> {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return Ok(HttpResponse::NotFound().body(format!("Unknown network: {network}")));
+    }
+    if let Some(response) = disabled_response::<E>(&req) {
+        return Ok(response);
+    }
+    let (api_key, plan) = plans::check_access(&req, E::STREAM_KEY)?;
+    let Some(api_key) = api_key else {
+        return Ok(HttpResponse::Unauthorized().body("Exports need an API key"));
+    };
+    let filter = match query.filter.as_deref().map(fields::parse_filter_str::<F>) {
+        Some(Ok(filter)) => Some(filter),
+        Some(Err(err)) => {
+            return Ok(HttpResponse::BadRequest().body(format!("Invalid filter: {err}")))
+        }
+        None => None,
+    };
+    let start = match (replay.start(), &plan) {
+        (Some(start), Some(plan)) => match plan.limit_replay(start, unix_time_ms()) {
+            Ok(start) => Some(start),
+            Err(message) => return Ok(HttpResponse::Forbidden().body(message)),
+        },
+        (start, _) => start,
+    };
+
+    // Subscribe before reading the stored events, so that nothing is missed in between
+    let live = query.live.then(|| broadcasts.subscribe::<E>(&network));
+    let mut export = Export {
+        stored: VecDeque::new(),
+        next_page: None,
+        live,
+        last_stored: HashMap::new(),
+        filter,
+        api_key: api_key.name.clone(),
+    };
+    if let Some(start) = start {
+        match server
+            .send(ReadReplay::<E> {
+                network: network.clone(),
+                start,
+                _marker: Default::default(),
+            })
+            .await
+        {
+            // Later pages are read when the response gets to them
+            Ok(Ok(page)) => export.add_page(page, server.get_ref().clone(), network),
+            Ok(Err(err)) => {
+                tracing::error!("Failed to read {} for an export: {err}", E::STREAM_KEY);
+                return Ok(HttpResponse::InternalServerError().body("Failed to read events"));
+            }
+            Err(err) => return Ok(HttpResponse::InternalServerError().body(err.to_string())),
+        }
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(futures_util::stream::unfold(export, Export::next_line)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nft_events::{FullNftMintEvent, NftMintFilter};
+
+    use super::*;
+
+    fn event(source: &str, id: &str, owner_id: &str) -> Arc<Event<FullNftMintEvent>> {
+        let mint = serde_json::json!({ "owner_id": owner_id, "token_ids": ["1"], "memo": null });
+        let context = serde_json::json!({
+            "transaction_id": "9ZsR3fUsVrbRYgpbLBaQrZR3xCTxV3DuL5fJ8qTH3QbW",
+            "receipt_id": "9ZsR3fUsVrbRYgpbLBaQrZR3xCTxV3DuL5fJ8qTH3QbW",
+            "block_height": 1,
+            "block_timestamp_nanosec": "1",
+            "contract_id": "nft.near",
+        });
+        Arc::new(Event {
+            source: source.into(),
+            id: id.parse().unwrap(),
+            duplicate: false,
+            event: FullNftMintEvent {
+                event: serde_json::from_value(mint).unwrap(),
+                context: serde_json::from_value(context).unwrap(),
+            },
+            span: tracing::Span::none(),
+            frames: Default::default(),
+            raw: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn sends_stored_then_live_events_once() {
+        let (sender, live) = tokio::sync::broadcast::channel(16);
+        let stored = vec![
+            event("a", "1-0", "alice.near"),
+            event("a", "2-0", "bob.near"),
+        ];
+        let mut export = Export::<FullNftMintEvent, NftMintFilter> {
+            last_stored: HashMap::from([(Arc::from("a"), StreamId(2, 0))]),
+            stored: stored.into(),
+            next_page: None,
+            live: Some(live),
+            filter: Some(serde_json::from_str(r#"{"owner_id": "alice.near"}"#).unwrap()),
+            api_key: "export-test".to_string(),
+        };
+        assert!(sender.send(event("a", "2-0", "alice.near")).is_ok());
+        assert!(sender.send(event("a", "3-0", "alice.near")).is_ok());
+        drop(sender);
+        let mut lines = Vec::new();
+        while let Some((line, next)) = export.next_line().await {
+            let line = String::from_utf8(line.unwrap().to_vec()).unwrap();
+            assert!(line.ends_with('\n'));
+            let line = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+            lines.push(line["stream_id"].as_str().unwrap().to_string());
+            export = next;
+        }
+        assert_eq!(lines, ["1-0", "3-0"]);
+    }
+}
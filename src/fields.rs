@@ -0,0 +1,126 @@
+//! Checks for unknown fields in filter messages. Filters can't use
+//! `deny_unknown_fields`, since the same message also has connection options.
+//! Serde only lists the fields of structs without flattened fields, so filters,
+//! which flatten `CommonFilter`, list theirs with [`FilterFields`].
+
+use serde::{
+    de::{self, value, DeserializeOwned, Visitor},
+    forward_to_deserialize_any, Deserializer,
+};
+use serde_json::Value;
+
+/// Returns the field names of a struct that derives `Deserialize`, or an empty list
+/// for other types.
+pub fn field_names<T: DeserializeOwned>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// The fields that clients can send in the filters of a stream.
+pub trait FilterFields {
+    /// With aliases, and the fields of a flattened `CommonFilter`.
+    fn filter_fields() -> Vec<&'static str>;
+}
+
+/// Returns an error with the unknown keys of `message` and the valid field names,
+/// if it's an object with keys that are not in `known`.
+pub fn check(message: &Value, known: &[&str]) -> Result<(), String> {
+    let Value::Object(object) = message else {
+        return Ok(());
+    };
+    let unknown = object
+        .keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| format!("`{key}`"))
+        .collect::<Vec<_>>();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    let valid = known
+        .iter()
+        .map(|key| format!("`{key}`"))
+        .collect::<Vec<_>>();
+    Err(format!(
+        "Unknown fields: {}. Valid fields: {}",
+        unknown.join(", "),
+        valid.join(", ")
+    ))
+}
+
+/// Parses a filter, rejecting fields that aren't fields of `F`. Every entry point
+/// that takes filters from clients parses them with this, so that a typo is an
+/// error instead of a filter that matches all events.
+pub fn parse_filter<F: FilterFields + DeserializeOwned>(filter: &Value) -> Result<F, String> {
+    check(filter, &F::filter_fields())?;
+    F::deserialize(filter).map_err(|err| err.to_string())
+}
+
+/// `parse_filter` of a JSON string.
+pub fn parse_filter_str<F: FilterFields + DeserializeOwned>(filter: &str) -> Result<F, String> {
+    parse_filter(&serde_json::from_str(filter).map_err(|err| err.to_string())?)
+}
+
+/// A deserializer that only records the field names of the struct it's asked for.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("only field names are read"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Filter {
+        contract_id: Option<String>,
+        #[serde(rename = "account")]
+        account_id: Option<String>,
+    }
+
+    impl FilterFields for Filter {
+        fn filter_fields() -> Vec<&'static str> {
+            field_names::<Self>().to_vec()
+        }
+    }
+
+    #[test]
+    fn lists_unknown_fields() {
+        assert_eq!(field_names::<Filter>(), ["contract_id", "account"]);
+        assert!(field_names::<u64>().is_empty());
+        let known = field_names::<Filter>();
+        assert_eq!(check(&json!({ "contract_id": "a.near" }), known), Ok(()));
+        assert_eq!(
+            check(&json!({ "contractid": "a.near" }), known),
+            Err("Unknown fields: `contractid`. Valid fields: `contract_id`, `account`".to_string())
+        );
+        assert!(parse_filter_str::<Filter>(r#"{"account": "a.near"}"#).is_ok());
+        assert!(parse_filter_str::<Filter>(r#"{"account_id": "a.near"}"#).is_err());
+        assert!(parse_filter_str::<Filter>("[").is_err());
+    }
+}
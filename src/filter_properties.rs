@@ -0,0 +1,126 @@
+//! Invariants that every `EventFilter` has to keep, checked with random events
+//! and filters by the tests of the event modules.
+
+use proptest::{prelude::*, test_runner::TestCaseError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{config::Encoding, fields::FilterFields, EventFilter, FromRedis};
+
+/// Accounts that events and filters are made of, few enough that filters often
+/// match.
+pub const ACCOUNTS: &[&str] = &["alice.near", "bob.near", "carol.near", "dave.tg"];
+
+pub fn account() -> impl Strategy<Value = String> {
+    prop::sample::select(ACCOUNTS).prop_map(str::to_string)
+}
+
+/// An account as a filter value.
+pub fn account_value() -> BoxedStrategy<Value> {
+    account().prop_map(Value::from).boxed()
+}
+
+/// A list of up to 3 accounts as a filter value.
+pub fn accounts_value() -> BoxedStrategy<Value> {
+    prop::collection::vec(account(), 1..=3)
+        .prop_map(Value::from)
+        .boxed()
+}
+
+/// A block height as a filter value, around the block height 1 of the events.
+pub fn block_height_value() -> BoxedStrategy<Value> {
+    (0u64..=2).prop_map(Value::from).boxed()
+}
+
+/// The transaction or receipt ID of the events (`tx` or `receipt`) or another one,
+/// as a filter value.
+pub fn id_value(id: &'static str) -> BoxedStrategy<Value> {
+    prop::sample::select(vec![id, "other"])
+        .prop_map(Value::from)
+        .boxed()
+}
+
+/// Amounts in yocto, up to 2 NEAR.
+pub fn amount() -> impl Strategy<Value = String> {
+    (0u128..2000).prop_map(|milli| (milli * 10u128.pow(21)).to_string())
+}
+
+/// A filter of random fields, each with the value of the strategy or left out.
+pub fn filter(
+    fields: Vec<(&'static str, BoxedStrategy<Value>)>,
+) -> BoxedStrategy<Map<String, Value>> {
+    fields
+        .into_iter()
+        .map(|(name, value)| prop::option::of(value).prop_map(move |value| (name, value)))
+        .collect::<Vec<_>>()
+        .prop_map(|fields| {
+            fields
+                .into_iter()
+                .filter_map(|(name, value)| Some((name.to_string(), value?)))
+                .collect()
+        })
+        .boxed()
+}
+
+/// The event of a stream entry with these fields.
+pub fn event<E: FromRedis>(fields: Vec<(&str, Value)>) -> E {
+    let values = fields
+        .into_iter()
+        .map(|(field, value)| {
+            let value = redis::Value::Data(value.to_string().into_bytes());
+            (field.to_string(), value)
+        })
+        .collect();
+    E::decode(&values, Encoding::Json).expect("Invalid event")
+}
+
+fn matches<E, F: EventFilter<E> + DeserializeOwned>(
+    filter: &Map<String, Value>,
+    event: &E,
+) -> bool {
+    serde_json::from_value::<F>(Value::Object(filter.clone()))
+        .expect("Invalid filter")
+        .matches(event)
+}
+
+/// The empty filter matches every event, and a filter matches at most the events
+/// that it matches without any one of its fields.
+pub fn check<E, F: EventFilter<E> + DeserializeOwned>(
+    event: &E,
+    filter: &Map<String, Value>,
+) -> Result<(), TestCaseError> {
+    prop_assert!(matches::<E, F>(&Map::new(), event), "Empty filter");
+    if !matches::<E, F>(filter, event) {
+        return Ok(());
+    }
+    for field in filter.keys() {
+        let mut looser = filter.clone();
+        looser.remove(field);
+        prop_assert!(
+            matches::<E, F>(&looser, event),
+            "Matches with {filter:?} but not without {field}"
+        );
+    }
+    Ok(())
+}
+
+/// `filter_fields` lists the fields that the filter serializes, and only fields
+/// that it reads.
+pub fn check_fields<F: FilterFields + DeserializeOwned + Serialize>() {
+    let listed = F::filter_fields();
+    let empty = serde_json::from_value::<F>(Value::Object(Map::new())).expect("Invalid filter");
+    let Value::Object(serialized) = serde_json::to_value(empty).unwrap() else {
+        panic!("Filters are objects");
+    };
+    for field in serialized.keys() {
+        assert!(listed.contains(&field.as_str()), "{field} isn't listed");
+    }
+    for field in listed {
+        // Not a valid value of any field, so it's only an error for fields
+        let invalid = serde_json::json!({ field: [{}] });
+        assert!(
+            serde_json::from_value::<F>(invalid).is_err(),
+            "{field} isn't a field"
+        );
+    }
+}
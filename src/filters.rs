@@ -0,0 +1,252 @@
+//! Saved filters: `POST /v0/filters` saves a filter in Redis and responds with its
+//! ID, and clients connect with `?filter_id=<id>` to start with this filter.
+//! Clients with an API key also get their last filter of an endpoint back when they
+//! reconnect, and in the `ack_timeout_ms` mode, the events that they didn't ack
+//! yet, with a cursor of the stream per consumer.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use actix_web::{web, HttpResponse};
+use redis::aio::ConnectionManager;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    config::ApiKeyConfig,
+    fields,
+    replay::StreamId,
+    EventFilter, FromRedis,
+};
+
+pub struct SavedFilters {
+    pub connection: ConnectionManager,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedFilter {
+    /// Stream key of the endpoint, e.g. `nft_transfer`.
+    stream: String,
+    /// Filter in the same format as the WebSocket filter message.
+    filter: Value,
+}
+
+#[derive(Serialize)]
+struct SaveFilterResponse {
+    filter_id: String,
+}
+
+fn redis_key(id: &str) -> String {
+    format!("events_api_filter_{id}")
+}
+
+/// Saves the filter, responds with its ID. The same filter always gets the same ID.
+pub async fn save(body: web::Json<SavedFilter>, filters: web::Data<SavedFilters>) -> HttpResponse {
+    let mut validator = Validator {
+        filter: &body,
+        result: None,
+    };
+    for_each_event_type(&mut validator);
+    match validator.result {
+        Some(Ok(())) => {}
+        Some(Err(err)) => return HttpResponse::BadRequest().body(format!("Invalid filter: {err}")),
+        None => return HttpResponse::BadRequest().body(format!("Unknown stream: {}", body.stream)),
+    }
+
+    let json = serde_json::to_string(&*body).unwrap();
+    let mut hasher = DefaultHasher::new();
+    json.hash(&mut hasher);
+    let id = format!("{:016x}", hasher.finish());
+    let result: redis::RedisResult<()> = redis::cmd("SET")
+        .arg(redis_key(&id))
+        .arg(&json)
+        .query_async(&mut filters.connection.clone())
+        .await;
+    match result {
+        Ok(()) => HttpResponse::Ok().json(SaveFilterResponse { filter_id: id }),
+        Err(err) => {
+            tracing::error!("Failed to save filter: {err}");
+            HttpResponse::InternalServerError().body("Failed to save filter")
+        }
+    }
+}
+
+/// The last filter message that an API key sent to an endpoint, applied when it
+/// connects again.
+pub struct LastFilter {
+    connection: ConnectionManager,
+    redis_key: String,
+}
+
+impl LastFilter {
+    pub fn new(
+        filters: &SavedFilters,
+        api_key: &ApiKeyConfig,
+        network: &str,
+        stream: &str,
+    ) -> Self {
+        Self {
+            connection: filters.connection.clone(),
+            redis_key: format!("events_api_last_filter_{}_{network}_{stream}", api_key.name),
+        }
+    }
+
+    pub async fn load(&self) -> redis::RedisResult<Option<String>> {
+        redis::cmd("GET")
+            .arg(&self.redis_key)
+            .query_async(&mut self.connection.clone())
+            .await
+    }
+
+    /// Saves the message in the background.
+    pub fn save(&self, message: &str) {
+        let mut connection = self.connection.clone();
+        let command = redis::cmd("SET").arg(&self.redis_key).arg(message).clone();
+        tokio::spawn(async move {
+            if let Err(err) = command.query_async::<_, ()>(&mut connection).await {
+                tracing::warn!("Failed to save the last filter: {err}");
+            }
+        });
+    }
+}
+
+/// Where a consumer of an API key in the `ack_timeout_ms` mode left off on an
+/// endpoint: all events up to this stream ID were acked or filtered out.
+pub struct Cursor {
+    connection: ConnectionManager,
+    /// The key without the consumer.
+    prefix: String,
+    redis_key: String,
+}
+
+impl Cursor {
+    pub fn new(
+        filters: &SavedFilters,
+        api_key: &ApiKeyConfig,
+        network: &str,
+        stream: &str,
+        message: &Map<String, Value>,
+    ) -> Self {
+        let prefix = format!("events_api_cursor_{}_{network}_{stream}", api_key.name);
+        Self {
+            connection: filters.connection.clone(),
+            redis_key: format!("{prefix}_{}", consumer(message)),
+            prefix,
+        }
+    }
+
+    /// Switches to the cursor of the consumer of a new message.
+    pub fn set_message(&mut self, message: &Map<String, Value>) {
+        self.redis_key = format!("{}_{}", self.prefix, consumer(message));
+    }
+
+    pub async fn load(&self) -> anyhow::Result<Option<StreamId>> {
+        let id: Option<String> = redis::cmd("GET")
+            .arg(&self.redis_key)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        id.map(|id| id.parse()).transpose()
+    }
+
+    /// Saves the cursor in the background.
+    pub fn save(&self, id: StreamId) {
+        let mut connection = self.connection.clone();
+        let command = redis::cmd("SET")
+            .arg(&self.redis_key)
+            .arg(id.to_string())
+            .clone();
+        tokio::spawn(async move {
+            if let Err(err) = command.query_async::<_, ()>(&mut connection).await {
+                tracing::warn!("Failed to save the cursor: {err}");
+            }
+        });
+    }
+}
+
+/// Who a cursor is of: the `client_id` option of the message, else its `group`,
+/// else its filter and options, so that connections of a key with different
+/// filters or groups don't move each other's cursor.
+fn consumer(message: &Map<String, Value>) -> String {
+    if let Some(Value::String(client_id)) = message.get("client_id") {
+        return format!("client_{client_id}");
+    }
+    if let Some(Value::String(group)) = message.get("group") {
+        return format!("group_{group}");
+    }
+    let mut hasher = DefaultHasher::new();
+    Value::Object(message.clone()).to_string().hash(&mut hasher);
+    format!("filter_{:016x}", hasher.finish())
+}
+
+impl SavedFilters {
+    /// Loads the filter with this ID and its JSON, `Err` with a message for the
+    /// client if it doesn't exist or is a filter of another stream.
+    pub async fn load<E: FromRedis, F: DeserializeOwned>(
+        &self,
+        id: &str,
+    ) -> anyhow::Result<Result<(F, Value), String>> {
+        let json: Option<String> = redis::cmd("GET")
+            .arg(redis_key(id))
+            .query_async(&mut self.connection.clone())
+            .await?;
+        let Some(json) = json else {
+            return Ok(Err(format!("Unknown filter: {id}")));
+        };
+        let saved = serde_json::from_str::<SavedFilter>(&json)?;
+        if saved.stream != E::STREAM_KEY {
+            return Ok(Err(format!("Filter {id} is a filter of {}", saved.stream)));
+        }
+        Ok(F::deserialize(&saved.filter)
+            .map(|filter| (filter, saved.filter))
+            .map_err(|err| err.to_string()))
+    }
+}
+
+/// Checks that the filter is a valid filter of its stream.
+struct Validator<'a> {
+    filter: &'a SavedFilter,
+    /// `None` if the stream doesn't exist.
+    result: Option<Result<(), String>>,
+}
+
+impl EventTypeVisitor for Validator<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        if self.filter.stream == E::STREAM_KEY {
+            self.result = Some(
+                fields::check(&self.filter.filter, &F::filter_fields()).and_then(|()| {
+                    F::deserialize(&self.filter.filter)
+                        .map(drop)
+                        .map_err(|e| e.to_string())
+                }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(value: Value) -> Map<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn cursors_are_per_consumer() {
+        let alice = message(json!({"owner_id": "alice.near", "ack_timeout_ms": 1000}));
+        let bob = message(json!({"owner_id": "bob.near", "ack_timeout_ms": 1000}));
+        assert_ne!(consumer(&alice), consumer(&bob));
+        assert_eq!(consumer(&alice), consumer(&alice.clone()));
+        let grouped = message(json!({"owner_id": "alice.near", "group": "workers"}));
+        assert_eq!(consumer(&grouped), "group_workers");
+        let client = message(json!({"group": "workers", "client_id": "worker-1"}));
+        assert_eq!(consumer(&client), "client_worker-1");
+    }
+}
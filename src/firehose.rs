@@ -0,0 +1,441 @@
+//! Firehose for mirrors of this server in other regions. `/v0/firehose` sends
+//! every stream entry that the readers of a network handle, unfiltered, to clients
+//! with an API key that has `firehose` set. With `firehose_upstream` in the config,
+//! this server is a relay: its readers read the firehose of another instance
+//! instead of Redis, and it resumes after the last entry of every stream when it
+//! reconnects.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use actix::prelude::*;
+use actix_web::{error::ErrorBadRequest, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use bytestring::ByteString;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    api_keys,
+    broadcast::Broadcasts,
+    config::FirehoseUpstreamConfig,
+    drain,
+    redis_reader::{set_last_read_id, EventHandler},
+    replay::StreamId,
+    ws_client::WsClient,
+    FromRedis, Networks, CLIENT_TIMEOUT, DEFAULT_NETWORK, HEARTBEAT_INTERVAL,
+};
+
+/// How long the upstream is reconnected to after the connection failed.
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+/// Entries that the readers of a mirror can fall behind by before they miss them.
+const UPSTREAM_CAPACITY: usize = 16 * 1024;
+/// Entries that are kept for mirrors that reconnect, and that connected mirrors
+/// can fall behind by.
+const RESUME_WINDOW: usize = 16 * 1024;
+/// Header with the last entry of every stream that a mirror got, as a JSON list
+/// of `{"source", "stream", "stream_id"}`.
+const RESUME_HEADER: &str = "X-Firehose-Resume";
+
+/// One stream entry, `{"type": "entry", "source", "stream", "stream_id", "fields"}`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "entry")]
+pub struct FirehoseEntry {
+    pub source: String,
+    /// Stream key without the source's prefix.
+    pub stream: String,
+    pub stream_id: StreamId,
+    /// The fields of the stream entry as the indexer wrote them.
+    pub fields: HashMap<String, String>,
+}
+
+/// The frame of a stream entry, or `None` if a field isn't a string.
+fn frame(
+    source: &str,
+    stream: &str,
+    stream_id: StreamId,
+    values: &HashMap<String, redis::Value>,
+) -> Option<ByteString> {
+    let fields = values
+        .iter()
+        .map(|(field, value)| Some((field.clone(), redis::from_redis_value(value).ok()?)))
+        .collect::<Option<_>>()?;
+    let entry = FirehoseEntry {
+        source: source.to_string(),
+        stream: stream.to_string(),
+        stream_id,
+        fields,
+    };
+    Some(serde_json::to_string(&entry).ok()?.into())
+}
+
+/// Where a mirror left off in a stream.
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumePosition {
+    source: String,
+    stream: String,
+    stream_id: StreamId,
+}
+
+/// The last stream ID that a mirror got by source and stream.
+type ResumeIds = HashMap<(String, String), StreamId>;
+
+struct RecentEntry {
+    source: Arc<str>,
+    stream: &'static str,
+    stream_id: StreamId,
+    frame: ByteString,
+}
+
+/// Frames of the entries of one network, and the last `RESUME_WINDOW` of them.
+pub struct Firehose {
+    sender: broadcast::Sender<ByteString>,
+    recent: Mutex<VecDeque<RecentEntry>>,
+}
+
+impl Firehose {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(RESUME_WINDOW).0,
+            recent: Mutex::new(VecDeque::with_capacity(RESUME_WINDOW)),
+        }
+    }
+
+    pub fn publish(
+        &self,
+        source: &Arc<str>,
+        stream: &'static str,
+        stream_id: StreamId,
+        values: &HashMap<String, redis::Value>,
+    ) {
+        let Some(frame) = frame(source, stream, stream_id, values) else {
+            return;
+        };
+        // Sending under the lock keeps the order of `recent` and the channel the same
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RESUME_WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(RecentEntry {
+            source: Arc::clone(source),
+            stream,
+            stream_id,
+            frame: frame.clone(),
+        });
+        // Fails only if no mirror is connected
+        let _ = self.sender.send(frame);
+    }
+
+    /// The kept frames after the resume IDs, and a receiver of the frames after
+    /// those. Streams that aren't resumed start with the next entry.
+    fn subscribe(&self, resume: &ResumeIds) -> (Vec<ByteString>, broadcast::Receiver<ByteString>) {
+        let recent = self.recent.lock().unwrap();
+        let missed = recent
+            .iter()
+            .filter(|entry| {
+                resume
+                    .get(&(entry.source.to_string(), entry.stream.to_string()))
+                    .is_some_and(|last_id| entry.stream_id > *last_id)
+            })
+            .map(|entry| entry.frame.clone())
+            .collect();
+        (missed, self.sender.subscribe())
+    }
+}
+
+pub async fn firehose(
+    req: HttpRequest,
+    stream: web::Payload,
+    networks: web::Data<Networks>,
+    broadcasts: web::Data<Broadcasts>,
+) -> Result<HttpResponse, Error> {
+    match api_keys::authenticate(&req)? {
+        Some(key) if key.firehose => {}
+        Some(_) => {
+            return Ok(HttpResponse::Forbidden().body("This API key can't read the firehose"))
+        }
+        None => return Ok(HttpResponse::Unauthorized().body("The firehose needs an API key")),
+    }
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return Ok(HttpResponse::NotFound().body(format!("Unknown network: {network}")));
+    }
+    if let Some(response) = drain::draining_response(&req) {
+        return Ok(response);
+    }
+    let resume = match req.headers().get(RESUME_HEADER) {
+        Some(header) => serde_json::from_slice::<Vec<ResumePosition>>(header.as_bytes())
+            .map_err(|err| ErrorBadRequest(format!("Invalid {RESUME_HEADER}: {err}")))?
+            .into_iter()
+            .map(|position| ((position.source, position.stream), position.stream_id))
+            .collect(),
+        None => ResumeIds::new(),
+    };
+    let Some(firehose) = broadcasts.firehose(&network) else {
+        return Ok(HttpResponse::NotFound().body("The firehose isn't enabled"));
+    };
+    let (missed, entries) = firehose.subscribe(&resume);
+    ws::start(
+        FirehoseWebSocket {
+            last_heartbeat: Instant::now(),
+            missed,
+            entries: Some(entries),
+            forwarder: None,
+        },
+        &req,
+        stream,
+    )
+}
+
+struct FirehoseWebSocket {
+    last_heartbeat: Instant,
+    /// Entries that the mirror missed while it was disconnected, sent first.
+    missed: Vec<ByteString>,
+    /// Taken by the forwarder when the actor starts.
+    entries: Option<broadcast::Receiver<ByteString>>,
+    /// Forwards the entries of the network to this actor.
+    forwarder: Option<JoinHandle<()>>,
+}
+
+impl Actor for FirehoseWebSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+            }
+            ctx.ping(b"");
+        });
+        for frame in self.missed.drain(..) {
+            ctx.text(frame);
+        }
+        let Some(mut entries) = self.entries.take() else {
+            return;
+        };
+        let addr = ctx.address();
+        self.forwarder = Some(tokio::spawn(async move {
+            loop {
+                match entries.recv().await {
+                    Ok(frame) => addr.do_send(Frame(frame)),
+                    Err(RecvError::Lagged(count)) => {
+                        tracing::warn!("Firehose client fell behind by {count} entries")
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        }));
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(forwarder) = self.forwarder.take() {
+            forwarder.abort();
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for FirehoseWebSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+            }
+            // There's nothing to configure
+            Ok(ws::Message::Text(_)) => {}
+            _ => ctx.stop(),
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Frame(ByteString);
+
+impl Handler<Frame> for FirehoseWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Frame, ctx: &mut Self::Context) -> Self::Result {
+        ctx.text(msg.0);
+    }
+}
+
+/// The firehose of another instance, that the readers of a mirror read instead
+/// of Redis.
+pub struct Upstream {
+    entries: broadcast::Sender<Arc<FirehoseEntry>>,
+    /// Where to resume after a reconnect.
+    last_ids: Mutex<ResumeIds>,
+}
+
+impl Upstream {
+    /// Connects to the upstream, and reconnects whenever the connection fails.
+    /// Entries that the upstream no longer keeps when it reconnects are missed.
+    pub fn spawn(config: FirehoseUpstreamConfig) -> Arc<Self> {
+        let upstream = Arc::new(Self {
+            entries: broadcast::channel(UPSTREAM_CAPACITY).0,
+            last_ids: Mutex::new(ResumeIds::new()),
+        });
+        let receiver = Arc::clone(&upstream);
+        tokio::spawn(async move {
+            loop {
+                match receiver.receive(&config).await {
+                    Ok(()) => tracing::warn!("Firehose upstream closed the connection"),
+                    Err(err) => tracing::error!("Firehose upstream failed: {err:#}"),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+        upstream
+    }
+
+    /// Hands the entries of `E`'s stream to the handler until `shutdown` changes.
+    /// `stream_key` is the key that the last read ID is recorded under.
+    pub async fn read<E: FromRedis>(
+        &self,
+        source: &str,
+        stream_key: &str,
+        handler: &impl EventHandler,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        let mut entries = self.entries.subscribe();
+        loop {
+            let entry = tokio::select! {
+                entry = entries.recv() => entry,
+                _ = shutdown.changed() => return Ok(()),
+            };
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(RecvError::Lagged(count)) => {
+                    tracing::warn!("Reader fell behind the firehose by {count} entries");
+                    continue;
+                }
+                Err(RecvError::Closed) => anyhow::bail!("Firehose upstream stopped"),
+            };
+            if entry.source != source || entry.stream != E::STREAM_KEY {
+                continue;
+            }
+            let id = entry.stream_id.to_string();
+            let values = entry
+                .fields
+                .iter()
+                .map(|(field, value)| {
+                    let value = redis::Value::Data(value.clone().into_bytes());
+                    (field.clone(), value)
+                })
+                .collect();
+            handler
+                .handle(&id, values)
+                .await
+                .map_err(|err| err.context(format!("Failed to handle event {id}")))?;
+            set_last_read_id(source, stream_key, &id);
+        }
+    }
+
+    async fn receive(&self, config: &FirehoseUpstreamConfig) -> anyhow::Result<()> {
+        let authorization = format!("Bearer {}", config.api_key);
+        let resume = self
+            .last_ids
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((source, stream), stream_id)| ResumePosition {
+                source: source.clone(),
+                stream: stream.clone(),
+                stream_id: *stream_id,
+            })
+            .collect::<Vec<_>>();
+        let resume = serde_json::to_string(&resume)?;
+        let mut client = WsClient::connect(
+            &config.url,
+            &[("Authorization", &authorization), (RESUME_HEADER, &resume)],
+        )
+        .await?;
+        tracing::info!("Connected to the firehose upstream");
+        loop {
+            // The upstream pings every HEARTBEAT_INTERVAL
+            let Some(message) =
+                tokio::time::timeout(CLIENT_TIMEOUT, client.next_message()).await??
+            else {
+                return Ok(());
+            };
+            let entry = match serde_json::from_slice::<FirehoseEntry>(&message) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    tracing::warn!("Skipping an invalid firehose message: {err}");
+                    continue;
+                }
+            };
+            self.last_ids.lock().unwrap().insert(
+                (entry.source.clone(), entry.stream.clone()),
+                entry.stream_id,
+            );
+            // Fails only if no reader is subscribed
+            let _ = self.entries.send(Arc::new(entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let values = HashMap::from([(
+            "event".to_string(),
+            redis::Value::Data(br#"{"a":1}"#.to_vec()),
+        )]);
+        let frame = frame("main", "nft_mint", "5-1".parse().unwrap(), &values).unwrap();
+        let entry = serde_json::from_str::<FirehoseEntry>(&frame).unwrap();
+        assert_eq!(entry.source, "main");
+        assert_eq!(entry.stream, "nft_mint");
+        assert_eq!(entry.stream_id.to_string(), "5-1");
+        assert_eq!(entry.fields["event"], r#"{"a":1}"#);
+        assert!(frame.starts_with(r#"{"type":"entry","#));
+    }
+
+    #[test]
+    fn resumes_after_last_ids() {
+        let firehose = Firehose::new();
+        let source = Arc::<str>::from("main");
+        for id in ["1-0", "2-0", "3-0"] {
+            let values = HashMap::from([("id".to_string(), redis::Value::Data(id.into()))]);
+            firehose.publish(&source, "nft_mint", id.parse().unwrap(), &values);
+            firehose.publish(&source, "nft_burn", id.parse().unwrap(), &values);
+        }
+        let resume = ResumeIds::from([(
+            ("main".to_string(), "nft_mint".to_string()),
+            "1-0".parse().unwrap(),
+        )]);
+        let (missed, _) = firehose.subscribe(&resume);
+        let missed = missed
+            .iter()
+            .map(|frame| serde_json::from_str::<FirehoseEntry>(frame).unwrap())
+            .map(|entry| (entry.stream, entry.fields["id"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            missed,
+            [
+                ("nft_mint".to_string(), "2-0".to_string()),
+                ("nft_mint".to_string(), "3-0".to_string())
+            ]
+        );
+    }
+}
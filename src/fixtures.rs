@@ -0,0 +1,233 @@
+//! Fixtures to reproduce bugs with real events: `record <file>` saves the new
+//! entries of all streams to a file for some minutes, and `--replay=<file>` makes
+//! the readers of a local server hand them to it again, with the original timing
+//! or `--replay-speed=<factor>` times faster, instead of reading Redis.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use redis::FromRedisValue;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{
+    broadcast::for_each_event_type,
+    config::StreamConfig,
+    redis_reader::{stream_events, Checkpoints, EventHandler, ReaderConnections},
+    streams::StreamKeys,
+    RedisSource,
+};
+
+const DEFAULT_RECORD_MINUTES: f64 = 5.0;
+/// Prefix of the recorder's checkpoints, apart from those of the server.
+const RECORDER_CHECKPOINT_PREFIX: &str = "events_api_recorder_last_id_";
+
+/// One line of a recording.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedEntry {
+    /// Milliseconds since the recording started.
+    offset_ms: u64,
+    source: String,
+    /// Stream key without the source's prefix.
+    stream: String,
+    id: String,
+    fields: HashMap<String, String>,
+}
+
+pub struct RecordArgs {
+    path: PathBuf,
+    duration: Duration,
+}
+
+/// The arguments of `record <file> [--minutes=<n>]`, or `None` if the server
+/// should run.
+pub fn record_args() -> Option<RecordArgs> {
+    let mut args = std::env::args().skip(1);
+    if args.next()? != "record" {
+        return None;
+    }
+    let path = args.next().expect("Usage: record <file> [--minutes=<n>]");
+    let minutes = args
+        .find_map(|arg| {
+            let minutes = arg.strip_prefix("--minutes=")?;
+            Some(minutes.parse::<f64>().expect("Invalid --minutes"))
+        })
+        .unwrap_or(DEFAULT_RECORD_MINUTES);
+    Some(RecordArgs {
+        path: path.into(),
+        duration: Duration::from_secs_f64(minutes * 60.0),
+    })
+}
+
+struct Recorder {
+    source: String,
+    stream: &'static str,
+    started: Instant,
+    output: Arc<Mutex<BufWriter<File>>>,
+}
+
+#[async_trait::async_trait]
+impl EventHandler for Recorder {
+    async fn handle(&self, id: &str, values: HashMap<String, redis::Value>) -> anyhow::Result<()> {
+        let fields = values
+            .iter()
+            .map(|(field, value)| Ok((field.clone(), String::from_redis_value(value)?)))
+            .collect::<redis::RedisResult<_>>()?;
+        let line = serde_json::to_string(&RecordedEntry {
+            offset_ms: self.started.elapsed().as_millis() as u64,
+            source: self.source.clone(),
+            stream: self.stream.to_string(),
+            id: id.to_string(),
+            fields,
+        })?;
+        writeln!(self.output.lock().unwrap(), "{line}")?;
+        Ok(())
+    }
+}
+
+/// Records new entries of every stream that the sources read.
+pub async fn record(
+    sources: &[RedisSource],
+    configs: &HashMap<String, StreamConfig>,
+    args: RecordArgs,
+) {
+    let file = File::create(&args.path).expect("Failed to create the recording");
+    let output = Arc::new(Mutex::new(BufWriter::new(file)));
+    let (shutdown, _) = watch::channel(false);
+    let checkpoints = Checkpoints {
+        prefix: RECORDER_CHECKPOINT_PREFIX.to_string(),
+        fresh: true,
+    };
+    let mut stream_keys = StreamKeys::default();
+    for_each_event_type(&mut stream_keys);
+
+    let started = Instant::now();
+    let mut recorders = Vec::new();
+    for source in sources {
+        for &stream in &stream_keys.0 {
+            if !source.reads(stream) {
+                continue;
+            }
+            let handler = Recorder {
+                source: source.name.to_string(),
+                stream,
+                started,
+                output: Arc::clone(&output),
+            };
+            let stream_key = source.stream_key(stream);
+            let config = configs.get(stream).cloned().unwrap_or_default();
+            let connection = source.connection.clone();
+            let checkpoints = checkpoints.clone();
+            let mut shutdown = shutdown.subscribe();
+            recorders.push(tokio::spawn(async move {
+                if let Err(err) = stream_events(
+                    &handler.source,
+                    &stream_key,
+                    &handler,
+                    ReaderConnections {
+                        read: connection.clone(),
+                        checkpoint: connection,
+                    },
+                    &config,
+                    &checkpoints,
+                    &mut shutdown,
+                )
+                .await
+                {
+                    tracing::error!("Recording of {stream_key} stopped: {err:#}");
+                }
+            }));
+        }
+    }
+    tracing::info!(
+        "Recording to {} for {:?}",
+        args.path.display(),
+        args.duration
+    );
+    tokio::time::sleep(args.duration).await;
+    shutdown.send_replace(true);
+    for recorder in recorders {
+        let _ = recorder.await;
+    }
+    output
+        .lock()
+        .unwrap()
+        .flush()
+        .expect("Failed to write the recording");
+    tracing::info!("Recording finished");
+}
+
+/// Entries of a recording by source and stream, oldest first.
+pub struct Recording {
+    entries: HashMap<(String, String), Vec<RecordedEntry>>,
+    speed: f64,
+}
+
+/// The recording of `--replay=<file>`, or `None` without it.
+pub fn replay_args() -> Option<Recording> {
+    let path = std::env::args().find_map(|arg| Some(arg.strip_prefix("--replay=")?.to_string()))?;
+    let speed = std::env::args()
+        .find_map(|arg| {
+            let speed = arg.strip_prefix("--replay-speed=")?;
+            match speed.parse::<f64>() {
+                Ok(speed) if speed > 0.0 => Some(speed),
+                _ => panic!("Invalid --replay-speed: {speed}"),
+            }
+        })
+        .unwrap_or(1.0);
+    let file = File::open(&path).expect("Failed to open the recording");
+    let mut entries = HashMap::<_, Vec<_>>::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("Failed to read the recording");
+        let entry = serde_json::from_str::<RecordedEntry>(&line).expect("Invalid recording");
+        entries
+            .entry((entry.source.clone(), entry.stream.clone()))
+            .or_default()
+            .push(entry);
+    }
+    Some(Recording { entries, speed })
+}
+
+impl Recording {
+    /// Hands the recorded entries of the stream to the handler at their time.
+    /// Entries that can't be handled are logged and skipped, so that the replay
+    /// isn't started over.
+    pub async fn replay(
+        &self,
+        source: &str,
+        stream: &str,
+        handler: &impl EventHandler,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> anyhow::Result<()> {
+        let Some(entries) = self.entries.get(&(source.to_string(), stream.to_string())) else {
+            return Ok(());
+        };
+        let started = tokio::time::Instant::now();
+        for entry in entries {
+            let at = started + Duration::from_millis(entry.offset_ms).div_f64(self.speed);
+            tokio::select! {
+                _ = tokio::time::sleep_until(at) => {}
+                _ = shutdown.changed() => return Ok(()),
+            }
+            let values = entry
+                .fields
+                .iter()
+                .map(|(field, value)| {
+                    let value = redis::Value::Data(value.clone().into_bytes());
+                    (field.clone(), value)
+                })
+                .collect();
+            if let Err(err) = handler.handle(&entry.id, values).await {
+                tracing::error!(id = %entry.id, "Failed to handle recorded event: {err}");
+            }
+        }
+        tracing::info!("Replayed {} entries", entries.len());
+        Ok(())
+    }
+}
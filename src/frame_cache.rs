@@ -0,0 +1,155 @@
+//! Work that the connections an event is delivered to share. Many clients use the
+//! same filter, like a preset of a UI, so whether an event matches is decided once
+//! per filter message, and every frame is serialized once per event. Frames are
+//! `ByteString`s, reference-counted `Bytes`, so writing one to every socket
+//! doesn't copy it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use bytestring::ByteString;
+
+use crate::key_style::KeyStyle;
+
+#[derive(Default)]
+pub struct FrameCache {
+    /// Whether the event matches, by the key of the filter message.
+    matches: Mutex<HashMap<Arc<str>, bool>>,
+    /// The event in protocol 1.
+    tagged: OnceLock<ByteString>,
+    /// The envelope of the event in protocol 2.
+    envelope: OnceLock<ByteString>,
+    /// The connection that the event goes to, by the key of its queue group.
+    group_members: Mutex<HashMap<Arc<str>, Option<String>>>,
+}
+
+impl FrameCache {
+    /// Whether the event matches the filter with this key, deciding it with
+    /// `matches` if no other connection with the filter did yet.
+    pub fn matches(&self, key: &Arc<str>, matches: impl FnOnce() -> bool) -> bool {
+        if let Some(matched) = self.matches.lock().unwrap().get(key) {
+            return *matched;
+        }
+        // Connections with the same filter might both decide it, but never wait for each other
+        let matched = matches();
+        self.matches
+            .lock()
+            .unwrap()
+            .insert(Arc::clone(key), matched);
+        matched
+    }
+
+    /// Whether the event goes to this member of a queue group, picking the member
+    /// with `next_member` if no other member of the group did yet.
+    pub fn is_turn_of(
+        &self,
+        group: &Arc<str>,
+        connection_id: &str,
+        next_member: impl FnOnce() -> Option<String>,
+    ) -> bool {
+        // Unlike matches, the pick must be the same for all members
+        let mut group_members = self.group_members.lock().unwrap();
+        let member = group_members
+            .entry(Arc::clone(group))
+            .or_insert_with(next_member);
+        member.as_deref() == Some(connection_id)
+    }
+
+    pub fn tagged(&self, serialize: impl FnOnce() -> String) -> ByteString {
+        self.tagged.get_or_init(|| serialize().into()).clone()
+    }
+
+    pub fn envelope(&self, serialize: impl FnOnce() -> String) -> ByteString {
+        self.envelope.get_or_init(|| serialize().into()).clone()
+    }
+}
+
+/// Serializes a frame with the fields that only one socket has, like its
+/// `conflated_count`, and in its `key_style`, or returns `None` if it has none,
+/// so that the shared frame is sent.
+pub fn with_fields(
+    frame: &impl serde::Serialize,
+    fields: &[(&str, Option<u64>)],
+    key_style: KeyStyle,
+) -> Option<ByteString> {
+    if fields.iter().all(|(_, value)| value.is_none()) && key_style == KeyStyle::Snake {
+        return None;
+    }
+    let mut frame = serde_json::to_value(frame).unwrap();
+    for (name, value) in fields {
+        if let Some(value) = value {
+            frame[*name] = (*value).into();
+        }
+    }
+    key_style.apply(&mut frame);
+    Some(frame.to_string().into())
+}
+
+/// The key of a filter message, that connections with the same filter share. The
+/// keys of JSON objects are sorted, so the order that a client sent them in
+/// doesn't matter. A `preset` is replaced with the filter it resolves to in the
+/// `presets` of the connection, since presets like `me` are different for every
+/// account. `None` for messages that the filter wasn't parsed from.
+pub fn filter_key(
+    message: &serde_json::Map<String, serde_json::Value>,
+    presets: &HashMap<String, serde_json::Value>,
+) -> Option<Arc<str>> {
+    if message.is_empty() {
+        return None;
+    }
+    let preset = message
+        .get("preset")
+        .and_then(serde_json::Value::as_str)
+        .and_then(|name| presets.get(name));
+    let key = match preset {
+        Some(filter) => {
+            let mut message = message.clone();
+            message.insert("preset".to_string(), filter.clone());
+            serde_json::to_string(&message)
+        }
+        None => serde_json::to_string(message),
+    };
+    Some(key.unwrap().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(message: &str, presets: &HashMap<String, serde_json::Value>) -> Arc<str> {
+        filter_key(&serde_json::from_str(message).unwrap(), presets).unwrap()
+    }
+
+    #[test]
+    fn decides_once_per_filter() {
+        let cache = FrameCache::default();
+        let first = key(r#"{"a": 1, "b": 2}"#, &HashMap::new());
+        let same = key(r#"{"b": 2, "a": 1}"#, &HashMap::new());
+        let other = key(r#"{"a": 2}"#, &HashMap::new());
+        assert!(cache.matches(&first, || true));
+        assert!(cache.matches(&same, || unreachable!()));
+        assert!(!cache.matches(&other, || false));
+    }
+
+    #[test]
+    fn me_presets_of_two_accounts_dont_share_matches() {
+        let presets_of = |account: &str| {
+            HashMap::from([(
+                "me".to_string(),
+                serde_json::json!({ "involved_account_ids": [account] }),
+            )])
+        };
+        let cache = FrameCache::default();
+        let alice = key(r#"{"preset": "me"}"#, &presets_of("alice.near"));
+        let bob = key(r#"{"preset": "me"}"#, &presets_of("bob.near"));
+        assert_ne!(alice, bob);
+        assert!(cache.matches(&alice, || true));
+        assert!(!cache.matches(&bob, || false));
+        assert!(cache.matches(
+            &key(r#"{"preset": "me"}"#, &presets_of("alice.near")),
+            || { unreachable!() }
+        ));
+    }
+}
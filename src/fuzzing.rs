@@ -0,0 +1,270 @@
+//! Entry points of the fuzz targets in `fuzz/`, for the parsers of untrusted
+//! input: filter messages and commands of clients, stream entries of the indexer,
+//! and the text messages of a WebSocket connection. Random input may be rejected,
+//! but must never panic. The tests run them with proptest as part of `cargo test`.
+
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map};
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    config::Encoding,
+    filter_command,
+    harness::{ConnectOptions, Harness},
+    parse_filter,
+    stats::Stats,
+    ConnectionOptions, EventFilter, FromRedis,
+};
+
+/// The stream keys of all endpoints.
+pub fn stream_keys() -> Vec<&'static str> {
+    struct StreamKeys(Vec<&'static str>);
+
+    impl EventTypeVisitor for StreamKeys {
+        fn visit<
+            E: Serialize + FromRedis + Send + Sync + 'static,
+            F: EventFilter<E> + DeserializeOwned + Send + 'static,
+        >(
+            &mut self,
+        ) {
+            self.0.push(E::STREAM_KEY);
+        }
+    }
+
+    let mut stream_keys = StreamKeys(Vec::new());
+    for_each_event_type(&mut stream_keys);
+    stream_keys.0
+}
+
+/// Parses a client message as the filter and options of every endpoint, and
+/// `command` as a filter command after it. If the message has `stats`,
+/// `command` is also added to them as an event.
+pub fn filter(message: &str, command: &str) {
+    for_each_event_type(&mut FilterMessage { message, command });
+}
+
+struct FilterMessage<'a> {
+    message: &'a str,
+    command: &'a str,
+}
+
+impl EventTypeVisitor for FilterMessage<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        let presets = HashMap::from([("preset".to_string(), json!({ "owner_id": "alice.near" }))]);
+        let _ = parse_filter::<F>(&presets, self.message);
+        if let Ok(options) = ConnectionOptions::parse(self.message) {
+            if let (Some(stats), Ok(event)) = (&options.stats, serde_json::from_str(self.command)) {
+                let mut window = Stats::default();
+                window.add(stats, &event);
+                let _ = serde_json::to_string(&window.take(stats));
+            }
+        }
+        let message = serde_json::from_str::<Map<_, _>>(self.message).unwrap_or_default();
+        let _ = filter_command::<F>(&message, &presets, self.command);
+    }
+}
+
+/// Decodes a stream entry with these fields as an event of every endpoint.
+pub fn from_redis(fields: &[(String, Vec<u8>)]) {
+    for_each_event_type(&mut StreamEntry(fields));
+}
+
+struct StreamEntry<'a>(&'a [(String, Vec<u8>)]);
+
+impl EventTypeVisitor for StreamEntry<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        let values = self
+            .0
+            .iter()
+            .map(|(field, value)| (field.clone(), redis::Value::Data(value.clone())))
+            .collect();
+        for encoding in [Encoding::Json, Encoding::Borsh] {
+            if let Ok(event) = E::decode(&values, encoding) {
+                event.head();
+                let _ = serde_json::to_string(&event);
+            }
+        }
+    }
+}
+
+/// Sends text messages on a connection to an endpoint, like a client, and reads
+/// the answers until the server closes the connection. Every text message goes
+/// through the proof-of-work answer, the ack and the filter and options of the
+/// connection, in that order.
+pub async fn text_messages(
+    harness: &Harness,
+    stream_key: &str,
+    options: &ConnectOptions,
+    messages: &[String],
+) {
+    if let Some(connection) = harness.open(stream_key, options).await {
+        connection.exchange(messages).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{prelude::*, test_runner::TestRunner};
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Field names of filters, options and commands, so that random objects often
+    /// have the right keys with the wrong values.
+    const KEYS: &[&str] = &[
+        "owner_id",
+        "contract_id",
+        "contract_ids",
+        "involved_account_ids",
+        "old_owner_id",
+        "new_owner_id",
+        "pot_id",
+        "project_id",
+        "donor_id",
+        "referrer_id",
+        "min_amounts",
+        "min_balance_changes",
+        "min_amount_near",
+        "pool_id",
+        "account_id",
+        "involved_token_account_ids",
+        "token_bought",
+        "token_sold",
+        "preset",
+        "protocol",
+        "set_filter",
+        "update_filter",
+        "clear_filter",
+        "exactly_once_window",
+        "batch_ms",
+        "stats",
+        "window_sec",
+        "sum",
+        "unique",
+        "keepalive_sec",
+        "head_sec",
+        "sample_rate",
+        "sample_deterministic",
+        "conflate_ms",
+        "conflate_key",
+        "max_age_ms",
+        "ack_timeout_ms",
+        "ack",
+        "group",
+        "client_id",
+        "active_hours",
+        "from",
+        "to",
+        "days",
+        "key_style",
+    ];
+
+    fn key() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::sample::select(KEYS).prop_map(str::to_string),
+            "[a-z_/]{1,10}",
+        ]
+    }
+
+    fn json_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<u64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            "[a-z0-9_.-]{0,12}".prop_map(Value::from),
+            prop::sample::select(KEYS).prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 32, 6, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..6).prop_map(Value::from),
+                prop::collection::hash_map(key(), inner, 0..6)
+                    .prop_map(|object| Value::Object(object.into_iter().collect())),
+            ]
+        })
+    }
+
+    /// A message as a client could send it: JSON, or any text.
+    fn client_text() -> impl Strategy<Value = String> {
+        prop_oneof![
+            3 => json_value().prop_map(|value| value.to_string()),
+            1 => any::<String>(),
+        ]
+    }
+
+    struct FuzzParsers {
+        runner: TestRunner,
+    }
+
+    impl EventTypeVisitor for FuzzParsers {
+        fn visit<
+            E: Serialize + FromRedis + Send + Sync + 'static,
+            F: EventFilter<E> + DeserializeOwned + Send + 'static,
+        >(
+            &mut self,
+        ) {
+            let field = prop_oneof![
+                prop::sample::select(E::FIELDS).prop_map(str::to_string),
+                "[a-z_]{1,8}",
+            ];
+            let value = prop_oneof![
+                3 => json_value().prop_map(|value| value.to_string().into_bytes()),
+                1 => any::<Vec<u8>>(),
+            ];
+            self.runner
+                .run(&prop::collection::vec((field, value), 0..4), |fields| {
+                    from_redis(&fields);
+                    Ok(())
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn parsers_dont_panic() {
+        let mut runner = TestRunner::default();
+        runner
+            .run(&(client_text(), client_text()), |(message, command)| {
+                filter(&message, &command);
+                Ok(())
+            })
+            .unwrap();
+        for_each_event_type(&mut FuzzParsers { runner });
+    }
+
+    #[test]
+    fn connections_dont_panic() {
+        let system = actix::System::new();
+        let harness = system.block_on(async { Harness::start() });
+        let stream_keys = stream_keys();
+        let input = (
+            prop::sample::select(stream_keys),
+            any::<bool>(),
+            prop::option::of(0..2u8),
+            prop::collection::vec(client_text(), 0..4),
+        );
+        TestRunner::new(ProptestConfig::with_cases(64))
+            .run(&input, |(stream_key, v1, proof_of_work_bits, messages)| {
+                let options = ConnectOptions {
+                    v1,
+                    proof_of_work_bits,
+                };
+                system.block_on(text_messages(&harness, stream_key, &options, &messages));
+                Ok(())
+            })
+            .unwrap();
+    }
+}
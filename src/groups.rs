@@ -0,0 +1,73 @@
+//! Queue groups: connections with the same API key and `group` option, on the same
+//! endpoint and network, share the matching events round-robin instead of each
+//! getting all of them, so that bot workers can scale horizontally.
+
+use std::sync::{Arc, LazyLock};
+
+use dashmap::DashMap;
+
+/// Connection IDs of the members and the index of the next one, by group key.
+static GROUPS: LazyLock<DashMap<Arc<str>, Group>> = LazyLock::new(DashMap::new);
+
+#[derive(Default)]
+struct Group {
+    members: Vec<String>,
+    next: usize,
+}
+
+/// The key of a group, that the frames of an event are shared by.
+pub fn key(api_key: &str, network: &str, stream: &str, group: &str) -> Arc<str> {
+    format!("{api_key}/{network}/{stream}/{group}").into()
+}
+
+pub fn join(key: &Arc<str>, connection_id: &str) {
+    GROUPS
+        .entry(Arc::clone(key))
+        .or_default()
+        .members
+        .push(connection_id.to_string());
+}
+
+pub fn leave(key: &Arc<str>, connection_id: &str) {
+    GROUPS.remove_if_mut(key, |_, group| {
+        group.members.retain(|member| member != connection_id);
+        group.members.is_empty()
+    });
+}
+
+/// The member whose turn it is, and moves on to the next one.
+pub fn next_member(key: &Arc<str>) -> Option<String> {
+    let mut group = GROUPS.get_mut(key)?;
+    if group.members.is_empty() {
+        return None;
+    }
+    let index = group.next % group.members.len();
+    group.next = index + 1;
+    Some(group.members[index].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_turns() {
+        let key = key("bot", "mainnet", "nft_mint", "workers");
+        join(&key, "a");
+        join(&key, "b");
+        join(&key, "c");
+        let turns = (0..4)
+            .map(|_| next_member(&key).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(turns, ["a", "b", "c", "a"]);
+        leave(&key, "b");
+        let turns = (0..3)
+            .map(|_| next_member(&key).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(turns, ["c", "a", "c"]);
+        leave(&key, "a");
+        leave(&key, "c");
+        assert!(next_member(&key).is_none());
+        assert!(!GROUPS.contains_key(&key));
+    }
+}
@@ -0,0 +1,480 @@
+//! gRPC API on `GRPC_BIND_ADDRESS`, with a server-streaming RPC for every event
+//! type, see `proto/events.proto`. The service is generated by `tonic-build`, and
+//! events are converted to their messages here. Events come from the same readers
+//! as the WebSocket endpoints, through `Broadcasts`.
+
+use std::{collections::HashMap, pin::Pin, sync::Arc};
+
+use futures_util::{stream, Stream};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    broadcast::{Broadcasts, EventReceiver},
+    fields,
+    nft_events::{
+        FullNftBurnEvent, FullNftMintEvent, FullNftTransferEvent, NftBurnFilter, NftEventContext,
+        NftMintFilter, NftTransferFilter,
+    },
+    pool_metadata::PoolToken,
+    potlock_events::{
+        FullPotlockDonationEvent, FullPotlockPotDonationEvent, FullPotlockPotProjectDonationEvent,
+        PotlockDonationEventFilter, PotlockPotDonationEventFilter,
+        PotlockPotProjectDonationEventFilter,
+    },
+    trade_events::{
+        FullTradePoolChangeEvent, FullTradePoolEvent, FullTradeSwapEvent, RawPoolSwap,
+        TradePoolChangeEventFilter, TradePoolEventFilter, TradeSwapEventFilter,
+    },
+    types::EventContext,
+    Event, EventFilter, FromRedis, Networks, DEFAULT_NETWORK,
+};
+
+mod proto {
+    tonic::include_proto!("intear.events.v0");
+}
+
+use proto::{
+    events_server::{Events, EventsServer},
+    Subscription,
+};
+
+pub async fn serve(
+    address: String,
+    broadcasts: Arc<Broadcasts>,
+    networks: Arc<Networks>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&address).await?;
+    tracing::info!("gRPC API listening on {address}");
+    Server::builder()
+        .trace_fn(|request| tracing::info_span!("grpc_call", method = %request.uri().path()))
+        .add_service(EventsServer::new(Service {
+            broadcasts,
+            networks,
+        }))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await?;
+    Ok(())
+}
+
+type EventStream<M> = Pin<Box<dyn Stream<Item = Result<M, Status>> + Send>>;
+
+struct Service {
+    broadcasts: Arc<Broadcasts>,
+    networks: Arc<Networks>,
+}
+
+impl Service {
+    /// The events of `E` on the network of the subscription that match its
+    /// filter, as messages of type `M`.
+    // `Status` is what the RPCs return
+    #[allow(clippy::result_large_err)]
+    fn stream<E, F, M>(&self, subscription: Subscription) -> Result<EventStream<M>, Status>
+    where
+        E: FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+        M: for<'a> From<&'a Event<E>> + Send + 'static,
+    {
+        let network = if subscription.network.is_empty() {
+            DEFAULT_NETWORK.to_string()
+        } else {
+            subscription.network
+        };
+        if !self.networks.contains(&network) {
+            return Err(Status::not_found(format!("Unknown network: {network}")));
+        }
+        let filter = if subscription.filter.is_empty() {
+            None
+        } else {
+            match fields::parse_filter_str::<F>(&subscription.filter) {
+                Ok(filter) => Some(filter),
+                Err(err) => return Err(Status::invalid_argument(format!("Invalid filter: {err}"))),
+            }
+        };
+        let events = self.broadcasts.subscribe::<E>(&network);
+        Ok(Box::pin(stream::unfold(
+            Some((events, filter)),
+            |state| async move {
+                let (mut events, filter): (EventReceiver<E>, Option<F>) = state?;
+                loop {
+                    match events.recv().await {
+                        Ok(event) => {
+                            if filter.as_ref().is_none_or(|f| f.matches(&event.event)) {
+                                return Some((Ok(M::from(&event)), Some((events, filter))));
+                            }
+                        }
+                        // The status ends the call
+                        Err(RecvError::Lagged(count)) => {
+                            let status = Status::resource_exhausted(format!(
+                                "Client is too slow, {count} events were skipped"
+                            ));
+                            return Some((Err(status), None));
+                        }
+                        Err(RecvError::Closed) => {
+                            return Some((
+                                Err(Status::unavailable("Server is shutting down")),
+                                None,
+                            ))
+                        }
+                    }
+                }
+            },
+        )))
+    }
+}
+
+/// Implements an RPC of the service for every event type.
+macro_rules! rpcs {
+    ($($method:ident, $stream:ident: $event:ty, $filter:ty => $message:ty;)*) => {
+        #[tonic::async_trait]
+        impl Events for Service {
+            $(
+                type $stream = EventStream<$message>;
+
+                async fn $method(
+                    &self,
+                    request: Request<Subscription>,
+                ) -> Result<Response<Self::$stream>, Status> {
+                    self.stream::<$event, $filter, $message>(request.into_inner())
+                        .map(Response::new)
+                }
+            )*
+        }
+    };
+}
+
+rpcs! {
+    nft_mint, NftMintStream: FullNftMintEvent, NftMintFilter => proto::NftMintEvent;
+    nft_transfer, NftTransferStream: FullNftTransferEvent, NftTransferFilter => proto::NftTransferEvent;
+    nft_burn, NftBurnStream: FullNftBurnEvent, NftBurnFilter => proto::NftBurnEvent;
+
+    potlock_donation, PotlockDonationStream:
+        FullPotlockDonationEvent, PotlockDonationEventFilter => proto::PotlockDonationEvent;
+    potlock_pot_project_donation, PotlockPotProjectDonationStream:
+        FullPotlockPotProjectDonationEvent, PotlockPotProjectDonationEventFilter
+        => proto::PotlockPotProjectDonationEvent;
+    potlock_pot_donation, PotlockPotDonationStream:
+        FullPotlockPotDonationEvent, PotlockPotDonationEventFilter => proto::PotlockPotDonationEvent;
+
+    trade_pool, TradePoolStream: FullTradePoolEvent, TradePoolEventFilter => proto::TradePoolEvent;
+    trade_swap, TradeSwapStream: FullTradeSwapEvent, TradeSwapEventFilter => proto::TradeSwapEvent;
+    trade_pool_change, TradePoolChangeStream:
+        FullTradePoolChangeEvent, TradePoolChangeEventFilter => proto::TradePoolChangeEvent;
+}
+
+fn origin<E>(event: &Event<E>) -> Option<proto::Origin> {
+    Some(proto::Origin {
+        source: event.source.to_string(),
+        stream_id: event.id.to_string(),
+    })
+}
+
+/// Unknown fields as JSON, by name.
+fn extra(extra: &Map<String, Value>) -> HashMap<String, String> {
+    extra
+        .iter()
+        .map(|(name, value)| (name.clone(), value.to_string()))
+        .collect()
+}
+
+fn context(context: &EventContext) -> Option<proto::EventContext> {
+    Some(proto::EventContext {
+        transaction_id: context.transaction_id.clone(),
+        receipt_id: context.receipt_id.clone(),
+        block_height: context.block_height,
+        block_timestamp_nanosec: context.block_timestamp_nanosec.clone(),
+        extra: extra(&context.extra),
+    })
+}
+
+fn nft_context(context: &NftEventContext) -> (Option<proto::EventContext>, String) {
+    (
+        self::context(&context.common),
+        context.contract_id.to_string(),
+    )
+}
+
+fn pool_swap(swap: &RawPoolSwap) -> proto::PoolSwap {
+    proto::PoolSwap {
+        pool: swap.pool.clone(),
+        token_in: swap.token_in.to_string(),
+        token_out: swap.token_out.to_string(),
+        amount_in: swap.amount_in.to_string(),
+        amount_out: swap.amount_out.to_string(),
+        extra: extra(&swap.extra),
+    }
+}
+
+fn pool_token(token: &PoolToken) -> proto::PoolToken {
+    proto::PoolToken {
+        account_id: token.account_id.to_string(),
+        symbol: token.symbol.clone(),
+        decimals: token.decimals.map(u32::from),
+    }
+}
+
+impl From<&Event<FullNftMintEvent>> for proto::NftMintEvent {
+    fn from(event: &Event<FullNftMintEvent>) -> Self {
+        let (context, contract_id) = nft_context(&event.event.context);
+        let mint = &event.event.event;
+        Self {
+            origin: origin(event),
+            context,
+            contract_id,
+            owner_id: mint.owner_id.to_string(),
+            token_ids: mint.token_ids.clone(),
+            memo: mint.memo.clone(),
+            extra: extra(&mint.extra),
+        }
+    }
+}
+
+impl From<&Event<FullNftTransferEvent>> for proto::NftTransferEvent {
+    fn from(event: &Event<FullNftTransferEvent>) -> Self {
+        let (context, contract_id) = nft_context(&event.event.context);
+        let transfer = &event.event.event;
+        Self {
+            origin: origin(event),
+            context,
+            contract_id,
+            old_owner_id: transfer.old_owner_id.to_string(),
+            new_owner_id: transfer.new_owner_id.to_string(),
+            token_ids: transfer.token_ids.clone(),
+            memo: transfer.memo.clone(),
+            token_prices_near: transfer
+                .token_prices_near
+                .iter()
+                .map(|price| price.map(|price| price.to_string()).unwrap_or_default())
+                .collect(),
+            extra: extra(&transfer.extra),
+        }
+    }
+}
+
+impl From<&Event<FullNftBurnEvent>> for proto::NftBurnEvent {
+    fn from(event: &Event<FullNftBurnEvent>) -> Self {
+        let (context, contract_id) = nft_context(&event.event.context);
+        let burn = &event.event.event;
+        Self {
+            origin: origin(event),
+            context,
+            contract_id,
+            owner_id: burn.owner_id.to_string(),
+            token_ids: burn.token_ids.clone(),
+            memo: burn.memo.clone(),
+            extra: extra(&burn.extra),
+        }
+    }
+}
+
+impl From<&Event<FullPotlockDonationEvent>> for proto::PotlockDonationEvent {
+    fn from(event: &Event<FullPotlockDonationEvent>) -> Self {
+        let donation = &event.event.event;
+        Self {
+            origin: origin(event),
+            context: context(&event.event.context),
+            donation_id: donation.donation_id,
+            donor_id: donation.donor_id.to_string(),
+            total_amount: donation.total_amount.to_string(),
+            account_id: donation.account_id.to_string(),
+            message: donation.message.clone(),
+            donated_at: donation.donated_at,
+            project_id: donation.project_id.to_string(),
+            protocol_fee: donation.protocol_fee.to_string(),
+            referrer_id: donation.referrer_id.as_ref().map(ToString::to_string),
+            referrer_fee: donation.referrer_fee.map(|fee| fee.to_string()),
+            extra: extra(&donation.extra),
+        }
+    }
+}
+
+impl From<&Event<FullPotlockPotProjectDonationEvent>> for proto::PotlockPotProjectDonationEvent {
+    fn from(event: &Event<FullPotlockPotProjectDonationEvent>) -> Self {
+        let donation = &event.event.event;
+        Self {
+            origin: origin(event),
+            context: context(&event.event.context),
+            donation_id: donation.donation_id,
+            pot_id: donation.pot_id.to_string(),
+            donor_id: donation.donor_id.to_string(),
+            total_amount: donation.total_amount.to_string(),
+            net_amount: donation.net_amount.to_string(),
+            message: donation.message.clone(),
+            donated_at: donation.donated_at,
+            project_id: donation.project_id.to_string(),
+            referrer_id: donation.referrer_id.as_ref().map(ToString::to_string),
+            referrer_fee: donation.referrer_fee.map(|fee| fee.to_string()),
+            protocol_fee: donation.protocol_fee.to_string(),
+            chef_id: donation.chef_id.as_ref().map(ToString::to_string),
+            chef_fee: donation.chef_fee.map(|fee| fee.to_string()),
+            extra: extra(&donation.extra),
+        }
+    }
+}
+
+impl From<&Event<FullPotlockPotDonationEvent>> for proto::PotlockPotDonationEvent {
+    fn from(event: &Event<FullPotlockPotDonationEvent>) -> Self {
+        let donation = &event.event.event;
+        Self {
+            origin: origin(event),
+            context: context(&event.event.context),
+            donation_id: donation.donation_id,
+            pot_id: donation.pot_id.to_string(),
+            donor_id: donation.donor_id.to_string(),
+            total_amount: donation.total_amount.to_string(),
+            net_amount: donation.net_amount.to_string(),
+            message: donation.message.clone(),
+            donated_at: donation.donated_at,
+            referrer_id: donation.referrer_id.as_ref().map(ToString::to_string),
+            referrer_fee: donation.referrer_fee.map(|fee| fee.to_string()),
+            protocol_fee: donation.protocol_fee.to_string(),
+            chef_id: donation.chef_id.as_ref().map(ToString::to_string),
+            chef_fee: donation.chef_fee.map(|fee| fee.to_string()),
+            extra: extra(&donation.extra),
+        }
+    }
+}
+
+impl From<&Event<FullTradePoolEvent>> for proto::TradePoolEvent {
+    fn from(event: &Event<FullTradePoolEvent>) -> Self {
+        let trade = &event.event;
+        Self {
+            origin: origin(event),
+            context: context(&trade.context.common),
+            trader: trade.context.trader.to_string(),
+            swap: Some(pool_swap(&trade.event)),
+            pool_tokens: trade.pool_tokens.iter().flatten().map(pool_token).collect(),
+            amount_in_decimal: trade.amount_in_decimal.clone(),
+            amount_out_decimal: trade.amount_out_decimal.clone(),
+        }
+    }
+}
+
+impl From<&Event<FullTradeSwapEvent>> for proto::TradeSwapEvent {
+    fn from(event: &Event<FullTradeSwapEvent>) -> Self {
+        let trade = &event.event;
+        Self {
+            origin: origin(event),
+            context: context(&trade.context.common),
+            trader: trade.context.trader.to_string(),
+            balance_changes: trade
+                .event
+                .balance_changes
+                .iter()
+                .map(|(token, change)| (token.to_string(), change.to_string()))
+                .collect(),
+            pool_swaps: trade.event.pool_swaps.iter().map(pool_swap).collect(),
+            balance_changes_decimal: trade
+                .balance_changes_decimal
+                .iter()
+                .flatten()
+                .map(|(token, change)| (token.to_string(), change.clone()))
+                .collect(),
+            extra: extra(&trade.event.extra),
+        }
+    }
+}
+
+impl From<&Event<FullTradePoolChangeEvent>> for proto::TradePoolChangeEvent {
+    fn from(event: &Event<FullTradePoolChangeEvent>) -> Self {
+        let change = &event.event.event;
+        Self {
+            origin: origin(event),
+            pool_id: change.pool_id.clone(),
+            receipt_id: change.receipt_id.clone(),
+            block_timestamp_nanosec: change.block_timestamp_nanosec.clone(),
+            block_height: change.block_height,
+            pool_json: change.pool.to_string(),
+            extra: extra(&change.extra),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use prost::Message;
+
+    use super::*;
+
+    fn mint(id: &str, owner_id: &str) -> Arc<Event<FullNftMintEvent>> {
+        let mint = serde_json::json!({ "owner_id": owner_id, "token_ids": ["1"], "memo": null });
+        let context = serde_json::json!({
+            "transaction_id": "tx",
+            "receipt_id": "receipt",
+            "block_height": 7,
+            "block_timestamp_nanosec": "1",
+            "contract_id": "nft.near",
+            "shard_id": 3,
+        });
+        Arc::new(Event {
+            source: "mainnet".into(),
+            id: id.parse().unwrap(),
+            duplicate: false,
+            event: FullNftMintEvent {
+                event: serde_json::from_value(mint).unwrap(),
+                context: serde_json::from_value(context).unwrap(),
+            },
+            span: tracing::Span::none(),
+            frames: Default::default(),
+            raw: None,
+        })
+    }
+
+    #[test]
+    fn messages_match_known_bytes() {
+        // network = "testnet", filter = "{}"
+        let subscription = b"\x0a\x07testnet\x12\x02{}";
+        assert_eq!(
+            Subscription::decode(&subscription[..]).unwrap(),
+            Subscription {
+                network: "testnet".to_string(),
+                filter: "{}".to_string(),
+            }
+        );
+
+        let message = proto::NftMintEvent::from(&*mint("5-1", "alice.near"));
+        assert_eq!(message.context.as_ref().unwrap().block_height, 7);
+        assert_eq!(message.context.as_ref().unwrap().extra["shard_id"], "3");
+        let bytes = message.encode_to_vec();
+        // origin = { source = "mainnet", stream_id = "5-1" }
+        assert!(bytes.starts_with(b"\x0a\x0e\x0a\x07mainnet\x12\x035-1"));
+        assert_eq!(proto::NftMintEvent::decode(&bytes[..]).unwrap(), message);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn streams_matching_events() {
+        let broadcasts = Arc::new(Broadcasts::default());
+        let service = Service {
+            broadcasts: Arc::clone(&broadcasts),
+            networks: Arc::new(Networks::from([DEFAULT_NETWORK.to_string()])),
+        };
+        let subscribe = |network: &str, filter: &str| {
+            service.stream::<FullNftMintEvent, NftMintFilter, proto::NftMintEvent>(Subscription {
+                network: network.to_string(),
+                filter: filter.to_string(),
+            })
+        };
+        let Err(status) = subscribe("unknown", "") else {
+            panic!("Subscribed to an unknown network");
+        };
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let Err(status) = subscribe("", "{\"owner_id\": 5}") else {
+            panic!("Accepted an invalid filter");
+        };
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let mut events = subscribe("", r#"{"owner_id": "alice.near"}"#).unwrap();
+        let sender = broadcasts.sender::<FullNftMintEvent>(DEFAULT_NETWORK);
+        assert!(sender.send(mint("1-0", "bob.near")).is_ok());
+        assert!(sender.send(mint("2-0", "alice.near")).is_ok());
+        drop(sender);
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.origin.unwrap().stream_id, "2-0");
+        assert_eq!(event.owner_id, "alice.near");
+    }
+}
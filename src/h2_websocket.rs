@@ -0,0 +1,347 @@
+//! WebSockets over HTTP/2 (RFC 8441) on `H2_BIND_ADDRESS`, for proxies and
+//! clients that multiplex WebSockets over one HTTP/2 connection. actix-web only
+//! upgrades HTTP/1.1 connections, so every extended CONNECT stream is bridged to
+//! a WebSocket connection over HTTP/1.1 to the HTTP server itself, on a loopback
+//! listener. WebSocket frames are the same in both, so they're copied as bytes.
+//! Like the gRPC API, it's HTTP/2 without TLS.
+
+use std::{
+    future::poll_fn,
+    net::{SocketAddr, TcpListener as StdTcpListener},
+};
+
+use base64::prelude::*;
+use bytes::{Bytes, BytesMut};
+use h2::{
+    ext::Protocol,
+    server::{self, SendResponse},
+    RecvStream, SendStream,
+};
+use http::{request, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Response headers of the HTTP server that only apply to its HTTP/1.1
+/// connection.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "upgrade",
+    "keep-alive",
+    "transfer-encoding",
+    "content-length",
+    "sec-websocket-accept",
+];
+/// Request headers that the bridge sets itself.
+const REPLACED: &[&str] = &[
+    "host",
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "x-forwarded-for",
+];
+/// Longest response head of the HTTP server.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// The loopback address of the HTTP server that streams are bridged to. Requests
+/// on it have the address of the client in `X-Forwarded-For`.
+pub struct Bridge {
+    pub upstream: SocketAddr,
+}
+
+/// Binds the loopback listener, that the HTTP server has to listen on too.
+pub fn bind_upstream() -> std::io::Result<(Bridge, StdTcpListener)> {
+    let listener = StdTcpListener::bind("127.0.0.1:0")?;
+    let upstream = listener.local_addr()?;
+    Ok((Bridge { upstream }, listener))
+}
+
+pub async fn serve(address: String, upstream: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&address).await?;
+    tracing::info!("WebSockets over HTTP/2 listening on {address}");
+    accept(listener, upstream).await
+}
+
+async fn accept(listener: TcpListener, upstream: SocketAddr) -> anyhow::Result<()> {
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = connection(socket, peer, upstream).await {
+                tracing::debug!(%peer, "HTTP/2 connection failed: {err}");
+            }
+        });
+    }
+}
+
+async fn connection(
+    socket: TcpStream,
+    peer: SocketAddr,
+    upstream: SocketAddr,
+) -> Result<(), h2::Error> {
+    let mut connection = server::Builder::new()
+        .enable_connect_protocol()
+        .handshake(socket)
+        .await?;
+    while let Some(request) = connection.accept().await {
+        let (request, respond) = request?;
+        tokio::spawn(async move {
+            if let Err(err) = stream(request, respond, peer, upstream).await {
+                tracing::debug!(%peer, "WebSocket over HTTP/2 failed: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Bridges one extended CONNECT stream, or refuses other requests.
+async fn stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer: SocketAddr,
+    upstream: SocketAddr,
+) -> anyhow::Result<()> {
+    let is_websocket = request.method() == Method::CONNECT
+        && request
+            .extensions()
+            .get::<Protocol>()
+            .is_some_and(|protocol| protocol.as_str() == "websocket");
+    if !is_websocket {
+        let response = Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(())?;
+        respond.send_response(response, true)?;
+        return Ok(());
+    }
+    let (parts, mut body) = request.into_parts();
+    let mut upstream = TcpStream::connect(upstream).await?;
+    upstream.write_all(&upgrade_request(&parts, peer)).await?;
+    let (head, mut rest) = read_head(&mut upstream).await?;
+    if head.status() != StatusCode::SWITCHING_PROTOCOLS {
+        // The body of a refused upgrade, like the reason for a 400
+        let length = head
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+            .unwrap_or(0);
+        while rest.len() < length {
+            if upstream.read_buf(&mut rest).await? == 0 {
+                break;
+            }
+        }
+        rest.truncate(length);
+        let mut send = respond.send_response(head, rest.is_empty())?;
+        if !rest.is_empty() {
+            send_all(&mut send, rest.freeze()).await?;
+            send.send_data(Bytes::new(), true)?;
+        }
+        return Ok(());
+    }
+
+    // Extended CONNECT streams are accepted with a 200 instead of a 101
+    let (mut head, ()) = head.into_parts();
+    head.status = StatusCode::OK;
+    let mut send = respond.send_response(Response::from_parts(head, ()), false)?;
+    let (mut read, mut write) = upstream.into_split();
+    let to_client = async {
+        send_all(&mut send, rest.freeze()).await?;
+        let mut buffer = BytesMut::with_capacity(16 * 1024);
+        while read.read_buf(&mut buffer).await? != 0 {
+            send_all(&mut send, buffer.split().freeze()).await?;
+        }
+        send.send_data(Bytes::new(), true)?;
+        anyhow::Ok(())
+    };
+    let to_server = async {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            body.flow_control().release_capacity(chunk.len())?;
+            write.write_all(&chunk).await?;
+        }
+        write.shutdown().await?;
+        anyhow::Ok(())
+    };
+    tokio::pin!(to_client, to_server);
+    // The server can still reply after the client closed its side, but not the
+    // other way around
+    tokio::select! {
+        result = &mut to_client => result,
+        result = &mut to_server => {
+            result?;
+            to_client.await
+        }
+    }
+}
+
+/// The HTTP/1.1 upgrade request of an extended CONNECT request, with its path
+/// and headers.
+fn upgrade_request(parts: &request::Parts, peer: SocketAddr) -> Vec<u8> {
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    let host = parts
+        .uri
+        .authority()
+        .map_or("localhost", |authority| authority.as_str());
+    let mut key = [0u8; 16];
+    getrandom::getrandom(&mut key).expect("Failed to generate a WebSocket key");
+    let mut head = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: {}\r\n",
+        BASE64_STANDARD.encode(key),
+    )
+    .into_bytes();
+    for (name, value) in &parts.headers {
+        if REPLACED.contains(&name.as_str()) {
+            continue;
+        }
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    // Proxies append the address they got the request from
+    let mut forwarded_for = parts
+        .headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+    let peer = peer.ip().to_canonical().to_string();
+    forwarded_for.push(&peer);
+    head.extend_from_slice(
+        format!("X-Forwarded-For: {}\r\n\r\n", forwarded_for.join(", ")).as_bytes(),
+    );
+    head
+}
+
+/// Reads the response head of the HTTP server, without the hop-by-hop headers,
+/// and what it sent after it.
+async fn read_head(upstream: &mut TcpStream) -> anyhow::Result<(Response<()>, BytesMut)> {
+    let mut received = BytesMut::with_capacity(1024);
+    loop {
+        if upstream.read_buf(&mut received).await? == 0 {
+            anyhow::bail!("The server closed the connection before responding");
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let length = match parsed.parse(&received)? {
+            httparse::Status::Complete(length) => length,
+            httparse::Status::Partial if received.len() < MAX_HEAD_SIZE => continue,
+            httparse::Status::Partial => anyhow::bail!("The response head is too long"),
+        };
+        let mut response = Response::new(());
+        *response.status_mut() = StatusCode::from_u16(parsed.code.unwrap_or(502))?;
+        *response.headers_mut() = response_headers(parsed.headers)?;
+        return Ok((response, received.split_off(length)));
+    }
+}
+
+fn response_headers(headers: &[httparse::Header<'_>]) -> anyhow::Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let name = HeaderName::from_bytes(header.name.as_bytes())?;
+        if HOP_BY_HOP.contains(&name.as_str()) {
+            continue;
+        }
+        map.append(name, HeaderValue::from_bytes(header.value)?);
+    }
+    Ok(map)
+}
+
+/// Sends data as the flow control of the client allows.
+async fn send_all(send: &mut SendStream<Bytes>, mut data: Bytes) -> anyhow::Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = poll_fn(|cx| send.poll_capacity(cx))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("The client closed the stream"))??;
+        if capacity > 0 {
+            send.send_data(data.split_to(capacity.min(data.len())), false)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A masked text frame `hi` from a client, and the unmasked reply.
+    const CLIENT_FRAME: &[u8] = &[0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2];
+    const SERVER_FRAME: &[u8] = &[0x81, 0x02, b'h', b'i'];
+
+    #[tokio::test]
+    async fn bridges_extended_connect_streams() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept(listener, upstream_addr));
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = upstream.accept().await.unwrap();
+            let mut received = Vec::new();
+            while !received.ends_with(b"\r\n\r\n") {
+                received.push(socket.read_u8().await.unwrap());
+            }
+            socket
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\nX-Connection-Id: 1\r\n\r\n")
+                .await
+                .unwrap();
+            socket.write_all(SERVER_FRAME).await.unwrap();
+            let mut frame = vec![0; CLIENT_FRAME.len()];
+            socket.read_exact(&mut frame).await.unwrap();
+            (String::from_utf8(received).unwrap(), frame)
+        });
+
+        let (client, connection) = h2::client::handshake(TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        // The setting arrives after the handshake
+        while !client.is_extended_connect_protocol_enabled() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let request = Request::builder()
+            .method(Method::CONNECT)
+            .uri("http://localhost/v0/nft/nft_mint?network=testnet")
+            .extension(Protocol::from("websocket"))
+            .header("sec-websocket-version", "13")
+            .header("x-filter", r#"{"owner_id":"alice.near"}"#)
+            .body(())
+            .unwrap();
+        let (response, mut send) = client.send_request(request, false).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-connection-id"], "1");
+        assert!(response.headers().get("sec-websocket-accept").is_none());
+        let mut body = response.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), SERVER_FRAME);
+        send.send_data(Bytes::from_static(CLIENT_FRAME), false)
+            .unwrap();
+
+        let (head, frame) = server.await.unwrap();
+        let lines = head.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "GET /v0/nft/nft_mint?network=testnet HTTP/1.1");
+        for line in [
+            "Host: localhost",
+            "Upgrade: websocket",
+            "sec-websocket-version: 13",
+            r#"x-filter: {"owner_id":"alice.near"}"#,
+            "X-Forwarded-For: 127.0.0.1",
+        ] {
+            assert!(lines.contains(&line), "{line} is missing in {head}");
+        }
+        assert_eq!(frame, CLIENT_FRAME);
+
+        // Other requests are refused
+        let request = Request::builder()
+            .uri("http://localhost/v0/nft/nft_mint")
+            .body(())
+            .unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        assert_eq!(response.await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+}
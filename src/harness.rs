@@ -0,0 +1,443 @@
+//! An in-process server without Redis, for the fuzz targets in `fuzz/` and the
+//! benchmarks in `benches/`. Connections go through the real `connect` handler
+//! and `EventWebSocket` actor, with WebSocket frames in memory instead of a TCP
+//! connection, and events through the `SocketEventHandler` of a reader. Needs a
+//! running actix system, like `actix::System::new().block_on(...)`.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use actix::{Actor, Addr};
+use actix_codec::{Decoder, Encoder};
+use actix_http::{
+    ws::{Codec, Frame, Message},
+    BoxedPayloadStream,
+};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev,
+    error::PayloadError,
+    http::{header, StatusCode},
+    test::TestRequest,
+    web, FromRequest,
+};
+use bytes::{Bytes, BytesMut};
+use dashmap::DashSet;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    config::Encoding,
+    dedup::RecentIds,
+    nft_events::{
+        FullNftBurnEvent, FullNftMintEvent, FullNftTransferEvent, NftBurnFilter, NftMintFilter,
+        NftTransferFilter,
+    },
+    potlock_events::{
+        FullPotlockDonationEvent, FullPotlockPotDonationEvent, FullPotlockPotProjectDonationEvent,
+        PotlockDonationEventFilter, PotlockPotDonationEventFilter,
+        PotlockPotProjectDonationEventFilter,
+    },
+    redis_reader::{Checkpoints, EventHandler},
+    synthetic,
+    trade_events::{
+        FullTradePoolChangeEvent, FullTradePoolEvent, FullTradeSwapEvent,
+        TradePoolChangeEventFilter, TradePoolEventFilter, TradeSwapEventFilter,
+    },
+    ApiVersion, Broadcasts, EventFilter, EventOrigin, EventWebSocket, FromRedis, NetworkSockets,
+    Networks, Presets, ProofOfWork, ReadReplay, Server, SocketEventHandler, SubscribeToEvents,
+    UnsubscribeFromEvents, DEDUPLICATION_WINDOW, DEFAULT_NETWORK,
+};
+
+/// Frames from the server are at most this large.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// How a connection is opened.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Connect to `/v1`, where the client can pick the protocol, instead of `/v0`.
+    pub v1: bool,
+    /// Anonymous clients have to solve a challenge with this many bits first.
+    pub proof_of_work_bits: Option<u8>,
+}
+
+pub struct Harness {
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+    presets: web::Data<Presets>,
+    /// The connections of the server, to publish events to.
+    sockets: NetworkSockets,
+    broadcasts: Arc<Broadcasts>,
+}
+
+impl Harness {
+    /// Starts the server actor, with a `preset` preset on every endpoint.
+    pub fn start() -> Self {
+        let networks = Networks::from([DEFAULT_NETWORK.to_string()]);
+        let sockets = NetworkSockets::default();
+        let broadcasts = Arc::new(Broadcasts::default());
+        let server = Server {
+            redis_sources: Vec::new(),
+            stream_configs: HashMap::new(),
+            checkpoints: Checkpoints {
+                prefix: String::new(),
+                fresh: true,
+            },
+            networks: HashMap::from([(DEFAULT_NETWORK.to_string(), sockets.clone())]),
+            shutdown: watch::channel(false).0,
+            readers: Vec::new(),
+            broadcasts: Arc::clone(&broadcasts),
+            origin: EventOrigin::Redis,
+        };
+        let mut presets = EndpointPresets(Presets::new());
+        for_each_event_type(&mut presets);
+        Self {
+            server: web::Data::new(server.start()),
+            networks: web::Data::new(networks),
+            presets: web::Data::new(presets.0),
+            sockets,
+            broadcasts,
+        }
+    }
+
+    /// A publisher of events to the connections of the endpoint of a stream key,
+    /// or `None` if there's no such endpoint.
+    pub fn publisher(&self, stream_key: &str) -> Option<Publisher> {
+        let sockets = &self.sockets;
+        Some(match stream_key {
+            FullNftMintEvent::STREAM_KEY => self.publisher_of(&sockets.nft_mint_sockets),
+            FullNftTransferEvent::STREAM_KEY => self.publisher_of(&sockets.nft_transfer_sockets),
+            FullNftBurnEvent::STREAM_KEY => self.publisher_of(&sockets.nft_burn_sockets),
+            FullPotlockDonationEvent::STREAM_KEY => {
+                self.publisher_of(&sockets.potlock_donation_sockets)
+            }
+            FullPotlockPotProjectDonationEvent::STREAM_KEY => {
+                self.publisher_of(&sockets.potlock_pot_project_donation_sockets)
+            }
+            FullPotlockPotDonationEvent::STREAM_KEY => {
+                self.publisher_of(&sockets.potlock_pot_donation_sockets)
+            }
+            FullTradePoolEvent::STREAM_KEY => self.publisher_of(&sockets.trade_pool_sockets),
+            FullTradeSwapEvent::STREAM_KEY => self.publisher_of(&sockets.trade_swap_sockets),
+            FullTradePoolChangeEvent::STREAM_KEY => {
+                self.publisher_of(&sockets.trade_pool_change_sockets)
+            }
+            _ => return None,
+        })
+    }
+
+    fn publisher_of<
+        E: Serialize + FromRedis + Send + Sync + Unpin + 'static,
+        F: EventFilter<E> + Unpin + 'static,
+    >(
+        &self,
+        sockets: &Arc<DashSet<Addr<EventWebSocket<E, F>>>>,
+    ) -> Publisher
+    where
+        Server: actix::Handler<UnsubscribeFromEvents<E, F>>,
+    {
+        let subscribers = Arc::clone(sockets);
+        Publisher {
+            handler: Box::new(SocketEventHandler {
+                sockets: Arc::clone(sockets),
+                source: DEFAULT_NETWORK.into(),
+                network: DEFAULT_NETWORK.to_string(),
+                recent_ids: Mutex::new(RecentIds::new(DEDUPLICATION_WINDOW)),
+                broadcast: self.broadcasts.sender(DEFAULT_NETWORK),
+                firehose: None,
+                dead_letters: None,
+                encoding: Encoding::Json,
+                passthrough: false,
+            }),
+            subscribers: Box::new(move || subscribers.len()),
+        }
+    }
+
+    /// Opens a connection to the endpoint of a stream key, or returns `None` if
+    /// there's no such endpoint.
+    pub async fn open(&self, stream_key: &str, options: &ConnectOptions) -> Option<Connection> {
+        Some(match stream_key {
+            FullNftMintEvent::STREAM_KEY => self.connect::<_, NftMintFilter>(options).await,
+            FullNftTransferEvent::STREAM_KEY => self.connect::<_, NftTransferFilter>(options).await,
+            FullNftBurnEvent::STREAM_KEY => self.connect::<_, NftBurnFilter>(options).await,
+            FullPotlockDonationEvent::STREAM_KEY => {
+                self.connect::<_, PotlockDonationEventFilter>(options).await
+            }
+            FullPotlockPotProjectDonationEvent::STREAM_KEY => {
+                self.connect::<_, PotlockPotProjectDonationEventFilter>(options)
+                    .await
+            }
+            FullPotlockPotDonationEvent::STREAM_KEY => {
+                self.connect::<_, PotlockPotDonationEventFilter>(options)
+                    .await
+            }
+            FullTradePoolEvent::STREAM_KEY => {
+                self.connect::<_, TradePoolEventFilter>(options).await
+            }
+            FullTradeSwapEvent::STREAM_KEY => {
+                self.connect::<_, TradeSwapEventFilter>(options).await
+            }
+            FullTradePoolChangeEvent::STREAM_KEY => {
+                self.connect::<_, TradePoolChangeEventFilter>(options).await
+            }
+            _ => return None,
+        })
+    }
+
+    async fn connect<
+        E: Serialize + FromRedis + Send + Sync + Unpin + 'static,
+        F: EventFilter<E> + DeserializeOwned + Unpin + 'static,
+    >(
+        &self,
+        options: &ConnectOptions,
+    ) -> Connection
+    where
+        Server: actix::Handler<SubscribeToEvents<E, F>>
+            + actix::Handler<UnsubscribeFromEvents<E, F>>
+            + actix::Handler<ReadReplay<E>>,
+    {
+        let mut request = TestRequest::get()
+            .uri(&format!("/{}", E::STREAM_KEY))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .app_data(self.presets.clone());
+        if options.v1 {
+            request = request.app_data(ApiVersion::V1);
+        }
+        if let Some(bits) = options.proof_of_work_bits {
+            let streams = ProofOfWork::from([(E::STREAM_KEY.to_string(), bits)]);
+            request = request.app_data(web::Data::new(streams));
+        }
+        let request = request.to_http_request();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stream: BoxedPayloadStream = Box::pin(UnboundedReceiverStream::new(receiver));
+        let mut payload = dev::Payload::from(stream);
+        let payload = web::Payload::from_request(&request, &mut payload)
+            .await
+            .expect("Failed to take the payload");
+        let response =
+            crate::connect::<E, F>(request, payload, self.server.clone(), self.networks.clone())
+                .await
+                .expect("Failed to connect");
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        Connection {
+            sender: Some(sender),
+            body: response.into_body(),
+            codec: Codec::new().client_mode().max_size(MAX_FRAME_SIZE),
+            received: BytesMut::new(),
+        }
+    }
+}
+
+/// Hands stream entries to the connections of an endpoint, like a reader.
+pub struct Publisher {
+    handler: Box<dyn EventHandler + Send + Sync>,
+    subscribers: Box<dyn Fn() -> usize + Send + Sync>,
+}
+
+impl Publisher {
+    /// Parses the entry and sends it to the connections whose filter matches.
+    /// Entries are numbered by `id`, like `1-0`, and ones that were published
+    /// before are sent as duplicates. Returns once every connection took the
+    /// event, so all of them have to be read at the same time, e.g. with `join!`
+    /// and `join_all`.
+    pub async fn publish(
+        &self,
+        id: &str,
+        values: HashMap<String, redis::Value>,
+    ) -> anyhow::Result<()> {
+        self.handler.handle(id, values).await
+    }
+
+    /// How many connections sent a filter, and receive events.
+    pub fn subscribers(&self) -> usize {
+        (self.subscribers)()
+    }
+}
+
+/// A made-up entry of a stream, like the indexer writes it.
+pub fn sample(stream_key: &str) -> HashMap<String, redis::Value> {
+    synthetic::sample(stream_key)
+}
+
+/// The same `preset` on every endpoint.
+struct EndpointPresets(Presets);
+
+impl EventTypeVisitor for EndpointPresets {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        let preset = serde_json::json!({ "min_block_height": 1 });
+        self.0.insert(
+            E::STREAM_KEY.to_string(),
+            HashMap::from([("preset".to_string(), preset)]),
+        );
+    }
+}
+
+/// The client side of a connection.
+pub struct Connection {
+    /// `None` once the client closed the connection.
+    sender: Option<mpsc::UnboundedSender<Result<Bytes, PayloadError>>>,
+    body: BoxBody,
+    codec: Codec,
+    received: BytesMut,
+}
+
+impl Connection {
+    /// Sends a text message, like a client.
+    pub fn send(&mut self, text: &str) {
+        self.write(Message::Text(text.into()));
+    }
+
+    fn write(&mut self, message: Message) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let mut frame = BytesMut::new();
+        self.codec
+            .encode(message, &mut frame)
+            .expect("Failed to encode a message");
+        // Fails only if the server stopped reading
+        let _ = sender.send(Ok(frame.freeze()));
+    }
+
+    /// Closes the client side. The server stops once it handled the messages
+    /// before it, and `next_text` returns `None` after its last frame.
+    pub fn close(&mut self) {
+        self.sender = None;
+    }
+
+    /// The next text frame from the server, or `None` when the server closed the
+    /// connection. Pings are answered while waiting, so that the server doesn't
+    /// time out the connection.
+    pub async fn next_text(&mut self) -> Option<String> {
+        loop {
+            match self.codec.decode(&mut self.received) {
+                Ok(Some(Frame::Text(text))) => {
+                    return Some(String::from_utf8_lossy(&text).into_owned())
+                }
+                Ok(Some(Frame::Ping(data))) => {
+                    self.write(Message::Pong(data));
+                    continue;
+                }
+                Ok(Some(Frame::Close(_))) | Err(_) => return None,
+                Ok(Some(_)) => continue,
+                Ok(None) => {}
+            }
+            let chunk = std::future::poll_fn(|cx| self.poll_body(cx)).await?;
+            self.received.extend_from_slice(&chunk);
+        }
+    }
+
+    /// Lets the server handle the messages sent so far, without waiting for a
+    /// frame. The actor of a connection only runs while the connection is read.
+    pub async fn settle(&mut self) {
+        loop {
+            let chunk = std::future::poll_fn(|cx| match self.poll_body(cx) {
+                Poll::Pending => Poll::Ready(None),
+                ready => ready,
+            });
+            let Some(chunk) = chunk.await else {
+                return;
+            };
+            self.received.extend_from_slice(&chunk);
+        }
+    }
+
+    fn poll_body(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        Pin::new(&mut self.body)
+            .poll_next(cx)
+            .map(|chunk| chunk.and_then(Result::ok))
+    }
+
+    /// Sends the messages, closes the connection and returns all text frames of
+    /// the server.
+    pub async fn exchange(mut self, messages: &[String]) -> Vec<String> {
+        for message in messages {
+            self.send(message);
+        }
+        self.close();
+        let mut frames = Vec::new();
+        while let Some(frame) = self.next_text().await {
+            frames.push(frame);
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix::test]
+    async fn answers_client_messages() {
+        let harness = Harness::start();
+        let options = ConnectOptions {
+            v1: true,
+            ..Default::default()
+        };
+        let connection = harness.open("nft_mint", &options).await.unwrap();
+        let frames = connection
+            .exchange(&[
+                r#"{"owner_id": "alice.near"}"#.to_string(),
+                r#"{"preset": "preset"}"#.to_string(),
+                r#"{"owner": "alice.near"}"#.to_string(),
+            ])
+            .await;
+        let types = frames
+            .iter()
+            .map(|frame| serde_json::from_str::<serde_json::Value>(frame).unwrap()["type"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(types, ["ack", "ack", "error"]);
+
+        // Protocol 1 closes the connection instead
+        let connection = harness
+            .open("nft_mint", &ConnectOptions::default())
+            .await
+            .unwrap();
+        let frames = connection
+            .exchange(&[r#"{"owner": "alice.near"}"#.to_string()])
+            .await;
+        assert!(frames.is_empty());
+        assert!(harness.open("nft", &options).await.is_none());
+    }
+
+    #[actix::test]
+    async fn delivers_published_events() {
+        let harness = Harness::start();
+        let publisher = harness.publisher("nft_mint").unwrap();
+        let mut connection = harness
+            .open("nft_mint", &ConnectOptions::default())
+            .await
+            .unwrap();
+        connection.send(r#"{"min_block_height": 1}"#);
+        while publisher.subscribers() == 0 {
+            connection.settle().await;
+            actix::clock::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let (published, frame) = futures_util::join!(
+            publisher.publish("1-0", sample("nft_mint")),
+            connection.next_text(),
+        );
+        published.unwrap();
+        let frame = frame.unwrap();
+        let event = serde_json::from_str::<serde_json::Value>(&frame).unwrap();
+        assert!(event["token_ids"].is_array());
+
+        // Entries that can't be parsed are skipped
+        publisher.publish("2-0", HashMap::new()).await.unwrap();
+        assert!(harness.publisher("nft").is_none());
+    }
+}
@@ -0,0 +1,36 @@
+//! The latest block that each stream of each network has events of, which
+//! WebSocket clients get with the `head_sec` option to tell indexer lag apart from
+//! a quiet stream.
+
+use std::sync::LazyLock;
+
+use dashmap::DashMap;
+
+use crate::types::BlockHeight;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Head {
+    pub block_height: BlockHeight,
+    pub block_timestamp_nanosec: u128,
+}
+
+/// By network and stream key.
+static HEADS: LazyLock<DashMap<(String, &'static str), Head>> = LazyLock::new(DashMap::new);
+
+/// Records the block of an event, unless the stream already has a later one.
+pub fn record(network: &str, stream_key: &'static str, head: Head) {
+    HEADS
+        .entry((network.to_string(), stream_key))
+        .and_modify(|current| {
+            if head.block_height > current.block_height {
+                *current = head;
+            }
+        })
+        .or_insert(head);
+}
+
+pub fn get(network: &str, stream_key: &'static str) -> Option<Head> {
+    HEADS
+        .get(&(network.to_string(), stream_key))
+        .map(|head| *head)
+}
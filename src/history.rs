@@ -0,0 +1,372 @@
+//! Historical events at `/v0/.../<endpoint>/history`, read from the Redis streams,
+//! and from the archive before that if it's configured. Results are paginated
+//! with `cursor`.
+
+use std::{collections::HashMap, sync::Arc};
+
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    archive::Archive,
+    config::Encoding,
+    dead_letters::DeadLetters,
+    disabled_response, fields, plans,
+    redis_reader::read_page,
+    replay::{ReplayStart, StreamId},
+    types::BlockHeight,
+    unix_time_ms, EventFilter, FromRedis, Networks, Server, StreamSource, TaggedEvent,
+    DEFAULT_NETWORK,
+};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+/// Stream entries read per request at most, even if fewer than `limit` matched.
+const MAX_SCANNED_ENTRIES: usize = 10_000;
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    from_block: Option<BlockHeight>,
+    to_block: Option<BlockHeight>,
+    /// Filter in the same JSON format as the WebSocket filter message.
+    filter: Option<String>,
+    /// Return events after this stream ID, from `next_cursor` of the previous page.
+    cursor: Option<StreamId>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    events: Vec<serde_json::Value>,
+    /// Cursor of the next page, or `null` if there are no more events in the range.
+    next_cursor: Option<StreamId>,
+}
+
+pub async fn history<
+    E: Serialize + FromRedis + Send + Sync + 'static,
+    F: EventFilter<E> + DeserializeOwned,
+>(
+    req: HttpRequest,
+    query: web::Query<HistoryQuery>,
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+) -> HttpResponse {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return HttpResponse::NotFound().body(format!("Unknown network: {network}"));
+    }
+    if let Some(response) = disabled_response::<E>(&req) {
+        return response;
+    }
+    let plan = match plans::check_access(&req, E::STREAM_KEY) {
+        Ok((_, plan)) => plan,
+        Err(err) => return err.error_response(),
+    };
+    let now_ms = unix_time_ms();
+    if let (Some(plan), Some(cursor)) = (&plan, query.cursor) {
+        if let Err(message) = plan.limit_replay(ReplayStart::After(cursor), now_ms) {
+            return HttpResponse::Forbidden().body(message);
+        }
+    }
+    let filter = match query.filter.as_deref().map(fields::parse_filter_str::<F>) {
+        Some(Ok(filter)) => Some(filter),
+        Some(Err(err)) => return HttpResponse::BadRequest().body(format!("Invalid filter: {err}")),
+        None => None,
+    };
+    let sources = match server
+        .send(StreamSources {
+            network: network.clone(),
+            stream_key: E::STREAM_KEY,
+        })
+        .await
+    {
+        Ok(sources) => sources,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    let archive = req
+        .app_data::<web::Data<Archive>>()
+        .filter(|archive| archive.serves(&network, E::STREAM_KEY));
+    match read_history(
+        &network,
+        &query,
+        filter.as_ref(),
+        plan.and_then(|plan| plan.earliest(now_ms)),
+        archive.map(|archive| archive.get_ref()),
+        sources,
+    )
+    .await
+    {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(err) => {
+            tracing::error!("Failed to read {} history: {err}", E::STREAM_KEY);
+            HttpResponse::InternalServerError().body("Failed to read events")
+        }
+    }
+}
+
+async fn read_history<E: Serialize + FromRedis, F: EventFilter<E>>(
+    network: &str,
+    query: &HistoryQuery,
+    filter: Option<&F>,
+    // The first event that the plan of the API key can read
+    earliest: Option<StreamId>,
+    archive: Option<&Archive>,
+    sources: Vec<StreamSource>,
+) -> anyhow::Result<HistoryResponse> {
+    let mut page = Page {
+        network,
+        dead_letters: sources
+            .iter()
+            .map(|source| {
+                let connection = source.connection.clone();
+                (Arc::clone(&source.name), DeadLetters { connection })
+            })
+            .collect(),
+        encodings: sources
+            .iter()
+            .map(|source| (Arc::clone(&source.name), source.encoding))
+            .collect(),
+        query,
+        filter,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        events: Vec::new(),
+        after: query.cursor.max(earliest),
+        scanned: 0,
+    };
+
+    // The archive has everything up to a while ago, Redis has everything after that
+    if let Some(archive) = archive {
+        loop {
+            let entries = archive
+                .read(
+                    E::STREAM_KEY,
+                    page.after,
+                    query.from_block,
+                    query.to_block,
+                    PAGE_SIZE,
+                )
+                .await?;
+            let is_last_page = entries.len() < PAGE_SIZE;
+            for (id, source, values) in entries {
+                if let Some(response) = page.push(id, &source, values)? {
+                    return Ok(response);
+                }
+            }
+            if is_last_page {
+                break;
+            }
+        }
+    }
+
+    loop {
+        let mut entries = Vec::new();
+        // Entries after the end of a full page of one source may be on its next
+        // page, so only the entries up to the earliest such end are in order
+        let mut end = None;
+        for source in &sources {
+            let redis_page = read_page(
+                source.connection.clone(),
+                &source.stream_key,
+                page.after,
+                PAGE_SIZE,
+            )
+            .await?;
+            if redis_page.len() == PAGE_SIZE {
+                let last = redis_page.last().unwrap().0.parse::<StreamId>()?;
+                end = Some(end.map_or(last, |end: StreamId| end.min(last)));
+            }
+            for (id, values) in redis_page {
+                entries.push((id.parse::<StreamId>()?, Arc::clone(&source.name), values));
+            }
+        }
+        if entries.is_empty() {
+            return Ok(page.finish(false));
+        }
+        entries.sort_by_key(|(id, _, _)| *id);
+        if let Some(end) = end {
+            entries.retain(|(id, _, _)| *id <= end);
+        }
+        for (id, source, values) in entries {
+            if let Some(response) = page.push(id, &source, values)? {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Collects the matching events of a page.
+struct Page<'a, F> {
+    network: &'a str,
+    /// Of every source, for the entries that can't be read.
+    dead_letters: HashMap<Arc<str>, DeadLetters>,
+    /// Of every source. Entries of other sources are JSON.
+    encodings: HashMap<Arc<str>, Encoding>,
+    query: &'a HistoryQuery,
+    filter: Option<&'a F>,
+    limit: usize,
+    events: Vec<serde_json::Value>,
+    /// The last entry that was read.
+    after: Option<StreamId>,
+    scanned: usize,
+}
+
+impl<F> Page<'_, F> {
+    /// Adds the entry if it matches, returns the response if the page is complete.
+    fn push<E: Serialize + FromRedis>(
+        &mut self,
+        id: StreamId,
+        source: &str,
+        values: HashMap<String, redis::Value>,
+    ) -> anyhow::Result<Option<HistoryResponse>>
+    where
+        F: EventFilter<E>,
+    {
+        self.scanned += 1;
+        self.after = Some(id);
+        let encoding = self.encodings.get(source).copied().unwrap_or_default();
+        let mut event = match E::decode(&values, encoding) {
+            Ok(event) => event,
+            Err(err) => {
+                // Skipped like the live events, instead of failing the page. Entries
+                // of the archive can be of sources that aren't configured anymore.
+                match self.dead_letters.get(source) {
+                    Some(dead_letters) => {
+                        dead_letters.push(source, E::STREAM_KEY, &id.to_string(), &err, &values)
+                    }
+                    None => tracing::warn!(%id, "Skipping an entry that can't be read: {err}"),
+                }
+                return Ok(self.is_full().then(|| self.finish(true)));
+            }
+        };
+        event.enrich(self.network);
+        let value = serde_json::to_value(TaggedEvent {
+            source,
+            stream_id: id,
+            event: &event,
+        })?;
+        let block_height = value["block_height"].as_u64();
+        if let (Some(to_block), Some(block_height)) = (self.query.to_block, block_height) {
+            if block_height > to_block {
+                return Ok(Some(self.finish(false)));
+            }
+        }
+        let in_range = self
+            .query
+            .from_block
+            .is_none_or(|from_block| block_height.is_none_or(|h| h >= from_block));
+        if in_range && self.filter.is_none_or(|f| f.matches(&event)) {
+            self.events.push(value);
+        }
+        Ok(self.is_full().then(|| self.finish(true)))
+    }
+
+    fn is_full(&self) -> bool {
+        self.events.len() >= self.limit || self.scanned >= MAX_SCANNED_ENTRIES
+    }
+
+    fn finish(&mut self, has_more: bool) -> HistoryResponse {
+        HistoryResponse {
+            events: std::mem::take(&mut self.events),
+            next_cursor: if has_more { self.after } else { None },
+        }
+    }
+}
+
+/// Every source of `network` that reads the stream.
+#[derive(Message)]
+#[rtype(result = "Vec<StreamSource>")]
+pub struct StreamSources {
+    pub network: String,
+    pub stream_key: &'static str,
+}
+
+impl Handler<StreamSources> for Server {
+    type Result = Vec<StreamSource>;
+
+    fn handle(&mut self, msg: StreamSources, _ctx: &mut Self::Context) -> Self::Result {
+        self.redis_sources
+            .iter()
+            .filter(|source| source.network == msg.network && source.reads(msg.stream_key))
+            .map(|source| source.stream_source(msg.stream_key))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nft_events::{FullNftMintEvent, NftMintFilter};
+
+    use super::*;
+
+    fn entry(mint: &str) -> HashMap<String, redis::Value> {
+        let context = serde_json::json!({
+            "transaction_id": "9ZsR3fUsVrbRYgpbLBaQrZR3xCTxV3DuL5fJ8qTH3QbW",
+            "receipt_id": "9ZsR3fUsVrbRYgpbLBaQrZR3xCTxV3DuL5fJ8qTH3QbW",
+            "block_height": 1,
+            "block_timestamp_nanosec": "1",
+            "contract_id": "nft.near",
+        });
+        HashMap::from([
+            ("mint".to_string(), redis::Value::Data(mint.into())),
+            (
+                "context".to_string(),
+                redis::Value::Data(context.to_string().into_bytes()),
+            ),
+        ])
+    }
+
+    #[test]
+    fn skips_entries_that_cant_be_read() {
+        let query = serde_json::from_str::<HistoryQuery>(r#"{"limit": 1}"#).unwrap();
+        let mut page = Page::<NftMintFilter> {
+            network: "mainnet",
+            dead_letters: HashMap::new(),
+            encodings: HashMap::new(),
+            query: &query,
+            filter: None,
+            limit: 1,
+            events: Vec::new(),
+            after: None,
+            scanned: 0,
+        };
+        let pushed = page.push::<FullNftMintEvent>(StreamId(1, 0), "a", entry("{"));
+        assert!(pushed.unwrap().is_none());
+        let mint = r#"{"owner_id": "alice.near", "token_ids": ["1"], "memo": null}"#;
+        let response = page
+            .push::<FullNftMintEvent>(StreamId(2, 0), "a", entry(mint))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.events.len(), 1);
+        assert_eq!(response.next_cursor, Some(StreamId(2, 0)));
+    }
+
+    #[test]
+    fn reads_entries_in_the_encoding_of_their_source() {
+        let query = serde_json::from_str::<HistoryQuery>(r#"{"limit": 1}"#).unwrap();
+        let mut page = Page::<NftMintFilter> {
+            network: "mainnet",
+            dead_letters: HashMap::new(),
+            encodings: HashMap::from([("borsh".into(), Encoding::Borsh)]),
+            query: &query,
+            filter: None,
+            limit: 1,
+            events: Vec::new(),
+            after: None,
+            scanned: 0,
+        };
+        let mint = r#"{"owner_id": "alice.near", "token_ids": ["1"], "memo": null}"#;
+        let pushed = page.push::<FullNftMintEvent>(StreamId(1, 0), "borsh", entry(mint));
+        assert!(pushed.unwrap().is_none());
+        let response = page
+            .push::<FullNftMintEvent>(StreamId(2, 0), "json", entry(mint))
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.events[0]["source"], "json");
+    }
+}
@@ -0,0 +1,339 @@
+//! Tiny HTTP/1.1 client for pushing JSON to collectors and querying ClickHouse,
+//! over plain TCP or TLS.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use actix_web::http::Uri;
+use rustls::pki_types::ServerName;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub static TLS_CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs().expect("Failed to load root certificates")
+    {
+        // Some systems have certificates that rustls doesn't support
+        let _ = roots.add(cert);
+    }
+    TlsConnector::from(Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+});
+
+pub struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+    base_path: String,
+    /// Query string of the URL, with the leading `?`.
+    query: String,
+    /// Only connect to public addresses, see `public_only`.
+    public_only: bool,
+}
+
+/// Whether an address is reachable on the internet, and isn't the server itself,
+/// its local network or a cloud metadata service.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // Reserved, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Checks that a header can't end the line it's on, e.g. to inject another header.
+pub fn check_header(name: &str, value: &str) -> anyhow::Result<()> {
+    let valid_name = !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+    if !valid_name {
+        anyhow::bail!("Invalid header name {name:?}");
+    }
+    if value
+        .bytes()
+        .any(|byte| byte.is_ascii_control() && byte != b'\t')
+    {
+        anyhow::bail!("Invalid value of header {name}");
+    }
+    Ok(())
+}
+
+impl Endpoint {
+    /// Parses an `http://` or `https://` URL. Paths passed to `post` are appended to its path,
+    /// and its query string is kept if they don't have one.
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        let uri = url.parse::<Uri>()?;
+        let tls = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => anyhow::bail!("Only http:// and https:// URLs are supported, got {url}"),
+        };
+        let Some(host) = uri.host() else {
+            anyhow::bail!("No host in {url}");
+        };
+        Ok(Endpoint {
+            tls,
+            host: host.to_string(),
+            port: uri.port_u16().unwrap_or(if tls { 443 } else { 80 }),
+            base_path: uri.path().trim_end_matches('/').to_string(),
+            query: uri
+                .query()
+                .map(|query| format!("?{query}"))
+                .unwrap_or_default(),
+            public_only: false,
+        })
+    }
+
+    /// Only connects to public addresses, for URLs that clients chose, so that they
+    /// can't make the server send requests to itself or to internal hosts. The
+    /// host is checked every time it's resolved, so it can't change to an internal
+    /// address later.
+    pub fn public_only(mut self) -> Self {
+        self.public_only = true;
+        self
+    }
+
+    /// The addresses of the host, or an error if it has one that isn't public
+    /// and only public ones are allowed.
+    pub async fn resolve(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let addrs = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .collect::<Vec<_>>();
+        if self.public_only {
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                anyhow::bail!(
+                    "{} resolves to {}, which isn't public",
+                    self.host,
+                    addr.ip()
+                );
+            }
+        }
+        Ok(addrs)
+    }
+
+    pub async fn post_json(
+        &self,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &Value,
+    ) -> anyhow::Result<()> {
+        self.post(path, headers, "application/json", &body.to_string())
+            .await
+            .map(drop)
+    }
+
+    /// Posts the body and returns the body of the response.
+    pub async fn post(
+        &self,
+        path: &str,
+        headers: &[(&str, &str)],
+        content_type: &str,
+        body: &str,
+    ) -> anyhow::Result<String> {
+        tokio::time::timeout(REQUEST_TIMEOUT, async {
+            let stream = TcpStream::connect(&self.resolve().await?[..]).await?;
+            if self.tls {
+                let server_name = ServerName::try_from(self.host.clone())?;
+                let stream = TLS_CONNECTOR.connect(server_name, stream).await?;
+                self.send(stream, path, headers, content_type, body).await
+            } else {
+                self.send(stream, path, headers, content_type, body).await
+            }
+        })
+        .await?
+    }
+
+    async fn send(
+        &self,
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        path: &str,
+        headers: &[(&str, &str)],
+        content_type: &str,
+        body: &str,
+    ) -> anyhow::Result<String> {
+        let mut target = format!("{}{path}", self.base_path);
+        if !target.starts_with('/') {
+            target.insert(0, '/');
+        }
+        if !path.contains('?') {
+            target.push_str(&self.query);
+        }
+        let mut request = format!(
+            "POST {target} HTTP/1.1\r\nHost: {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.host,
+            body.len(),
+        );
+        for (name, value) in headers {
+            check_header(name, value)?;
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        let mut response = Vec::new();
+        // TLS servers may close without close_notify after Connection: close
+        if let Err(err) = stream.read_to_end(&mut response).await {
+            if response.is_empty() {
+                return Err(err.into());
+            }
+        }
+        let head_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(response.len(), |position| position + 4);
+        let head = String::from_utf8_lossy(&response[..head_end]);
+        let status_line = head.lines().next().unwrap_or_default();
+        let chunked = head.lines().any(|line| {
+            line.to_ascii_lowercase()
+                .starts_with("transfer-encoding: chunked")
+        });
+        let body = &response[head_end..];
+        let body = if chunked {
+            String::from_utf8_lossy(&dechunk(body)).into_owned()
+        } else {
+            String::from_utf8_lossy(body).into_owned()
+        };
+        match status_line.split(' ').nth(1) {
+            Some(status) if status.starts_with('2') => Ok(body),
+            _ => Err(anyhow::anyhow!(
+                "Server responded with {status_line:?}: {}",
+                body.trim()
+            )),
+        }
+    }
+}
+
+/// Joins the chunks of a `Transfer-Encoding: chunked` body.
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut joined = Vec::new();
+    while let Some(line_end) = body.windows(2).position(|window| window == b"\r\n") {
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            break;
+        };
+        let rest = &body[line_end + 2..];
+        if size == 0 || rest.len() < size {
+            break;
+        }
+        joined.extend_from_slice(&rest[..size]);
+        body = rest[size..].strip_prefix(b"\r\n").unwrap_or(&rest[size..]);
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Posts `{"a":1}` with the response, and returns the request that was sent.
+    async fn post(response: &'static [u8]) -> (anyhow::Result<String>, String) {
+        let endpoint = Endpoint::parse("http://collector.example.com:4318/v1/?key=1").unwrap();
+        let (client, mut server) = duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            // The head and the body are written separately
+            let mut request = Vec::new();
+            while !request.ends_with(br#"{"a":1}"#) {
+                server.read_buf(&mut request).await.unwrap();
+            }
+            server.write_all(response).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let result = endpoint
+            .send(
+                client,
+                "/traces",
+                &[("X-Api-Key", "k")],
+                "application/json",
+                r#"{"a":1}"#,
+            )
+            .await;
+        (result, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn posts_and_reads_responses() {
+        let (body, request) = post(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"ok\r\n4;ext=1\r\n\":1}\r\n0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(
+            request,
+            "POST /v1/traces?key=1 HTTP/1.1\r\nHost: collector.example.com\r\nContent-Type: application/json\r\nContent-Length: 7\r\nConnection: close\r\nX-Api-Key: k\r\n\r\n{\"a\":1}",
+        );
+        assert_eq!(body.unwrap(), r#"{"ok":1}"#);
+
+        let (body, _) =
+            post(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 5\r\n\r\nbusy\n").await;
+        assert_eq!(
+            body.unwrap_err().to_string(),
+            r#"Server responded with "HTTP/1.1 503 Service Unavailable": busy"#,
+        );
+    }
+
+    #[test]
+    fn rejects_internal_addresses_and_header_injection() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "100.128.0.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+
+        assert!(check_header("X-Webhook-Signature", "t=1,v1=ab").is_ok());
+        assert!(check_header("X-Test", "a\r\nHost: internal").is_err());
+        assert!(check_header("X-Test: a\r\n", "b").is_err());
+    }
+}
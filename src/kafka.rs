@@ -0,0 +1,1084 @@
+//! Mirrors events into Kafka topics, configured with `kafka` in the config file.
+//! Events are JSON (the same as the WebSocket endpoints of protocol 1) or Avro
+//! with the schemas of `avro/`, keyed by `contract_id` if they have one and
+//! partitioned like the Java client does, so all events of a contract stay in
+//! order in one partition.
+//!
+//! This is a small producer that speaks just enough of the Kafka protocol:
+//! Metadata v1 to find partition leaders and Produce v3 with record batches, over
+//! plaintext or TLS, with SaslHandshake v1 and SaslAuthenticate v0 for SASL/PLAIN.
+//! Every topic has a producer of its own, so a topic that fails doesn't hold up
+//! the others. Batches that fail are retried after refreshing the metadata, so
+//! events are delivered at least once, unless they still fail after
+//! `MAX_ATTEMPTS`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use apache_avro::{types::Value as AvroValue, writer::datum::GenericDatumWriter, Schema};
+use rustls::pki_types::ServerName;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{broadcast::error::RecvError, mpsc},
+};
+
+use crate::{
+    broadcast::{for_each_event_type, Broadcasts, EventReceiver, EventTypeVisitor},
+    config::{KafkaConfig, KafkaFormat, KafkaSasl},
+    http_client::{Endpoint, TLS_CONNECTOR},
+    EventFilter, FromRedis, TaggedEvent,
+};
+
+/// Messages waiting to be produced to a topic while the brokers are slow or down.
+const MAX_QUEUED_MESSAGES: usize = 10_000;
+const MAX_BATCH_MESSAGES: usize = 500;
+/// How long to wait for more messages before producing a batch.
+const LINGER: Duration = Duration::from_millis(50);
+/// The delay before the first retry, doubled after every failure in a row.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Batches that fail this often are dropped, since retrying doesn't fix errors
+/// like messages that are too large or topics that the producer can't write to.
+const MAX_ATTEMPTS: u32 = 5;
+/// The longest delay between attempts to register an Avro schema.
+const MAX_REGISTER_DELAY: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Responses are far smaller, even metadata of large clusters, so a larger
+/// length is a broken or hostile broker, and isn't allocated.
+const MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+const CLIENT_ID: &str = env!("CARGO_PKG_NAME");
+
+struct Message {
+    key: Option<String>,
+    value: Vec<u8>,
+    timestamp_ms: i64,
+}
+
+/// Starts the producers. Panics if the schema registry URL is invalid, or the
+/// format is `avro` and there's none.
+pub fn spawn(config: KafkaConfig, broadcasts: &Broadcasts) {
+    let registry = config.schema_registry.as_deref().map(|url| {
+        Endpoint::parse(url).unwrap_or_else(|err| panic!("Invalid schema registry URL: {err}"))
+    });
+    if config.format == KafkaFormat::Avro && registry.is_none() {
+        panic!("The avro format of kafka needs a schema_registry");
+    }
+    for_each_event_type(&mut Forwarders {
+        config: &config,
+        broadcasts,
+        registry: registry.map(Arc::new),
+        producers: HashMap::new(),
+    });
+}
+
+struct Forwarders<'a> {
+    config: &'a KafkaConfig,
+    broadcasts: &'a Broadcasts,
+    registry: Option<Arc<Endpoint>>,
+    /// Senders to the producers by topic.
+    producers: HashMap<String, mpsc::Sender<Message>>,
+}
+
+impl EventTypeVisitor for Forwarders<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        if !self
+            .config
+            .streams
+            .as_ref()
+            .is_none_or(|streams| streams.iter().any(|s| s == E::STREAM_KEY))
+        {
+            return;
+        }
+        let topic = self.config.topic(E::STREAM_KEY);
+        let sender = self
+            .producers
+            .entry(topic.clone())
+            .or_insert_with(|| {
+                let (sender, receiver) = mpsc::channel(MAX_QUEUED_MESSAGES);
+                tokio::spawn(produce(Producer::new(self.config, &topic), receiver));
+                sender
+            })
+            .clone();
+        let encoder = match self.config.format {
+            KafkaFormat::Json => Encoder::Json,
+            KafkaFormat::Avro => Encoder::Avro {
+                registry: Arc::clone(self.registry.as_ref().unwrap()),
+                subject: format!("{topic}-{}", avro_record_name(E::STREAM_KEY)),
+            },
+        };
+        tokio::spawn(forward(
+            self.broadcasts.subscribe::<E>(&self.config.network),
+            encoder,
+            sender,
+        ));
+    }
+}
+
+/// How the events of a stream are encoded into messages.
+enum Encoder {
+    Json,
+    /// Registers the schema of the stream under `subject` first.
+    Avro {
+        registry: Arc<Endpoint>,
+        subject: String,
+    },
+}
+
+/// Avro with the schema of a stream, in the wire format of the Confluent schema
+/// registry: a zero byte and the ID of the schema before the datum.
+struct AvroEncoder {
+    schema: Schema,
+    schema_id: u32,
+}
+
+impl AvroEncoder {
+    fn encode(&self, value: &Value) -> anyhow::Result<Vec<u8>> {
+        let mut message = vec![0];
+        message.extend_from_slice(&self.schema_id.to_be_bytes());
+        GenericDatumWriter::builder(&self.schema)
+            .build()?
+            .write_value(&mut message, avro_value(value, &self.schema)?)?;
+        Ok(message)
+    }
+}
+
+/// The schema of a stream's events, from `avro/`.
+fn avro_schema(stream_key: &str) -> &'static str {
+    match stream_key {
+        "nft_mint" => include_str!("../avro/nft_mint.avsc"),
+        "nft_transfer" => include_str!("../avro/nft_transfer.avsc"),
+        "nft_burn" => include_str!("../avro/nft_burn.avsc"),
+        "potlock_donation" => include_str!("../avro/potlock_donation.avsc"),
+        "potlock_pot_project_donation" => include_str!("../avro/potlock_pot_project_donation.avsc"),
+        "potlock_pot_donation" => include_str!("../avro/potlock_pot_donation.avsc"),
+        "trade_pool" => include_str!("../avro/trade_pool.avsc"),
+        "trade_swap" => include_str!("../avro/trade_swap.avsc"),
+        "trade_pool_change" => include_str!("../avro/trade_pool_change.avsc"),
+        _ => unreachable!("No Avro schema for {stream_key}"),
+    }
+}
+
+/// The full name of the record of a stream's schema, for the subject that it's
+/// registered under, like `TopicRecordNameStrategy` of the Java serializer does.
+fn avro_record_name(stream_key: &str) -> String {
+    match Schema::parse_str(avro_schema(stream_key)) {
+        Ok(Schema::Record(record)) => record.name.fullname(None),
+        _ => unreachable!("The Avro schema of {stream_key} isn't a record"),
+    }
+}
+
+/// Registers the schema of a stream, or finds its ID if it's already registered.
+async fn register_schema(
+    registry: &Endpoint,
+    subject: &str,
+    stream_key: &str,
+) -> anyhow::Result<AvroEncoder> {
+    let schema_json = avro_schema(stream_key);
+    let body = serde_json::json!({ "schema": schema_json }).to_string();
+    let response = registry
+        .post(
+            &format!("/subjects/{subject}/versions"),
+            &[],
+            "application/vnd.schemaregistry.v1+json",
+            &body,
+        )
+        .await?;
+    let schema_id = serde_json::from_str::<Value>(&response)?["id"]
+        .as_u64()
+        .and_then(|id| u32::try_from(id).ok())
+        .ok_or_else(|| anyhow::anyhow!("No schema ID in {response}"))?;
+    Ok(AvroEncoder {
+        schema: Schema::parse_str(schema_json)?,
+        schema_id,
+    })
+}
+
+/// Converts the JSON of an event to the types of its schema. Fields that aren't
+/// in the schema are left out, and JSON without a fixed schema, like the `pool`
+/// of pool changes, is a string of the JSON.
+fn avro_value(value: &Value, schema: &Schema) -> anyhow::Result<AvroValue> {
+    Ok(match (schema, value) {
+        (Schema::Null, Value::Null) => AvroValue::Null,
+        (Schema::Boolean, Value::Bool(value)) => AvroValue::Boolean(*value),
+        (Schema::Int, Value::Number(number)) => AvroValue::Int(
+            number
+                .as_i64()
+                .and_then(|number| i32::try_from(number).ok())
+                .ok_or_else(|| anyhow::anyhow!("{number} isn't an int"))?,
+        ),
+        (Schema::Long, Value::Number(number)) => AvroValue::Long(
+            number
+                .as_i64()
+                .ok_or_else(|| anyhow::anyhow!("{number} isn't a long"))?,
+        ),
+        (Schema::Double, Value::Number(number)) => {
+            AvroValue::Double(number.as_f64().unwrap_or_default())
+        }
+        (Schema::String, Value::String(value)) => AvroValue::String(value.clone()),
+        (Schema::String, value) if !value.is_null() => AvroValue::String(value.to_string()),
+        (Schema::Array(array), Value::Array(items)) => AvroValue::Array(
+            items
+                .iter()
+                .map(|item| avro_value(item, &array.items))
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        (Schema::Map(map), Value::Object(entries)) => AvroValue::Map(
+            entries
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), avro_value(value, &map.types)?)))
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        (Schema::Union(union), value) => union
+            .variants()
+            .iter()
+            .enumerate()
+            .find_map(|(index, variant)| {
+                let value = avro_value(value, variant).ok()?;
+                Some(AvroValue::Union(index as u32, Box::new(value)))
+            })
+            .ok_or_else(|| anyhow::anyhow!("{value} matches no type of {union:?}"))?,
+        (Schema::Record(record), Value::Object(fields)) => AvroValue::Record(
+            record
+                .fields
+                .iter()
+                .map(|field| {
+                    let value = fields.get(&field.name).unwrap_or(&Value::Null);
+                    let value = avro_value(value, &field.schema)
+                        .map_err(|err| anyhow::anyhow!("{}: {err}", field.name))?;
+                    Ok((field.name.clone(), value))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        (schema, value) => anyhow::bail!("Expected {schema}, got {value}"),
+    })
+}
+
+async fn forward<E: Serialize + FromRedis>(
+    mut events: EventReceiver<E>,
+    encoder: Encoder,
+    sender: mpsc::Sender<Message>,
+) {
+    let avro = match encoder {
+        Encoder::Json => None,
+        Encoder::Avro { registry, subject } => {
+            let mut delay = RETRY_DELAY;
+            loop {
+                match register_schema(&registry, &subject, E::STREAM_KEY).await {
+                    Ok(encoder) => break Some(encoder),
+                    Err(err) => {
+                        tracing::warn!("Failed to register the Avro schema {subject}: {err}");
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(MAX_REGISTER_DELAY);
+                    }
+                }
+            }
+        }
+    };
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => {
+                tracing::warn!(
+                    "Kafka sink fell behind, {count} {} events were skipped",
+                    E::STREAM_KEY
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let value = serde_json::to_value(TaggedEvent {
+            source: &event.source,
+            stream_id: event.id,
+            event: &event.event,
+        })
+        .unwrap();
+        let encoded = match &avro {
+            Some(avro) => match avro.encode(&value) {
+                Ok(encoded) => encoded,
+                Err(err) => {
+                    tracing::error!(
+                        "Failed to encode {} {} as Avro: {err}",
+                        E::STREAM_KEY,
+                        event.id
+                    );
+                    continue;
+                }
+            },
+            None => value.to_string().into_bytes(),
+        };
+        let message = Message {
+            key: value["contract_id"].as_str().map(str::to_string),
+            value: encoded,
+            timestamp_ms: event.id.0 as i64,
+        };
+        if sender.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn produce(mut producer: Producer, mut messages: mpsc::Receiver<Message>) {
+    let mut batch = Vec::new();
+    let mut attempts = 0;
+    loop {
+        if batch.is_empty() {
+            match messages.recv().await {
+                Some(message) => batch.push(message),
+                None => return,
+            }
+            let linger = tokio::time::sleep(LINGER);
+            tokio::pin!(linger);
+            while batch.len() < MAX_BATCH_MESSAGES {
+                tokio::select! {
+                    message = messages.recv() => match message {
+                        Some(message) => batch.push(message),
+                        None => break,
+                    },
+                    _ = &mut linger => break,
+                }
+            }
+        }
+        attempts += 1;
+        match producer.send(&batch).await {
+            Ok(()) => {}
+            Err(err) if attempts >= MAX_ATTEMPTS => {
+                tracing::error!(
+                    "Dropped {} messages to Kafka topic {} after {attempts} attempts: {err}",
+                    batch.len(),
+                    producer.topic,
+                );
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to produce {} messages to Kafka topic {}: {err}",
+                    batch.len(),
+                    producer.topic,
+                );
+                producer.metadata = Metadata::default();
+                producer.connections.clear();
+                tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempts - 1)).await;
+                continue;
+            }
+        }
+        batch.clear();
+        attempts = 0;
+    }
+}
+
+#[derive(Default)]
+struct Metadata {
+    /// Address by node ID.
+    brokers: HashMap<i32, String>,
+    /// Leader node IDs of all partitions by topic.
+    partitions: HashMap<String, Vec<i32>>,
+}
+
+/// Produces to one topic.
+struct Producer {
+    topic: String,
+    bootstrap: Vec<String>,
+    acks: i16,
+    tls: bool,
+    sasl: Option<KafkaSasl>,
+    metadata: Metadata,
+    connections: HashMap<i32, Connection>,
+}
+
+/// Messages of a batch by partition.
+type PartitionMessages<'a> = HashMap<i32, Vec<&'a Message>>;
+
+impl Producer {
+    fn new(config: &KafkaConfig, topic: &str) -> Self {
+        Producer {
+            topic: topic.to_string(),
+            bootstrap: config.brokers.clone(),
+            acks: config.acks,
+            tls: config.tls,
+            sasl: config.sasl.clone(),
+            metadata: Metadata::default(),
+            connections: HashMap::new(),
+        }
+    }
+
+    async fn send(&mut self, batch: &[Message]) -> anyhow::Result<()> {
+        if !self.metadata.partitions.contains_key(&self.topic) {
+            self.refresh_metadata().await?;
+        }
+
+        let leaders = &self.metadata.partitions[&self.topic];
+        let mut by_leader = HashMap::<i32, PartitionMessages>::new();
+        for message in batch {
+            let partition = partition(message.key.as_deref(), leaders.len());
+            by_leader
+                .entry(leaders[partition])
+                .or_default()
+                .entry(partition as i32)
+                .or_default()
+                .push(message);
+        }
+        for (leader, partitions) in by_leader {
+            let request = produce_request(self.acks, &self.topic, &partitions);
+            let connection = self.connection(leader).await?;
+            let response = connection.request(0, 3, &request).await?;
+            if self.acks != 0 {
+                check_produce_response(&response)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn connection(&mut self, node_id: i32) -> anyhow::Result<&mut Connection> {
+        if !self.connections.contains_key(&node_id) {
+            let address = self
+                .metadata
+                .brokers
+                .get(&node_id)
+                .ok_or_else(|| anyhow::anyhow!("Unknown broker {node_id}"))?;
+            let connection = Connection::connect(address, self.tls, self.sasl.as_ref()).await?;
+            self.connections.insert(node_id, connection);
+        }
+        Ok(self.connections.get_mut(&node_id).unwrap())
+    }
+
+    async fn refresh_metadata(&mut self) -> anyhow::Result<()> {
+        let mut request = Vec::new();
+        put_i32(&mut request, 1);
+        put_string(&mut request, &self.topic);
+        let mut last_error = anyhow::anyhow!("No Kafka brokers configured");
+        for address in &self.bootstrap {
+            let response = match Connection::connect(address, self.tls, self.sasl.as_ref()).await {
+                Ok(mut connection) => connection.request(3, 1, &request).await,
+                Err(err) => Err(err),
+            };
+            match response.and_then(|response| parse_metadata(&response)) {
+                Ok(metadata) => {
+                    if !metadata.partitions.contains_key(&self.topic) {
+                        anyhow::bail!("Topic {} doesn't exist", self.topic);
+                    }
+                    self.metadata = metadata;
+                    return Ok(());
+                }
+                Err(err) => last_error = err,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+struct Connection {
+    stream: Box<dyn Io>,
+    correlation_id: i32,
+}
+
+impl Connection {
+    async fn connect(address: &str, tls: bool, sasl: Option<&KafkaSasl>) -> anyhow::Result<Self> {
+        let tcp = tokio::time::timeout(REQUEST_TIMEOUT, TcpStream::connect(address)).await??;
+        let stream: Box<dyn Io> = if tls {
+            let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let server_name = ServerName::try_from(host.to_string())?;
+            let connect = TLS_CONNECTOR.connect(server_name, tcp);
+            Box::new(tokio::time::timeout(REQUEST_TIMEOUT, connect).await??)
+        } else {
+            Box::new(tcp)
+        };
+        let mut connection = Connection {
+            stream,
+            correlation_id: 0,
+        };
+        if let Some(sasl) = sasl {
+            connection.authenticate(sasl).await?;
+        }
+        Ok(connection)
+    }
+
+    /// Authenticates with SASL/PLAIN.
+    async fn authenticate(&mut self, sasl: &KafkaSasl) -> anyhow::Result<()> {
+        let mut request = Vec::new();
+        put_string(&mut request, "PLAIN");
+        let response = self.request(17, 1, &request).await?;
+        let mut reader = Reader(&response);
+        let error_code = reader.i16()?;
+        if error_code != 0 {
+            let mut mechanisms = Vec::new();
+            for _ in 0..reader.i32()? {
+                mechanisms.push(reader.string()?);
+            }
+            anyhow::bail!(
+                "SASL/PLAIN isn't enabled, error code {error_code}, the broker supports {mechanisms:?}"
+            );
+        }
+
+        let mut request = Vec::new();
+        put_bytes(
+            &mut request,
+            format!("\0{}\0{}", sasl.username, sasl.password).as_bytes(),
+        );
+        let response = self.request(36, 0, &request).await?;
+        let mut reader = Reader(&response);
+        let error_code = reader.i16()?;
+        if error_code != 0 {
+            let message = reader.nullable_string()?.unwrap_or_default();
+            anyhow::bail!("SASL authentication failed with error code {error_code}: {message}");
+        }
+        Ok(())
+    }
+
+    /// Sends a request with header v1 and returns the response body.
+    async fn request(
+        &mut self,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.correlation_id += 1;
+        let mut request = Vec::new();
+        put_i16(&mut request, api_key);
+        put_i16(&mut request, api_version);
+        put_i32(&mut request, self.correlation_id);
+        put_string(&mut request, CLIENT_ID);
+        request.extend_from_slice(body);
+
+        tokio::time::timeout(REQUEST_TIMEOUT, async {
+            self.stream
+                .write_all(&(request.len() as i32).to_be_bytes())
+                .await?;
+            self.stream.write_all(&request).await?;
+            // acks = 0 has no response
+            if api_key == 0 && body.get(2..4) == Some(&[0, 0]) {
+                return Ok(Vec::new());
+            }
+            let length = self.stream.read_i32().await?;
+            let length = usize::try_from(length)
+                .ok()
+                .filter(|length| (4..=MAX_RESPONSE_BYTES).contains(length))
+                .ok_or_else(|| anyhow::anyhow!("Invalid response length {length}"))?;
+            let mut response = vec![0; length];
+            self.stream.read_exact(&mut response).await?;
+            let mut reader = Reader(&response);
+            if reader.i32()? != self.correlation_id {
+                anyhow::bail!("Unexpected correlation ID");
+            }
+            Ok(response[4..].to_vec())
+        })
+        .await?
+    }
+}
+
+fn parse_metadata(response: &[u8]) -> anyhow::Result<Metadata> {
+    let mut reader = Reader(response);
+    let mut metadata = Metadata::default();
+    for _ in 0..reader.i32()? {
+        let node_id = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        reader.nullable_string()?; // rack
+        metadata.brokers.insert(node_id, format!("{host}:{port}"));
+    }
+    reader.i32()?; // controller_id
+    for _ in 0..reader.i32()? {
+        let error_code = reader.i16()?;
+        let topic = reader.string()?;
+        reader.i8()?; // is_internal
+        let mut leaders = Vec::new();
+        for _ in 0..reader.i32()? {
+            reader.i16()?; // error_code
+            let partition = reader.i32()?;
+            let leader = reader.i32()?;
+            for _ in 0..2 {
+                // replicas, isr
+                for _ in 0..reader.i32()? {
+                    reader.i32()?;
+                }
+            }
+            leaders.push((partition, leader));
+        }
+        // Partitions are numbered from 0, and a broker that says otherwise
+        // mustn't make the producer allocate for a partition number
+        leaders.sort_unstable();
+        if leaders
+            .iter()
+            .enumerate()
+            .any(|(index, (partition, _))| usize::try_from(*partition) != Ok(index))
+        {
+            anyhow::bail!("Invalid partitions of {topic} in the metadata");
+        }
+        let leaders = leaders
+            .into_iter()
+            .map(|(_, leader)| leader)
+            .collect::<Vec<_>>();
+        if error_code == 0 && !leaders.is_empty() && !leaders.contains(&-1) {
+            metadata.partitions.insert(topic, leaders);
+        }
+    }
+    Ok(metadata)
+}
+
+fn produce_request(acks: i16, topic: &str, partitions: &PartitionMessages) -> Vec<u8> {
+    let mut request = Vec::new();
+    put_i16(&mut request, -1); // transactional_id
+    put_i16(&mut request, acks);
+    put_i32(&mut request, REQUEST_TIMEOUT.as_millis() as i32);
+    put_i32(&mut request, 1);
+    put_string(&mut request, topic);
+    put_i32(&mut request, partitions.len() as i32);
+    for (partition, messages) in partitions {
+        put_i32(&mut request, *partition);
+        let records = record_batch(messages);
+        put_i32(&mut request, records.len() as i32);
+        request.extend_from_slice(&records);
+    }
+    request
+}
+
+fn check_produce_response(response: &[u8]) -> anyhow::Result<()> {
+    let mut reader = Reader(response);
+    for _ in 0..reader.i32()? {
+        let topic = reader.string()?;
+        for _ in 0..reader.i32()? {
+            let partition = reader.i32()?;
+            let error_code = reader.i16()?;
+            reader.i64()?; // base_offset
+            reader.i64()?; // log_append_time
+            if error_code != 0 {
+                anyhow::bail!("Error {error_code} producing to {topic}/{partition}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encodes a record batch (magic 2) without compression.
+fn record_batch(messages: &[&Message]) -> Vec<u8> {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    let first_timestamp = messages
+        .iter()
+        .map(|m| m.timestamp_ms)
+        .min()
+        .unwrap_or(now_ms);
+    let max_timestamp = messages
+        .iter()
+        .map(|m| m.timestamp_ms)
+        .max()
+        .unwrap_or(now_ms);
+
+    // Everything after the CRC
+    let mut body = Vec::new();
+    put_i16(&mut body, 0); // attributes
+    put_i32(&mut body, messages.len() as i32 - 1); // last_offset_delta
+    put_i64(&mut body, first_timestamp);
+    put_i64(&mut body, max_timestamp);
+    put_i64(&mut body, -1); // producer_id
+    put_i16(&mut body, -1); // producer_epoch
+    put_i32(&mut body, -1); // base_sequence
+    put_i32(&mut body, messages.len() as i32);
+    for (offset_delta, message) in messages.iter().enumerate() {
+        let mut record = Vec::new();
+        record.push(0); // attributes
+        put_varint(&mut record, message.timestamp_ms - first_timestamp);
+        put_varint(&mut record, offset_delta as i64);
+        match &message.key {
+            Some(key) => {
+                put_varint(&mut record, key.len() as i64);
+                record.extend_from_slice(key.as_bytes());
+            }
+            None => put_varint(&mut record, -1),
+        }
+        put_varint(&mut record, message.value.len() as i64);
+        record.extend_from_slice(&message.value);
+        put_varint(&mut record, 0); // headers
+        put_varint(&mut body, record.len() as i64);
+        body.extend_from_slice(&record);
+    }
+
+    let mut batch = Vec::new();
+    put_i64(&mut batch, 0); // base_offset
+    put_i32(&mut batch, (4 + 1 + 4 + body.len()) as i32); // batch_length
+    put_i32(&mut batch, -1); // partition_leader_epoch
+    batch.push(2); // magic
+    batch.extend_from_slice(&crc32c(&body).to_be_bytes());
+    batch.extend_from_slice(&body);
+    batch
+}
+
+static ROUND_ROBIN: AtomicU32 = AtomicU32::new(0);
+
+/// Same as the default partitioner of the Java client: murmur2 of the key, or
+/// round-robin for messages without a key.
+fn partition(key: Option<&str>, partitions: usize) -> usize {
+    let hash = match key {
+        Some(key) => murmur2(key.as_bytes()),
+        None => ROUND_ROBIN.fetch_add(1, Ordering::Relaxed),
+    };
+    (hash & 0x7fffffff) as usize % partitions
+}
+
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1e995;
+    let mut h = 0x9747b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let rest = chunks.remainder();
+    if rest.len() >= 3 {
+        h ^= (rest[2] as u32) << 16;
+    }
+    if rest.len() >= 2 {
+        h ^= (rest[1] as u32) << 8;
+    }
+    if !rest.is_empty() {
+        h ^= rest[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn put_i16(buffer: &mut Vec<u8>, value: i16) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(buffer: &mut Vec<u8>, value: i32) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i64(buffer: &mut Vec<u8>, value: i64) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    put_i16(buffer, value.len() as i16);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, value: &[u8]) {
+    put_i32(buffer, value.len() as i32);
+    buffer.extend_from_slice(value);
+}
+
+/// Zigzag varint, used in records.
+fn put_varint(buffer: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, length: usize) -> anyhow::Result<&[u8]> {
+        if self.0.len() < length {
+            anyhow::bail!("Truncated Kafka response");
+        }
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn i8(&mut self) -> anyhow::Result<i8> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> anyhow::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> anyhow::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn nullable_string(&mut self) -> anyhow::Result<Option<String>> {
+        let length = self.i16()?;
+        if length < 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            String::from_utf8_lossy(self.take(length as usize)?).into_owned(),
+        ))
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        Ok(self.nullable_string()?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use crate::{config::Encoding, nft_events::FullNftMintEvent};
+
+    use super::*;
+
+    /// Reads a request of the client, and returns its API key, correlation ID
+    /// and body.
+    async fn read_request(broker: &mut DuplexStream) -> (i16, i32, Vec<u8>) {
+        let length = broker.read_i32().await.unwrap();
+        let mut request = vec![0; length as usize];
+        broker.read_exact(&mut request).await.unwrap();
+        let mut reader = Reader(&request);
+        let api_key = reader.i16().unwrap();
+        reader.i16().unwrap();
+        let correlation_id = reader.i32().unwrap();
+        assert_eq!(reader.string().unwrap(), CLIENT_ID);
+        (api_key, correlation_id, reader.0.to_vec())
+    }
+
+    async fn respond(broker: &mut DuplexStream, correlation_id: i32, body: &[u8]) {
+        let mut response = Vec::new();
+        put_i32(&mut response, body.len() as i32 + 4);
+        put_i32(&mut response, correlation_id);
+        response.extend_from_slice(body);
+        broker.write_all(&response).await.unwrap();
+    }
+
+    fn connection(client: DuplexStream) -> Connection {
+        Connection {
+            stream: Box::new(client),
+            correlation_id: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_sasl_plain() {
+        let (client, mut broker) = duplex(64 * 1024);
+        let sasl = KafkaSasl {
+            username: "events".to_string(),
+            password: "secret".to_string(),
+        };
+        let client = tokio::spawn(async move { connection(client).authenticate(&sasl).await });
+
+        let (api_key, correlation_id, body) = read_request(&mut broker).await;
+        assert_eq!(api_key, 17);
+        assert_eq!(body, b"\x00\x05PLAIN");
+        let mut response = Vec::new();
+        put_i16(&mut response, 0);
+        put_i32(&mut response, 1);
+        put_string(&mut response, "PLAIN");
+        respond(&mut broker, correlation_id, &response).await;
+
+        let (api_key, correlation_id, body) = read_request(&mut broker).await;
+        assert_eq!(api_key, 36);
+        assert_eq!(body, b"\x00\x00\x00\x0e\x00events\x00secret");
+        let mut response = Vec::new();
+        put_i16(&mut response, 58);
+        put_string(&mut response, "Invalid username or password");
+        put_bytes(&mut response, b"");
+        respond(&mut broker, correlation_id, &response).await;
+
+        let err = client.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("Invalid username or password"));
+    }
+
+    #[tokio::test]
+    async fn rejects_responses_that_are_too_large() {
+        let (client, mut broker) = duplex(64 * 1024);
+        let client = tokio::spawn(async move { connection(client).request(3, 1, b"").await });
+        read_request(&mut broker).await;
+        broker.write_all(&i32::MAX.to_be_bytes()).await.unwrap();
+        let err = client.await.unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Invalid response length {}", i32::MAX)
+        );
+    }
+
+    #[test]
+    fn hashes_match_known_vectors() {
+        // From `UtilsTest.testMurmur2` of the Java client
+        for (key, hash) in [
+            ("21", -973932308),
+            ("foobar", -790332482),
+            ("a-little-bit-long-string", -985981536),
+            ("a-little-bit-longer-string", -1486304829),
+            (
+                "lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            ("abc", 479470107),
+        ] {
+            assert_eq!(murmur2(key.as_bytes()) as i32, hash, "{key}");
+        }
+        assert_eq!(
+            partition(Some("foobar"), 7),
+            (-790332482i32 & 0x7fffffff) as usize % 7
+        );
+
+        // Check values of RFC 3720
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a9136aa);
+        assert_eq!(crc32c(&[0xff; 32]), 0x62a8ab43);
+    }
+
+    #[test]
+    fn encodes_produce_requests() {
+        let message = Message {
+            key: Some("nft.near".to_string()),
+            value: b"{}".to_vec(),
+            timestamp_ms: 1000,
+        };
+        let request = produce_request(-1, "events", &HashMap::from([(0, vec![&message])]));
+        let expected = [
+            &b"\xff\xff"[..],                    // transactional_id
+            b"\xff\xff",                         // acks
+            b"\x00\x00\x75\x30",                 // timeout_ms
+            b"\x00\x00\x00\x01\x00\x06events",   // topics
+            b"\x00\x00\x00\x01\x00\x00\x00\x00", // partitions, partition
+            b"\x00\x00\x00\x4e",                 // record batch size
+            b"\x00\x00\x00\x00\x00\x00\x00\x00", // base_offset
+            b"\x00\x00\x00\x42",                 // batch_length
+            b"\xff\xff\xff\xff",                 // partition_leader_epoch
+            b"\x02",                             // magic
+            b"\xa7\x91\xaf\x51",                 // crc
+            b"\x00\x00",                         // attributes
+            b"\x00\x00\x00\x00",                 // last_offset_delta
+            b"\x00\x00\x00\x00\x00\x00\x03\xe8", // first_timestamp
+            b"\x00\x00\x00\x00\x00\x00\x03\xe8", // max_timestamp
+            b"\xff\xff\xff\xff\xff\xff\xff\xff", // producer_id
+            b"\xff\xff",                         // producer_epoch
+            b"\xff\xff\xff\xff",                 // base_sequence
+            b"\x00\x00\x00\x01",                 // records
+            b"\x20\x00\x00\x00",                 // length, attributes, timestamp and offset deltas
+            b"\x10nft.near\x04{}\x00",           // key, value, headers
+        ]
+        .concat();
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn checks_partitions_of_metadata() {
+        let metadata = |partitions: &[i32]| {
+            let mut response = Vec::new();
+            put_i32(&mut response, 1);
+            put_i32(&mut response, 1); // node_id
+            put_string(&mut response, "kafka");
+            put_i32(&mut response, 9092);
+            put_i16(&mut response, -1); // rack
+            put_i32(&mut response, 1); // controller_id
+            put_i32(&mut response, 1);
+            put_i16(&mut response, 0);
+            put_string(&mut response, "events");
+            response.push(0);
+            put_i32(&mut response, partitions.len() as i32);
+            for partition in partitions {
+                put_i16(&mut response, 0);
+                put_i32(&mut response, *partition);
+                put_i32(&mut response, 1); // leader
+                put_i32(&mut response, 0); // replicas
+                put_i32(&mut response, 0); // isr
+            }
+            parse_metadata(&response)
+        };
+        let parsed = metadata(&[1, 0]).unwrap();
+        assert_eq!(parsed.brokers[&1], "kafka:9092");
+        assert_eq!(parsed.partitions["events"], [1, 1]);
+        assert!(metadata(&[0, i32::MAX]).is_err());
+        assert!(metadata(&[0, -1]).is_err());
+        assert!(metadata(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn encodes_avro() {
+        let encoder = AvroEncoder {
+            schema: Schema::parse_str(
+                r#"{"type": "record", "name": "Event", "fields": [
+                    {"name": "a", "type": "string"},
+                    {"name": "b", "type": ["null", "long"]},
+                    {"name": "pool", "type": "string"}
+                ]}"#,
+            )
+            .unwrap(),
+            schema_id: 42,
+        };
+        let event = serde_json::json!({ "a": "x", "b": 3, "pool": { "k": 1 }, "unknown": true });
+        assert_eq!(
+            encoder.encode(&event).unwrap(),
+            b"\x00\x00\x00\x00\x2a\x02x\x02\x06\x0e{\"k\":1}"
+        );
+        assert!(encoder.encode(&serde_json::json!({ "b": 3 })).is_err());
+    }
+
+    #[test]
+    fn avro_schemas_fit_events() {
+        struct Check;
+
+        impl EventTypeVisitor for Check {
+            fn visit<
+                E: Serialize + FromRedis + Send + Sync + 'static,
+                F: EventFilter<E> + DeserializeOwned + Send + 'static,
+            >(
+                &mut self,
+            ) {
+                let event =
+                    E::decode(&crate::synthetic::sample(E::STREAM_KEY), Encoding::Json).unwrap();
+                let value = serde_json::to_value(TaggedEvent {
+                    source: "mainnet",
+                    stream_id: "1-0".parse().unwrap(),
+                    event: &event,
+                })
+                .unwrap();
+                let encoder = AvroEncoder {
+                    schema: Schema::parse_str(avro_schema(E::STREAM_KEY)).unwrap(),
+                    schema_id: 1,
+                };
+                if let Err(err) = encoder.encode(&value) {
+                    panic!("{}: {err}", E::STREAM_KEY);
+                }
+            }
+        }
+
+        for_each_event_type(&mut Check);
+        assert_eq!(
+            avro_record_name(FullNftMintEvent::STREAM_KEY),
+            "tech.intear.events.NftMintEvent"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! The `key_style` option, for clients that want the fields of events in another
+//! style than snake_case, like camelCase for JavaScript. Only the field names of
+//! event frames are renamed, not control frames, and not the keys of maps like
+//! `balance_changes`, which are account IDs.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Fields whose values are maps with data as keys.
+const MAP_FIELDS: &[&str] = &[
+    "balance_changes",
+    "balance_changes_decimal",
+    "min_amounts",
+    "min_balance_changes",
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum KeyStyle {
+    #[default]
+    #[serde(rename = "snake_case")]
+    Snake,
+    #[serde(rename = "camelCase")]
+    Camel,
+    #[serde(rename = "kebab-case")]
+    Kebab,
+}
+
+impl KeyStyle {
+    /// A snake_case field name in this style. Other keys stay as they are.
+    fn rename(self, key: &str) -> Option<String> {
+        let is_field = key.starts_with(|c: char| c.is_ascii_lowercase())
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !is_field || !key.contains('_') {
+            return None;
+        }
+        Some(match self {
+            KeyStyle::Snake => return None,
+            KeyStyle::Camel => {
+                let mut renamed = String::with_capacity(key.len());
+                let mut upper = false;
+                for c in key.chars() {
+                    if c == '_' {
+                        upper = true;
+                    } else if upper {
+                        renamed.push(c.to_ascii_uppercase());
+                        upper = false;
+                    } else {
+                        renamed.push(c);
+                    }
+                }
+                renamed
+            }
+            KeyStyle::Kebab => key.replace('_', "-"),
+        })
+    }
+
+    /// Renames the fields of a frame, recursively.
+    pub fn apply(self, value: &mut Value) {
+        if self == KeyStyle::Snake {
+            return;
+        }
+        match value {
+            Value::Object(object) => {
+                let renamed = std::mem::take(object)
+                    .into_iter()
+                    .map(|(key, mut value)| {
+                        if !MAP_FIELDS.contains(&key.as_str()) {
+                            self.apply(&mut value);
+                        }
+                        (self.rename(&key).unwrap_or(key), value)
+                    })
+                    .collect();
+                *object = renamed;
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.apply(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn renames_fields_but_not_map_keys() {
+        let mut frame = json!({
+            "stream_id": "1-0",
+            "event": {
+                "trader": "alice.near",
+                "block_timestamp_nanosec": "1",
+                "balance_changes": { "wrap_1.near": "-1", "token_a": "1" },
+                "pools": [{ "pool_id": "REF-1" }],
+            },
+        });
+        KeyStyle::Camel.apply(&mut frame);
+        assert_eq!(
+            frame,
+            json!({
+                "streamId": "1-0",
+                "event": {
+                    "trader": "alice.near",
+                    "blockTimestampNanosec": "1",
+                    "balanceChanges": { "wrap_1.near": "-1", "token_a": "1" },
+                    "pools": [{ "poolId": "REF-1" }],
+                },
+            })
+        );
+        let mut frame = json!({ "stream_id": "1-0", "Ref": { "SimplePool": {} } });
+        KeyStyle::Kebab.apply(&mut frame);
+        assert_eq!(
+            frame,
+            json!({ "stream-id": "1-0", "Ref": { "SimplePool": {} } })
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Leaderboards at `/v0/leaderboard`, like the top traders of the last hour or the
+//! top donors of the day. They're computed from the live events, once for all
+//! clients of the same leaderboard, and pushed to the clients when they change.
+//!
+//! Leaderboards are computed while they have clients, so they start empty and only
+//! count the events since the first client connected.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    broadcast::Broadcasts,
+    drain,
+    potlock_events::{
+        FullPotlockDonationEvent, FullPotlockPotDonationEvent, FullPotlockPotProjectDonationEvent,
+    },
+    protocol::ControlFrame,
+    trade_events::FullTradeSwapEvent,
+    types::AccountId,
+    FromRedis, Networks, CLIENT_TIMEOUT, DEFAULT_NETWORK, HEARTBEAT_INTERVAL,
+};
+
+/// Entries computed for every leaderboard, clients get the first `limit` of them.
+const MAX_ENTRIES: usize = 100;
+const DEFAULT_LIMIT: usize = 10;
+const DEFAULT_WINDOW_SEC: u64 = 3600;
+const MAX_WINDOW_SEC: u64 = 7 * 24 * 3600;
+/// Leaderboards are sent at most this often.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Number of swaps by trader, from `trade_swap`.
+    TradersBySwaps,
+    /// Amount of `token` bought and sold by trader, from `trade_swap`.
+    TradersByVolume,
+    /// Amount donated by donor, from all Potlock donation streams.
+    Donors,
+    /// Amount received by project, from `potlock_donation` and `potlock_pot_project_donation`.
+    Projects,
+}
+
+/// The client message, choosing the leaderboard.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LeaderboardQuery {
+    metric: Metric,
+    #[serde(default = "default_window_sec")]
+    window_sec: u64,
+    /// Token contract, for `traders_by_volume`.
+    token: Option<AccountId>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_window_sec() -> u64 {
+    DEFAULT_WINDOW_SEC
+}
+
+fn default_limit() -> usize {
+    DEFAULT_LIMIT
+}
+
+impl LeaderboardQuery {
+    fn validate(&self) -> Result<(), String> {
+        if self.window_sec == 0 || self.window_sec > MAX_WINDOW_SEC {
+            return Err(format!("window_sec must be from 1 to {MAX_WINDOW_SEC}"));
+        }
+        if self.limit > MAX_ENTRIES {
+            return Err(format!("limit must be at most {MAX_ENTRIES}"));
+        }
+        if self.metric == Metric::TradersByVolume && self.token.is_none() {
+            return Err("traders_by_volume needs a token".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct BoardKey {
+    network: String,
+    metric: Metric,
+    window_sec: u64,
+    token: Option<AccountId>,
+}
+
+/// Accounts and their totals, highest first.
+type Board = Arc<Vec<(AccountId, i128)>>;
+
+type Boards = Arc<Mutex<HashMap<BoardKey, watch::Receiver<Board>>>>;
+
+/// Leaderboards that have clients, shared by all of them.
+pub struct Leaderboards {
+    broadcasts: Arc<Broadcasts>,
+    boards: Boards,
+}
+
+impl Leaderboards {
+    pub fn new(broadcasts: Arc<Broadcasts>) -> Self {
+        Self {
+            broadcasts,
+            boards: Arc::default(),
+        }
+    }
+
+    fn subscribe(&self, network: &str, query: &LeaderboardQuery) -> watch::Receiver<Board> {
+        let key = BoardKey {
+            network: network.to_string(),
+            metric: query.metric,
+            window_sec: query.window_sec,
+            token: query.token.clone(),
+        };
+        let mut boards = self.boards.lock().unwrap();
+        if let Some(board) = boards.get(&key) {
+            return board.clone();
+        }
+        let (sender, receiver) = watch::channel(Board::default());
+        let (contributions, contributions_receiver) = mpsc::channel(1024);
+        match &key.metric {
+            Metric::TradersBySwaps => {
+                self.forward(network, contributions, |e: &FullTradeSwapEvent| {
+                    Some((e.context.trader.clone(), 1))
+                })
+            }
+            Metric::TradersByVolume => {
+                let token = key.token.clone();
+                self.forward(network, contributions, move |e: &FullTradeSwapEvent| {
+                    let amount = e.event.balance_changes.get(token.as_ref()?)?;
+                    Some((e.context.trader.clone(), amount.0.saturating_abs()))
+                })
+            }
+            Metric::Donors => {
+                self.forward(
+                    network,
+                    contributions.clone(),
+                    |e: &FullPotlockDonationEvent| {
+                        Some((
+                            e.event.donor_id.clone(),
+                            e.event.total_amount.0.try_into().ok()?,
+                        ))
+                    },
+                );
+                self.forward(
+                    network,
+                    contributions.clone(),
+                    |e: &FullPotlockPotProjectDonationEvent| {
+                        Some((
+                            e.event.donor_id.clone(),
+                            e.event.total_amount.0.try_into().ok()?,
+                        ))
+                    },
+                );
+                self.forward(network, contributions, |e: &FullPotlockPotDonationEvent| {
+                    Some((
+                        e.event.donor_id.clone(),
+                        e.event.total_amount.0.try_into().ok()?,
+                    ))
+                });
+            }
+            Metric::Projects => {
+                self.forward(
+                    network,
+                    contributions.clone(),
+                    |e: &FullPotlockDonationEvent| {
+                        Some((
+                            e.event.project_id.clone(),
+                            e.event.total_amount.0.try_into().ok()?,
+                        ))
+                    },
+                );
+                self.forward(
+                    network,
+                    contributions,
+                    |e: &FullPotlockPotProjectDonationEvent| {
+                        Some((
+                            e.event.project_id.clone(),
+                            e.event.total_amount.0.try_into().ok()?,
+                        ))
+                    },
+                );
+            }
+        }
+        tokio::spawn(compute(
+            key.clone(),
+            contributions_receiver,
+            sender,
+            Arc::clone(&self.boards),
+        ));
+        boards.insert(key, receiver.clone());
+        receiver
+    }
+
+    /// Sends what every event of type `E` adds to the leaderboard, until it's stopped.
+    fn forward<E: FromRedis + Send + Sync + 'static>(
+        &self,
+        network: &str,
+        contributions: mpsc::Sender<(AccountId, i128)>,
+        contribution: impl Fn(&E) -> Option<(AccountId, i128)> + Send + 'static,
+    ) {
+        let mut events = self.broadcasts.subscribe::<E>(network);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = contributions.closed() => return,
+                };
+                match event {
+                    Ok(event) => {
+                        if let Some(contribution) = contribution(&event.event) {
+                            if contributions.send(contribution).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        tracing::warn!("Leaderboard fell behind, {count} events were skipped");
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+}
+
+/// Keeps the totals of the window, and publishes the top entries when they change.
+/// Stops when the leaderboard has no clients.
+async fn compute(
+    key: BoardKey,
+    mut contributions: mpsc::Receiver<(AccountId, i128)>,
+    sender: watch::Sender<Board>,
+    boards: Boards,
+) {
+    let window = Duration::from_secs(key.window_sec);
+    let mut recent = VecDeque::<(Instant, AccountId, i128)>::new();
+    let mut totals = HashMap::<AccountId, i128>::new();
+    let mut ticks = tokio::time::interval(UPDATE_INTERVAL);
+    loop {
+        tokio::select! {
+            contribution = contributions.recv() => {
+                let Some((account_id, amount)) = contribution else {
+                    return;
+                };
+                let total = totals.entry(account_id.clone()).or_default();
+                *total = total.saturating_add(amount);
+                recent.push_back((Instant::now(), account_id, amount));
+            }
+            _ = ticks.tick() => {
+                {
+                    let mut boards = boards.lock().unwrap();
+                    // The only receiver left is the one in `boards`
+                    if sender.receiver_count() <= 1 {
+                        boards.remove(&key);
+                        return;
+                    }
+                }
+                while let Some((_, account_id, amount)) =
+                    recent.front().filter(|(at, _, _)| at.elapsed() > window).cloned()
+                {
+                    recent.pop_front();
+                    if let Some(total) = totals.get_mut(&account_id) {
+                        *total = total.saturating_sub(amount);
+                        if *total == 0 {
+                            totals.remove(&account_id);
+                        }
+                    }
+                }
+                let top = top_entries(&totals);
+                sender.send_if_modified(|board| {
+                    if **board == top {
+                        return false;
+                    }
+                    *board = Arc::new(top);
+                    true
+                });
+            }
+        }
+    }
+}
+
+fn top_entries(totals: &HashMap<AccountId, i128>) -> Vec<(AccountId, i128)> {
+    let mut entries = totals
+        .iter()
+        .map(|(account_id, total)| (account_id.clone(), *total))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    entries.truncate(MAX_ENTRIES);
+    entries
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "leaderboard")]
+struct LeaderboardFrame<'a> {
+    metric: Metric,
+    window_sec: u64,
+    token: Option<&'a str>,
+    entries: Vec<LeaderboardEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct LeaderboardEntry<'a> {
+    account_id: &'a str,
+    /// Stringified, since amounts don't fit into JSON numbers.
+    value: String,
+}
+
+pub async fn leaderboard(
+    req: HttpRequest,
+    stream: web::Payload,
+    networks: web::Data<Networks>,
+    leaderboards: web::Data<Leaderboards>,
+) -> Result<HttpResponse, Error> {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return Ok(HttpResponse::NotFound().body(format!("Unknown network: {network}")));
+    }
+    if let Some(response) = drain::draining_response(&req) {
+        return Ok(response);
+    }
+    ws::start(
+        LeaderboardWebSocket {
+            last_heartbeat: Instant::now(),
+            network,
+            leaderboards,
+            query: None,
+            updates: None,
+            generation: 0,
+        },
+        &req,
+        stream,
+    )
+}
+
+struct LeaderboardWebSocket {
+    last_heartbeat: Instant,
+    network: String,
+    leaderboards: web::Data<Leaderboards>,
+    query: Option<LeaderboardQuery>,
+    /// Forwards the changes of the chosen leaderboard to this actor.
+    updates: Option<JoinHandle<()>>,
+    /// Incremented when the leaderboard changes, to skip updates of the previous one.
+    generation: u64,
+}
+
+impl Actor for LeaderboardWebSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(updates) = self.updates.take() {
+            updates.abort();
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LeaderboardWebSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+            }
+            Ok(ws::Message::Text(text)) => {
+                let query = serde_json::from_str::<LeaderboardQuery>(&text)
+                    .map_err(|err| err.to_string())
+                    .and_then(|query| query.validate().map(|()| query));
+                match query {
+                    Ok(query) => self.choose(query, ctx),
+                    Err(message) => ctx.text(
+                        serde_json::to_string(&ControlFrame::Error { message: &message }).unwrap(),
+                    ),
+                }
+            }
+            _ => ctx.stop(),
+        }
+    }
+}
+
+impl LeaderboardWebSocket {
+    /// Switches to the leaderboard, and sends it right away.
+    fn choose(&mut self, query: LeaderboardQuery, ctx: &mut <Self as Actor>::Context) {
+        if let Some(updates) = self.updates.take() {
+            updates.abort();
+        }
+        let mut board = self.leaderboards.subscribe(&self.network, &query);
+        let addr = ctx.address();
+        self.generation += 1;
+        let generation = self.generation;
+        self.updates = Some(tokio::spawn(async move {
+            loop {
+                addr.do_send(Update(generation, board.borrow_and_update().clone()));
+                if board.changed().await.is_err() {
+                    return;
+                }
+            }
+        }));
+        self.query = Some(query);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Update(u64, Board);
+
+impl Handler<Update> for LeaderboardWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Update, ctx: &mut Self::Context) -> Self::Result {
+        let Some(query) = self.query.as_ref().filter(|_| msg.0 == self.generation) else {
+            return;
+        };
+        let frame = LeaderboardFrame {
+            metric: query.metric,
+            window_sec: query.window_sec,
+            token: query.token.as_deref(),
+            entries: msg
+                .1
+                .iter()
+                .take(query.limit)
+                .map(|(account_id, value)| LeaderboardEntry {
+                    account_id,
+                    value: value.to_string(),
+                })
+                .collect(),
+        };
+        ctx.text(serde_json::to_string(&frame).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(id: &str) -> AccountId {
+        id.try_into().unwrap()
+    }
+
+    #[test]
+    fn sorts_top_entries() {
+        let totals = HashMap::from([
+            (account("b.near"), 5),
+            (account("a.near"), 5),
+            (account("c.near"), 10),
+        ]);
+        assert_eq!(
+            top_entries(&totals),
+            vec![
+                (account("c.near"), 10),
+                (account("a.near"), 5),
+                (account("b.near"), 5),
+            ]
+        );
+    }
+}
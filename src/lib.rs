@@ -0,0 +1,2720 @@
+mod abuse;
+mod account_id;
+mod acks;
+mod activity;
+mod admin;
+mod api_keys;
+mod archive;
+mod audit;
+mod balance;
+#[cfg(feature = "bench")]
+pub mod benchmarks;
+mod broadcast;
+mod collection_stats;
+mod common_filter;
+mod config;
+mod conflation;
+mod connections;
+mod dead_letters;
+mod dedup;
+mod digests;
+mod drain;
+mod encodings;
+#[cfg(feature = "explorer")]
+mod explorer;
+mod export;
+mod fields;
+#[cfg(test)]
+mod filter_properties;
+mod filters;
+mod firehose;
+mod fixtures;
+mod frame_cache;
+pub mod fuzzing;
+mod groups;
+mod grpc;
+mod h2_websocket;
+pub mod harness;
+mod heads;
+mod history;
+mod http_client;
+mod kafka;
+mod key_style;
+mod leaderboard;
+mod listeners;
+mod logging;
+mod metrics;
+mod mqtt;
+mod nats;
+mod near_auth;
+mod nft_events;
+mod otlp;
+mod passthrough;
+mod plans;
+mod poll;
+mod pool_metadata;
+mod potlock_events;
+mod presets;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod proof_of_work;
+mod protocol;
+mod readers;
+mod redis_reader;
+mod replay;
+mod reporting;
+mod samples;
+mod sampling;
+mod schedule;
+mod sessions;
+mod signing;
+mod smtp;
+mod stats;
+mod status;
+mod stream_checks;
+mod streams;
+mod subscriptions;
+mod synthetic;
+mod trade_events;
+mod tx_wait;
+mod types;
+mod usage;
+mod version;
+mod webhooks;
+pub mod ws_client;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::BufReader,
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use abuse::AbuseControl;
+use acks::Acks;
+use actix::prelude::*;
+use actix_cors::Cors;
+use actix_web::{
+    http::{header, KeepAlive},
+    web, App, Error, HttpRequest, HttpResponse, HttpServer,
+};
+use actix_web_actors::ws::{self, WsResponseBuilder};
+use audit::AuditLog;
+use base64::prelude::*;
+use borsh::BorshDeserialize;
+use broadcast::{Broadcasts, EventSender};
+use bytestring::ByteString;
+use config::{Config, Encoding, HttpConfig, StreamConfig};
+use conflation::Conflation;
+use dashmap::DashSet;
+use dead_letters::{DeadLetters, FromRedisError};
+use dedup::RecentIds;
+use fields::FilterFields;
+use filters::{Cursor, LastFilter, SavedFilters};
+use frame_cache::FrameCache;
+use futures_util::FutureExt;
+use logging::LogFormat;
+use near_auth::NearAuth;
+use nft_events::{
+    FullNftBurnEvent, FullNftMintEvent, FullNftTransferEvent, NftBurnFilter, NftMintFilter,
+    NftTransferFilter,
+};
+use plans::Plans;
+use potlock_events::{
+    FullPotlockDonationEvent, FullPotlockPotDonationEvent, FullPotlockPotProjectDonationEvent,
+    PotlockDonationEventFilter, PotlockPotDonationEventFilter,
+    PotlockPotProjectDonationEventFilter,
+};
+use proof_of_work::Challenge;
+use protocol::{
+    batch_frame, ApiVersion, ControlFrame, Envelope, Negotiation, Protocol, MAX_BATCH_EVENTS,
+};
+use redis::aio::ConnectionManager;
+use redis_reader::{
+    create_connection, read_range, stream_events, Checkpoints, EventHandler, ReaderConnections,
+};
+use replay::{
+    handover, skip_replayed, ReplayPage, ReplayQuery, ReplayStart, StreamId, MAX_REPLAY_EVENTS,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sessions::{Session, Sessions};
+use stats::{Stats, StatsOptions};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+use tracing::{level_filters::LevelFilter, Instrument};
+use tracing_actix_web::TracingLogger;
+use trade_events::{
+    FullTradePoolChangeEvent, FullTradePoolEvent, FullTradeSwapEvent, TradePoolChangeEventFilter,
+    TradePoolEventFilter, TradeSwapEventFilter,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long to wait for stream readers to save their checkpoints on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of recent stream entry IDs remembered per reader to detect duplicates.
+const DEDUPLICATION_WINDOW: usize = 1024;
+
+/// Names of networks that have at least one Redis source.
+pub type Networks = HashSet<String>;
+
+/// Deprecation messages by stream key, sent to new clients of these endpoints.
+pub type Deprecations = HashMap<String, String>;
+
+/// Filters by stream key and preset name.
+pub type Presets = HashMap<String, HashMap<String, serde_json::Value>>;
+
+/// Stream keys that are disabled in the config.
+pub type DisabledStreams = HashSet<String>;
+
+/// Stream keys with `require_filter` in the config.
+pub type FilterRequired = HashSet<String>;
+
+/// `proof_of_work_bits` of the streams that have it in the config.
+pub type ProofOfWork = HashMap<String, u8>;
+
+/// The answer to requests for endpoints of a disabled stream, or `None` if the
+/// stream of `E` isn't disabled.
+fn disabled_response<E: FromRedis>(req: &HttpRequest) -> Option<HttpResponse> {
+    req.app_data::<web::Data<DisabledStreams>>()
+        .is_some_and(|disabled| disabled.contains(E::STREAM_KEY))
+        .then(|| HttpResponse::Gone().body(format!("{} is disabled", E::STREAM_KEY)))
+}
+
+const DEFAULT_NETWORK: &str = "mainnet";
+
+// EventWebSocket is the client, Server is the server.
+// Typical flow:
+// 1. EventWebSocket -> Server: SubscribeToEvents
+// 2. Server: Adds the client to the list of subscribers
+// 3. Server: Deserializes the Redis event using FromRedis trait
+// 4. Server -> EventWebSocket: Event, Event, Event, ...
+// 5. EventWebSocket: Checks if the event matches the filter using EventFilter trait and sends JSON-serialized event to the client
+// 6. EventWebSocket -> Server: UnsubscribeFromEvents
+// 7. Server: Removes the client from the list of subscribers
+//
+// If the client asked for a replay (`from_stream_id` or `replay_last`), the EventWebSocket
+// buffers live events from step 4 until the Server returns the replayed events (ReadReplay),
+// page by page for long replays, then sends the replayed events followed by the buffered
+// ones that weren't replayed.
+// Events of one stream are read and sent by a single reader task one at a time, and actor
+// mailboxes are FIFO, so a client always receives the events of one stream in Redis stream
+// order, including across the replay -> live handover.
+
+struct Server {
+    redis_sources: Vec<RedisSource>,
+    stream_configs: HashMap<String, StreamConfig>,
+    checkpoints: Checkpoints,
+    networks: HashMap<String, NetworkSockets>,
+    shutdown: watch::Sender<bool>,
+    readers: Vec<JoinHandle<()>>,
+    broadcasts: Arc<Broadcasts>,
+    origin: EventOrigin,
+}
+
+impl Server {
+    fn network(&self, network: &str) -> &NetworkSockets {
+        &self.networks[network]
+    }
+}
+
+#[derive(Default, Clone)]
+struct NetworkSockets {
+    nft_mint_sockets: Arc<DashSet<Addr<EventWebSocket<FullNftMintEvent, NftMintFilter>>>>,
+    nft_transfer_sockets:
+        Arc<DashSet<Addr<EventWebSocket<FullNftTransferEvent, NftTransferFilter>>>>,
+    nft_burn_sockets: Arc<DashSet<Addr<EventWebSocket<FullNftBurnEvent, NftBurnFilter>>>>,
+
+    potlock_donation_sockets:
+        Arc<DashSet<Addr<EventWebSocket<FullPotlockDonationEvent, PotlockDonationEventFilter>>>>,
+    potlock_pot_project_donation_sockets: Arc<
+        DashSet<
+            Addr<
+                EventWebSocket<
+                    FullPotlockPotProjectDonationEvent,
+                    PotlockPotProjectDonationEventFilter,
+                >,
+            >,
+        >,
+    >,
+    potlock_pot_donation_sockets: Arc<
+        DashSet<Addr<EventWebSocket<FullPotlockPotDonationEvent, PotlockPotDonationEventFilter>>>,
+    >,
+
+    trade_pool_sockets:
+        Arc<DashSet<Addr<EventWebSocket<FullTradePoolEvent, TradePoolEventFilter>>>>,
+    trade_swap_sockets:
+        Arc<DashSet<Addr<EventWebSocket<FullTradeSwapEvent, TradeSwapEventFilter>>>>,
+    trade_pool_change_sockets:
+        Arc<DashSet<Addr<EventWebSocket<FullTradePoolChangeEvent, TradePoolChangeEventFilter>>>>,
+}
+
+impl Actor for Server {
+    type Context = actix::Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        let mut readers = Vec::new();
+        for source in &self.redis_sources {
+            let sockets = self.network(&source.network);
+            let mut spawner = ReaderSpawner {
+                source,
+                configs: &self.stream_configs,
+                checkpoints: &self.checkpoints,
+                shutdown: &self.shutdown,
+                readers: &mut readers,
+                broadcasts: &self.broadcasts,
+                origin: &self.origin,
+            };
+
+            spawner.spawn(&sockets.nft_mint_sockets);
+            spawner.spawn(&sockets.nft_transfer_sockets);
+            spawner.spawn(&sockets.nft_burn_sockets);
+
+            spawner.spawn(&sockets.potlock_donation_sockets);
+            spawner.spawn(&sockets.potlock_pot_project_donation_sockets);
+            spawner.spawn(&sockets.potlock_pot_donation_sockets);
+
+            spawner.spawn(&sockets.trade_pool_sockets);
+            spawner.spawn(&sockets.trade_swap_sockets);
+            spawner.spawn(&sockets.trade_pool_change_sockets);
+        }
+        self.readers = readers;
+    }
+}
+
+struct RedisSource {
+    name: Arc<str>,
+    network: String,
+    stream_prefix: String,
+    url: String,
+    connection: ConnectionManager,
+    streams: Option<Vec<String>>,
+    disabled: Arc<DisabledStreams>,
+    /// By stream key, without the prefix.
+    encodings: HashMap<String, Encoding>,
+}
+
+impl RedisSource {
+    fn reads(&self, stream_key: &str) -> bool {
+        !self.disabled.contains(stream_key)
+            && self
+                .streams
+                .as_ref()
+                .is_none_or(|streams| streams.iter().any(|stream| stream == stream_key))
+    }
+
+    fn stream_key(&self, stream_key: &str) -> String {
+        format!("{}{stream_key}", self.stream_prefix)
+    }
+
+    fn encoding(&self, stream_key: &str) -> Encoding {
+        self.encodings.get(stream_key).copied().unwrap_or_default()
+    }
+
+    fn stream_source(&self, stream_key: &str) -> StreamSource {
+        StreamSource {
+            name: Arc::clone(&self.name),
+            stream_key: self.stream_key(stream_key),
+            connection: self.connection.clone(),
+            encoding: self.encoding(stream_key),
+        }
+    }
+}
+
+/// A stream of a Redis source, for reading its entries outside of the readers.
+#[derive(Clone)]
+pub struct StreamSource {
+    pub name: Arc<str>,
+    /// With the prefix of the source.
+    pub stream_key: String,
+    pub connection: ConnectionManager,
+    /// Of the fields of the stream's entries.
+    pub encoding: Encoding,
+}
+
+/// Where the readers get events from.
+#[derive(Clone)]
+enum EventOrigin {
+    Redis,
+    /// Made-up events, this many per second and stream.
+    Synthetic(f64),
+    Replay(Arc<fixtures::Recording>),
+    /// The firehose of another instance.
+    Firehose(Arc<firehose::Upstream>),
+}
+
+struct ReaderSpawner<'a> {
+    source: &'a RedisSource,
+    configs: &'a HashMap<String, StreamConfig>,
+    checkpoints: &'a Checkpoints,
+    shutdown: &'a watch::Sender<bool>,
+    readers: &'a mut Vec<JoinHandle<()>>,
+    broadcasts: &'a Broadcasts,
+    origin: &'a EventOrigin,
+}
+
+impl ReaderSpawner<'_> {
+    fn spawn<
+        E: Serialize + Send + Sync + FromRedis + Unpin + 'static,
+        F: EventFilter<E> + Unpin + 'static,
+    >(
+        &mut self,
+        sockets: &Arc<DashSet<Addr<EventWebSocket<E, F>>>>,
+    ) where
+        Server: Handler<UnsubscribeFromEvents<E, F>>,
+    {
+        if !self.source.reads(E::STREAM_KEY) {
+            return;
+        }
+        let config = self.configs.get(E::STREAM_KEY).cloned().unwrap_or_default();
+        let stream_key = self.source.stream_key(E::STREAM_KEY);
+        // Synthetic events are always JSON
+        let encoding = match self.origin {
+            EventOrigin::Synthetic(_) => Encoding::Json,
+            _ => self.source.encoding(E::STREAM_KEY),
+        };
+        let handler = SocketEventHandler {
+            sockets: Arc::clone(sockets),
+            source: Arc::clone(&self.source.name),
+            network: self.source.network.clone(),
+            recent_ids: Mutex::new(RecentIds::new(DEDUPLICATION_WINDOW)),
+            broadcast: self.broadcasts.sender(&self.source.network),
+            firehose: self.broadcasts.firehose(&self.source.network),
+            dead_letters: Some(DeadLetters {
+                connection: self.source.connection.clone(),
+            }),
+            encoding,
+            passthrough: config.passthrough && encoding == Encoding::Json,
+        };
+        let connection = self.source.connection.clone();
+        let shared_connection = self.source.connection.clone();
+        let source = Arc::clone(&self.source.name);
+        let url = self.source.url.clone();
+        let origin = self.origin.clone();
+        let checkpoints = self.checkpoints.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        let restart = readers::register(&source, &stream_key, &self.source.network);
+        let span = tracing::info_span!("reader", source = %self.source.name, stream = %stream_key);
+        self.readers.push(tokio::spawn(
+            async move {
+                // XREAD BLOCK 0 would hold the shared connection until the next entry,
+                // so the reader connects on its own, and a failure to connect is a
+                // failure of the reader
+                let mut connection = (config.xread_block_ms != 0).then_some(connection);
+                let mut failures = 0;
+                loop {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                    let started = Instant::now();
+                    // Restarts stop the read like a shutdown, so that it saves its
+                    // checkpoint before the events after it are read again
+                    let (stop, mut stopped) = watch::channel(false);
+                    let read = async {
+                        match &origin {
+                            EventOrigin::Synthetic(rate) => {
+                                synthetic::generate(E::STREAM_KEY, *rate, &handler, &mut stopped)
+                                    .await
+                            }
+                            EventOrigin::Replay(recording) => {
+                                recording
+                                    .replay(&source, E::STREAM_KEY, &handler, &mut stopped)
+                                    .await
+                            }
+                            EventOrigin::Firehose(upstream) => {
+                                upstream
+                                    .read::<E>(&source, &stream_key, &handler, &mut stopped)
+                                    .await
+                            }
+                            EventOrigin::Redis if config.pubsub => {
+                                redis_reader::channel_events(
+                                    &source,
+                                    &stream_key,
+                                    &handler,
+                                    &url,
+                                    &mut stopped,
+                                )
+                                .await
+                            }
+                            EventOrigin::Redis => {
+                                let read = match &connection {
+                                    Some(connection) => connection.clone(),
+                                    None => redis_reader::connect(&url).await.map_err(|err| {
+                                        anyhow::Error::from(err)
+                                            .context("Failed to connect to Redis")
+                                    })?,
+                                };
+                                stream_events(
+                                    &source,
+                                    &stream_key,
+                                    &handler,
+                                    ReaderConnections {
+                                        read,
+                                        checkpoint: shared_connection.clone(),
+                                    },
+                                    &config,
+                                    &checkpoints,
+                                    &mut stopped,
+                                )
+                                .await
+                            }
+                        }
+                    };
+                    let mut restarting = false;
+                    let result = {
+                        // A panic is a failure like any other, instead of ending the reader
+                        let read = AssertUnwindSafe(read).catch_unwind();
+                        tokio::pin!(read);
+                        let grace = tokio::time::sleep(Duration::MAX);
+                        tokio::pin!(grace);
+                        loop {
+                            tokio::select! {
+                                result = &mut read => {
+                                    break result.unwrap_or_else(|_| {
+                                        Err(anyhow::anyhow!("Reader panicked"))
+                                    });
+                                }
+                                _ = restart.notified(), if !restarting => {
+                                    tracing::warn!("Restarting reader on request");
+                                    restarting = true;
+                                    stop.send_replace(true);
+                                    grace
+                                        .as_mut()
+                                        .reset(tokio::time::Instant::now() + RESTART_GRACE);
+                                }
+                                _ = &mut grace, if restarting => {
+                                    break Err(anyhow::anyhow!(
+                                        "Reader didn't stop within {RESTART_GRACE:?}"
+                                    ));
+                                }
+                                _ = shutdown.changed(), if !*stop.borrow() => {
+                                    stop.send_replace(true);
+                                }
+                            }
+                        }
+                    };
+                    if restarting {
+                        if let Err(err) = result {
+                            tracing::error!("Reader failed while restarting: {err:#}");
+                        }
+                        readers::restarted(&source, &stream_key);
+                        continue;
+                    }
+                    let Err(err) = result else {
+                        break;
+                    };
+                    metrics::increment(
+                        metrics::READER_RESTARTS,
+                        &[("source", &source), ("stream", &stream_key)],
+                    );
+                    // Only count failures that happen soon after each other
+                    if started.elapsed() > READER_HEALTHY_AFTER {
+                        failures = 0;
+                    }
+                    failures += 1;
+                    let delay = reader_backoff(failures);
+                    tracing::error!(
+                        "Reader failed ({failures} in a row), restarting in {delay:?}: {err:#}"
+                    );
+                    readers::record_failure(&source, &stream_key, failures, format!("{err:#}"));
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = restart.notified() => {}
+                        _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+                    }
+                    readers::restarted(&source, &stream_key);
+                    // The old connection may be the reason, e.g. if it's stuck
+                    match redis_reader::connect(&url).await {
+                        Ok(new_connection) => connection = Some(new_connection),
+                        Err(err) => tracing::error!("Failed to reconnect to Redis: {err}"),
+                    }
+                }
+                readers::stopped(&source, &stream_key);
+            }
+            .instrument(span),
+        ));
+    }
+}
+
+/// How long a reader that is restarted on request gets to finish its batch and
+/// save its checkpoint, before it's dropped, e.g. if it's stuck.
+const RESTART_GRACE: Duration = Duration::from_secs(10);
+/// A reader that ran this long before failing starts over with the shortest delay.
+const READER_HEALTHY_AFTER: Duration = Duration::from_secs(60);
+const MAX_READER_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Doubles with every failure in a row, with up to 50% jitter so that the readers
+/// of all streams don't reconnect at the same time.
+fn reader_backoff(failures: u32) -> Duration {
+    let delay = Duration::from_secs(1 << failures.min(6)).min(MAX_READER_BACKOFF);
+    let jitter = RandomState::new().build_hasher().finish() % 1000;
+    delay + delay / 2 * jitter as u32 / 1000
+}
+
+/// Stops all stream readers, resolves when they have saved their checkpoints.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct StopReaders;
+
+impl Handler<StopReaders> for Server {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, _msg: StopReaders, _ctx: &mut Self::Context) -> Self::Result {
+        self.shutdown.send_replace(true);
+        let readers = std::mem::take(&mut self.readers);
+        Box::pin(async move {
+            for reader in readers {
+                if let Err(err) = reader.await {
+                    tracing::error!("Stream reader failed: {err}");
+                }
+            }
+        })
+    }
+}
+
+struct EventWebSocket<E, F: EventFilter<E> + Unpin> {
+    /// Random UUID, that clients can mention when they report a problem.
+    connection_id: String,
+    last_heartbeat: Instant,
+    filter: Option<F>,
+    /// Shared by the connections with the same filter, see `frame_cache`.
+    filter_key: Option<Arc<str>>,
+    /// The filter and options of the last applied message, that filter commands
+    /// change.
+    message: serde_json::Map<String, serde_json::Value>,
+    options: ConnectionOptions,
+    server: Addr<Server>,
+    network: String,
+    /// Live events received while a replay is being read.
+    replay_buffer: Option<Vec<Arc<Event<E>>>>,
+    /// The last event that was delivered or filtered out, to resume from.
+    last_id: Option<StreamId>,
+    protocol: Protocol,
+    /// The protocol can still be chosen by the next client message.
+    negotiable: bool,
+    /// Filters of this endpoint by preset name.
+    presets: HashMap<String, serde_json::Value>,
+    /// Serialized envelopes waiting to be sent, if `batch_ms` is set.
+    batch: Vec<ByteString>,
+    /// The last event of each conflation key in the current window, if
+    /// `conflate_ms` is set.
+    conflation: Conflation<Arc<Event<E>>>,
+    /// Events that the client didn't ack yet with their `conflated_count`, if
+    /// `ack_timeout_ms` is set.
+    acks: Acks<(Arc<Event<E>>, Option<u64>)>,
+    ack_timer: Option<SpawnHandle>,
+    /// Aggregates of the current window, if the `stats` option is set.
+    stats: Stats,
+    stats_timer: Option<SpawnHandle>,
+    keepalive_timer: Option<SpawnHandle>,
+    head_timer: Option<SpawnHandle>,
+    /// Where the filter messages are saved, for clients with an API key.
+    last_filter: Option<LastFilter>,
+    /// Where the events are saved up to which the client acked, for clients with
+    /// an API key, and the last saved stream ID.
+    cursor: Option<Cursor>,
+    saved_cursor: Option<StreamId>,
+    /// The oldest event that was given up on because too many were waiting for
+    /// their ack, which the cursor stays before.
+    given_up: Option<StreamId>,
+    /// For the `group` option, which needs an API key.
+    api_key: Option<String>,
+    /// The key of the queue group that the connection is in.
+    group: Option<Arc<str>>,
+    remote_addr: String,
+    /// The key of the connections of the client with the same filter, see
+    /// `connections::identity`.
+    identity: Option<String>,
+    /// Issues session tokens, if `session_secret` is configured.
+    sessions: Option<web::Data<Sessions>>,
+    /// The session of the last token that was sent.
+    session: Option<Session>,
+    session_timer: Option<SpawnHandle>,
+    /// The client that the events per second are limited for, an API key or the
+    /// fingerprint of an anonymous client, see `plans::take_event`, and the limit.
+    event_limit: Option<(String, u32)>,
+    /// Signs the event frames, for API keys with a `signing_secret`.
+    signing_key: Option<ring::hmac::Key>,
+    /// Record of the delivered events, for API keys with `audit` set.
+    audit: Option<AuditLog>,
+    /// Every filter has to set a field, for streams with `require_filter`.
+    require_filter: bool,
+    /// Subscribes the socket when the first filter is applied, for streams with
+    /// `require_filter`.
+    deferred_subscription: Option<oneshot::Sender<()>>,
+    /// The proof-of-work challenge that an anonymous client has to solve before
+    /// it's subscribed.
+    challenge: Option<Challenge>,
+    span: tracing::Span,
+    _marker: PhantomData<E>,
+}
+
+/// Delivery options that are sent in the same message as the filter.
+#[derive(Debug, Default, Deserialize)]
+pub struct ConnectionOptions {
+    /// Don't send events that were already delivered within the last
+    /// `DEDUPLICATION_WINDOW` entries of the stream.
+    #[serde(default)]
+    exactly_once_window: bool,
+    /// Protocol 2 only: collect events for up to this long and send them in one frame.
+    batch_ms: Option<u64>,
+    /// Send aggregates of the matching events every `window_sec` instead of the events.
+    stats: Option<StatsOptions>,
+    /// Send a keepalive frame this often, for proxies that close idle connections.
+    keepalive_sec: Option<u64>,
+    /// Send the latest block of the stream this often.
+    head_sec: Option<u64>,
+    /// Only send this fraction of the matching events.
+    sample_rate: Option<f64>,
+    /// Sample by stream ID, so that every connection with the same rate gets the
+    /// same events, instead of randomly.
+    #[serde(default)]
+    sample_deterministic: bool,
+    /// Only send the latest event of each value of `conflate_key` every this often.
+    conflate_ms: Option<u64>,
+    /// Field of the events to conflate by, e.g. `owner_id`, or `a/b` for nested
+    /// fields. Defaults to the `CONFLATION_KEY` of the endpoint.
+    conflate_key: Option<String>,
+    /// Skip events whose block is older than this, e.g. while catching up.
+    max_age_ms: Option<u64>,
+    /// Add an `ack_id` to every event and send it again if the client doesn't ack
+    /// it within this long.
+    ack_timeout_ms: Option<u64>,
+    /// Share the events round-robin with the other connections of the API key
+    /// with this group.
+    group: Option<String>,
+    /// Who the cursor of the ack mode is of, instead of the group or the filter.
+    client_id: Option<String>,
+    /// Only deliver events during these hours, and drop them outside of them.
+    active_hours: Option<schedule::ActiveHours>,
+    /// Style of the field names of events, e.g. `camelCase`.
+    #[serde(default)]
+    key_style: key_style::KeyStyle,
+}
+
+impl ConnectionOptions {
+    /// Reads the options of a client message.
+    fn parse(text: &str) -> Result<Self, String> {
+        let options = serde_json::from_str::<Self>(text).map_err(|e| e.to_string())?;
+        if let Some(rate) = options.sample_rate {
+            sampling::validate(rate)?;
+        }
+        if options.ack_timeout_ms == Some(0) {
+            return Err("ack_timeout_ms must be above 0".to_string());
+        }
+        if options.client_id.as_deref() == Some("") {
+            return Err("client_id can't be empty".to_string());
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct InitialFilterQuery {
+    /// Start with a filter saved with `POST /v0/filters`.
+    filter_id: Option<String>,
+    /// Start with this message, like the first message of the client.
+    filter: Option<String>,
+    /// Start with the filter of a session token and replay the events after it.
+    session: Option<String>,
+}
+
+/// Header with the first message, an alternative to `?filter=`.
+const FILTER_HEADER: &str = "x-filter";
+
+/// Upgrades the request to a WebSocket connection that receives events of type `E`
+/// from the network in the `{network}` path segment, or mainnet if there's none.
+/// The protocol depends on the API version in the app data, `/v0` if there's none.
+async fn connect<
+    E: Serialize + FromRedis + Send + Sync + Unpin + 'static,
+    F: EventFilter<E> + DeserializeOwned + Unpin + 'static,
+>(
+    req: HttpRequest,
+    stream: web::Payload,
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+) -> Result<HttpResponse, Error>
+where
+    Server: Handler<SubscribeToEvents<E, F>>
+        + Handler<UnsubscribeFromEvents<E, F>>
+        + Handler<ReadReplay<E>>,
+{
+    let replay_start = match web::Query::<ReplayQuery>::from_query(req.query_string()) {
+        Ok(query) => query.start(),
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+    };
+    let (filter_id, initial_message, session_token) =
+        match web::Query::<InitialFilterQuery>::from_query(req.query_string()) {
+            Ok(query) => {
+                let query = query.into_inner();
+                let header = req
+                    .headers()
+                    .get(FILTER_HEADER)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                (query.filter_id, query.filter.or(header), query.session)
+            }
+            Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+        };
+    if [
+        filter_id.is_some(),
+        initial_message.is_some(),
+        session_token.is_some(),
+    ]
+    .into_iter()
+    .filter(|given| *given)
+    .count()
+        > 1
+    {
+        return Ok(HttpResponse::BadRequest().body("Use either filter, filter_id or session"));
+    }
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return Ok(HttpResponse::NotFound().body(format!("Unknown network: {network}")));
+    }
+    if let Some(response) = disabled_response::<E>(&req) {
+        return Ok(response);
+    }
+    if let Some(response) = drain::draining_response(&req) {
+        return Ok(response);
+    }
+    let sessions = req.app_data::<web::Data<Sessions>>().cloned();
+    let session = match (session_token, &sessions) {
+        (Some(token), Some(sessions)) => match sessions.verify(&token) {
+            Some(session) if session.endpoint == E::STREAM_KEY && session.network == network => {
+                Some(session)
+            }
+            _ => return Ok(HttpResponse::BadRequest().body("Invalid session token")),
+        },
+        (Some(_), None) => {
+            return Ok(HttpResponse::BadRequest().body("Sessions aren't enabled"));
+        }
+        (None, _) => None,
+    };
+    let replay_start = replay_start.or_else(|| {
+        session
+            .as_ref()
+            .and_then(|session| session.resume_from)
+            .map(ReplayStart::After)
+    });
+    let initial_message = initial_message.or(session.map(|session| session.message));
+
+    let (api_key, plan) = plans::check_access(&req, E::STREAM_KEY)?;
+    if let (Some(plan), Some(api_key)) = (&plan, &api_key) {
+        if let Some(max_connections) = plan.max_connections {
+            if connections::count_of_key(&api_key.name) >= max_connections {
+                return Ok(HttpResponse::TooManyRequests().body(format!(
+                    "Your plan allows {max_connections} connections at once"
+                )));
+            }
+        }
+    }
+    let near_account = near_auth::authenticate(&req, &network).await?;
+    let anonymous = api_key.is_none() && near_account.is_none();
+    let abuse_control = req.app_data::<web::Data<AbuseControl>>();
+    let fingerprint = abuse_control
+        .filter(|_| anonymous)
+        .map(|_| abuse::fingerprint(&req));
+    if let Some((abuse_control, fingerprint)) = abuse_control.zip(fingerprint.as_ref()) {
+        if let Some(response) = abuse_control
+            .banned_response(fingerprint, unix_time_ms())
+            .await
+        {
+            return Ok(response);
+        }
+    }
+    let event_limit = match (&api_key, &plan, &fingerprint) {
+        (Some(api_key), Some(plan), _) => plan
+            .max_events_per_sec
+            .map(|limit| (api_key.name.clone(), limit)),
+        (_, _, Some(fingerprint)) => abuse_control
+            .and_then(|abuse_control| abuse_control.config.max_events_per_sec)
+            .map(|limit| (format!("anonymous/{fingerprint}"), limit)),
+        _ => None,
+    };
+
+    let saved_filters = req.app_data::<web::Data<SavedFilters>>();
+    let (filter, message) = match (&filter_id, saved_filters) {
+        (Some(id), Some(filters)) => match filters.load::<E, F>(id).await {
+            Ok(Ok((filter, serde_json::Value::Object(message)))) => (Some(filter), message),
+            Ok(Ok((filter, _))) => (Some(filter), serde_json::Map::new()),
+            Ok(Err(message)) => return Ok(HttpResponse::BadRequest().body(message)),
+            Err(err) => {
+                tracing::error!("Failed to load filter {id}: {err}");
+                return Ok(HttpResponse::InternalServerError().body("Failed to load filter"));
+            }
+        },
+        _ => (None, serde_json::Map::new()),
+    };
+    let last_filter = saved_filters
+        .zip(api_key.as_ref())
+        .map(|(filters, api_key)| LastFilter::new(filters, api_key, &network, E::STREAM_KEY));
+    let connection_id = connections::new_connection_id();
+    let audit = saved_filters
+        .zip(api_key.as_ref())
+        .and_then(|(filters, api_key)| {
+            AuditLog::new(
+                api_key,
+                &filters.connection,
+                &connection_id,
+                &network,
+                E::STREAM_KEY,
+            )
+        });
+    // A filter in the request takes precedence over the last one
+    let last_message = match &last_filter {
+        Some(last_filter) if filter_id.is_none() && initial_message.is_none() => {
+            match last_filter.load().await {
+                Ok(message) => message,
+                Err(err) => {
+                    tracing::warn!("Failed to load the last filter: {err}");
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    // The cursor of the consumer of the first message
+    let cursor_message = initial_message
+        .as_deref()
+        .or(last_message.as_deref())
+        .map_or_else(
+            || message.clone(),
+            |text| serde_json::from_str(text).unwrap_or_default(),
+        );
+    let cursor = saved_filters
+        .zip(api_key.as_ref())
+        .map(|(filters, api_key)| {
+            Cursor::new(filters, api_key, &network, E::STREAM_KEY, &cursor_message)
+        });
+    // In the ack mode, clients resume where they left off, unless they ask for a replay
+    let acks_enabled = initial_message
+        .as_deref()
+        .or(last_message.as_deref())
+        .and_then(|message| ConnectionOptions::parse(message).ok())
+        .is_some_and(|options| options.ack_timeout_ms.is_some());
+    let replay_start = match &cursor {
+        Some(cursor) if replay_start.is_none() && acks_enabled => match cursor.load().await {
+            Ok(id) => id.map(ReplayStart::After),
+            Err(err) => {
+                tracing::warn!("Failed to load the cursor: {err}");
+                None
+            }
+        },
+        _ => replay_start,
+    };
+    let replay_start = match (replay_start, &plan) {
+        (Some(start), Some(plan)) => match plan.limit_replay(start, unix_time_ms()) {
+            Ok(start) => Some(start),
+            Err(message) => return Ok(HttpResponse::Forbidden().body(message)),
+        },
+        (start, _) => start,
+    };
+
+    let api_version = req
+        .app_data::<ApiVersion>()
+        .copied()
+        .unwrap_or(ApiVersion::V0);
+    let deprecation = req
+        .app_data::<web::Data<Deprecations>>()
+        .and_then(|deprecations| deprecations.get(E::STREAM_KEY).cloned());
+    let mut presets = req
+        .app_data::<web::Data<Presets>>()
+        .and_then(|presets| presets.get(E::STREAM_KEY).cloned())
+        .unwrap_or_default();
+    // Signed-in clients start with the events of their account
+    let initial_message = match &near_account {
+        Some(account_id) if F::filter_fields().contains(&"involved_account_ids") => {
+            presets.insert(
+                near_auth::ME_PRESET.to_string(),
+                serde_json::json!({ "involved_account_ids": [account_id] }),
+            );
+            initial_message.or_else(|| {
+                (filter_id.is_none() && last_message.is_none())
+                    .then(|| format!(r#"{{"preset":"{}"}}"#, near_auth::ME_PRESET))
+            })
+        }
+        _ => initial_message,
+    };
+    let require_filter = req
+        .app_data::<web::Data<FilterRequired>>()
+        .is_some_and(|streams| streams.contains(E::STREAM_KEY));
+    if let Some(message) = &initial_message {
+        if let Err(err) =
+            parse_filter::<F>(&presets, message).and_then(|_| ConnectionOptions::parse(message))
+        {
+            return Ok(HttpResponse::BadRequest().body(err));
+        }
+        if require_filter && !has_filter::<F>(&presets, message) {
+            return Ok(HttpResponse::BadRequest().body(FILTER_REQUIRED));
+        }
+    }
+
+    let subscribe = {
+        let server = server.get_ref().clone();
+        let network = network.clone();
+        move |addr: Addr<EventWebSocket<E, F>>| async move {
+            server
+                .send(SubscribeToEvents(addr.clone(), network))
+                .await
+                .unwrap();
+            // Only start reading the replay after subscribing, so that no event is
+            // missed between the end of the replay and the first buffered live event
+            if let Some(start) = replay_start {
+                addr.do_send(StartReplay(start));
+            }
+        }
+    };
+    // Without a filter yet, the first filter message subscribes the socket
+    let filter_message = serde_json::to_string(&message).unwrap();
+    let challenge = req
+        .app_data::<web::Data<ProofOfWork>>()
+        .and_then(|streams| streams.get(E::STREAM_KEY).copied())
+        .filter(|_| anonymous)
+        .map(Challenge::new);
+    let (deferred_subscription, first_filter) =
+        if (require_filter && !has_filter::<F>(&presets, &filter_message)) || challenge.is_some() {
+            let (sender, receiver) = oneshot::channel();
+            (Some(sender), Some(receiver))
+        } else {
+            (None, None)
+        };
+    let remote_addr = connections::client_addr(&req);
+    let builder = WsResponseBuilder::new(
+        EventWebSocket::<E, F> {
+            connection_id: connection_id.clone(),
+            last_heartbeat: Instant::now(),
+            filter,
+            filter_key: frame_cache::filter_key(&message, &presets),
+            message,
+            options: ConnectionOptions::default(),
+            server: server.get_ref().clone(),
+            network: network.clone(),
+            replay_buffer: replay_start.map(|_| Vec::new()),
+            last_id: None,
+            protocol: match api_version {
+                ApiVersion::V0 => Protocol::V1,
+                ApiVersion::V1 => Protocol::LATEST,
+            },
+            negotiable: api_version == ApiVersion::V1,
+            presets,
+            batch: Vec::new(),
+            conflation: Conflation::default(),
+            acks: Acks::default(),
+            ack_timer: None,
+            stats: Stats::default(),
+            stats_timer: None,
+            keepalive_timer: None,
+            head_timer: None,
+            last_filter,
+            cursor,
+            saved_cursor: None,
+            given_up: None,
+            api_key: api_key.as_ref().map(|key| key.name.clone()),
+            group: None,
+            remote_addr: remote_addr.clone(),
+            identity: None,
+            sessions,
+            session: None,
+            session_timer: None,
+            event_limit,
+            signing_key: api_key
+                .as_ref()
+                .and_then(|key| key.signing_secret.as_deref())
+                .map(signing::key),
+            audit,
+            require_filter,
+            deferred_subscription,
+            challenge,
+            span: tracing::info_span!(
+                "connection",
+                id = %connection_id,
+                endpoint = E::STREAM_KEY,
+                network = %network,
+                api_key = api_key.as_ref().map_or("", |key| &key.name),
+                remote_addr = %remote_addr,
+            ),
+            _marker: PhantomData,
+        },
+        &req,
+        stream,
+    );
+    let builder = match req
+        .app_data::<web::Data<HttpConfig>>()
+        .and_then(|config| config.max_frame_size)
+    {
+        Some(size) => builder.frame_size(size),
+        None => builder,
+    };
+    let (addr, mut res) = builder.start_with_addr()?;
+    connections::register(
+        &connection_id,
+        E::STREAM_KEY,
+        &network,
+        &remote_addr,
+        api_key.as_ref().map(|key| key.name.as_str()),
+    );
+    if let Ok(value) = header::HeaderValue::from_str(&connection_id) {
+        res.headers_mut().insert(
+            header::HeaderName::from_static(connections::CONNECTION_ID_HEADER),
+            value,
+        );
+    }
+    if let Some(message) = deprecation {
+        res.headers_mut().insert(
+            header::HeaderName::from_static("deprecation"),
+            header::HeaderValue::from_static("true"),
+        );
+        addr.do_send(Arc::new(Notice::new(&message)));
+    }
+    // Applied before any event arrives, since the socket isn't subscribed yet
+    if let Some(message) = initial_message.or(last_message) {
+        addr.do_send(InitialFilter(message));
+    }
+    match first_filter {
+        Some(first_filter) => {
+            actix::spawn(async move {
+                // Fails if the client disconnected without a filter or a solution
+                if first_filter.await.is_ok() {
+                    subscribe(addr).await;
+                }
+            });
+        }
+        None => subscribe(addr).await,
+    }
+    Ok(res)
+}
+
+pub trait EventFilter<E>: FilterFields {
+    fn matches(&self, event: &E) -> bool;
+}
+
+struct SocketEventHandler<E: Unpin + 'static, F: EventFilter<E> + Unpin + 'static>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    sockets: Arc<DashSet<Addr<EventWebSocket<E, F>>>>,
+    source: Arc<str>,
+    network: String,
+    recent_ids: Mutex<RecentIds>,
+    broadcast: EventSender<E>,
+    /// Frames of `/v0/firehose`, if an API key can read them.
+    firehose: Option<Arc<firehose::Firehose>>,
+    /// `None` in the harness, which has no Redis.
+    dead_letters: Option<DeadLetters>,
+    /// Of the fields of the stream's entries in this source.
+    encoding: Encoding,
+    /// Events keep the fields of their entries, for `passthrough`.
+    passthrough: bool,
+}
+
+impl<E, F: EventFilter<E> + Unpin> EventWebSocket<E, F> {
+    fn flush_audit(&mut self) {
+        if let Some(audit) = &mut self.audit {
+            self.span.in_scope(|| audit.flush());
+        }
+    }
+
+    /// Saves the stream ID before the oldest unacked event, or of the last event
+    /// if all were acked, in the ack mode.
+    fn save_cursor(&mut self) {
+        let Some(cursor) = &self.cursor else {
+            return;
+        };
+        if self.options.ack_timeout_ms.is_none() {
+            return;
+        }
+        let oldest = self.acks.pending().map(|(event, _)| event.id).min();
+        let id = match oldest.into_iter().chain(self.given_up).min() {
+            Some(oldest) => Some(oldest.previous()),
+            None => self.last_id,
+        };
+        if let Some(id) = id {
+            if self.saved_cursor != Some(id) {
+                cursor.save(id);
+                self.saved_cursor = Some(id);
+            }
+        }
+    }
+}
+
+impl<E: Unpin + 'static, F: EventFilter<E> + Unpin + 'static> Actor for EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.span.in_scope(|| tracing::info!("Connected"));
+        self.last_heartbeat = Instant::now();
+
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.last_heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+            }
+
+            ctx.ping(b"");
+        });
+        if self.audit.is_some() {
+            ctx.run_interval(audit::FLUSH_INTERVAL, |act, _ctx| act.flush_audit());
+        }
+        if let Some(challenge) = &self.challenge {
+            let frame = ControlFrame::Challenge {
+                challenge: &challenge.challenge,
+                bits: challenge.bits,
+            };
+            ctx.text(serde_json::to_string(&frame).unwrap());
+            ctx.run_later(proof_of_work::CHALLENGE_TIMEOUT, |act, ctx| {
+                if act.challenge.is_some() {
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Policy,
+                        description: Some("The challenge wasn't solved in time".to_string()),
+                    }));
+                    ctx.stop();
+                }
+            });
+        }
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        self.server
+            .do_send(UnsubscribeFromEvents(ctx.address(), self.network.clone()));
+        Running::Stop
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.flush_audit();
+        self.save_cursor();
+        if let Some(group) = &self.group {
+            groups::leave(group, &self.connection_id);
+        }
+        if let Some(identity) = &self.identity {
+            connections::remove_identical(identity);
+        }
+        connections::unregister(&self.connection_id);
+        self.span.in_scope(|| tracing::info!("Disconnected"));
+    }
+}
+
+pub trait FromRedis {
+    /// Key of the Redis stream that these events are read from.
+    const STREAM_KEY: &'static str;
+    /// Fields of the stream entries that events are read from.
+    const FIELDS: &'static [&'static str];
+    /// Field that the `conflate_ms` option conflates by if the client doesn't
+    /// choose a `conflate_key`.
+    const CONFLATION_KEY: Option<&'static str> = None;
+
+    /// Reads an entry whose fields are in this encoding.
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError>
+    where
+        Self: Sized;
+
+    /// The block that the event happened in.
+    fn head(&self) -> heads::Head;
+
+    /// Called with every live event of the network before it's delivered, e.g. to
+    /// add what other streams tell about it.
+    fn enrich(&mut self, _network: &str) {}
+}
+
+/// Deserializes a field of a stream entry.
+fn redis_field<T: DeserializeOwned + BorshDeserialize>(
+    values: &HashMap<String, redis::Value>,
+    field: &'static str,
+    encoding: Encoding,
+) -> Result<T, FromRedisError> {
+    let value = values
+        .get(field)
+        .ok_or(FromRedisError::MissingField(field))?;
+    let text = redis::from_redis_value::<String>(value)
+        .map_err(|error| FromRedisError::NotAString { field, error })?;
+    match encoding {
+        Encoding::Json => {
+            serde_json::from_str(&text).map_err(|error| FromRedisError::Json { field, error })
+        }
+        Encoding::Borsh => {
+            let bytes = BASE64_STANDARD
+                .decode(text)
+                .map_err(|error| FromRedisError::Base64 { field, error })?;
+            borsh::from_slice(&bytes).map_err(|error| FromRedisError::Borsh { field, error })
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: Serialize + Send + Sync + FromRedis + Unpin, F: EventFilter<E> + Unpin> EventHandler
+    for SocketEventHandler<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    async fn handle(&self, id: &str, values: HashMap<String, redis::Value>) -> anyhow::Result<()> {
+        let span = tracing::debug_span!("event", stream_id = id);
+        let deserialized = tracing::debug_span!(parent: &span, "deserialize")
+            .in_scope(|| E::decode(&values, self.encoding));
+        let mut event = match deserialized {
+            Ok(event) => event,
+            Err(err) => {
+                let _span = span.enter();
+                match &self.dead_letters {
+                    Some(dead_letters) => {
+                        dead_letters.push(&self.source, E::STREAM_KEY, id, &err, &values)
+                    }
+                    None => tracing::warn!(%id, "Skipping an entry that can't be read: {err}"),
+                }
+                return Ok(());
+            }
+        };
+        event.enrich(&self.network);
+        let duplicate = !self.recent_ids.lock().unwrap().insert(id);
+        let stream_id = id.parse()?;
+        if let Some(firehose) = self.firehose.as_ref().filter(|_| !duplicate) {
+            firehose.publish(&self.source, E::STREAM_KEY, stream_id, &values);
+        }
+        let event = Arc::new(Event {
+            source: Arc::clone(&self.source),
+            id: stream_id,
+            duplicate,
+            event,
+            span: span.clone(),
+            frames: FrameCache::default(),
+            raw: self
+                .passthrough
+                .then(|| passthrough::event_json(&values, E::FIELDS))
+                .flatten(),
+        });
+        heads::record(&self.network, E::STREAM_KEY, event.event.head());
+        async {
+            for socket in self.sockets.iter() {
+                socket.send(Arc::clone(&event)).await?;
+            }
+            // Fails only if there are no other consumers
+            let _ = self.broadcast.send(Arc::clone(&event));
+            Ok(())
+        }
+        .instrument(tracing::debug_span!(parent: &span, "fanout", sockets = self.sockets.len()))
+        .await
+    }
+
+    fn recent_ids(&self) -> Option<String> {
+        Some(self.recent_ids.lock().unwrap().to_saved())
+    }
+
+    fn restore_recent_ids(&self, saved: &str) {
+        self.recent_ids.lock().unwrap().restore(saved);
+    }
+}
+
+impl<
+        E: Serialize + FromRedis + Send + Unpin + 'static,
+        F: EventFilter<E> + DeserializeOwned + Unpin + 'static,
+    > StreamHandler<Result<ws::Message, ws::ProtocolError>> for EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.last_heartbeat = Instant::now();
+            }
+            Ok(ws::Message::Text(text)) => {
+                let solution = self
+                    .challenge
+                    .as_ref()
+                    .and_then(|_| proof_of_work::parse(&text));
+                if let Some(solution) = solution {
+                    self.solve(&solution, ctx);
+                } else if let Some(id) = acks::parse(&text) {
+                    self.acks.ack(id);
+                } else {
+                    self.configure(&text, ctx);
+                }
+            }
+            _ => ctx.stop(),
+        }
+    }
+}
+
+impl<
+        E: Serialize + FromRedis + Send + Unpin + 'static,
+        F: EventFilter<E> + DeserializeOwned + Unpin + 'static,
+    > EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    /// Applies a filter and options message, after choosing the protocol if it's
+    /// the first message on `/v1`.
+    fn configure(&mut self, text: &str, ctx: &mut <Self as Actor>::Context) {
+        if std::mem::take(&mut self.negotiable) {
+            if let Ok(Negotiation {
+                protocol: Some(version),
+            }) = serde_json::from_str(text)
+            {
+                let Some(protocol) = Protocol::from_version(version) else {
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Policy,
+                        description: Some(format!("Unsupported protocol version {version}")),
+                    }));
+                    ctx.stop();
+                    return;
+                };
+                self.protocol = protocol;
+            }
+        }
+
+        let result = match self.command(text) {
+            Ok(Some(message)) => self.apply(&message, ctx).map(|()| message),
+            Ok(None) => self.apply(text, ctx).map(|()| text.to_string()),
+            Err(err) => Err(err),
+        };
+        let reply = match result {
+            Ok(message) => {
+                if let Some(last_filter) = &self.last_filter {
+                    last_filter.save(&message);
+                }
+                ControlFrame::Ack {
+                    protocol: self.protocol as u32,
+                    connection_id: &self.connection_id,
+                }
+            }
+            Err(ref message) => ControlFrame::Error { message },
+        };
+        match reply {
+            _ if self.protocol != Protocol::V1 => {
+                ctx.text(serde_json::to_string(&reply).unwrap());
+            }
+            // Protocol 1 has no error frames, and ignoring the message would leave
+            // the socket with the events of its old filter, or all events
+            ControlFrame::Error { message } => {
+                ctx.close(Some(protocol::policy_close(message)));
+                ctx.stop();
+                return;
+            }
+            _ => {}
+        }
+        self.send_session(ctx);
+    }
+
+    /// Subscribes the socket if the solution of its challenge is right, and the
+    /// filter that the stream requires was sent.
+    fn solve(&mut self, solution: &str, ctx: &mut <Self as Actor>::Context) {
+        let Some(challenge) = &self.challenge else {
+            return;
+        };
+        if !challenge.is_solved_by(solution) {
+            let frame = ControlFrame::Error {
+                message: "Wrong solution",
+            };
+            ctx.text(serde_json::to_string(&frame).unwrap());
+            return;
+        }
+        self.challenge = None;
+        ctx.text(serde_json::to_string(&ControlFrame::ChallengeSolved).unwrap());
+        if !self.require_filter || self.filter.is_some() {
+            if let Some(subscription) = self.deferred_subscription.take() {
+                let _ = subscription.send(());
+            }
+        }
+    }
+
+    /// Sends a session token on protocol 2 if the filter or the last event changed
+    /// since the last one.
+    fn send_session(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let Some(sessions) = &self.sessions else {
+            return;
+        };
+        if self.protocol == Protocol::V1 || self.message.is_empty() {
+            return;
+        }
+        let session = Session {
+            endpoint: E::STREAM_KEY.to_string(),
+            network: self.network.clone(),
+            message: serde_json::to_string(&self.message).unwrap(),
+            resume_from: self.last_id,
+        };
+        if self.session.as_ref() != Some(&session) {
+            let frame = ControlFrame::Session {
+                token: &sessions.issue(&session),
+            };
+            ctx.text(serde_json::to_string(&frame).unwrap());
+            self.session = Some(session);
+        }
+    }
+
+    /// Sets the filter and options of a client message.
+    fn apply(&mut self, text: &str, ctx: &mut <Self as Actor>::Context) -> Result<(), String> {
+        let filter = self.filter(text)?;
+        if self.require_filter && !has_filter::<F>(&self.presets, text) {
+            return Err(FILTER_REQUIRED.to_string());
+        }
+        let options = ConnectionOptions::parse(text)?;
+        if options.conflate_ms.is_some()
+            && options.conflate_key.is_none()
+            && E::CONFLATION_KEY.is_none()
+        {
+            return Err(format!(
+                "conflate_ms needs a conflate_key on {}",
+                E::STREAM_KEY
+            ));
+        }
+        let group = match (&options.group, &self.api_key) {
+            (Some(group), Some(api_key)) => {
+                Some(groups::key(api_key, &self.network, E::STREAM_KEY, group))
+            }
+            (Some(_), None) => return Err("group needs an API key".to_string()),
+            (None, _) => None,
+        };
+        if group != self.group {
+            if let Some(group) = &self.group {
+                groups::leave(group, &self.connection_id);
+            }
+            if let Some(group) = &group {
+                groups::join(group, &self.connection_id);
+            }
+            self.group = group;
+        }
+        self.options = options;
+        self.conflation = Conflation::default();
+        self.filter = Some(filter);
+        self.message = serde_json::from_str(text).unwrap_or_default();
+        self.filter_key = frame_cache::filter_key(&self.message, &self.presets);
+        if let Some(cursor) = &mut self.cursor {
+            cursor.set_message(&self.message);
+            self.saved_cursor = None;
+            self.given_up = None;
+        }
+        self.count_identical(ctx);
+        if self.sessions.is_some() && self.session_timer.is_none() {
+            self.session_timer = Some(
+                ctx.run_interval(sessions::SESSION_INTERVAL, |act, ctx| act.send_session(ctx)),
+            );
+        }
+        self.restart_stats(ctx);
+        self.restart_keepalive(ctx);
+        self.restart_heads(ctx);
+        self.restart_acks(ctx);
+        if self.challenge.is_none() {
+            if let Some(subscription) = self.deferred_subscription.take() {
+                let _ = subscription.send(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns a filter command into a whole message with the current options, or
+    /// returns `None` if the message isn't a command.
+    fn command(&self, text: &str) -> Result<Option<String>, String> {
+        filter_command::<F>(&self.message, &self.presets, text)
+    }
+
+    /// Parses the filter of a client message, or looks up its `preset`.
+    fn filter(&self, text: &str) -> Result<F, String> {
+        parse_filter(&self.presets, text)
+    }
+
+    /// Counts the connection with its current filter, and warns the client if it
+    /// has many identical connections.
+    fn count_identical(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let identity = connections::identity(
+            self.api_key.as_deref().unwrap_or(&self.remote_addr),
+            E::STREAM_KEY,
+            &self.network,
+            self.filter_key.as_deref(),
+        );
+        if self.identity.as_ref() == Some(&identity) {
+            return;
+        }
+        if let Some(old) = self.identity.replace(identity.clone()) {
+            connections::remove_identical(&old);
+        }
+        let count = connections::add_identical(&identity);
+        if count >= connections::DUPLICATE_WARNING_THRESHOLD {
+            self.span.in_scope(|| {
+                tracing::warn!("{count} identical connections of the same client");
+            });
+            // Protocol 1 only has events
+            if self.protocol == Protocol::V1 {
+                return;
+            }
+            let message = format!(
+                "This client has {count} connections with the same filter on this endpoint, \
+                 check that it closes old connections when it reconnects"
+            );
+            let frame = ControlFrame::Warning { message: &message };
+            ctx.text(serde_json::to_string(&frame).unwrap());
+        }
+    }
+
+    /// Starts sending keepalive frames with the current options, or stops.
+    fn restart_keepalive(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some(timer) = self.keepalive_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        if let Some(interval) = self.options.keepalive_sec {
+            let interval = Duration::from_secs(interval.max(1));
+            self.keepalive_timer = Some(ctx.run_interval(interval, |_, ctx| {
+                let frame = ControlFrame::Keepalive {
+                    server_time: unix_time_ms(),
+                };
+                ctx.text(serde_json::to_string(&frame).unwrap());
+            }));
+        }
+    }
+
+    /// Starts sending the latest block of the stream with the current options, or stops.
+    fn restart_heads(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some(timer) = self.head_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        if let Some(interval) = self.options.head_sec {
+            let interval = Duration::from_secs(interval.max(1));
+            self.head_timer = Some(ctx.run_interval(interval, |act, ctx| {
+                let Some(head) = heads::get(&act.network, E::STREAM_KEY) else {
+                    return;
+                };
+                let frame = ControlFrame::Head {
+                    stream: E::STREAM_KEY,
+                    block_height: head.block_height,
+                    block_timestamp_nanosec: head.block_timestamp_nanosec.to_string(),
+                    server_time: unix_time_ms(),
+                };
+                ctx.text(serde_json::to_string(&frame).unwrap());
+            }));
+        }
+    }
+
+    /// Starts a new stats window with the current options, or stops sending stats.
+    fn restart_stats(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some(timer) = self.stats_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        self.stats = Stats::default();
+        if let Some(options) = &self.options.stats {
+            let window = Duration::from_secs(options.window_sec.max(1));
+            self.stats_timer = Some(ctx.run_interval(window, |act, ctx| {
+                if let Some(options) = &act.options.stats {
+                    ctx.text(serde_json::to_string(&act.stats.take(options)).unwrap());
+                }
+            }));
+        }
+    }
+}
+
+fn unix_time_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Messages that change the filter and keep the connection options.
+#[derive(Debug, Deserialize)]
+enum FilterCommand {
+    /// Replaces the whole filter.
+    #[serde(rename = "set_filter")]
+    Set(serde_json::Map<String, serde_json::Value>),
+    /// Changes these fields of the filter, and removes the fields that are `null`.
+    #[serde(rename = "update_filter")]
+    Update(serde_json::Map<String, serde_json::Value>),
+    /// Removes the filter, so that all events are sent.
+    #[serde(rename = "clear_filter")]
+    Clear(bool),
+}
+
+impl FilterCommand {
+    const NAMES: [&str; 3] = ["set_filter", "update_filter", "clear_filter"];
+}
+
+/// Parses the filter of a client message, or looks up its `preset` in `presets`.
+fn parse_filter<F: FilterFields + DeserializeOwned>(
+    presets: &HashMap<String, serde_json::Value>,
+    text: &str,
+) -> Result<F, String> {
+    #[derive(Deserialize)]
+    struct PresetChoice {
+        preset: Option<String>,
+    }
+    if let Ok(message) = serde_json::from_str(text) {
+        let known = [
+            &F::filter_fields()[..],
+            fields::field_names::<ConnectionOptions>(),
+            &["preset", "protocol"],
+        ]
+        .concat();
+        fields::check(&message, &known)?;
+    }
+    match serde_json::from_str::<PresetChoice>(text) {
+        Ok(PresetChoice { preset: Some(name) }) => match presets.get(&name) {
+            Some(filter) => F::deserialize(filter).map_err(|e| e.to_string()),
+            None => Err(format!("Unknown preset {name}")),
+        },
+        _ => serde_json::from_str::<F>(text).map_err(|e| e.to_string()),
+    }
+}
+
+/// Error of filters without fields on streams with `require_filter`.
+const FILTER_REQUIRED: &str = "This stream requires a filter";
+
+/// Whether a message sets a field of the filter, itself or with its preset.
+fn has_filter<F: FilterFields + DeserializeOwned>(
+    presets: &HashMap<String, serde_json::Value>,
+    text: &str,
+) -> bool {
+    let Ok(serde_json::Value::Object(message)) = serde_json::from_str(text) else {
+        return false;
+    };
+    let filter = match message.get("preset").and_then(serde_json::Value::as_str) {
+        Some(name) => presets.get(name).and_then(serde_json::Value::as_object),
+        None => Some(&message),
+    };
+    filter.is_some_and(|filter| {
+        F::filter_fields()
+            .iter()
+            .any(|field| filter.get(*field).is_some_and(|value| !value.is_null()))
+    })
+}
+
+/// Turns a filter command into a whole message, changing the last applied
+/// `message`, or returns `None` if the text isn't a command.
+fn filter_command<F: FilterFields + DeserializeOwned>(
+    message: &serde_json::Map<String, serde_json::Value>,
+    presets: &HashMap<String, serde_json::Value>,
+    text: &str,
+) -> Result<Option<String>, String> {
+    let Ok(serde_json::Value::Object(object)) = serde_json::from_str(text) else {
+        return Ok(None);
+    };
+    if object.len() != 1
+        || !object
+            .keys()
+            .any(|key| FilterCommand::NAMES.contains(&key.as_str()))
+    {
+        return Ok(None);
+    }
+    let command =
+        serde_json::from_value(serde_json::Value::Object(object)).map_err(|e| e.to_string())?;
+
+    let filter_fields = F::filter_fields();
+    let is_filter = |key: &str| key == "preset" || filter_fields.contains(&key);
+    let mut message = message.clone();
+    match command {
+        FilterCommand::Set(filter) => {
+            message.retain(|key, _| !is_filter(key));
+            message.extend(filter);
+        }
+        FilterCommand::Update(changes) => {
+            // Changes to a preset apply to the fields of the preset
+            if let Some(serde_json::Value::String(name)) = message.remove("preset") {
+                if let Some(serde_json::Value::Object(filter)) = presets.get(&name) {
+                    message.extend(filter.clone());
+                }
+            }
+            for (key, value) in changes {
+                if value.is_null() {
+                    message.remove(&key);
+                } else {
+                    message.insert(key, value);
+                }
+            }
+        }
+        FilterCommand::Clear(true) => message.retain(|key, _| !is_filter(key)),
+        FilterCommand::Clear(false) => {}
+    }
+    Ok(Some(serde_json::Value::Object(message).to_string()))
+}
+
+/// Applies a filter message from the upgrade request, or the last filter message
+/// of the client's API key, without an answer.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct InitialFilter(String);
+
+impl<
+        E: Serialize + FromRedis + Send + Unpin + 'static,
+        F: EventFilter<E> + DeserializeOwned + Unpin + 'static,
+    > Handler<InitialFilter> for EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: InitialFilter, ctx: &mut Self::Context) -> Self::Result {
+        if let Err(err) = self.apply(&msg.0, ctx) {
+            self.span
+                .in_scope(|| tracing::warn!("Failed to apply the initial filter: {err}"));
+        }
+        self.send_session(ctx);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Event<E> {
+    source: Arc<str>,
+    id: StreamId,
+    /// This stream entry was already delivered recently, e.g. after the reader
+    /// restarted from an older checkpoint.
+    duplicate: bool,
+    event: E,
+    /// Stays open until every socket has handled the event, `Span::none()` for replays.
+    span: tracing::Span,
+    frames: FrameCache,
+    /// The event as the indexer wrote it, for streams with `passthrough`.
+    raw: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TaggedEvent<'a, E> {
+    source: &'a str,
+    stream_id: StreamId,
+    #[serde(flatten)]
+    event: &'a E,
+}
+
+impl<E: Serialize + FromRedis + Send + Unpin + 'static, F: EventFilter<E> + Unpin + 'static>
+    Handler<Arc<Event<E>>> for EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Arc<Event<E>>, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(buffer) = &mut self.replay_buffer {
+            buffer.push(msg);
+            return;
+        }
+        self.deliver(&msg, ctx);
+    }
+}
+
+impl<E: Serialize + FromRedis + Send + Unpin + 'static, F: EventFilter<E> + Unpin + 'static>
+    EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    /// Starts sending unacked events again with the current options, or stops and
+    /// forgets them.
+    fn restart_acks(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some(timer) = self.ack_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        let Some(timeout) = self.options.ack_timeout_ms else {
+            self.acks = Acks::default();
+            return;
+        };
+        let timeout = Duration::from_millis(timeout);
+        let interval = (timeout / 4).max(Duration::from_millis(10));
+        self.ack_timer = Some(ctx.run_interval(interval, move |act, ctx| {
+            let due = act
+                .acks
+                .due(timeout, Instant::now())
+                .into_iter()
+                .map(|(id, (event, conflated_count))| (id, Arc::clone(event), *conflated_count))
+                .collect::<Vec<_>>();
+            for (id, event, conflated_count) in due {
+                act.write(&event, conflated_count, Some(id), ctx);
+            }
+            act.save_cursor();
+        }));
+    }
+
+    fn deliver(&mut self, event: &Arc<Event<E>>, ctx: &mut <Self as Actor>::Context) {
+        self.last_id = Some(event.id);
+        if event.duplicate && self.options.exactly_once_window {
+            return;
+        }
+        if let Some(max_age_ms) = self.options.max_age_ms {
+            let block_time_ms = event.event.head().block_timestamp_nanosec / 1_000_000;
+            // Events without a timestamp can't be stale
+            if block_time_ms != 0 && block_time_ms + u128::from(max_age_ms) < unix_time_ms() {
+                return;
+            }
+        }
+        if let Some(active_hours) = &self.options.active_hours {
+            if !active_hours.contains(time::OffsetDateTime::now_utc()) {
+                return;
+            }
+        }
+        let matches = match (&self.filter, &self.filter_key) {
+            (Some(filter), Some(key)) => event.frames.matches(key, || filter.matches(&event.event)),
+            (Some(filter), None) => filter.matches(&event.event),
+            (None, _) => true,
+        };
+        if !matches {
+            return;
+        }
+        if let Some(group) = &self.group {
+            if !event
+                .frames
+                .is_turn_of(group, &self.connection_id, || groups::next_member(group))
+            {
+                return;
+            }
+        }
+        if let Some(rate) = self.options.sample_rate {
+            if !sampling::sampled(rate, self.options.sample_deterministic, event.id) {
+                return;
+            }
+        }
+        if let Some(options) = &self.options.stats {
+            self.stats
+                .add(options, &serde_json::to_value(&event.event).unwrap());
+            return;
+        }
+        if let Some(conflate_ms) = self.options.conflate_ms {
+            let name = self.options.conflate_key.as_deref().or(E::CONFLATION_KEY);
+            let key = name.and_then(|name| {
+                conflation::key(&serde_json::to_value(&event.event).unwrap(), name)
+            });
+            // Events without the key aren't conflated
+            if let Some(key) = key {
+                if self.conflation.add(key, Arc::clone(event)) {
+                    ctx.run_later(Duration::from_millis(conflate_ms), |act, ctx| {
+                        for (event, count) in act.conflation.take() {
+                            act.send(&event, Some(count), ctx);
+                        }
+                    });
+                }
+                return;
+            }
+        }
+        self.send(event, None, ctx);
+    }
+
+    /// Writes a matching event to the socket, or adds it to the batch, and waits
+    /// for its ack if `ack_timeout_ms` is set. Conflated events have a
+    /// `conflated_count`, so they aren't shared with other sockets.
+    fn send(
+        &mut self,
+        event: &Arc<Event<E>>,
+        conflated_count: Option<u64>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if let Some((client, max_events_per_sec)) = &self.event_limit {
+            if let Err(dropped) = plans::take_event(client, *max_events_per_sec, unix_time_ms()) {
+                // Once per second, for the first dropped event, and only in protocol 2
+                if dropped == 1 && self.protocol != Protocol::V1 {
+                    let message = format!(
+                        "Events over the limit of {max_events_per_sec} per second are dropped"
+                    );
+                    let frame = ControlFrame::Warning { message: &message };
+                    ctx.text(serde_json::to_string(&frame).unwrap());
+                }
+                return;
+            }
+        }
+        if let Some(audit) = &mut self.audit {
+            audit.record(event.id);
+        }
+        let ack_id = self.options.ack_timeout_ms.map(|_| {
+            let (ack_id, given_up) = self
+                .acks
+                .add((Arc::clone(event), conflated_count), Instant::now());
+            if let Some((given_up, _)) = given_up {
+                let id = given_up.id;
+                self.given_up = Some(self.given_up.map_or(id, |oldest| oldest.min(id)));
+            }
+            ack_id
+        });
+        self.write(event, conflated_count, ack_id, ctx);
+    }
+
+    /// Writes an event frame, also when it's sent again because it wasn't acked.
+    fn write(
+        &mut self,
+        event: &Event<E>,
+        conflated_count: Option<u64>,
+        ack_id: Option<u64>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let _span = (!event.span.is_none())
+            .then(|| tracing::debug_span!(parent: &event.span, "frame_write").entered());
+        let fields = [("conflated_count", conflated_count), ("ack_id", ack_id)];
+        match self.protocol {
+            Protocol::V1 => {
+                let frame = match &event.raw {
+                    Some(raw) => {
+                        let frame = event
+                            .frames
+                            .tagged(|| passthrough::tagged(&event.source, event.id, raw));
+                        passthrough::with_fields(&frame, &fields, self.options.key_style)
+                            .unwrap_or(frame)
+                    }
+                    None => {
+                        let tagged = TaggedEvent {
+                            source: &event.source,
+                            stream_id: event.id,
+                            event: &event.event,
+                        };
+                        frame_cache::with_fields(&tagged, &fields, self.options.key_style)
+                            .unwrap_or_else(|| {
+                                event
+                                    .frames
+                                    .tagged(|| serde_json::to_string(&tagged).unwrap())
+                            })
+                    }
+                };
+                let frame = self.signed(frame);
+                self.record_usage(&frame);
+                ctx.text(frame);
+            }
+            Protocol::V2 => {
+                let envelope = match &event.raw {
+                    Some(raw) => {
+                        let envelope = event
+                            .frames
+                            .envelope(|| passthrough::envelope(&event.source, event.id, raw));
+                        passthrough::with_fields(&envelope, &fields, self.options.key_style)
+                            .unwrap_or(envelope)
+                    }
+                    None => {
+                        let envelope = Envelope {
+                            source: &event.source,
+                            stream_id: event.id,
+                            event: &event.event,
+                        };
+                        frame_cache::with_fields(&envelope, &fields, self.options.key_style)
+                            .unwrap_or_else(|| {
+                                event
+                                    .frames
+                                    .envelope(|| serde_json::to_string(&envelope).unwrap())
+                            })
+                    }
+                };
+                let envelope = self.signed(envelope);
+                self.record_usage(&envelope);
+                let Some(batch_ms) = self.options.batch_ms else {
+                    ctx.text(envelope);
+                    return;
+                };
+                self.batch.push(envelope);
+                if self.batch.len() >= MAX_BATCH_EVENTS {
+                    self.flush(ctx);
+                } else if self.batch.len() == 1 {
+                    ctx.run_later(Duration::from_millis(batch_ms), |act, ctx| act.flush(ctx));
+                }
+            }
+        }
+    }
+
+    /// Appends the signature to an event frame, for API keys with a
+    /// `signing_secret`.
+    fn signed(&self, frame: ByteString) -> ByteString {
+        match &self.signing_key {
+            Some(key) => signing::sign(key, &frame),
+            None => frame,
+        }
+    }
+
+    /// Counts a delivered event for the API key of the connection.
+    fn record_usage(&self, frame: &str) {
+        if let Some(api_key) = &self.api_key {
+            usage::record(api_key, frame.len());
+        }
+    }
+
+    fn flush(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if !self.batch.is_empty() {
+            ctx.text(batch_frame(&std::mem::take(&mut self.batch)));
+        }
+    }
+}
+
+/// A message from the server operators, e.g. about maintenance. Sent to clients of
+/// protocol 2 as `{"type": "notice", "message": ...}`, serialized once for all of
+/// them.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Notice(ByteString);
+
+impl Notice {
+    fn new(message: &str) -> Self {
+        #[derive(Serialize)]
+        #[serde(tag = "type", rename = "notice")]
+        struct NoticeFrame<'a> {
+            message: &'a str,
+        }
+        Self(
+            serde_json::to_string(&NoticeFrame { message })
+                .unwrap()
+                .into(),
+        )
+    }
+}
+
+impl<E: Unpin + 'static, F: EventFilter<E> + Unpin + 'static> Handler<Arc<Notice>>
+    for EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Arc<Notice>, ctx: &mut Self::Context) -> Self::Result {
+        if self.protocol != Protocol::V1 {
+            ctx.text(msg.0.clone());
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct StartReplay(ReplayStart);
+
+impl<
+        E: Serialize + FromRedis + Send + Sync + Unpin + 'static,
+        F: EventFilter<E> + Unpin + 'static,
+    > Handler<StartReplay> for EventWebSocket<E, F>
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>> + Handler<ReadReplay<E>>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: StartReplay, ctx: &mut Self::Context) -> Self::Result {
+        self.server
+            .send(ReadReplay {
+                network: self.network.clone(),
+                start: msg.0,
+                _marker: PhantomData,
+            })
+            .into_actor(self)
+            .map(|result, act, ctx| {
+                let _span = act.span.clone().entered();
+                match result {
+                    // Live events stay buffered until the replay caught up with them
+                    Ok(Ok(ReplayPage {
+                        events,
+                        next: Some(next),
+                    })) => {
+                        skip_replayed(&events, act.replay_buffer.get_or_insert_default());
+                        for event in events {
+                            act.deliver(&event, ctx);
+                        }
+                        ctx.notify(StartReplay(ReplayStart::After(next)));
+                    }
+                    Ok(Ok(ReplayPage { events, next: None })) => {
+                        let buffered = act.replay_buffer.take().unwrap_or_default();
+                        for event in handover(events, buffered) {
+                            act.deliver(&event, ctx);
+                        }
+                    }
+                    Ok(Err(err)) => {
+                        tracing::error!("Failed to replay {}: {err}", E::STREAM_KEY);
+                        ctx.close(Some(ws::CloseReason {
+                            code: ws::CloseCode::Error,
+                            description: Some("Failed to replay events".to_string()),
+                        }));
+                        ctx.stop();
+                    }
+                    Err(err) => {
+                        tracing::error!("Failed to replay {}: {err}", E::STREAM_KEY);
+                        ctx.stop();
+                    }
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+/// Reads a page of the events requested by a replay from all sources of the
+/// network. Replays `After` an ID continue with the `next` page until there is none.
+#[derive(Message)]
+#[rtype(result = "anyhow::Result<ReplayPage<E>>")]
+struct ReadReplay<E: 'static> {
+    network: String,
+    start: ReplayStart,
+    _marker: PhantomData<E>,
+}
+
+impl<E: FromRedis + Send + Sync + 'static> Handler<ReadReplay<E>> for Server {
+    type Result = ResponseFuture<anyhow::Result<ReplayPage<E>>>;
+
+    fn handle(&mut self, msg: ReadReplay<E>, _ctx: &mut Self::Context) -> Self::Result {
+        let sources = self
+            .redis_sources
+            .iter()
+            .filter(|source| source.network == msg.network && source.reads(E::STREAM_KEY))
+            .map(|source| source.stream_source(E::STREAM_KEY))
+            .collect::<Vec<_>>();
+        let passthrough = self
+            .stream_configs
+            .get(E::STREAM_KEY)
+            .is_some_and(|config| config.passthrough);
+        Box::pin(async move {
+            let mut events = Vec::new();
+            let mut truncated = Vec::new();
+            for StreamSource {
+                name: source,
+                stream_key,
+                connection,
+                encoding,
+            } in sources
+            {
+                let dead_letters = DeadLetters {
+                    connection: connection.clone(),
+                };
+                let entries = read_range(connection, &stream_key, msg.start).await?;
+                let is_full = entries.len() >= MAX_REPLAY_EVENTS;
+                let mut last_id = None;
+                for (id, values) in entries {
+                    let stream_id = id.parse()?;
+                    last_id = Some(stream_id);
+                    // Skipped like the live events, instead of failing the replay
+                    let mut event = match E::decode(&values, encoding) {
+                        Ok(event) => event,
+                        Err(err) => {
+                            dead_letters.push(&source, E::STREAM_KEY, &id, &err, &values);
+                            continue;
+                        }
+                    };
+                    event.enrich(&msg.network);
+                    events.push(Arc::new(Event {
+                        source: Arc::clone(&source),
+                        id: stream_id,
+                        duplicate: false,
+                        event,
+                        span: tracing::Span::none(),
+                        frames: FrameCache::default(),
+                        raw: (passthrough && encoding == Encoding::Json)
+                            .then(|| passthrough::event_json(&values, E::FIELDS))
+                            .flatten(),
+                    }));
+                }
+                if let (ReplayStart::After(_), true, Some(last_id)) = (msg.start, is_full, last_id)
+                {
+                    truncated.push(last_id);
+                }
+            }
+            Ok(ReplayPage::new(events, &truncated))
+        })
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SubscribeToEvents<E: Unpin + 'static, F: EventFilter<E> + Unpin + 'static>(
+    Addr<EventWebSocket<E, F>>,
+    String,
+)
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct UnsubscribeFromEvents<E: Unpin + 'static, F: EventFilter<E> + Unpin + 'static>(
+    Addr<EventWebSocket<E, F>>,
+    String,
+)
+where
+    Server: Handler<UnsubscribeFromEvents<E, F>>;
+
+/// Runs the server, or the command in the arguments, in the actix system of
+/// `main`.
+pub async fn run() -> std::io::Result<()> {
+    dotenvy::dotenv().ok();
+    status::mark_started();
+    // Reports are sent until the guard is dropped at the end of run
+    let reporter = reporting::init().expect("Failed to start error reporter");
+    let reports_errors = reporter.is_some();
+    logging::init(
+        match std::env::var("LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        },
+        LevelFilter::INFO,
+        otlp::init().expect("Failed to start OTLP exporter"),
+        reports_errors,
+    );
+    if reports_errors {
+        reporting::log_panics();
+    }
+
+    let config = Config::load();
+    let disabled_streams = Arc::new(
+        config
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.disabled)
+            .map(|(stream_key, _)| stream_key.clone())
+            .collect::<DisabledStreams>(),
+    );
+    let mut redis_sources = Vec::new();
+    let mut networks = HashMap::new();
+    for source in config.redis_sources {
+        networks
+            .entry(source.network.clone())
+            .or_insert_with(NetworkSockets::default);
+        redis_sources.push(RedisSource {
+            name: source.name.into(),
+            network: source.network,
+            stream_prefix: source
+                .stream_prefix
+                .unwrap_or_else(|| config.stream_prefix.clone()),
+            connection: create_connection(&source.url).await,
+            url: source.url,
+            streams: source.streams,
+            disabled: Arc::clone(&disabled_streams),
+            encodings: config
+                .streams
+                .iter()
+                .map(|(stream_key, stream)| (stream_key.clone(), stream.encoding))
+                .chain(source.encodings)
+                .collect(),
+        });
+    }
+    if let Some(args) = fixtures::record_args() {
+        fixtures::record(&redis_sources, &config.streams, args).await;
+        return Ok(());
+    }
+    let origin = if let Some(rate) = synthetic::rate() {
+        tracing::warn!("Serving synthetic events instead of reading Redis");
+        EventOrigin::Synthetic(rate)
+    } else if let Some(recording) = fixtures::replay_args() {
+        tracing::warn!("Replaying a recording instead of reading Redis");
+        EventOrigin::Replay(Arc::new(recording))
+    } else if let Some(upstream) = config.firehose_upstream {
+        tracing::warn!("Reading the firehose of {} instead of Redis", upstream.url);
+        EventOrigin::Firehose(firehose::Upstream::spawn(upstream))
+    } else {
+        stream_checks::validate(&redis_sources, &config.streams, config.require_streams).await;
+        EventOrigin::Redis
+    };
+    let network_names = networks.keys().cloned().collect::<Networks>();
+    // Saved filters aren't specific to a network, so they're kept in the first source
+    let saved_filters = web::Data::new(SavedFilters {
+        connection: redis_sources[0].connection.clone(),
+    });
+    let archive = config.archive.map(|config| {
+        let archive = Arc::new(archive::Archive::new(config).expect("Invalid archive config"));
+        archive::spawn(&archive, &redis_sources);
+        web::Data::from(archive)
+    });
+    presets::validate(&config.presets);
+    let firehose_keys = config.api_keys.iter().any(|key| key.firehose);
+    let plans = web::Data::new(Plans::new(config.plans, &config.api_keys));
+    let abuse_control = config.anonymous.map(|config| {
+        web::Data::new(AbuseControl::new(
+            config,
+            redis_sources[0].connection.clone(),
+        ))
+    });
+    let near_auth = config
+        .near_auth
+        .map(|config| web::Data::new(NearAuth::new(config, redis_sources[0].connection.clone())));
+    let api_keys = web::Data::new(api_keys::ApiKeys::new(config.api_keys));
+    let presets = config.presets;
+    let filter_required = config
+        .streams
+        .iter()
+        .filter(|(_, config)| config.require_filter)
+        .map(|(stream_key, _)| stream_key.clone())
+        .collect::<FilterRequired>();
+    let proof_of_work = config
+        .streams
+        .iter()
+        .filter_map(|(stream_key, config)| {
+            let bits = config.proof_of_work_bits?;
+            assert!(
+                bits <= proof_of_work::MAX_BITS,
+                "proof_of_work_bits of {stream_key} is over {}",
+                proof_of_work::MAX_BITS
+            );
+            Some((stream_key.clone(), bits))
+        })
+        .collect::<ProofOfWork>();
+    let deprecations = config
+        .streams
+        .iter()
+        .filter_map(|(stream_key, config)| Some((stream_key.clone(), config.deprecated.clone()?)))
+        .collect::<Deprecations>();
+    let broadcasts = Arc::new(if firehose_keys {
+        Broadcasts::with_firehose()
+    } else {
+        Broadcasts::default()
+    });
+    let usage = web::Data::new(usage::Usage::spawn(redis_sources[0].connection.clone()));
+    let subscriptions = web::Data::from(subscriptions::Subscriptions::spawn(
+        redis_sources[0].connection.clone(),
+        redis_sources[0].url.clone(),
+        Arc::clone(&broadcasts),
+    ));
+    let collection_stats = web::Data::from(collection_stats::CollectionStats::spawn(
+        &network_names,
+        redis_sources
+            .iter()
+            .filter(|source| source.reads(FullNftTransferEvent::STREAM_KEY))
+            .map(|source| collection_stats::BackfillSource {
+                network: source.network.clone(),
+                source: source.stream_source(FullNftTransferEvent::STREAM_KEY),
+            })
+            .collect(),
+        &broadcasts,
+    ));
+    let mut features = Vec::new();
+    if cfg!(feature = "explorer") {
+        features.push("explorer");
+    }
+    if firehose_keys {
+        features.push("firehose");
+    }
+    if let Ok(address) = std::env::var("GRPC_BIND_ADDRESS") {
+        features.push("grpc");
+        let broadcasts = Arc::clone(&broadcasts);
+        let networks = Arc::new(network_names.clone());
+        tokio::spawn(async move {
+            if let Err(err) = grpc::serve(address, broadcasts, networks).await {
+                tracing::error!("gRPC API stopped: {err}");
+            }
+        });
+    }
+    let (h2_bridge, h2_listener) = match std::env::var("H2_BIND_ADDRESS") {
+        Ok(address) => {
+            features.push("h2_websocket");
+            let (bridge, listener) = h2_websocket::bind_upstream()?;
+            let upstream = bridge.upstream;
+            tokio::spawn(async move {
+                if let Err(err) = h2_websocket::serve(address, upstream).await {
+                    tracing::error!("WebSockets over HTTP/2 stopped: {err}");
+                }
+            });
+            Some((web::Data::new(bridge), listener))
+        }
+        Err(_) => None,
+    }
+    .unzip();
+    if let Some(nats) = config.nats {
+        features.push("nats");
+        nats::spawn(nats, &broadcasts);
+    }
+    if let Some(kafka) = config.kafka {
+        features.push("kafka");
+        kafka::spawn(kafka, &broadcasts);
+    }
+    if let Some(mqtt) = config.mqtt {
+        features.push("mqtt");
+        mqtt::spawn(mqtt, &broadcasts);
+    }
+    if !config.webhooks.is_empty() {
+        features.push("webhooks");
+        webhooks::spawn(config.webhooks, &broadcasts);
+    }
+    let sessions = config.session_secret.map(|secret| {
+        features.push("sessions");
+        web::Data::new(Sessions::new(&secret))
+    });
+    if let Some(pool_metadata) = config.pool_metadata {
+        features.push("pool_metadata");
+        pool_metadata::configure(pool_metadata);
+    }
+    if !config.digests.is_empty() {
+        features.push("digests");
+        let smtp = config.smtp.expect("Digests are configured without smtp");
+        digests::spawn(config.digests, smtp, &broadcasts);
+    }
+    let server = Server {
+        redis_sources,
+        stream_configs: config.streams,
+        checkpoints: Checkpoints {
+            prefix: config
+                .checkpoint_prefix
+                .unwrap_or("events_api_websocket_last_id_".to_string()),
+            fresh: std::env::args().any(|arg| arg == "--fresh"),
+        },
+        networks,
+        shutdown: watch::channel(false).0,
+        readers: Vec::new(),
+        broadcasts: Arc::clone(&broadcasts),
+        origin,
+    };
+    let server_addr = server.start();
+
+    let tls_config = if let Ok(files) = std::env::var("SSL") {
+        #[allow(clippy::iter_nth_zero)]
+        let mut certs_file = BufReader::new(File::open(files.split(',').nth(0).unwrap()).unwrap());
+        let mut key_file = BufReader::new(File::open(files.split(',').nth(1).unwrap()).unwrap());
+        let tls_certs = rustls_pemfile::certs(&mut certs_file)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let tls_key = rustls_pemfile::pkcs8_private_keys(&mut key_file)
+            .next()
+            .unwrap()
+            .unwrap();
+        Some(
+            rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(tls_certs, rustls::pki_types::PrivateKeyDer::Pkcs8(tls_key))
+                .unwrap(),
+        )
+    } else {
+        None
+    };
+
+    let admin_token = admin::AdminToken::from_env();
+    if admin_token.is_none() {
+        tracing::info!("ADMIN_TOKEN is not set, admin API is disabled");
+    }
+    let admin_token = admin_token.map(web::Data::new);
+    for (enabled, feature) in [
+        (archive.is_some(), "archive"),
+        (tls_config.is_some(), "tls"),
+        (admin_token.is_some(), "admin"),
+        (cfg!(feature = "pprof") && admin_token.is_some(), "pprof"),
+    ] {
+        if enabled {
+            features.push(feature);
+        }
+    }
+    let features = web::Data::new(version::Features(features));
+    let drain = web::Data::new(drain::Drain::new(config.drain_reconnect_url));
+    let http_drain = drain.clone();
+
+    let http_server_addr = server_addr.clone();
+    let http_broadcasts = web::Data::from(Arc::clone(&broadcasts));
+    let leaderboards = web::Data::new(leaderboard::Leaderboards::new(Arc::clone(&broadcasts)));
+    let http_config = web::Data::new(config.http.clone());
+    let mut server = HttpServer::new(move || {
+        let cors = Cors::default()
+            .allow_any_origin()
+            .allowed_methods(vec!["GET", "POST"])
+            .max_age(3600)
+            .supports_credentials();
+
+        let api_v0 = web::scope("/v0")
+            .service(web::resource("/filters").route(web::post().to(filters::save)))
+            .configure(subscriptions::services)
+            .service(web::resource("/usage").route(web::get().to(usage::usage)))
+            .service(web::resource("/auth/near").route(web::get().to(near_auth::challenge)))
+            .configure(event_services)
+            .service(web::scope("/{network}").configure(event_services));
+        let api_v1 = web::scope("/v1")
+            .app_data(ApiVersion::V1)
+            .service(web::resource("/filters").route(web::post().to(filters::save)))
+            .configure(subscriptions::services)
+            .service(web::resource("/usage").route(web::get().to(usage::usage)))
+            .service(web::resource("/auth/near").route(web::get().to(near_auth::challenge)))
+            .configure(event_services)
+            .service(web::scope("/{network}").configure(event_services));
+
+        let mut app = App::new()
+            .app_data(web::Data::new(http_server_addr.clone()))
+            .app_data(web::Data::new(network_names.clone()))
+            .app_data(web::Data::new(deprecations.clone()))
+            .app_data(web::Data::new(filter_required.clone()))
+            .app_data(web::Data::new(proof_of_work.clone()))
+            .app_data(web::Data::new(presets.clone()))
+            .app_data(web::Data::from(Arc::clone(&disabled_streams)))
+            .app_data(http_drain.clone())
+            .app_data(http_config.clone())
+            .app_data(http_broadcasts.clone())
+            .app_data(leaderboards.clone())
+            .app_data(collection_stats.clone())
+            .app_data(saved_filters.clone())
+            .app_data(subscriptions.clone())
+            .app_data(usage.clone())
+            .app_data(api_keys.clone())
+            .app_data(plans.clone())
+            .app_data(features.clone())
+            .service(api_v0)
+            .service(api_v1)
+            .service(web::resource("/metrics").route(web::get().to(metrics::metrics)))
+            .service(web::resource("/status").route(web::get().to(status::status)))
+            .service(web::resource("/version").route(web::get().to(version::version)));
+        if let Some(near_auth) = &near_auth {
+            app = app.app_data(near_auth.clone());
+        }
+        if let Some(abuse_control) = &abuse_control {
+            app = app.app_data(abuse_control.clone());
+        }
+        if let Some(archive) = &archive {
+            app = app.app_data(archive.clone());
+        }
+        if let Some(sessions) = &sessions {
+            app = app.app_data(sessions.clone());
+        }
+        if let Some(bridge) = &h2_bridge {
+            app = app.app_data(bridge.clone());
+        }
+        #[cfg(feature = "explorer")]
+        {
+            app = app.service(web::scope("/explorer").configure(explorer::services));
+        }
+        if let Some(admin_token) = &admin_token {
+            app = app.service(
+                web::scope("/admin")
+                    .app_data(admin_token.clone())
+                    .configure(admin::services),
+            );
+            #[cfg(feature = "pprof")]
+            {
+                app = app.service(
+                    web::scope("/debug/pprof")
+                        .app_data(admin_token.clone())
+                        .configure(profiling::services),
+                );
+            }
+        }
+        app.wrap(cors)
+            .wrap(TracingLogger::<logging::AccessLog>::new())
+    });
+
+    let http = config.http;
+    if let Some(workers) = http.workers {
+        server = server.workers(workers);
+    }
+    if let Some(backlog) = http.backlog {
+        server = server.backlog(backlog);
+    }
+    if let Some(max_connections) = http.max_connections {
+        server = server.max_connections(max_connections);
+    }
+    if let Some(keep_alive) = http.keep_alive_sec {
+        server = server.keep_alive(match keep_alive {
+            0 => KeepAlive::Disabled,
+            secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+        });
+    }
+    if let Some(timeout) = http.client_request_timeout_ms {
+        server = server.client_request_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(timeout) = http.client_disconnect_timeout_ms {
+        server = server.client_disconnect_timeout(Duration::from_millis(timeout));
+    }
+
+    for listener in listeners::bind(&listeners::addresses(&http.bind_addresses), http.backlog)? {
+        server = if let Some(tls_config) = &tls_config {
+            server.listen_rustls_0_22(listener, tls_config.clone())?
+        } else {
+            server.listen(listener)?
+        };
+    }
+    if let Some(listener) = h2_listener {
+        // Plain HTTP/1.1 on loopback, even with TLS on the other listeners
+        server = server.listen(listener)?;
+    }
+    let server = match std::env::var("BIND_UDS").ok().or(http.bind_uds) {
+        #[cfg(unix)]
+        Some(path) => {
+            // A socket file left behind by a previous run would make the bind fail
+            if let Err(err) = std::fs::remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err);
+                }
+            }
+            tracing::info!("Listening on Unix socket {path}");
+            server.bind_uds(path)?
+        }
+        #[cfg(not(unix))]
+        Some(_) => panic!("BIND_UDS is only supported on Unix"),
+        None => server,
+    };
+
+    let server = server.run();
+    drain.set_handle(server.handle());
+    let result = server.await;
+    tracing::info!("Stopping stream readers");
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, server_addr.send(StopReaders))
+        .await
+        .is_err()
+    {
+        tracing::warn!("Stream readers didn't stop in {SHUTDOWN_TIMEOUT:?}");
+    }
+    result
+}
+
+fn event_services(cfg: &mut web::ServiceConfig) {
+    let nft = web::scope("/nft")
+        .service(web::resource("/nft_mint").route(web::get().to(nft_events::nft_mint)))
+        .configure(rest_services::<FullNftMintEvent, NftMintFilter>)
+        .service(web::resource("/nft_transfer").route(web::get().to(nft_events::nft_transfer)))
+        .configure(rest_services::<FullNftTransferEvent, NftTransferFilter>)
+        .service(web::resource("/nft_burn").route(web::get().to(nft_events::nft_burn)))
+        .configure(rest_services::<FullNftBurnEvent, NftBurnFilter>)
+        .service(
+            web::resource("/collection_stats")
+                .route(web::get().to(collection_stats::collection_stats)),
+        );
+
+    let potlock =
+        web::scope("/potlock")
+            .service(
+                web::resource("/potlock_donation")
+                    .route(web::get().to(potlock_events::potlock_donation)),
+            )
+            .configure(rest_services::<FullPotlockDonationEvent, PotlockDonationEventFilter>)
+            .service(
+                web::resource("/potlock_pot_project_donation")
+                    .route(web::get().to(potlock_events::potlock_pot_project_donation)),
+            )
+            .configure(
+                rest_services::<
+                    FullPotlockPotProjectDonationEvent,
+                    PotlockPotProjectDonationEventFilter,
+                >,
+            )
+            .service(
+                web::resource("/potlock_pot_donation")
+                    .route(web::get().to(potlock_events::potlock_pot_donation)),
+            )
+            .configure(rest_services::<FullPotlockPotDonationEvent, PotlockPotDonationEventFilter>);
+
+    let trade = web::scope("/trade")
+        .service(web::resource("/trade_pool").route(web::get().to(trade_events::trade_pool)))
+        .configure(rest_services::<FullTradePoolEvent, TradePoolEventFilter>)
+        .service(web::resource("/trade_swap").route(web::get().to(trade_events::trade_swap)))
+        .configure(rest_services::<FullTradeSwapEvent, TradeSwapEventFilter>)
+        .service(
+            web::resource("/trade_pool_change")
+                .route(web::get().to(trade_events::trade_pool_change)),
+        )
+        .configure(rest_services::<FullTradePoolChangeEvent, TradePoolChangeEventFilter>);
+
+    cfg.service(web::resource("/streams").route(web::get().to(streams::streams)))
+        .service(web::resource("/firehose").route(web::get().to(firehose::firehose)))
+        .service(web::resource("/me/activity").route(web::get().to(activity::activity)))
+        .service(web::resource("/leaderboard").route(web::get().to(leaderboard::leaderboard)))
+        .service(web::resource("/tx/{transaction_id}/wait").route(web::get().to(tx_wait::wait)))
+        .service(nft)
+        .service(potlock)
+        .service(trade);
+}
+
+/// HTTP endpoints next to the WebSocket endpoint of every event type, e.g.
+/// `/nft_mint/poll`, `/nft_mint/history`, `/nft_mint/export` and `/nft_mint/sample`.
+fn rest_services<
+    E: Serialize + FromRedis + Send + Sync + 'static,
+    F: EventFilter<E> + DeserializeOwned + 'static,
+>(
+    cfg: &mut web::ServiceConfig,
+) {
+    cfg.service(
+        web::resource(format!("/{}/poll", E::STREAM_KEY)).route(web::get().to(poll::poll::<E, F>)),
+    )
+    .service(
+        web::resource(format!("/{}/history", E::STREAM_KEY))
+            .route(web::get().to(history::history::<E, F>)),
+    )
+    .service(
+        web::resource(format!("/{}/export", E::STREAM_KEY))
+            .route(web::get().to(export::export::<E, F>)),
+    )
+    .service(
+        web::resource(format!("/{}/sample", E::STREAM_KEY))
+            .route(web::get().to(samples::sample::<E>)),
+    );
+}
@@ -0,0 +1,57 @@
+//! TCP listeners of the HTTP server, for any number of addresses.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Pending connections per listener if the `backlog` isn't configured, the same
+/// as actix-web's default.
+const DEFAULT_BACKLOG: u32 = 2048;
+
+/// Addresses from `BIND_ADDRESS` (comma-separated), or the `bind_addresses` of the
+/// config, or `0.0.0.0:3000`.
+pub fn addresses(configured: &[String]) -> Vec<String> {
+    match std::env::var("BIND_ADDRESS") {
+        Ok(addresses) => addresses
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) if !configured.is_empty() => configured.to_vec(),
+        Err(_) => vec!["0.0.0.0:3000".to_string()],
+    }
+}
+
+/// Binds every address. When there are several, IPv6 listeners only accept IPv6
+/// connections, so that `[::]:3000` and `0.0.0.0:3000` can be bound together.
+pub fn bind(addresses: &[String], backlog: Option<u32>) -> io::Result<Vec<TcpListener>> {
+    let mut resolved = Vec::new();
+    for address in addresses {
+        resolved.extend(address.to_socket_addrs()?);
+    }
+    let dual_stack = resolved.len() == 1;
+    resolved
+        .into_iter()
+        .map(|address| listen(address, backlog.unwrap_or(DEFAULT_BACKLOG), dual_stack))
+        .collect()
+}
+
+fn listen(address: SocketAddr, backlog: u32, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    if address.is_ipv6() && !dual_stack {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&address.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    tracing::info!("Listening on {address}");
+    Ok(socket.into())
+}
@@ -0,0 +1,316 @@
+//! Logging with `tracing-subscriber`: events are printed with the fields of all
+//! spans they happened in, as text or JSON lines, and `log` records from
+//! dependencies are turned into events by `tracing-log`. Requests are logged by
+//! `tracing-actix-web`, with the fields of [`AccessLog`].
+//!
+//! When OTLP export is enabled, closed spans are also sent to the exporter,
+//! including `debug` spans that trace the way of every event to the clients.
+//! Errors are reported to Sentry by `sentry-tracing` if `reporting` is enabled.
+
+use std::{
+    fmt::Debug,
+    time::{Instant, SystemTime},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    Error, HttpMessage,
+};
+use sentry::integrations::tracing::{self as sentry_tracing, EventMapping, SentryLayer};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Level, Span, Subscriber,
+};
+use tracing_actix_web::{root_span, DefaultRootSpanBuilder, RootSpanBuilder};
+use tracing_subscriber::{
+    filter::filter_fn, layer::Context, prelude::*, registry::LookupSpan, Layer,
+};
+
+use crate::{
+    connections::{self, CONNECTION_ID_HEADER},
+    otlp::{self, FinishedSpan, SpanSender},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Installs the subscriber for both `tracing` and `log`, exporting spans to
+/// `exporter` if it's set and reporting errors to Sentry if `report_errors`.
+pub fn init(
+    format: LogFormat,
+    level: LevelFilter,
+    exporter: Option<SpanSender>,
+    report_errors: bool,
+) {
+    let printer = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_ansi(false).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+    // Debug spans are only created for the exporter, not to print them
+    let span_level = match exporter {
+        Some(_) => level.max(LevelFilter::DEBUG),
+        None => level,
+    };
+    let exports = Exports { exporter }.with_filter(filter_fn(move |metadata| {
+        if metadata.is_span() {
+            metadata.level() <= &span_level
+        } else {
+            metadata.level() <= &level
+        }
+    }));
+    let subscriber = tracing_subscriber::registry()
+        .with(printer.with_filter(level))
+        .with(exports)
+        .with(report_errors.then(reporter));
+    tracing::subscriber::set_global_default(subscriber).expect("Failed to set tracing subscriber");
+    tracing_log::LogTracer::init_with_filter(match level.into_level() {
+        Some(Level::TRACE) => log::LevelFilter::Trace,
+        Some(Level::DEBUG) => log::LevelFilter::Debug,
+        Some(Level::INFO) => log::LevelFilter::Info,
+        Some(Level::WARN) => log::LevelFilter::Warn,
+        Some(Level::ERROR) => log::LevelFilter::Error,
+        None => log::LevelFilter::Off,
+    })
+    .expect("Failed to set logger");
+}
+
+/// Collects the fields of spans and events, except for the message.
+#[derive(Default)]
+struct FieldVisitor {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() != "message" {
+            self.fields.push((field.name(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() != "message" {
+            self.fields.push((field.name(), format!("{value:?}")));
+        }
+    }
+}
+
+/// What the exporter and the reporter need of a span, kept in its extensions.
+struct SpanData {
+    fields: Vec<(&'static str, String)>,
+    trace_id: u128,
+    /// Random, since the registry reuses the IDs of closed spans.
+    span_id: u64,
+    start: SystemTime,
+}
+
+/// Keeps the fields of spans for the reporter, and sends closed spans to the OTLP
+/// exporter.
+struct Exports {
+    exporter: Option<SpanSender>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Exports {
+    fn on_new_span(&self, attributes: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        attributes.record(&mut visitor);
+        let trace_id = span
+            .parent()
+            .and_then(|parent| {
+                parent
+                    .extensions()
+                    .get::<SpanData>()
+                    .map(|data| data.trace_id)
+            })
+            .unwrap_or_else(otlp::random_trace_id);
+        span.extensions_mut().insert(SpanData {
+            fields: visitor.fields,
+            trace_id,
+            span_id: otlp::random_trace_id() as u64,
+            start: SystemTime::now(),
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                data.fields.extend(visitor.fields);
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(exporter) = &self.exporter else {
+            return;
+        };
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        let parent_span_id = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| data.span_id)
+        });
+        // Dropped if the exporter can't keep up
+        let _ = exporter.try_send(FinishedSpan {
+            trace_id: data.trace_id,
+            span_id: data.span_id,
+            parent_span_id,
+            name: span.name(),
+            start: data.start,
+            end: SystemTime::now(),
+            attributes: data.fields,
+        });
+    }
+}
+
+/// Reports errors to Sentry, with the fields of their spans and their own
+/// fields as tags.
+fn reporter<S: Subscriber + for<'a> LookupSpan<'a>>() -> SentryLayer<S> {
+    sentry_tracing::layer()
+        .span_filter(|_| false)
+        // The mapper replaces the event filter
+        .event_mapper(|event, ctx| {
+            if *event.metadata().level() != Level::ERROR {
+                return EventMapping::Ignore;
+            }
+            let mut report = sentry_tracing::event_from_event(event, &ctx);
+            for span in ctx
+                .event_scope(event)
+                .into_iter()
+                .flat_map(|scope| scope.from_root())
+            {
+                if let Some(data) = span.extensions().get::<SpanData>() {
+                    for (key, value) in &data.fields {
+                        report.tags.insert(key.to_string(), value.clone());
+                    }
+                }
+            }
+            let mut visitor = FieldVisitor::default();
+            event.record(&mut visitor);
+            for (key, value) in visitor.fields {
+                report.tags.insert(key.to_string(), value);
+            }
+            EventMapping::Event(Box::new(report))
+        })
+}
+
+/// When a request started, to log how long it took.
+struct RequestStart(Instant);
+
+/// The span of every request, with the client address (see
+/// `connections::client_addr`), and an access log line when it's done, with the
+/// status, the duration and the ID of the connection that it opened, if any.
+pub struct AccessLog;
+
+impl RootSpanBuilder for AccessLog {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        request
+            .extensions_mut()
+            .insert(RequestStart(Instant::now()));
+        let client_addr = connections::client_addr(request.request());
+        root_span!(
+            request,
+            client_addr = %client_addr,
+            connection_id = tracing::field::Empty,
+        )
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span.clone(), outcome);
+        let Ok(response) = outcome else {
+            return;
+        };
+        let connection_id = response
+            .headers()
+            .get(CONNECTION_ID_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if let Some(connection_id) = connection_id {
+            span.record("connection_id", connection_id);
+        }
+        let duration = response
+            .request()
+            .extensions()
+            .get::<RequestStart>()
+            .map(|start| start.0.elapsed());
+        let request = response.request();
+        span.in_scope(|| {
+            tracing::info!(
+                target: "access",
+                status = response.status().as_u16(),
+                duration = ?duration.unwrap_or_default(),
+                referer = request
+                    .headers()
+                    .get("referer")
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or(""),
+                "{} {}",
+                request.method(),
+                request.uri(),
+            );
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn exports_spans_and_reports_errors() {
+        let (exporter, mut spans) = mpsc::channel(16);
+        let subscriber = tracing_subscriber::registry()
+            .with(Exports {
+                exporter: Some(exporter),
+            })
+            .with(reporter());
+        let reports = sentry::test::with_captured_events(|| {
+            tracing::subscriber::with_default(subscriber, || {
+                let reader = tracing::info_span!("reader", stream = "nft_mint");
+                let _reader = reader.enter();
+                let event = tracing::debug_span!("event", stream_id = "1-0");
+                event.in_scope(|| tracing::error!(field = "mint", "Failed to read"));
+                drop(event);
+                tracing::warn!("Not reported");
+            });
+        });
+
+        let [report] = &reports[..] else {
+            panic!("Expected one report, got {reports:?}");
+        };
+        assert_eq!(report.message.as_deref(), Some("Failed to read"));
+        for (key, value) in [
+            ("stream", "nft_mint"),
+            ("stream_id", "1-0"),
+            ("field", "mint"),
+        ] {
+            assert_eq!(report.tags.get(key).map(String::as_str), Some(value));
+        }
+
+        let event = spans.try_recv().unwrap();
+        let reader = spans.try_recv().unwrap();
+        assert_eq!((event.name, reader.name), ("event", "reader"));
+        assert_eq!(event.trace_id, reader.trace_id);
+        assert_eq!(event.parent_span_id, Some(reader.span_id));
+        assert_eq!(reader.parent_span_id, None);
+    }
+}
@@ -0,0 +1,123 @@
+use std::{fmt::Write, sync::LazyLock, time::Instant};
+
+use actix_web::HttpResponse;
+use dashmap::DashMap;
+
+/// Seconds since the last read ID of a stream was saved.
+pub const CHECKPOINT_AGE: &str = "events_api_checkpoint_age_seconds";
+/// Times that a stream reader failed and was restarted.
+pub const READER_RESTARTS: &str = "events_api_reader_restarts_total";
+/// Stream entries that couldn't be turned into events and were skipped.
+pub const INVALID_ENTRIES: &str = "events_api_invalid_entries_total";
+
+enum Value {
+    /// Rendered as the number of seconds since this instant.
+    Age(Instant),
+    Counter(u64),
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct Key {
+    name: &'static str,
+    labels: Labels,
+}
+
+static METRICS: LazyLock<DashMap<Key, Value>> = LazyLock::new(DashMap::new);
+
+fn key(name: &'static str, labels: &[(&str, &str)]) -> Key {
+    let labels = labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    Key { name, labels }
+}
+
+pub fn set_age(name: &'static str, labels: &[(&str, &str)], since: Instant) {
+    METRICS.insert(key(name, labels), Value::Age(since));
+}
+
+pub fn increment(name: &'static str, labels: &[(&str, &str)]) {
+    let mut value = METRICS
+        .entry(key(name, labels))
+        .or_insert(Value::Counter(0));
+    if let Value::Counter(count) = value.value_mut() {
+        *count += 1;
+    }
+}
+
+pub type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Can go up and down, like the age of a checkpoint.
+    Gauge,
+    /// Only goes up, from 0 when the server started.
+    Counter,
+}
+
+pub struct Sample {
+    pub name: &'static str,
+    pub labels: Labels,
+    pub value: f64,
+    pub kind: Kind,
+}
+
+/// Current values of all metrics, sorted by name and labels.
+pub fn snapshot() -> Vec<Sample> {
+    let mut samples = METRICS
+        .iter()
+        .map(|entry| {
+            let (value, kind) = match entry.value() {
+                Value::Age(since) => (since.elapsed().as_secs_f64(), Kind::Gauge),
+                Value::Counter(count) => (*count as f64, Kind::Counter),
+            };
+            Sample {
+                name: entry.key().name,
+                labels: entry.key().labels.clone(),
+                value,
+                kind,
+            }
+        })
+        .collect::<Vec<_>>();
+    samples.sort_by(|a, b| (a.name, &a.labels).cmp(&(b.name, &b.labels)));
+    samples
+}
+
+/// Prometheus text exposition of all metrics.
+pub fn render() -> String {
+    let mut output = String::new();
+    let mut last_name = "";
+    for Sample {
+        name,
+        labels,
+        value,
+        kind,
+    } in snapshot()
+    {
+        if name != last_name {
+            let kind = match kind {
+                Kind::Gauge => "gauge",
+                Kind::Counter => "counter",
+            };
+            writeln!(output, "# TYPE {name} {kind}").unwrap();
+            last_name = name;
+        }
+        let labels = labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect::<Vec<_>>()
+            .join(",");
+        if labels.is_empty() {
+            writeln!(output, "{name} {value}").unwrap();
+        } else {
+            writeln!(output, "{name}{{{labels}}} {value}").unwrap();
+        }
+    }
+    output
+}
+
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render())
+}
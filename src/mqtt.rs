@@ -0,0 +1,348 @@
+//! Publishes events to an MQTT broker, configured with `mqtt` in the config file.
+//! Every stream has its own topic, and events that have a `contract_id` are
+//! published to a subtopic of it, so subscribers filter with the topic hierarchy:
+//! `events/nft_transfer/#` for all transfers, `events/nft_transfer/<contract_id>`
+//! for the transfers of one collection.
+//!
+//! This is a small MQTT 3.1.1 client that only publishes with QoS 0.
+
+use std::time::Duration;
+
+use actix_web::http::Uri;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter},
+    net::TcpStream,
+    sync::{broadcast::error::RecvError, mpsc},
+};
+
+use crate::{
+    broadcast::{for_each_event_type, Broadcasts, EventReceiver, EventTypeVisitor},
+    config::MqttConfig,
+    EventFilter, FromRedis, TaggedEvent,
+};
+
+/// Messages waiting to be published while the connection is slow or down.
+const MAX_QUEUED_MESSAGES: usize = 10_000;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+struct Message {
+    topic: String,
+    payload: String,
+}
+
+pub fn spawn(config: MqttConfig, broadcasts: &Broadcasts) {
+    let (sender, receiver) = mpsc::channel(MAX_QUEUED_MESSAGES);
+    for_each_event_type(&mut Forwarders {
+        config: &config,
+        broadcasts,
+        sender: &sender,
+    });
+    tokio::spawn(publish(config, receiver));
+}
+
+struct Forwarders<'a> {
+    config: &'a MqttConfig,
+    broadcasts: &'a Broadcasts,
+    sender: &'a mpsc::Sender<Message>,
+}
+
+impl EventTypeVisitor for Forwarders<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        if !self
+            .config
+            .streams
+            .as_ref()
+            .is_none_or(|streams| streams.iter().any(|s| s == E::STREAM_KEY))
+        {
+            return;
+        }
+        tokio::spawn(forward(
+            self.broadcasts.subscribe::<E>(&self.config.network),
+            format!("{}/{}", self.config.topic_prefix, E::STREAM_KEY),
+            self.sender.clone(),
+        ));
+    }
+}
+
+async fn forward<E: Serialize + FromRedis>(
+    mut events: EventReceiver<E>,
+    topic: String,
+    sender: mpsc::Sender<Message>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => {
+                tracing::warn!(
+                    "MQTT bridge fell behind, {count} {} events were skipped",
+                    E::STREAM_KEY
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let value = serde_json::to_value(TaggedEvent {
+            source: &event.source,
+            stream_id: event.id,
+            event: &event.event,
+        })
+        .unwrap();
+        let topic = match value["contract_id"].as_str() {
+            // Wildcards and separators can't be part of a topic level
+            Some(contract_id) if !contract_id.contains(['/', '+', '#']) => {
+                format!("{topic}/{contract_id}")
+            }
+            _ => topic.clone(),
+        };
+        let message = Message {
+            topic,
+            payload: value.to_string(),
+        };
+        if sender.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn publish(config: MqttConfig, mut messages: mpsc::Receiver<Message>) {
+    // Not published yet because the connection broke
+    let mut pending = None;
+    loop {
+        match connect_and_publish(&config, &mut messages, &mut pending).await {
+            Ok(()) => return,
+            Err(err) => tracing::warn!("MQTT connection failed: {err}"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn connect_and_publish(
+    config: &MqttConfig,
+    messages: &mut mpsc::Receiver<Message>,
+    pending: &mut Option<Message>,
+) -> anyhow::Result<()> {
+    let uri = config.url.parse::<Uri>()?;
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("No host in MQTT URL"))?;
+    let stream = TcpStream::connect((host, uri.port_u16().unwrap_or(1883))).await?;
+    let (mut reader, writer) = stream.into_split();
+    let mut writer = BufWriter::new(writer);
+
+    let credentials = uri
+        .authority()
+        .and_then(|authority| authority.as_str().rsplit_once('@'))
+        .map(|(credentials, _)| credentials.split_once(':').unwrap_or((credentials, "")));
+    writer.write_all(&connect_packet(credentials)).await?;
+    writer.flush().await?;
+    let mut incoming = Vec::new();
+    let (packet_type, body) = read_packet(&mut reader, &mut incoming).await?;
+    if packet_type != 2 || body.len() != 2 {
+        anyhow::bail!("Expected CONNACK, got packet type {packet_type}");
+    }
+    if body[1] != 0 {
+        anyhow::bail!("Connection refused with return code {}", body[1]);
+    }
+    tracing::info!("Connected to MQTT broker at {host}");
+
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE / 2);
+    loop {
+        let message = match pending.take() {
+            Some(message) => message,
+            None => tokio::select! {
+                message = messages.recv() => match message {
+                    Some(message) => message,
+                    None => return Ok(()),
+                },
+                packet = read_packet(&mut reader, &mut incoming) => {
+                    // Only PINGRESP is expected, QoS 0 publishes aren't acknowledged
+                    let (packet_type, _) = packet?;
+                    if packet_type != 13 {
+                        tracing::debug!("Ignoring MQTT packet type {packet_type}");
+                    }
+                    continue;
+                }
+                _ = keep_alive.tick() => {
+                    // PINGREQ
+                    writer.write_all(&[0xc0, 0]).await?;
+                    writer.flush().await?;
+                    continue;
+                }
+            },
+        };
+        let packet = publish_packet(&message, config.retain);
+        if let Err(err) = writer.write_all(&packet).await {
+            *pending = Some(message);
+            return Err(err.into());
+        }
+        if messages.is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+fn connect_packet(credentials: Option<(&str, &str)>) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    let mut body = Vec::new();
+    put_string(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+    if let Some((_, password)) = credentials {
+        flags |= 0x80;
+        if !password.is_empty() {
+            flags |= 0x40;
+        }
+    }
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    // An empty client ID lets the broker assign one
+    put_string(&mut body, "");
+    if let Some((user, password)) = credentials {
+        put_string(&mut body, user);
+        if !password.is_empty() {
+            put_string(&mut body, password);
+        }
+    }
+    packet(0x10, &body)
+}
+
+fn publish_packet(message: &Message, retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, &message.topic);
+    body.extend_from_slice(message.payload.as_bytes());
+    packet(0x30 | retain as u8, &body)
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+/// Returns the first complete packet in `buffer`, reading more if needed: its type
+/// and everything after the fixed header. Cancel safe, partial packets stay in `buffer`.
+async fn read_packet(
+    reader: &mut (impl AsyncRead + Unpin),
+    buffer: &mut Vec<u8>,
+) -> anyhow::Result<(u8, Vec<u8>)> {
+    loop {
+        let mut length = 0usize;
+        for (i, &byte) in buffer.iter().enumerate().skip(1).take(4) {
+            length |= ((byte & 0x7f) as usize) << (7 * (i - 1));
+            if byte & 0x80 == 0 {
+                let end = i + 1 + length;
+                if buffer.len() >= end {
+                    let packet_type = buffer[0] >> 4;
+                    let body = buffer[i + 1..end].to_vec();
+                    buffer.drain(..end);
+                    return Ok((packet_type, body));
+                }
+                break;
+            }
+            if i == 4 {
+                anyhow::bail!("Malformed remaining length");
+            }
+        }
+        if reader.read_buf(buffer).await? == 0 {
+            anyhow::bail!("Connection closed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_packets() {
+        #[rustfmt::skip]
+        let connect = [
+            0x10, 24,
+            0, 4, b'M', b'Q', b'T', b'T',
+            4,
+            // User name, password and clean session
+            0xc2,
+            0, 60,
+            // Client ID
+            0, 0,
+            0, 4, b'u', b's', b'e', b'r',
+            0, 4, b'p', b'a', b's', b's',
+        ];
+        assert_eq!(connect_packet(Some(("user", "pass"))), connect);
+        assert_eq!(
+            connect_packet(None),
+            [0x10, 12, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 0],
+        );
+        assert_eq!(connect_packet(Some(("token", "")))[9], 0x82);
+
+        let message = Message {
+            topic: "events/nft_mint".to_string(),
+            payload: "{}".to_string(),
+        };
+        let mut publish = vec![0x30, 19, 0, 15];
+        publish.extend_from_slice(b"events/nft_mint{}");
+        assert_eq!(publish_packet(&message, false), publish);
+        publish[0] = 0x31;
+        assert_eq!(publish_packet(&message, true), publish);
+
+        // The examples of the remaining length in the specification
+        for (length, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xff, 0x7f]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let packet = packet(0x30, &vec![0; length]);
+            assert_eq!(&packet[1..=encoded.len()], encoded, "{length}");
+            assert_eq!(packet.len(), 1 + encoded.len() + length);
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_packets() {
+        // CONNACK, PINGRESP and a PUBLISH with a two-byte length
+        let mut publish = vec![0x30, 0x83, 0x01, 0, 1, b't'];
+        publish.extend_from_slice(&[b'x'; 128]);
+        let input = [&[0x20, 2, 0, 0, 0xd0, 0][..], &publish].concat();
+        let mut reader = &input[..];
+        let mut buffer = Vec::new();
+        assert_eq!(
+            read_packet(&mut reader, &mut buffer).await.unwrap(),
+            (2, vec![0, 0])
+        );
+        assert_eq!(
+            read_packet(&mut reader, &mut buffer).await.unwrap(),
+            (13, vec![])
+        );
+        let (packet_type, body) = read_packet(&mut reader, &mut buffer).await.unwrap();
+        assert_eq!((packet_type, body.len()), (3, 131));
+        assert!(read_packet(&mut reader, &mut buffer).await.is_err());
+
+        let mut reader = &[0x30, 0xff, 0xff, 0xff, 0xff, 0x01][..];
+        let err = read_packet(&mut reader, &mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Malformed remaining length");
+    }
+}
@@ -0,0 +1,339 @@
+//! Sign-in with a NEAR wallet, configured with `near_auth` in the config file.
+//! Clients sign a NEP-413 message with a full access key of their account and
+//! connect with the signature, which is checked against the key and the keys of
+//! the account on chain. Signed-in connections aren't anonymous, and get the
+//! `me` preset, the events that involve their account, on streams whose filters
+//! have `involved_account_ids`.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorUnauthorized},
+    web, Error, HttpRequest, HttpResponse,
+};
+use base64::prelude::*;
+use borsh::BorshSerialize;
+use redis::aio::ConnectionManager;
+use ring::{digest, signature};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{config::NearAuthConfig, http_client::Endpoint, types::AccountId};
+
+/// The message that clients sign.
+pub const MESSAGE: &str = "Sign in to the events API";
+/// The preset of signed-in clients.
+pub const ME_PRESET: &str = "me";
+/// `2^31 + 413`, so that signed messages can't be valid transactions.
+const NEP413_TAG: u32 = (1 << 31) + 413;
+/// How long a nonce can be used after it was issued.
+const NONCE_VALIDITY: Duration = Duration::from_secs(5 * 60);
+
+pub struct NearAuth {
+    recipient: String,
+    rpc: HashMap<String, Endpoint>,
+    /// Used nonces are kept in Redis, so that a signature can't be used twice
+    /// on any instance.
+    connection: ConnectionManager,
+}
+
+/// The payload that NEP-413 wallets sign the SHA-256 of.
+#[derive(BorshSerialize)]
+struct Payload<'a> {
+    tag: u32,
+    message: &'a str,
+    nonce: [u8; 32],
+    recipient: &'a str,
+    callback_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NearAuthQuery {
+    near_account_id: Option<AccountId>,
+    /// `ed25519:<base58>`.
+    near_public_key: Option<String>,
+    /// Base64.
+    near_signature: Option<String>,
+    /// Base64, from `GET /v0/auth/near`.
+    near_nonce: Option<String>,
+}
+
+impl NearAuth {
+    /// Panics if an RPC URL is invalid.
+    pub fn new(config: NearAuthConfig, connection: ConnectionManager) -> Self {
+        Self {
+            recipient: config.recipient,
+            rpc: config
+                .rpc_urls
+                .into_iter()
+                .map(|(network, url)| {
+                    let endpoint = Endpoint::parse(&url)
+                        .unwrap_or_else(|err| panic!("Invalid RPC URL {url}: {err}"));
+                    (network, endpoint)
+                })
+                .collect(),
+            connection,
+        }
+    }
+
+    /// Records a nonce as used, and returns whether it wasn't used before.
+    async fn use_nonce(&self, nonce: &[u8; 32]) -> redis::RedisResult<bool> {
+        let set: Option<String> = redis::cmd("SET")
+            .arg(nonce_key(nonce))
+            .arg(1)
+            .arg("NX")
+            .arg("PX")
+            .arg(NONCE_VALIDITY.as_millis() as u64)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(set.is_some())
+    }
+}
+
+fn nonce_key(nonce: &[u8; 32]) -> String {
+    format!("events_api_near_nonce_{}", BASE64_STANDARD.encode(nonce))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The time in milliseconds, then random bytes.
+fn new_nonce(now_ms: u64) -> [u8; 32] {
+    let mut nonce = [0u8; 32];
+    nonce[..8].copy_from_slice(&now_ms.to_be_bytes());
+    getrandom::getrandom(&mut nonce[8..]).expect("Failed to generate a nonce");
+    nonce
+}
+
+fn issued_at(nonce: &[u8; 32]) -> u64 {
+    u64::from_be_bytes(nonce[..8].try_into().unwrap())
+}
+
+/// What a client signs, `GET /v0/auth/near`.
+pub async fn challenge(near_auth: web::Data<NearAuth>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "message": MESSAGE,
+        "recipient": near_auth.recipient,
+        "nonce": BASE64_STANDARD.encode(new_nonce(now_ms())),
+    }))
+}
+
+/// The 32 bytes of an `ed25519:<base58>` public key.
+fn parse_public_key(key: &str) -> Option<[u8; 32]> {
+    decode_base58(key.strip_prefix("ed25519:")?)?
+        .try_into()
+        .ok()
+}
+
+fn decode_base58(text: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+    // Little endian while decoding
+    let mut bytes = Vec::<u8>::new();
+    for c in text.bytes() {
+        let mut carry = ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in &mut bytes {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = text.bytes().take_while(|c| *c == b'1').count();
+    bytes.extend(std::iter::repeat_n(0, zeros));
+    bytes.reverse();
+    Some(bytes)
+}
+
+/// Checks the signature of a NEP-413 message, `Err` with the reason if it's wrong.
+fn verify_signature(
+    account_id: &AccountId,
+    public_key: &[u8; 32],
+    signature: &[u8],
+    nonce: [u8; 32],
+    recipient: &str,
+    now_ms: u64,
+) -> Result<(), &'static str> {
+    let issued_at = issued_at(&nonce);
+    if issued_at > now_ms + 60_000 || issued_at + (NONCE_VALIDITY.as_millis() as u64) < now_ms {
+        return Err("The nonce expired");
+    }
+    let payload = Payload {
+        tag: NEP413_TAG,
+        message: MESSAGE,
+        nonce,
+        recipient,
+        callback_url: None,
+    };
+    let hash = digest::digest(&digest::SHA256, &borsh::to_vec(&payload).unwrap());
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(hash.as_ref(), signature)
+        .map_err(|_| "Invalid signature")?;
+    tracing::debug!("Verified the NEAR signature of {account_id}");
+    Ok(())
+}
+
+/// Whether the key is a full access key of the account, from a `view_access_key`
+/// query.
+fn is_full_access_key(response: &str) -> anyhow::Result<bool> {
+    let response = serde_json::from_str::<Value>(response)?;
+    if let Some(error) = response.get("error") {
+        // Also for keys that don't exist
+        tracing::debug!("view_access_key failed: {error}");
+        return Ok(false);
+    }
+    Ok(response.pointer("/result/permission") == Some(&Value::from("FullAccess")))
+}
+
+/// Returns the NEAR account that the request is signed in with, `None` if it
+/// isn't, or an error if the signature is wrong.
+pub async fn authenticate(req: &HttpRequest, network: &str) -> Result<Option<AccountId>, Error> {
+    let query = web::Query::<NearAuthQuery>::from_query(req.query_string())
+        .map_err(|err| ErrorBadRequest(err.to_string()))?
+        .into_inner();
+    let (Some(account_id), Some(public_key), Some(signature), Some(nonce)) = (
+        query.near_account_id,
+        query.near_public_key,
+        query.near_signature,
+        query.near_nonce,
+    ) else {
+        return Ok(None);
+    };
+    let Some(near_auth) = req.app_data::<web::Data<NearAuth>>() else {
+        return Err(ErrorBadRequest("NEAR sign-in isn't enabled"));
+    };
+    let Some(rpc) = near_auth.rpc.get(network) else {
+        return Err(ErrorBadRequest(format!(
+            "NEAR sign-in isn't available on {network}"
+        )));
+    };
+    let key_bytes = parse_public_key(&public_key)
+        .ok_or_else(|| ErrorBadRequest("Invalid near_public_key, only ed25519 keys work"))?;
+    let signature = BASE64_STANDARD
+        .decode(&signature)
+        .map_err(|_| ErrorBadRequest("Invalid near_signature"))?;
+    let nonce: [u8; 32] = BASE64_STANDARD
+        .decode(&nonce)
+        .ok()
+        .and_then(|nonce| nonce.try_into().ok())
+        .ok_or_else(|| ErrorBadRequest("Invalid near_nonce"))?;
+    let now_ms = now_ms();
+    verify_signature(
+        &account_id,
+        &key_bytes,
+        &signature,
+        nonce,
+        &near_auth.recipient,
+        now_ms,
+    )
+    .map_err(ErrorUnauthorized)?;
+    // Before the RPC, so that concurrent requests with the same signature can't
+    // both get through while it's checked
+    match near_auth.use_nonce(&nonce).await {
+        Ok(true) => {}
+        Ok(false) => return Err(ErrorUnauthorized("The nonce was already used")),
+        Err(err) => {
+            tracing::warn!("Failed to record the nonce of {account_id}: {err}");
+            return Err(ErrorInternalServerError("Failed to check the nonce"));
+        }
+    }
+
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": "near_auth",
+        "method": "query",
+        "params": {
+            "request_type": "view_access_key",
+            "finality": "final",
+            "account_id": account_id,
+            "public_key": public_key,
+        },
+    });
+    let response = rpc
+        .post("", &[], "application/json", &request.to_string())
+        .await
+        .and_then(|response| is_full_access_key(&response));
+    match response {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(ErrorUnauthorized(
+                "The key isn't a full access key of the account",
+            ))
+        }
+        Err(err) => {
+            tracing::warn!("Failed to check the key of {account_id}: {err}");
+            return Err(ErrorInternalServerError("Failed to check the key"));
+        }
+    }
+    Ok(Some(account_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{rand::SystemRandom, signature::KeyPair};
+
+    use super::*;
+
+    #[test]
+    fn verifies_nep413_signatures() {
+        assert_eq!(decode_base58("1112").unwrap(), [0, 0, 0, 1]);
+        assert_eq!(decode_base58("5R").unwrap(), [1, 0]);
+        assert!(decode_base58("0OIl").is_none());
+        assert!(parse_public_key("ed25519:11111111111111111111111111111111").is_some());
+        assert!(parse_public_key("secp256k1:1").is_none());
+
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key: [u8; 32] = pair.public_key().as_ref().try_into().unwrap();
+        let account_id = AccountId::try_from("alice.near").unwrap();
+        let now_ms = 1_700_000_000_000;
+        let nonce = new_nonce(now_ms);
+        let payload = Payload {
+            tag: NEP413_TAG,
+            message: MESSAGE,
+            nonce,
+            recipient: "events.example.com",
+            callback_url: None,
+        };
+        let hash = digest::digest(&digest::SHA256, &borsh::to_vec(&payload).unwrap());
+        let signed = pair.sign(hash.as_ref());
+        let verify = |recipient, now_ms| {
+            verify_signature(
+                &account_id,
+                &public_key,
+                signed.as_ref(),
+                nonce,
+                recipient,
+                now_ms,
+            )
+        };
+        assert_eq!(verify("events.example.com", now_ms + 1000), Ok(()));
+        assert_eq!(
+            verify("other.example.com", now_ms),
+            Err("Invalid signature")
+        );
+        assert_eq!(
+            verify("events.example.com", now_ms + 10 * 60 * 1000),
+            Err("The nonce expired")
+        );
+
+        assert!(is_full_access_key(
+            r#"{"jsonrpc":"2.0","id":"1","result":{"nonce":1,"permission":"FullAccess","block_height":1}}"#
+        )
+        .unwrap());
+        assert!(!is_full_access_key(
+            r#"{"jsonrpc":"2.0","id":"1","result":{"nonce":1,"permission":{"FunctionCall":{}}}}"#
+        )
+        .unwrap());
+        assert!(!is_full_access_key(r#"{"jsonrpc":"2.0","id":"1","error":{}}"#).unwrap());
+    }
+}
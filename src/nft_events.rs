@@ -1,21 +1,30 @@
-use std::{collections::HashMap, marker::PhantomData, time::Instant};
+use std::collections::HashMap;
 
 use actix::prelude::*;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
-use actix_web_actors::ws::WsResponseBuilder;
-use redis::FromRedisValue;
+use borsh::BorshDeserialize;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    AccountId, Balance, BlockHeight, EventFilter, EventWebSocket, FromRedis, NftTokenId, ReceiptId,
-    Server, SubscribeToEvents, TransactionId, UnsubscribeFromEvents,
+    common_filter::CommonFilter,
+    config::Encoding,
+    connect,
+    dead_letters::FromRedisError,
+    fields::FilterFields,
+    heads::Head,
+    redis_field,
+    types::{AccountId, Balance, EventContext, NftTokenId, UnknownFields},
+    EventFilter, FromRedis, Networks, Server, SubscribeToEvents, UnsubscribeFromEvents,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct NftMintEvent {
     pub owner_id: AccountId,
     pub token_ids: Vec<NftTokenId>,
     pub memo: Option<String>,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Message)]
@@ -27,13 +36,18 @@ pub struct FullNftMintEvent {
     pub context: NftEventContext,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct NftTransferEvent {
     pub old_owner_id: AccountId,
     pub new_owner_id: AccountId,
     pub token_ids: Vec<NftTokenId>,
     pub memo: Option<String>,
+    /// Older indexers don't have prices.
+    #[serde(default)]
     pub token_prices_near: Vec<Option<Balance>>,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Message)]
@@ -45,11 +59,14 @@ pub struct FullNftTransferEvent {
     pub context: NftEventContext,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct NftBurnEvent {
     pub owner_id: AccountId,
     pub token_ids: Vec<NftTokenId>,
     pub memo: Option<String>,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Message)]
@@ -61,12 +78,10 @@ pub struct FullNftBurnEvent {
     pub context: NftEventContext,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct NftEventContext {
-    pub transaction_id: TransactionId,
-    pub receipt_id: ReceiptId,
-    pub block_height: BlockHeight,
-    pub block_timestamp_nanosec: String,
+    #[serde(flatten)]
+    pub common: EventContext,
     pub contract_id: AccountId,
 }
 
@@ -74,35 +89,27 @@ pub async fn nft_mint(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullNftMintEvent, NftMintFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
-    )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    connect::<FullNftMintEvent, NftMintFilter>(req, stream, server, networks).await
 }
 
 impl FromRedis for FullNftMintEvent {
-    fn from_redis(values: HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<NftEventContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<NftMintEvent>(&String::from_redis_value(
-                values.get("mint").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullNftMintEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "nft_mint";
+    const FIELDS: &'static [&'static str] = &["context", "mint"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullNftMintEvent {
+            event: redis_field(values, "mint", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.common.head()
     }
 }
 
@@ -110,10 +117,27 @@ impl FromRedis for FullNftMintEvent {
 pub struct NftMintFilter {
     owner_id: Option<AccountId>,
     contract_id: Option<AccountId>,
+    /// Only events of one of these contracts, e.g. the collections of a marketplace.
+    contract_ids: Option<Vec<AccountId>>,
+    #[serde(flatten)]
+    common: CommonFilter,
+}
+
+impl FilterFields for NftMintFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&["owner_id", "contract_id", "contract_ids"])
+    }
 }
 
 impl EventFilter<FullNftMintEvent> for NftMintFilter {
     fn matches(&self, event: &FullNftMintEvent) -> bool {
+        if !self
+            .common
+            .matches(&event.context.common, [&event.event.owner_id])
+        {
+            return false;
+        }
+
         if let Some(owner_id) = &self.owner_id {
             if event.event.owner_id != *owner_id {
                 return false;
@@ -126,6 +150,12 @@ impl EventFilter<FullNftMintEvent> for NftMintFilter {
             }
         }
 
+        if let Some(contract_ids) = &self.contract_ids {
+            if !contract_ids.contains(&event.context.contract_id) {
+                return false;
+            }
+        }
+
         true
     }
 }
@@ -138,7 +168,7 @@ impl Handler<SubscribeToEvents<FullNftMintEvent, NftMintFilter>> for Server {
         msg: SubscribeToEvents<FullNftMintEvent, NftMintFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.nft_mint_sockets.insert(msg.0);
+        self.network(&msg.1).nft_mint_sockets.insert(msg.0);
     }
 }
 
@@ -150,7 +180,7 @@ impl Handler<UnsubscribeFromEvents<FullNftMintEvent, NftMintFilter>> for Server
         msg: UnsubscribeFromEvents<FullNftMintEvent, NftMintFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.nft_mint_sockets.remove(&msg.0);
+        self.network(&msg.1).nft_mint_sockets.remove(&msg.0);
     }
 }
 
@@ -158,61 +188,75 @@ pub async fn nft_transfer(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullNftTransferEvent, NftTransferFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
-    )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    connect::<FullNftTransferEvent, NftTransferFilter>(req, stream, server, networks).await
 }
 
 impl FromRedis for FullNftTransferEvent {
-    fn from_redis(values: HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<NftEventContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<NftTransferEvent>(&String::from_redis_value(
-                values.get("transfer").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullNftTransferEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "nft_transfer";
+    const FIELDS: &'static [&'static str] = &["context", "transfer"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullNftTransferEvent {
+            event: redis_field(values, "transfer", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.common.head()
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NftTransferFilter {
-    involved_account_ids: Option<Vec<AccountId>>,
     old_owner_id: Option<AccountId>,
     new_owner_id: Option<AccountId>,
     contract_id: Option<AccountId>,
+    /// Only events of one of these contracts, e.g. the collections of a marketplace.
+    contract_ids: Option<Vec<AccountId>>,
+    #[serde(flatten)]
+    common: CommonFilter,
+}
+
+impl FilterFields for NftTransferFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&[
+            "old_owner_id",
+            "new_owner_id",
+            "contract_id",
+            "contract_ids",
+        ])
+    }
 }
 
 impl EventFilter<FullNftTransferEvent> for NftTransferFilter {
     fn matches(&self, event: &FullNftTransferEvent) -> bool {
+        if !self.common.matches(
+            &event.context.common,
+            [&event.event.old_owner_id, &event.event.new_owner_id],
+        ) {
+            return false;
+        }
+
         if let Some(contract_id) = &self.contract_id {
             if event.context.contract_id != *contract_id {
                 return false;
             }
         }
 
-        if let Some(involved) = &self.involved_account_ids {
-            if !involved.contains(&event.event.old_owner_id)
-                && !involved.contains(&event.event.new_owner_id)
-            {
+        if let Some(contract_ids) = &self.contract_ids {
+            if !contract_ids.contains(&event.context.contract_id) {
                 return false;
             }
-        } else {
+        }
+
+        // involved_account_ids replaces the owner fields
+        if self.common.involved_account_ids.is_none() {
             if let Some(old_owner_id) = &self.old_owner_id {
                 if event.event.old_owner_id != *old_owner_id {
                     return false;
@@ -238,7 +282,7 @@ impl Handler<SubscribeToEvents<FullNftTransferEvent, NftTransferFilter>> for Ser
         msg: SubscribeToEvents<FullNftTransferEvent, NftTransferFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.nft_transfer_sockets.insert(msg.0);
+        self.network(&msg.1).nft_transfer_sockets.insert(msg.0);
     }
 }
 
@@ -250,7 +294,7 @@ impl Handler<UnsubscribeFromEvents<FullNftTransferEvent, NftTransferFilter>> for
         msg: UnsubscribeFromEvents<FullNftTransferEvent, NftTransferFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.nft_transfer_sockets.remove(&msg.0);
+        self.network(&msg.1).nft_transfer_sockets.remove(&msg.0);
     }
 }
 
@@ -258,35 +302,27 @@ pub async fn nft_burn(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullNftBurnEvent, NftBurnFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
-    )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    connect::<FullNftBurnEvent, NftBurnFilter>(req, stream, server, networks).await
 }
 
 impl FromRedis for FullNftBurnEvent {
-    fn from_redis(values: HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<NftEventContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<NftBurnEvent>(&String::from_redis_value(
-                values.get("burn").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullNftBurnEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "nft_burn";
+    const FIELDS: &'static [&'static str] = &["context", "burn"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullNftBurnEvent {
+            event: redis_field(values, "burn", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.common.head()
     }
 }
 
@@ -294,10 +330,27 @@ impl FromRedis for FullNftBurnEvent {
 pub struct NftBurnFilter {
     owner_id: Option<AccountId>,
     contract_id: Option<AccountId>,
+    /// Only events of one of these contracts, e.g. the collections of a marketplace.
+    contract_ids: Option<Vec<AccountId>>,
+    #[serde(flatten)]
+    common: CommonFilter,
+}
+
+impl FilterFields for NftBurnFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&["owner_id", "contract_id", "contract_ids"])
+    }
 }
 
 impl EventFilter<FullNftBurnEvent> for NftBurnFilter {
     fn matches(&self, event: &FullNftBurnEvent) -> bool {
+        if !self
+            .common
+            .matches(&event.context.common, [&event.event.owner_id])
+        {
+            return false;
+        }
+
         if let Some(owner_id) = &self.owner_id {
             if event.event.owner_id != *owner_id {
                 return false;
@@ -310,6 +363,12 @@ impl EventFilter<FullNftBurnEvent> for NftBurnFilter {
             }
         }
 
+        if let Some(contract_ids) = &self.contract_ids {
+            if !contract_ids.contains(&event.context.contract_id) {
+                return false;
+            }
+        }
+
         true
     }
 }
@@ -322,7 +381,7 @@ impl Handler<SubscribeToEvents<FullNftBurnEvent, NftBurnFilter>> for Server {
         msg: SubscribeToEvents<FullNftBurnEvent, NftBurnFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.nft_burn_sockets.insert(msg.0);
+        self.network(&msg.1).nft_burn_sockets.insert(msg.0);
     }
 }
 
@@ -334,6 +393,189 @@ impl Handler<UnsubscribeFromEvents<FullNftBurnEvent, NftBurnFilter>> for Server
         msg: UnsubscribeFromEvents<FullNftBurnEvent, NftBurnFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.nft_burn_sockets.remove(&msg.0);
+        self.network(&msg.1).nft_burn_sockets.remove(&msg.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::filter_properties::{
+        account, account_value, accounts_value, block_height_value, check, check_fields, event,
+        filter, id_value,
+    };
+
+    const CONTRACTS: &[&str] = &["nft.herewallet.near", "x.paras.near"];
+
+    fn contract_value() -> BoxedStrategy<Value> {
+        prop::sample::select(CONTRACTS)
+            .prop_map(Value::from)
+            .boxed()
+    }
+
+    fn contracts_value() -> BoxedStrategy<Value> {
+        prop::sample::subsequence(CONTRACTS, 1..=2)
+            .prop_map(Value::from)
+            .boxed()
+    }
+
+    fn context() -> impl Strategy<Value = Value> {
+        prop::sample::select(CONTRACTS).prop_map(|contract_id| {
+            json!({
+                "transaction_id": "tx",
+                "receipt_id": "receipt",
+                "block_height": 1,
+                "block_timestamp_nanosec": "1",
+                "contract_id": contract_id,
+            })
+        })
+    }
+
+    fn transfer() -> impl Strategy<Value = FullNftTransferEvent> {
+        (context(), account(), account()).prop_map(|(context, old_owner_id, new_owner_id)| {
+            event(vec![
+                ("context", context),
+                (
+                    "transfer",
+                    json!({
+                        "old_owner_id": old_owner_id,
+                        "new_owner_id": new_owner_id,
+                        "token_ids": ["1"],
+                        "memo": null,
+                        "token_prices_near": [null],
+                    }),
+                ),
+            ])
+        })
+    }
+
+    #[test]
+    fn passes_unknown_fields_on() {
+        let event = event::<FullNftTransferEvent>(vec![
+            (
+                "context",
+                json!({
+                    "transaction_id": "tx",
+                    "receipt_id": "receipt",
+                    "block_height": 1,
+                    "block_timestamp_nanosec": "1",
+                    "contract_id": "nft.near",
+                    "shard_id": 3,
+                }),
+            ),
+            (
+                "transfer",
+                json!({
+                    "old_owner_id": "a.near",
+                    "new_owner_id": "b.near",
+                    "token_ids": ["1"],
+                    "memo": null,
+                    "authorized_id": "market.near",
+                }),
+            ),
+        ]);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["shard_id"], 3);
+        assert_eq!(json["authorized_id"], "market.near");
+        assert_eq!(json["token_prices_near"], json!([]));
+    }
+
+    #[test]
+    fn lists_filter_fields() {
+        check_fields::<NftMintFilter>();
+        check_fields::<NftTransferFilter>();
+        check_fields::<NftBurnFilter>();
+    }
+
+    proptest! {
+        #[test]
+        fn mint_filter_invariants(
+            context in context(),
+            owner_id in account(),
+            filter in filter(vec![
+                ("owner_id", account_value()),
+                ("contract_id", contract_value()),
+                ("contract_ids", contracts_value()),
+                ("min_block_height", block_height_value()),
+                ("max_block_height", block_height_value()),
+                ("transaction_id", id_value("tx")),
+                ("receipt_id", id_value("receipt")),
+                ("involved_account_ids", accounts_value()),
+            ]),
+        ) {
+            let event = event::<FullNftMintEvent>(vec![
+                ("context", context),
+                ("mint", json!({ "owner_id": owner_id, "token_ids": ["1"], "memo": null })),
+            ]);
+            check::<_, NftMintFilter>(&event, &filter)?;
+        }
+
+        #[test]
+        fn transfer_filter_invariants(
+            event in transfer(),
+            // involved_account_ids replaces the owner fields, so they're checked apart
+            filter in prop_oneof![
+                filter(vec![
+                    ("involved_account_ids", accounts_value()),
+                    ("contract_id", contract_value()),
+                    ("contract_ids", contracts_value()),
+                ("contract_ids", contracts_value()),
+                ]),
+                filter(vec![
+                    ("old_owner_id", account_value()),
+                    ("new_owner_id", account_value()),
+                    ("contract_id", contract_value()),
+                    ("contract_ids", contracts_value()),
+                ("contract_ids", contracts_value()),
+                ]),
+            ],
+        ) {
+            check::<_, NftTransferFilter>(&event, &filter)?;
+        }
+
+        #[test]
+        fn involved_accounts_take_precedence(
+            event in transfer(),
+            involved_account_ids in accounts_value(),
+            old_owner_id in account_value(),
+            new_owner_id in account_value(),
+        ) {
+            let involved = serde_json::from_value::<NftTransferFilter>(
+                json!({ "involved_account_ids": involved_account_ids }),
+            )
+            .unwrap();
+            let with_owners = serde_json::from_value::<NftTransferFilter>(json!({
+                "involved_account_ids": involved_account_ids,
+                "old_owner_id": old_owner_id,
+                "new_owner_id": new_owner_id,
+            }))
+            .unwrap();
+            prop_assert_eq!(involved.matches(&event), with_owners.matches(&event));
+        }
+
+        #[test]
+        fn burn_filter_invariants(
+            context in context(),
+            owner_id in account(),
+            filter in filter(vec![
+                ("owner_id", account_value()),
+                ("contract_id", contract_value()),
+                ("contract_ids", contracts_value()),
+                ("min_block_height", block_height_value()),
+                ("max_block_height", block_height_value()),
+                ("transaction_id", id_value("tx")),
+                ("receipt_id", id_value("receipt")),
+                ("involved_account_ids", accounts_value()),
+            ]),
+        ) {
+            let event = event::<FullNftBurnEvent>(vec![
+                ("context", context),
+                ("burn", json!({ "owner_id": owner_id, "token_ids": ["1"], "memo": null })),
+            ]);
+            check::<_, NftBurnFilter>(&event, &filter)?;
+        }
     }
 }
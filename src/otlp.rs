@@ -0,0 +1,246 @@
+//! Minimal OTLP/HTTP exporter with JSON encoding. Spans finished by the logger
+//! and a snapshot of all metrics are pushed to the collector at
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` every `EXPORT_INTERVAL`. Counters are cumulative
+//! monotonic sums since the exporter started, and the other metrics are gauges.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{
+    http_client::Endpoint,
+    metrics::{self, Kind},
+};
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Spans that are waiting to be exported. New spans are dropped when this is full,
+/// e.g. while the collector is unreachable.
+const MAX_QUEUED_SPANS: usize = 16_384;
+
+pub struct FinishedSpan {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+pub type SpanSender = mpsc::Sender<FinishedSpan>;
+
+pub fn random_trace_id() -> u128 {
+    let random = || RandomState::new().build_hasher().finish() as u128;
+    (random() << 64) | random()
+}
+
+struct Collector {
+    endpoint: Endpoint,
+    resource: Value,
+    /// The start of the cumulative sums, when counters were 0.
+    start: SystemTime,
+}
+
+/// Starts the exporter if `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Returns the channel
+/// that finished spans should be sent to.
+pub fn init() -> anyhow::Result<Option<SpanSender>> {
+    let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+        return Ok(None);
+    };
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+    let collector = Collector {
+        endpoint: Endpoint::parse(&endpoint)?,
+        resource: json!({
+            "attributes": [attribute("service.name", &service_name)],
+        }),
+        start: SystemTime::now(),
+    };
+    let (sender, receiver) = mpsc::channel(MAX_QUEUED_SPANS);
+    tokio::spawn(collector.run(receiver));
+    Ok(Some(sender))
+}
+
+impl Collector {
+    async fn run(self, mut receiver: mpsc::Receiver<FinishedSpan>) {
+        let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut spans = Vec::new();
+            while let Ok(span) = receiver.try_recv() {
+                spans.push(span);
+            }
+            if !spans.is_empty() {
+                if let Err(err) = self.post("/v1/traces", self.traces(spans)).await {
+                    tracing::warn!("Failed to export traces: {err}");
+                }
+            }
+            if let Err(err) = self.post("/v1/metrics", self.metrics()).await {
+                tracing::warn!("Failed to export metrics: {err}");
+            }
+        }
+    }
+
+    fn traces(&self, spans: Vec<FinishedSpan>) -> Value {
+        let spans = spans
+            .into_iter()
+            .map(|span| {
+                let mut value = json!({
+                    "traceId": format!("{:032x}", span.trace_id),
+                    "spanId": format!("{:016x}", span.span_id),
+                    "name": span.name,
+                    // SPAN_KIND_INTERNAL
+                    "kind": 1,
+                    "startTimeUnixNano": unix_nanos(span.start),
+                    "endTimeUnixNano": unix_nanos(span.end),
+                    "attributes": span
+                        .attributes
+                        .iter()
+                        .map(|(key, value)| attribute(key, value))
+                        .collect::<Vec<_>>(),
+                });
+                if let Some(parent_span_id) = span.parent_span_id {
+                    value["parentSpanId"] = format!("{parent_span_id:016x}").into();
+                }
+                value
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "resourceSpans": [{
+                "resource": self.resource,
+                "scopeSpans": [{ "scope": scope(), "spans": spans }],
+            }],
+        })
+    }
+
+    fn metrics(&self) -> Value {
+        let start = unix_nanos(self.start);
+        let now = unix_nanos(SystemTime::now());
+        let mut metrics = Vec::<Value>::new();
+        for sample in metrics::snapshot() {
+            let attributes = sample
+                .labels
+                .iter()
+                .map(|(key, value)| attribute(key, value))
+                .collect::<Vec<_>>();
+            let (data_point, data) = match sample.kind {
+                Kind::Gauge => (
+                    json!({
+                        "attributes": attributes,
+                        "timeUnixNano": now,
+                        "asDouble": sample.value,
+                    }),
+                    "gauge",
+                ),
+                Kind::Counter => (
+                    json!({
+                        "attributes": attributes,
+                        "startTimeUnixNano": start,
+                        "timeUnixNano": now,
+                        // int64 is a string in OTLP/JSON
+                        "asInt": (sample.value as u64).to_string(),
+                    }),
+                    "sum",
+                ),
+            };
+            match metrics.last_mut() {
+                Some(metric) if metric["name"] == sample.name => {
+                    metric[data]["dataPoints"]
+                        .as_array_mut()
+                        .unwrap()
+                        .push(data_point);
+                }
+                _ => metrics.push(match sample.kind {
+                    Kind::Gauge => json!({
+                        "name": sample.name,
+                        "gauge": { "dataPoints": [data_point] },
+                    }),
+                    Kind::Counter => json!({
+                        "name": sample.name,
+                        "sum": {
+                            "dataPoints": [data_point],
+                            // AGGREGATION_TEMPORALITY_CUMULATIVE
+                            "aggregationTemporality": 2,
+                            "isMonotonic": true,
+                        },
+                    }),
+                }),
+            }
+        }
+        json!({
+            "resourceMetrics": [{
+                "resource": self.resource,
+                "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+            }],
+        })
+    }
+
+    async fn post(&self, path: &str, body: Value) -> anyhow::Result<()> {
+        self.endpoint.post_json(path, &[], &body).await
+    }
+}
+
+fn scope() -> Value {
+    json!({ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_counters_as_sums() {
+        metrics::increment(metrics::READER_RESTARTS, &[("stream", "otlp_test")]);
+        metrics::set_age(
+            metrics::CHECKPOINT_AGE,
+            &[("stream", "otlp_test")],
+            std::time::Instant::now(),
+        );
+        let collector = Collector {
+            endpoint: Endpoint::parse("http://localhost:4318").unwrap(),
+            resource: json!({}),
+            start: UNIX_EPOCH + Duration::from_secs(1),
+        };
+        let exported = collector.metrics();
+        let metrics = exported["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap();
+        let metric = |name: &str| {
+            metrics
+                .iter()
+                .find(|metric| metric["name"] == name)
+                .unwrap()
+        };
+
+        let restarts = &metric(metrics::READER_RESTARTS)["sum"];
+        assert_eq!(restarts["aggregationTemporality"], 2);
+        assert_eq!(restarts["isMonotonic"], true);
+        let point = restarts["dataPoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|point| point["attributes"][0]["value"]["stringValue"] == "otlp_test")
+            .unwrap();
+        assert_eq!(point["startTimeUnixNano"], "1000000000");
+        assert_eq!(point["asInt"], "1");
+
+        let age = &metric(metrics::CHECKPOINT_AGE)["gauge"];
+        assert!(age["dataPoints"][0]["asDouble"].is_number());
+    }
+}
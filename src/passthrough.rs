@@ -0,0 +1,127 @@
+//! Streams with `passthrough`, whose events are sent to WebSocket clients with
+//! the fields of the stream entry as the indexer wrote them, instead of the
+//! event that the server read from them. Filters still run on the event, but
+//! the frame keeps fields that the server doesn't know yet, the order of fields
+//! and numbers that don't fit into 64 bits.
+
+use std::collections::HashMap;
+
+use bytestring::ByteString;
+
+use crate::{frame_cache, key_style::KeyStyle, replay::StreamId};
+
+/// The event of a stream entry as JSON, the objects of its fields merged into
+/// one like the fields of the event are, or `None` if a field isn't an object.
+pub fn event_json(values: &HashMap<String, redis::Value>, fields: &[&str]) -> Option<String> {
+    let mut json = String::from("{");
+    for field in fields {
+        let text = redis::from_redis_value::<String>(values.get(*field)?).ok()?;
+        let members = text.trim().strip_prefix('{')?.strip_suffix('}')?.trim();
+        if members.is_empty() {
+            continue;
+        }
+        if json.len() > 1 {
+            json.push(',');
+        }
+        json.push_str(members);
+    }
+    json.push('}');
+    Some(json)
+}
+
+/// The frame of protocol 1, like `TaggedEvent`.
+pub fn tagged(source: &str, stream_id: StreamId, event: &str) -> String {
+    let members = &event[1..];
+    let separator = if members == "}" { "" } else { "," };
+    format!(
+        r#"{{"source":{},"stream_id":"{stream_id}"{separator}{members}"#,
+        serde_json::to_string(source).unwrap(),
+    )
+}
+
+/// The frame of protocol 2, like `Envelope`.
+pub fn envelope(source: &str, stream_id: StreamId, event: &str) -> String {
+    format!(
+        r#"{{"type":"event","source":{},"stream_id":"{stream_id}","event":{event}}}"#,
+        serde_json::to_string(source).unwrap(),
+    )
+}
+
+/// Like [`frame_cache::with_fields`], for a frame of this module. The fields
+/// are added to the end of the text, only a `key_style` other than snake case
+/// parses and serializes the frame again.
+pub fn with_fields(
+    frame: &str,
+    fields: &[(&str, Option<u64>)],
+    key_style: KeyStyle,
+) -> Option<ByteString> {
+    if key_style != KeyStyle::Snake {
+        let frame = serde_json::from_str::<serde_json::Value>(frame).unwrap();
+        return frame_cache::with_fields(&frame, fields, key_style);
+    }
+    if fields.iter().all(|(_, value)| value.is_none()) {
+        return None;
+    }
+    let mut frame = frame.strip_suffix('}').unwrap().to_string();
+    for (name, value) in fields {
+        if let Some(value) = value {
+            frame.push_str(&format!(r#","{name}":{value}"#));
+        }
+    }
+    frame.push('}');
+    Some(frame.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(fields: &[(&str, &str)]) -> HashMap<String, redis::Value> {
+        fields
+            .iter()
+            .map(|(field, value)| (field.to_string(), redis::Value::Data((*value).into())))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_fields_as_the_indexer_wrote_them() {
+        let values = entry(&[
+            ("context", r#"{"block_height": 1, "new": {"b": 2, "a": 1}}"#),
+            ("swap", r#" {"amount": 123456789012345678901234567890} "#),
+        ]);
+        let event = event_json(&values, &["context", "swap"]).unwrap();
+        assert_eq!(
+            event,
+            r#"{"block_height": 1, "new": {"b": 2, "a": 1},"amount": 123456789012345678901234567890}"#
+        );
+        let frame = tagged("mainnet", StreamId(1, 2), &event);
+        assert!(frame.starts_with(r#"{"source":"mainnet","stream_id":"1-2","block_height": 1,"#));
+        let frame = serde_json::from_str::<serde_json::Value>(&frame).unwrap();
+        assert_eq!(frame["new"]["a"], 1);
+        let frame = envelope("mainnet", StreamId(1, 2), &event);
+        let frame = serde_json::from_str::<serde_json::Value>(&frame).unwrap();
+        assert_eq!(frame["event"]["block_height"], 1);
+    }
+
+    #[test]
+    fn falls_back_to_the_event_without_objects() {
+        let values = entry(&[("context", r#"{"block_height": 1}"#)]);
+        assert!(event_json(&values, &["context", "swap"]).is_none());
+        let values = entry(&[("context", r#""{\"block_height\": 1}""#)]);
+        assert!(event_json(&values, &["context"]).is_none());
+    }
+
+    #[test]
+    fn adds_fields_of_the_socket() {
+        let frame = tagged("mainnet", StreamId(1, 0), r#"{"amount": 1}"#);
+        assert_eq!(
+            with_fields(&frame, &[("ack_id", None)], KeyStyle::Snake),
+            None
+        );
+        let with_ack = with_fields(&frame, &[("ack_id", Some(7))], KeyStyle::Snake).unwrap();
+        assert_eq!(
+            &*with_ack,
+            r#"{"source":"mainnet","stream_id":"1-0","amount": 1,"ack_id":7}"#
+        );
+    }
+}
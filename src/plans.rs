@@ -0,0 +1,187 @@
+//! Plans, configured with `plans` in the config file, e.g. `free`, `pro` and
+//! `enterprise`, that API keys are on with their `plan`. A plan limits the
+//! endpoints that its keys can connect to, their connections at once, the events
+//! per second delivered to them, and how far back they can replay. Keys without a
+//! plan have no limits, connections without a key are limited in `abuse`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+};
+
+use actix_web::{error::ErrorForbidden, web, Error, HttpRequest};
+use dashmap::DashMap;
+
+use crate::{
+    api_keys::{self, ApiKey},
+    config::{ApiKeyConfig, PlanConfig},
+    replay::{ReplayStart, StreamId},
+};
+
+pub struct Plans(HashMap<String, Arc<PlanConfig>>);
+
+/// Events delivered to a client in the current second.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    second: u128,
+    events: u32,
+    dropped: u32,
+}
+
+static WINDOWS: LazyLock<DashMap<String, Window>> = LazyLock::new(DashMap::new);
+/// The second of `WINDOWS`, which is cleared when the next one starts.
+static SECOND: AtomicU64 = AtomicU64::new(0);
+
+impl Plans {
+    /// Panics if a key has a plan that doesn't exist.
+    pub fn new(plans: HashMap<String, PlanConfig>, keys: &[ApiKeyConfig]) -> Self {
+        for key in keys {
+            if let Some(plan) = &key.plan {
+                assert!(
+                    plans.contains_key(plan),
+                    "API key {} has unknown plan {plan}",
+                    key.name
+                );
+            }
+        }
+        Self(
+            plans
+                .into_iter()
+                .map(|(name, plan)| (name, Arc::new(plan)))
+                .collect(),
+        )
+    }
+
+    pub fn of(&self, key: &ApiKeyConfig) -> Option<Arc<PlanConfig>> {
+        key.plan
+            .as_ref()
+            .and_then(|plan| self.0.get(plan))
+            .map(Arc::clone)
+    }
+}
+
+/// The API key of a request and its plan, or an error if the key is unknown.
+pub fn plan_of(req: &HttpRequest) -> Result<(Option<ApiKey>, Option<Arc<PlanConfig>>), Error> {
+    let api_key = api_keys::authenticate(req)?;
+    let plan = req
+        .app_data::<web::Data<Plans>>()
+        .zip(api_key.as_ref())
+        .and_then(|(plans, api_key)| plans.of(api_key));
+    Ok((api_key, plan))
+}
+
+/// Like [`plan_of`], but also refuses the request if the plan doesn't include the
+/// stream. Every endpoint that delivers events of a stream checks this, so that
+/// a plan can't be worked around with another API.
+pub fn check_access(
+    req: &HttpRequest,
+    stream: &str,
+) -> Result<(Option<ApiKey>, Option<Arc<PlanConfig>>), Error> {
+    let (api_key, plan) = plan_of(req)?;
+    if plan.as_ref().is_some_and(|plan| !plan.includes(stream)) {
+        return Err(ErrorForbidden("Your plan doesn't include this endpoint"));
+    }
+    Ok((api_key, plan))
+}
+
+impl PlanConfig {
+    /// The first stream ID that the plan can read, if it limits the replay age.
+    pub fn earliest(&self, now_ms: u128) -> Option<StreamId> {
+        self.max_replay_age_sec.map(|max_age_sec| {
+            let earliest_ms = now_ms.saturating_sub(u128::from(max_age_sec) * 1000);
+            StreamId(earliest_ms as u64, 0)
+        })
+    }
+
+    pub fn includes(&self, endpoint: &str) -> bool {
+        self.endpoints
+            .as_ref()
+            .is_none_or(|endpoints| endpoints.iter().any(|allowed| allowed == endpoint))
+    }
+
+    /// The replay within the limits of the plan, or a message for the client if it
+    /// starts too far back.
+    pub fn limit_replay(&self, start: ReplayStart, now_ms: u128) -> Result<ReplayStart, String> {
+        match start {
+            ReplayStart::Last(count) => Ok(ReplayStart::Last(
+                self.max_replay_events.map_or(count, |max| count.min(max)),
+            )),
+            ReplayStart::After(id) => match self.max_replay_age_sec {
+                Some(max_age_sec) if u128::from(id.0) + u128::from(max_age_sec) * 1000 < now_ms => {
+                    Err(format!(
+                        "Your plan replays at most the last {max_age_sec} seconds"
+                    ))
+                }
+                _ => Ok(start),
+            },
+        }
+    }
+}
+
+/// Counts an event delivered to a client with `max_events_per_sec`, an API key or
+/// `anonymous/<fingerprint>`. `Err` with the number of events dropped in this
+/// second, including this one, if it's over the limit.
+pub fn take_event(client: &str, max_events_per_sec: u32, now_ms: u128) -> Result<(), u32> {
+    let second = now_ms / 1000;
+    if u128::from(SECOND.swap(second as u64, Ordering::Relaxed)) != second {
+        WINDOWS.retain(|_, window| window.second == second);
+    }
+    let mut window = WINDOWS.entry(client.to_string()).or_insert(Window {
+        second,
+        events: 0,
+        dropped: 0,
+    });
+    if window.second != second {
+        *window = Window {
+            second,
+            events: 0,
+            dropped: 0,
+        };
+    }
+    if window.events < max_events_per_sec {
+        window.events += 1;
+        Ok(())
+    } else {
+        window.dropped += 1;
+        Err(window.dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::StreamId;
+
+    #[test]
+    fn limits_replays_and_rate() {
+        let plan = PlanConfig {
+            endpoints: Some(vec!["nft_mint".to_string()]),
+            max_replay_events: Some(100),
+            max_replay_age_sec: Some(60),
+            ..Default::default()
+        };
+        assert!(plan.includes("nft_mint"));
+        assert!(!plan.includes("trade_swap"));
+        assert!(matches!(
+            plan.limit_replay(ReplayStart::Last(1000), 0),
+            Ok(ReplayStart::Last(100))
+        ));
+        let now_ms = 1_700_000_000_000;
+        assert!(plan
+            .limit_replay(ReplayStart::After(StreamId(1_699_999_950_000, 0)), now_ms)
+            .is_ok());
+        assert!(plan
+            .limit_replay(ReplayStart::After(StreamId(1_699_999_930_000, 0)), now_ms)
+            .is_err());
+        assert_eq!(plan.earliest(now_ms), Some(StreamId(1_699_999_940_000, 0)));
+
+        assert_eq!(take_event("plans-test", 2, now_ms), Ok(()));
+        assert_eq!(take_event("plans-test", 2, now_ms + 1), Ok(()));
+        assert_eq!(take_event("plans-test", 2, now_ms + 2), Err(1));
+        assert_eq!(take_event("plans-test", 2, now_ms + 3), Err(2));
+        assert_eq!(take_event("plans-test", 2, now_ms + 1000), Ok(()));
+    }
+}
@@ -0,0 +1,151 @@
+//! Long polling at `/v0/.../<endpoint>/poll`, for clients that can't keep a
+//! WebSocket open. Responds with the events after `cursor` that match `filter`,
+//! or waits up to `timeout` seconds for the next matching event if there are none.
+
+use std::{sync::Arc, time::Duration};
+
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    broadcast::Broadcasts,
+    disabled_response, fields, plans,
+    replay::{ReplayStart, StreamId},
+    unix_time_ms, EventFilter, FromRedis, Networks, ReadReplay, Server, TaggedEvent,
+    DEFAULT_NETWORK,
+};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const MAX_TIMEOUT_SECS: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct PollQuery {
+    /// Return events after this stream ID. Without it, only waits for new events.
+    cursor: Option<StreamId>,
+    /// Filter in the same JSON format as the WebSocket filter message.
+    filter: Option<String>,
+    /// Seconds to wait for a matching event if there are none yet.
+    timeout: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct PollResponse {
+    events: Vec<serde_json::Value>,
+    /// Pass this as `cursor` to the next request. It can be past the last returned
+    /// event if later events didn't match the filter.
+    cursor: Option<StreamId>,
+}
+
+pub async fn poll<
+    E: Serialize + FromRedis + Send + Sync + 'static,
+    F: EventFilter<E> + DeserializeOwned,
+>(
+    req: HttpRequest,
+    query: web::Query<PollQuery>,
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+    broadcasts: web::Data<Broadcasts>,
+) -> HttpResponse {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return HttpResponse::NotFound().body(format!("Unknown network: {network}"));
+    }
+    if let Some(response) = disabled_response::<E>(&req) {
+        return response;
+    }
+    let plan = match plans::check_access(&req, E::STREAM_KEY) {
+        Ok((_, plan)) => plan,
+        Err(err) => return err.error_response(),
+    };
+    if let (Some(plan), Some(cursor)) = (&plan, query.cursor) {
+        if let Err(message) = plan.limit_replay(ReplayStart::After(cursor), unix_time_ms()) {
+            return HttpResponse::Forbidden().body(message);
+        }
+    }
+    let filter = match query.filter.as_deref().map(fields::parse_filter_str::<F>) {
+        Some(Ok(filter)) => Some(filter),
+        Some(Err(err)) => return HttpResponse::BadRequest().body(format!("Invalid filter: {err}")),
+        None => None,
+    };
+    let matches = |event: &E| filter.as_ref().is_none_or(|f| f.matches(event));
+    let timeout = Duration::from_secs(
+        query
+            .timeout
+            .unwrap_or(DEFAULT_TIMEOUT_SECS)
+            .min(MAX_TIMEOUT_SECS),
+    );
+
+    // Subscribe before reading the stored events, so that nothing is missed in between
+    let mut live = broadcasts.subscribe::<E>(&network);
+    let mut cursor = query.cursor;
+    let mut events = Vec::new();
+    let mut has_more = false;
+    if let Some(after) = cursor {
+        let page = match server
+            .send(ReadReplay::<E> {
+                network: network.clone(),
+                start: ReplayStart::After(after),
+                _marker: Default::default(),
+            })
+            .await
+        {
+            Ok(Ok(page)) => page,
+            Ok(Err(err)) => {
+                tracing::error!("Failed to read {} for polling: {err}", E::STREAM_KEY);
+                return HttpResponse::InternalServerError().body("Failed to read events");
+            }
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        };
+        for event in page.events {
+            cursor = cursor.max(Some(event.id));
+            if matches(&event.event) {
+                events.push(tagged(&event.source, event.id, &event.event));
+            }
+        }
+        // Live events would skip the stored ones that weren't read yet
+        if let Some(next) = page.next {
+            cursor = cursor.max(Some(next));
+            has_more = true;
+        }
+    }
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    while events.is_empty() && !has_more {
+        let event = match tokio::time::timeout_at(deadline, live.recv()).await {
+            Ok(Ok(event)) => event,
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+        };
+        if cursor.is_some_and(|cursor| event.id <= cursor) {
+            continue;
+        }
+        cursor = Some(event.id);
+        if matches(&event.event) {
+            events.push(tagged(&event.source, event.id, &event.event));
+        }
+        // Also return the events that arrived at the same time
+        while let Ok(event) = live.try_recv() {
+            cursor = cursor.max(Some(event.id));
+            if matches(&event.event) {
+                events.push(tagged(&event.source, event.id, &event.event));
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(PollResponse { events, cursor })
+}
+
+fn tagged<E: Serialize>(source: &Arc<str>, stream_id: StreamId, event: &E) -> serde_json::Value {
+    serde_json::to_value(TaggedEvent {
+        source,
+        stream_id,
+        event,
+    })
+    .unwrap()
+}
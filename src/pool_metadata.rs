@@ -0,0 +1,247 @@
+//! Metadata of the tokens of pools, added to `trade_pool` events as `pool_tokens`
+//! if `pool_metadata` is configured, so that clients can show amounts without a
+//! registry of pools and tokens. The tokens of every pool are taken from its last
+//! `trade_pool_change` event, and the symbol and decimals of every token are
+//! fetched once with `ft_metadata` from the RPC of the network. With
+//! `decimal_amounts`, trade events also get their amounts with the decimals of
+//! the tokens, like `"12.5"`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, OnceLock},
+    time::{Duration, Instant},
+};
+
+use base64::prelude::*;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    config::PoolMetadataConfig,
+    http_client::Endpoint,
+    types::{AccountId, PoolId},
+};
+
+/// Tokens whose metadata couldn't be fetched are tried again after this long.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Set once on startup.
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+/// Tokens by network and pool.
+static POOLS: LazyLock<DashMap<(String, PoolId), Vec<AccountId>>> = LazyLock::new(DashMap::new);
+/// By network and token.
+static TOKENS: LazyLock<DashMap<(String, AccountId), Token>> = LazyLock::new(DashMap::new);
+
+struct Settings {
+    /// By network.
+    rpc: HashMap<String, Arc<Endpoint>>,
+    decimal_amounts: bool,
+}
+
+enum Token {
+    Fetching,
+    Failed(Instant),
+    Known(TokenMetadata),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct TokenMetadata {
+    symbol: String,
+    decimals: u8,
+}
+
+/// A token of the pool of an event, without `symbol` and `decimals` until they're
+/// fetched.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PoolToken {
+    pub account_id: AccountId,
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+}
+
+/// Starts recording pools. Panics if an RPC URL is invalid.
+pub fn configure(config: PoolMetadataConfig) {
+    let rpc = config
+        .rpc_urls
+        .into_iter()
+        .map(|(network, url)| {
+            let endpoint = Endpoint::parse(&url)
+                .unwrap_or_else(|err| panic!("Invalid RPC URL of {network}: {err}"));
+            (network, Arc::new(endpoint))
+        })
+        .collect();
+    let settings = Settings {
+        rpc,
+        decimal_amounts: config.decimal_amounts,
+    };
+    if SETTINGS.set(settings).is_err() {
+        panic!("Pool metadata is configured twice");
+    }
+}
+
+/// Records the tokens of a pool from its `trade_pool_change` event, and fetches
+/// the metadata of new ones.
+pub fn record_pool(network: &str, pool_id: &PoolId, pool: &Value) {
+    if SETTINGS.get().is_none() {
+        return;
+    }
+    let tokens = token_account_ids(pool);
+    if tokens.is_empty() {
+        return;
+    }
+    for token in &tokens {
+        metadata(network, token);
+    }
+    POOLS.insert((network.to_string(), pool_id.clone()), tokens);
+}
+
+/// The decimals of a token if `decimal_amounts` is set and they're known yet.
+pub fn decimals(network: &str, token: &AccountId) -> Option<u8> {
+    if !SETTINGS.get()?.decimal_amounts {
+        return None;
+    }
+    metadata(network, token).map(|metadata| metadata.decimals)
+}
+
+/// The metadata of a token, or `None` while it's fetched, which this starts if it
+/// wasn't yet.
+fn metadata(network: &str, token: &AccountId) -> Option<TokenMetadata> {
+    let rpc = SETTINGS.get()?.rpc.get(network)?;
+    let key = (network.to_string(), token.clone());
+    let fetch = match TOKENS.get(&key).as_deref() {
+        None => true,
+        Some(Token::Failed(at)) => at.elapsed() >= RETRY_AFTER,
+        Some(Token::Fetching) => false,
+        Some(Token::Known(metadata)) => return Some(metadata.clone()),
+    };
+    if fetch {
+        TOKENS.insert(key.clone(), Token::Fetching);
+        let rpc = Arc::clone(rpc);
+        tokio::spawn(async move {
+            let token = match ft_metadata(&rpc, &key.1).await {
+                Ok(metadata) => Token::Known(metadata),
+                Err(err) => {
+                    tracing::warn!("Failed to fetch the metadata of {}: {err}", key.1);
+                    Token::Failed(Instant::now())
+                }
+            };
+            TOKENS.insert(key, token);
+        });
+    }
+    None
+}
+
+/// The tokens of a pool, `None` if it had no `trade_pool_change` event since the
+/// server started, or pool metadata isn't configured.
+pub fn pool_tokens(network: &str, pool_id: &PoolId) -> Option<Vec<PoolToken>> {
+    SETTINGS.get()?;
+    let tokens = POOLS.get(&(network.to_string(), pool_id.clone()))?;
+    Some(
+        tokens
+            .iter()
+            .map(|account_id| {
+                let metadata = metadata(network, account_id);
+                PoolToken {
+                    account_id: account_id.clone(),
+                    symbol: metadata.as_ref().map(|metadata| metadata.symbol.clone()),
+                    decimals: metadata.map(|metadata| metadata.decimals),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// The `token_account_ids` of a pool, wherever the DEX nests them, e.g.
+/// `{"Ref": {"SimplePool": {"token_account_ids": [...]}}}`.
+fn token_account_ids(pool: &Value) -> Vec<AccountId> {
+    match pool {
+        Value::Object(object) => match object.get("token_account_ids") {
+            Some(Value::Array(tokens)) => tokens
+                .iter()
+                .filter_map(|token| AccountId::try_from(token.as_str()?).ok())
+                .collect(),
+            _ => object
+                .values()
+                .map(token_account_ids)
+                .find(|tokens| !tokens.is_empty())
+                .unwrap_or_default(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+async fn ft_metadata(rpc: &Endpoint, token: &AccountId) -> anyhow::Result<TokenMetadata> {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": "pool_metadata",
+        "method": "query",
+        "params": {
+            "request_type": "call_function",
+            "finality": "final",
+            "account_id": token,
+            "method_name": "ft_metadata",
+            "args_base64": BASE64_STANDARD.encode("{}"),
+        },
+    });
+    let response = rpc
+        .post("", &[], "application/json", &request.to_string())
+        .await?;
+    parse_ft_metadata(&response)
+}
+
+/// Reads the result of a `call_function` query, the bytes of the JSON that the
+/// contract returned.
+fn parse_ft_metadata(response: &str) -> anyhow::Result<TokenMetadata> {
+    #[derive(Deserialize)]
+    struct Response {
+        result: Option<CallResult>,
+        error: Option<Value>,
+    }
+    #[derive(Deserialize)]
+    struct CallResult {
+        result: Vec<u8>,
+    }
+    let response = serde_json::from_str::<Response>(response)?;
+    match (response.result, response.error) {
+        (Some(result), _) => Ok(serde_json::from_slice(&result.result)?),
+        (None, error) => anyhow::bail!("RPC error: {}", error.unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_pools_and_metadata() {
+        let pool = json!({
+            "Ref": {
+                "SimplePool": {
+                    "token_account_ids": ["wrap.near", "usdt.tether-token.near"],
+                    "amounts": ["1", "2"],
+                },
+            },
+        });
+        assert_eq!(
+            token_account_ids(&pool)
+                .iter()
+                .map(|token| token.to_string())
+                .collect::<Vec<_>>(),
+            ["wrap.near", "usdt.tether-token.near"],
+        );
+        assert!(token_account_ids(&json!({ "Other": { "tokens": 2 } })).is_empty());
+
+        let bytes = br#"{"spec":"ft-1.0.0","symbol":"USDt","decimals":6}"#.to_vec();
+        let response = json!({ "jsonrpc": "2.0", "id": "1", "result": { "result": bytes } });
+        assert_eq!(
+            parse_ft_metadata(&response.to_string()).unwrap(),
+            TokenMetadata {
+                symbol: "USDt".to_string(),
+                decimals: 6,
+            },
+        );
+        let error = json!({ "jsonrpc": "2.0", "id": "1", "error": { "name": "HANDLER_ERROR" } });
+        assert!(parse_ft_metadata(&error.to_string()).is_err());
+    }
+}
@@ -1,25 +1,23 @@
-use std::{collections::HashMap, marker::PhantomData, time::Instant};
+use std::collections::HashMap;
 
 use actix::prelude::{dev::Message, Addr, Handler};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
-use actix_web_actors::ws::WsResponseBuilder;
-use redis::FromRedisValue;
+use borsh::BorshDeserialize;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    AccountId, Balance, BlockHeight, DonationId, EventFilter, EventWebSocket, FromRedis, ProjectId,
-    ReceiptId, Server, SubscribeToEvents, TimestampMs, TransactionId, UnsubscribeFromEvents,
+    common_filter::CommonFilter,
+    config::Encoding,
+    connect,
+    dead_letters::FromRedisError,
+    fields::FilterFields,
+    heads::Head,
+    redis_field,
+    types::{AccountId, Balance, DonationId, EventContext, ProjectId, TimestampMs, UnknownFields},
+    EventFilter, FromRedis, Networks, Server, SubscribeToEvents, UnsubscribeFromEvents,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct PotlockEventContext {
-    pub transaction_id: TransactionId,
-    pub receipt_id: ReceiptId,
-    pub block_height: BlockHeight,
-    pub block_timestamp_nanosec: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct PotlockDonationEvent {
     pub donation_id: DonationId,
     pub donor_id: AccountId,
@@ -31,6 +29,9 @@ pub struct PotlockDonationEvent {
     pub protocol_fee: Balance,
     pub referrer_id: Option<AccountId>,
     pub referrer_fee: Option<Balance>,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Deserialize, Message)]
@@ -39,10 +40,10 @@ pub struct FullPotlockDonationEvent {
     #[serde(flatten)]
     pub event: PotlockDonationEvent,
     #[serde(flatten)]
-    pub context: PotlockEventContext,
+    pub context: EventContext,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct PotlockPotProjectDonationEvent {
     pub donation_id: DonationId,
     pub pot_id: AccountId,
@@ -57,6 +58,9 @@ pub struct PotlockPotProjectDonationEvent {
     pub protocol_fee: Balance,
     pub chef_id: Option<AccountId>,
     pub chef_fee: Option<Balance>,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Deserialize, Message)]
@@ -65,10 +69,10 @@ pub struct FullPotlockPotProjectDonationEvent {
     #[serde(flatten)]
     pub event: PotlockPotProjectDonationEvent,
     #[serde(flatten)]
-    pub context: PotlockEventContext,
+    pub context: EventContext,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct PotlockPotDonationEvent {
     pub donation_id: DonationId,
     pub pot_id: AccountId,
@@ -82,6 +86,9 @@ pub struct PotlockPotDonationEvent {
     pub protocol_fee: Balance,
     pub chef_id: Option<AccountId>,
     pub chef_fee: Option<Balance>,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Deserialize, Message)]
@@ -90,42 +97,35 @@ pub struct FullPotlockPotDonationEvent {
     #[serde(flatten)]
     pub event: PotlockPotDonationEvent,
     #[serde(flatten)]
-    pub context: PotlockEventContext,
+    pub context: EventContext,
 }
 
 pub async fn potlock_donation(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullPotlockDonationEvent, PotlockDonationEventFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
-    )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    connect::<FullPotlockDonationEvent, PotlockDonationEventFilter>(req, stream, server, networks)
+        .await
 }
 
 impl FromRedis for FullPotlockDonationEvent {
-    fn from_redis(values: std::collections::HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<PotlockEventContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<PotlockDonationEvent>(&String::from_redis_value(
-                values.get("donation").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullPotlockDonationEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "potlock_donation";
+    const FIELDS: &'static [&'static str] = &["context", "donation"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullPotlockDonationEvent {
+            event: redis_field(values, "donation", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.head()
     }
 }
 
@@ -135,10 +135,31 @@ pub struct PotlockDonationEventFilter {
     pub donor_id: Option<AccountId>,
     pub referrer_id: Option<AccountId>,
     pub min_amounts: Option<HashMap<AccountId, Balance>>,
+    #[serde(flatten)]
+    pub common: CommonFilter,
+}
+
+impl FilterFields for PotlockDonationEventFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&["project_id", "donor_id", "referrer_id", "min_amounts"])
+    }
 }
 
 impl EventFilter<FullPotlockDonationEvent> for PotlockDonationEventFilter {
     fn matches(&self, event: &FullPotlockDonationEvent) -> bool {
+        if !self.common.matches(
+            &event.context,
+            [
+                Some(&event.event.donor_id),
+                Some(&event.event.project_id),
+                event.event.referrer_id.as_ref(),
+            ]
+            .into_iter()
+            .flatten(),
+        ) {
+            return false;
+        }
+
         if let Some(project_id) = &self.project_id {
             if event.event.project_id != *project_id {
                 return false;
@@ -156,20 +177,14 @@ impl EventFilter<FullPotlockDonationEvent> for PotlockDonationEventFilter {
         }
         if let Some(min_amounts) = &self.min_amounts {
             if let Some(min_amount) = min_amounts.get(&event.event.donor_id) {
-                if let (Ok(total_amount), Ok(min_amount)) = (
-                    event.event.total_amount.parse::<u128>(),
-                    min_amount.parse::<u128>(),
-                ) {
-                    if total_amount < min_amount {
-                        return false;
-                    }
-                } else {
+                if event.event.total_amount < *min_amount {
                     return false;
                 }
             } else {
                 return false;
             }
         }
+
         true
     }
 }
@@ -182,7 +197,7 @@ impl Handler<SubscribeToEvents<FullPotlockDonationEvent, PotlockDonationEventFil
         msg: SubscribeToEvents<FullPotlockDonationEvent, PotlockDonationEventFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.potlock_donation_sockets.insert(msg.0);
+        self.network(&msg.1).potlock_donation_sockets.insert(msg.0);
     }
 }
 
@@ -196,7 +211,7 @@ impl Handler<UnsubscribeFromEvents<FullPotlockDonationEvent, PotlockDonationEven
         msg: UnsubscribeFromEvents<FullPotlockDonationEvent, PotlockDonationEventFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.potlock_donation_sockets.remove(&msg.0);
+        self.network(&msg.1).potlock_donation_sockets.remove(&msg.0);
     }
 }
 
@@ -204,35 +219,30 @@ pub async fn potlock_pot_project_donation(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullPotlockPotProjectDonationEvent, PotlockPotProjectDonationEventFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
+    connect::<FullPotlockPotProjectDonationEvent, PotlockPotProjectDonationEventFilter>(
+        req, stream, server, networks,
     )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    .await
 }
 
 impl FromRedis for FullPotlockPotProjectDonationEvent {
-    fn from_redis(values: std::collections::HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<PotlockEventContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<PotlockPotProjectDonationEvent>(&String::from_redis_value(
-                values.get("pot_project_donation").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullPotlockPotProjectDonationEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "potlock_pot_project_donation";
+    const FIELDS: &'static [&'static str] = &["context", "pot_project_donation"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullPotlockPotProjectDonationEvent {
+            event: redis_field(values, "pot_project_donation", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.head()
     }
 }
 
@@ -243,10 +253,38 @@ pub struct PotlockPotProjectDonationEventFilter {
     pub donor_id: Option<AccountId>,
     pub referrer_id: Option<AccountId>,
     pub min_amount_near: Option<Balance>,
+    #[serde(flatten)]
+    pub common: CommonFilter,
+}
+
+impl FilterFields for PotlockPotProjectDonationEventFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&[
+            "pot_id",
+            "project_id",
+            "donor_id",
+            "referrer_id",
+            "min_amount_near",
+        ])
+    }
 }
 
 impl EventFilter<FullPotlockPotProjectDonationEvent> for PotlockPotProjectDonationEventFilter {
     fn matches(&self, event: &FullPotlockPotProjectDonationEvent) -> bool {
+        if !self.common.matches(
+            &event.context,
+            [
+                Some(&event.event.donor_id),
+                Some(&event.event.project_id),
+                event.event.referrer_id.as_ref(),
+                event.event.chef_id.as_ref(),
+            ]
+            .into_iter()
+            .flatten(),
+        ) {
+            return false;
+        }
+
         if let Some(pot_id) = &self.pot_id {
             if event.event.pot_id != *pot_id {
                 return false;
@@ -268,17 +306,11 @@ impl EventFilter<FullPotlockPotProjectDonationEvent> for PotlockPotProjectDonati
             }
         }
         if let Some(min_amount_near) = &self.min_amount_near {
-            if let (Ok(total_amount), Ok(min_amount_near)) = (
-                event.event.total_amount.parse::<u128>(),
-                min_amount_near.parse::<u128>(),
-            ) {
-                if total_amount < min_amount_near {
-                    return false;
-                }
-            } else {
+            if event.event.total_amount < *min_amount_near {
                 return false;
             }
         }
+
         true
     }
 }
@@ -298,7 +330,9 @@ impl
         >,
         _ctx: &mut Self::Context,
     ) {
-        self.potlock_pot_project_donation_sockets.insert(msg.0);
+        self.network(&msg.1)
+            .potlock_pot_project_donation_sockets
+            .insert(msg.0);
     }
 }
 
@@ -320,7 +354,9 @@ impl
         >,
         _ctx: &mut Self::Context,
     ) {
-        self.potlock_pot_project_donation_sockets.remove(&msg.0);
+        self.network(&msg.1)
+            .potlock_pot_project_donation_sockets
+            .remove(&msg.0);
     }
 }
 
@@ -328,35 +364,30 @@ pub async fn potlock_pot_donation(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullPotlockPotDonationEvent, PotlockPotDonationEventFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
+    connect::<FullPotlockPotDonationEvent, PotlockPotDonationEventFilter>(
+        req, stream, server, networks,
     )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    .await
 }
 
 impl FromRedis for FullPotlockPotDonationEvent {
-    fn from_redis(values: std::collections::HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<PotlockEventContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<PotlockPotDonationEvent>(&String::from_redis_value(
-                values.get("pot_donation").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullPotlockPotDonationEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "potlock_pot_donation";
+    const FIELDS: &'static [&'static str] = &["context", "pot_donation"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullPotlockPotDonationEvent {
+            event: redis_field(values, "pot_donation", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.head()
     }
 }
 
@@ -366,10 +397,31 @@ pub struct PotlockPotDonationEventFilter {
     pub donor_id: Option<AccountId>,
     pub referrer_id: Option<AccountId>,
     pub min_amount_near: Option<Balance>,
+    #[serde(flatten)]
+    pub common: CommonFilter,
+}
+
+impl FilterFields for PotlockPotDonationEventFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&["pot_id", "donor_id", "referrer_id", "min_amount_near"])
+    }
 }
 
 impl EventFilter<FullPotlockPotDonationEvent> for PotlockPotDonationEventFilter {
     fn matches(&self, event: &FullPotlockPotDonationEvent) -> bool {
+        if !self.common.matches(
+            &event.context,
+            [
+                Some(&event.event.donor_id),
+                event.event.referrer_id.as_ref(),
+                event.event.chef_id.as_ref(),
+            ]
+            .into_iter()
+            .flatten(),
+        ) {
+            return false;
+        }
+
         if let Some(pot_id) = &self.pot_id {
             if event.event.pot_id != *pot_id {
                 return false;
@@ -386,17 +438,11 @@ impl EventFilter<FullPotlockPotDonationEvent> for PotlockPotDonationEventFilter
             }
         }
         if let Some(min_amount_near) = &self.min_amount_near {
-            if let (Ok(total_amount), Ok(min_amount_near)) = (
-                event.event.total_amount.parse::<u128>(),
-                min_amount_near.parse::<u128>(),
-            ) {
-                if total_amount < min_amount_near {
-                    return false;
-                }
-            } else {
+            if event.event.total_amount < *min_amount_near {
                 return false;
             }
         }
+
         true
     }
 }
@@ -411,7 +457,9 @@ impl Handler<SubscribeToEvents<FullPotlockPotDonationEvent, PotlockPotDonationEv
         msg: SubscribeToEvents<FullPotlockPotDonationEvent, PotlockPotDonationEventFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.potlock_pot_donation_sockets.insert(msg.0);
+        self.network(&msg.1)
+            .potlock_pot_donation_sockets
+            .insert(msg.0);
     }
 }
 
@@ -425,6 +473,168 @@ impl Handler<UnsubscribeFromEvents<FullPotlockPotDonationEvent, PotlockPotDonati
         msg: UnsubscribeFromEvents<FullPotlockPotDonationEvent, PotlockPotDonationEventFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.potlock_pot_donation_sockets.remove(&msg.0);
+        self.network(&msg.1)
+            .potlock_pot_donation_sockets
+            .remove(&msg.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::{json, Map, Value};
+
+    use super::*;
+    use crate::filter_properties::{
+        account, account_value, accounts_value, amount, block_height_value, check, check_fields,
+        event, filter, id_value,
+    };
+
+    const POTS: &[&str] = &[
+        "build.v1.potfactory.potlock.near",
+        "octopus.v1.potfactory.potlock.near",
+    ];
+
+    fn pot() -> impl Strategy<Value = String> {
+        prop::sample::select(POTS).prop_map(str::to_string)
+    }
+
+    fn pot_value() -> BoxedStrategy<Value> {
+        pot().prop_map(Value::from).boxed()
+    }
+
+    fn amount_value() -> BoxedStrategy<Value> {
+        amount().prop_map(Value::from).boxed()
+    }
+
+    fn min_amounts_value() -> BoxedStrategy<Value> {
+        prop::collection::hash_map(account(), amount_value(), 1..=3)
+            .prop_map(|amounts| Value::Object(amounts.into_iter().collect::<Map<_, _>>()))
+            .boxed()
+    }
+
+    fn context() -> Value {
+        json!({
+            "transaction_id": "tx",
+            "receipt_id": "receipt",
+            "block_height": 1,
+            "block_timestamp_nanosec": "1",
+        })
+    }
+
+    #[test]
+    fn lists_filter_fields() {
+        check_fields::<PotlockDonationEventFilter>();
+        check_fields::<PotlockPotProjectDonationEventFilter>();
+        check_fields::<PotlockPotDonationEventFilter>();
+    }
+
+    proptest! {
+        #[test]
+        fn donation_filter_invariants(
+            (project_id, donor_id, referrer_id, total_amount) in
+                (account(), account(), prop::option::of(account()), amount()),
+            filter in filter(vec![
+                ("project_id", account_value()),
+                ("donor_id", account_value()),
+                ("referrer_id", account_value()),
+                ("min_amounts", min_amounts_value()),
+                ("min_block_height", block_height_value()),
+                ("max_block_height", block_height_value()),
+                ("transaction_id", id_value("tx")),
+                ("receipt_id", id_value("receipt")),
+                ("involved_account_ids", accounts_value()),
+            ]),
+        ) {
+            let event = event::<FullPotlockDonationEvent>(vec![
+                ("context", context()),
+                (
+                    "donation",
+                    json!({
+                        "donation_id": 1,
+                        "donor_id": donor_id,
+                        "total_amount": total_amount,
+                        "account_id": project_id,
+                        "message": null,
+                        "donated_at": 1,
+                        "project_id": project_id,
+                        "protocol_fee": "0",
+                        "referrer_id": referrer_id,
+                        "referrer_fee": null,
+                    }),
+                ),
+            ]);
+            check::<_, PotlockDonationEventFilter>(&event, &filter)?;
+        }
+
+        #[test]
+        fn pot_project_donation_filter_invariants(
+            (pot_id, project_id, donor_id, referrer_id, total_amount) in
+                (pot(), account(), account(), prop::option::of(account()), amount()),
+            filter in filter(vec![
+                ("pot_id", pot_value()),
+                ("project_id", account_value()),
+                ("donor_id", account_value()),
+                ("referrer_id", account_value()),
+                ("min_amount_near", amount_value()),
+            ]),
+        ) {
+            let event = event::<FullPotlockPotProjectDonationEvent>(vec![
+                ("context", context()),
+                (
+                    "pot_project_donation",
+                    json!({
+                        "donation_id": 1,
+                        "pot_id": pot_id,
+                        "donor_id": donor_id,
+                        "total_amount": total_amount,
+                        "net_amount": total_amount,
+                        "message": null,
+                        "donated_at": 1,
+                        "project_id": project_id,
+                        "referrer_id": referrer_id,
+                        "referrer_fee": null,
+                        "protocol_fee": "0",
+                        "chef_id": null,
+                        "chef_fee": null,
+                    }),
+                ),
+            ]);
+            check::<_, PotlockPotProjectDonationEventFilter>(&event, &filter)?;
+        }
+
+        #[test]
+        fn pot_donation_filter_invariants(
+            (pot_id, donor_id, referrer_id, total_amount) in
+                (pot(), account(), prop::option::of(account()), amount()),
+            filter in filter(vec![
+                ("pot_id", pot_value()),
+                ("donor_id", account_value()),
+                ("referrer_id", account_value()),
+                ("min_amount_near", amount_value()),
+            ]),
+        ) {
+            let event = event::<FullPotlockPotDonationEvent>(vec![
+                ("context", context()),
+                (
+                    "pot_donation",
+                    json!({
+                        "donation_id": 1,
+                        "pot_id": pot_id,
+                        "donor_id": donor_id,
+                        "total_amount": total_amount,
+                        "net_amount": total_amount,
+                        "message": null,
+                        "donated_at": 1,
+                        "referrer_id": referrer_id,
+                        "referrer_fee": null,
+                        "protocol_fee": "0",
+                        "chef_id": null,
+                        "chef_fee": null,
+                    }),
+                ),
+            ]);
+            check::<_, PotlockPotDonationEventFilter>(&event, &filter)?;
+        }
     }
 }
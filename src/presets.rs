@@ -0,0 +1,48 @@
+//! Filter presets, configured with `presets` in the config file, that clients
+//! choose with `{"preset": <name>}` instead of a filter.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    EventFilter, FromRedis, Presets,
+};
+
+/// Panics if a preset isn't a valid filter of its stream, or its stream doesn't exist.
+pub fn validate(presets: &Presets) {
+    let mut validator = Validator {
+        presets,
+        used: Vec::new(),
+    };
+    for_each_event_type(&mut validator);
+    for stream in presets.keys() {
+        if !validator.used.contains(&stream.as_str()) {
+            panic!("Presets have unknown stream {stream}");
+        }
+    }
+}
+
+struct Validator<'a> {
+    presets: &'a Presets,
+    /// Streams that exist.
+    used: Vec<&'static str>,
+}
+
+impl EventTypeVisitor for Validator<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        self.used.push(E::STREAM_KEY);
+        for (name, filter) in self.presets.get(E::STREAM_KEY).into_iter().flatten() {
+            if let Err(err) = F::deserialize(filter) {
+                panic!(
+                    "Invalid filter of preset {name} of {}: {err}",
+                    E::STREAM_KEY
+                );
+            }
+        }
+    }
+}
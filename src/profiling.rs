@@ -0,0 +1,82 @@
+//! CPU profiles of the running server at `/debug/pprof/profile`, with the
+//! `pprof` cargo feature and `ADMIN_TOKEN` set, for finding where time goes under
+//! real traffic. Requests need the admin token like the admin API.
+
+use std::time::Duration;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use pprof::protos::Message;
+use serde::Deserialize;
+
+use crate::admin::{authorized, AdminToken};
+
+/// Samples per second.
+const FREQUENCY: i32 = 99;
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+/// Frames of signal handling and the unwinder, that are on every stack.
+const BLOCKLIST: &[&str] = &["libc", "libgcc", "pthread", "vdso"];
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/profile").route(web::get().to(profile)));
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ProfileFormat {
+    /// `profile.proto`, for `go tool pprof`.
+    #[default]
+    Protobuf,
+    /// An SVG flamegraph.
+    Flamegraph,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: ProfileFormat,
+}
+
+/// Samples the CPU for `seconds` and responds with the profile.
+async fn profile(
+    req: HttpRequest,
+    query: web::Query<ProfileQuery>,
+    token: web::Data<AdminToken>,
+) -> HttpResponse {
+    if !authorized(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let seconds = query
+        .seconds
+        .unwrap_or(DEFAULT_SECONDS)
+        .clamp(1, MAX_SECONDS);
+    // Fails if another profile is running, only one profiler can be installed
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(BLOCKLIST)
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(err) => return HttpResponse::Conflict().body(format!("Can't start profiling: {err}")),
+    };
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    let report = match guard.report().build() {
+        Ok(report) => report,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    drop(guard);
+    let profile = match query.format {
+        ProfileFormat::Protobuf => report
+            .pprof()
+            .map(|profile| ("application/octet-stream", profile.encode_to_vec())),
+        ProfileFormat::Flamegraph => {
+            let mut svg = Vec::new();
+            report.flamegraph(&mut svg).map(|()| ("image/svg+xml", svg))
+        }
+    };
+    match profile {
+        Ok((content_type, body)) => HttpResponse::Ok().content_type(content_type).body(body),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
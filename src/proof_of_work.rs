@@ -0,0 +1,93 @@
+//! Proof-of-work gate for anonymous clients of heavy streams, the ones with
+//! `proof_of_work_bits` in the config. Such clients get a `challenge` frame with a
+//! random string and a difficulty, and no events until they answer with
+//! `{"solution": <string>}`, any string for which the SHA-256 of the challenge
+//! followed by the solution starts with that many zero bits. That takes a browser
+//! a moment, but makes opening many connections from a script expensive.
+
+use std::time::Duration;
+
+use ring::digest;
+use serde::Deserialize;
+
+/// Clients that didn't solve their challenge by then are disconnected.
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(60);
+/// More would take too long to solve.
+pub const MAX_BITS: u8 = 32;
+
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub challenge: String,
+    pub bits: u8,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Answer {
+    solution: String,
+}
+
+impl Challenge {
+    pub fn new(bits: u8) -> Self {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).expect("Failed to generate a challenge");
+        Self {
+            challenge: bytes.iter().map(|b| format!("{b:02x}")).collect(),
+            bits,
+        }
+    }
+
+    pub fn is_solved_by(&self, solution: &str) -> bool {
+        let hash = digest::digest(
+            &digest::SHA256,
+            format!("{}{solution}", self.challenge).as_bytes(),
+        );
+        leading_zero_bits(hash.as_ref()) >= u32::from(self.bits)
+    }
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// The solution of an answer message, `None` for other messages.
+pub fn parse(text: &str) -> Option<String> {
+    serde_json::from_str::<Answer>(text)
+        .ok()
+        .map(|answer| answer.solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solves_challenges() {
+        assert_eq!(leading_zero_bits(&[0, 0x1f, 0xff]), 11);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+
+        let challenge = Challenge::new(8);
+        assert_eq!(challenge.challenge.len(), 32);
+        let solution = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|solution| challenge.is_solved_by(solution))
+            .unwrap();
+        assert!(Challenge {
+            bits: 0,
+            ..challenge.clone()
+        }
+        .is_solved_by("anything"));
+        assert_eq!(
+            parse(&format!(r#"{{"solution":"{solution}"}}"#)),
+            Some(solution)
+        );
+        assert_eq!(parse(r#"{"contract_id":"a.near"}"#), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Versions of the WebSocket protocol. `/v0` always speaks protocol 1, where
+//! events are sent as flat JSON objects and client messages aren't answered,
+//! but invalid ones close the connection with a policy violation.
+//! On `/v1` the client can pick the protocol with `"protocol"` in its first
+//! message, and gets the latest one otherwise.
+//!
+//! Protocol 2:
+//! - Events are sent in an envelope, `{"type": "event", "source", "stream_id", "event": {...}}`
+//! - Every client message is answered with `{"type": "ack", "protocol": 2}`, or
+//!   `{"type": "error", "message": ...}` if it's invalid
+//! - With the `batch_ms` option, events are collected for up to this long and sent
+//!   together as `{"type": "events", "events": [<envelope>, ...]}`
+
+use std::borrow::Borrow;
+
+use actix_web_actors::ws;
+use serde::{Deserialize, Serialize};
+
+use crate::{replay::StreamId, types::BlockHeight};
+
+/// API version of the endpoint, set as app data on the `/v1` scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V0,
+    V1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    V1 = 1,
+    V2 = 2,
+}
+
+impl Protocol {
+    pub const LATEST: Protocol = Protocol::V2;
+
+    pub fn from_version(version: u32) -> Option<Protocol> {
+        match version {
+            1 => Some(Protocol::V1),
+            2 => Some(Protocol::V2),
+            _ => None,
+        }
+    }
+}
+
+/// The `protocol` field of the first client message on `/v1`.
+#[derive(Debug, Deserialize)]
+pub struct Negotiation {
+    pub protocol: Option<u32>,
+}
+
+/// Maximum number of events in one `events` frame, more are sent in the next one.
+pub const MAX_BATCH_EVENTS: usize = 1000;
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "event")]
+pub struct Envelope<'a, E> {
+    pub source: &'a str,
+    pub stream_id: StreamId,
+    pub event: &'a E,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlFrame<'a> {
+    Ack {
+        protocol: u32,
+        connection_id: &'a str,
+    },
+    Error {
+        message: &'a str,
+    },
+    /// Sent every `keepalive_sec`, with the unix time in milliseconds.
+    Keepalive {
+        server_time: u128,
+    },
+    /// Sent when the server is draining, so that the client reconnects to
+    /// `reconnect_to` with `?from_stream_id=<resume_from>`.
+    Reconnect {
+        message: &'a str,
+        connection_id: &'a str,
+        reconnect_to: Option<&'a str>,
+        resume_from: Option<StreamId>,
+    },
+    /// The latest block that the stream has events of, sent every `head_sec`.
+    Head {
+        stream: &'a str,
+        block_height: BlockHeight,
+        /// Stringified, like in events.
+        block_timestamp_nanosec: String,
+        server_time: u128,
+    },
+    /// Sent every `SESSION_INTERVAL` if the filter or the last event changed, for
+    /// `?session=<token>`.
+    Session {
+        token: &'a str,
+    },
+    /// Sent when a client opens many identical connections.
+    Warning {
+        message: &'a str,
+    },
+    /// Sent to anonymous clients of streams with `proof_of_work_bits`, see
+    /// `proof_of_work`.
+    Challenge {
+        challenge: &'a str,
+        bits: u8,
+    },
+    ChallengeSolved,
+}
+
+/// Joins JSON-serialized envelopes into an `events` frame.
+pub fn batch_frame(envelopes: &[impl Borrow<str>]) -> String {
+    format!(r#"{{"type":"events","events":[{}]}}"#, envelopes.join(","))
+}
+
+/// The longest reason that fits into a close frame.
+const MAX_CLOSE_REASON: usize = 123;
+
+/// Closes the connection of a protocol 1 client, which can't be told about errors
+/// in frames.
+pub fn policy_close(message: &str) -> ws::CloseReason {
+    let mut end = message.len().min(MAX_CLOSE_REASON);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    ws::CloseReason {
+        code: ws::CloseCode::Policy,
+        description: Some(message[..end].to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_close_reasons() {
+        let reason = policy_close("Unknown field contractid");
+        assert_eq!(
+            reason.description.as_deref(),
+            Some("Unknown field contractid")
+        );
+        let reason = policy_close(&"é".repeat(100));
+        assert_eq!(reason.description.unwrap().len(), 122);
+    }
+}
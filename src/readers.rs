@@ -0,0 +1,189 @@
+//! State of every stream reader, for `/status` and the admin API, and a way to
+//! restart one without restarting the server. Readers register when they're
+//! spawned and report every state change here.
+
+use std::{
+    sync::{Arc, LazyLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReaderState {
+    Running,
+    /// Failed, and waiting to be restarted.
+    Backoff,
+    Stopped,
+}
+
+struct Reader {
+    network: String,
+    state: ReaderState,
+    /// Failures since it last ran for `READER_HEALTHY_AFTER`.
+    failures: u32,
+    restarts: u64,
+    last_error: Option<String>,
+    since_ms: u128,
+    restart: Arc<Notify>,
+}
+
+/// Readers by source and stream key (with the source's prefix).
+static READERS: LazyLock<DashMap<(String, String), Reader>> = LazyLock::new(DashMap::new);
+
+#[derive(Debug, Serialize)]
+pub struct ReaderStatus {
+    pub source: String,
+    pub stream_key: String,
+    pub network: String,
+    pub state: ReaderState,
+    pub failures: u32,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+    /// When the reader got into its state.
+    pub since_ms: u128,
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Registers a reader that is about to start, and returns what `restart` notifies.
+pub fn register(source: &str, stream_key: &str, network: &str) -> Arc<Notify> {
+    let restart = Arc::new(Notify::new());
+    READERS.insert(
+        (source.to_string(), stream_key.to_string()),
+        Reader {
+            network: network.to_string(),
+            state: ReaderState::Running,
+            failures: 0,
+            restarts: 0,
+            last_error: None,
+            since_ms: now_ms(),
+            restart: Arc::clone(&restart),
+        },
+    );
+    restart
+}
+
+fn update(source: &str, stream_key: &str, update: impl FnOnce(&mut Reader)) {
+    if let Some(mut reader) = READERS.get_mut(&(source.to_string(), stream_key.to_string())) {
+        update(&mut reader);
+    }
+}
+
+/// The reader starts over, after a failure or on request.
+pub fn restarted(source: &str, stream_key: &str) {
+    update(source, stream_key, |reader| {
+        reader.state = ReaderState::Running;
+        reader.restarts += 1;
+        reader.since_ms = now_ms();
+    });
+}
+
+/// The reader stopped for good, on shutdown.
+pub fn stopped(source: &str, stream_key: &str) {
+    update(source, stream_key, |reader| {
+        reader.state = ReaderState::Stopped;
+        reader.since_ms = now_ms();
+    });
+}
+
+pub fn record_failure(source: &str, stream_key: &str, failures: u32, error: String) {
+    update(source, stream_key, |reader| {
+        reader.state = ReaderState::Backoff;
+        reader.failures = failures;
+        reader.last_error = Some(error);
+        reader.since_ms = now_ms();
+    });
+}
+
+/// The state of the reader of a stream, `None` if it has none.
+pub fn state(source: &str, stream_key: &str) -> Option<ReaderState> {
+    READERS
+        .get(&(source.to_string(), stream_key.to_string()))
+        .map(|reader| reader.state)
+}
+
+/// All readers, sorted by source and stream key.
+pub fn list() -> Vec<ReaderStatus> {
+    let mut readers = READERS
+        .iter()
+        .map(|entry| {
+            let ((source, stream_key), reader) = entry.pair();
+            ReaderStatus {
+                source: source.clone(),
+                stream_key: stream_key.clone(),
+                network: reader.network.clone(),
+                state: reader.state,
+                failures: reader.failures,
+                restarts: reader.restarts,
+                last_error: reader.last_error.clone(),
+                since_ms: reader.since_ms,
+            }
+        })
+        .collect::<Vec<_>>();
+    readers.sort_by(|a, b| (&a.source, &a.stream_key).cmp(&(&b.source, &b.stream_key)));
+    readers
+}
+
+/// Restarts the running readers of `source` and `stream_key`, or all if they're not
+/// set, from where they left off. Readers in backoff restart immediately.
+/// Returns the number of restarted readers.
+pub fn restart(source: Option<&str>, stream_key: Option<&str>) -> usize {
+    let mut restarted = 0;
+    for entry in READERS.iter() {
+        let ((reader_source, reader_stream_key), reader) = entry.pair();
+        if source.is_some_and(|source| source != reader_source)
+            || stream_key.is_some_and(|stream_key| stream_key != reader_stream_key)
+            || reader.state == ReaderState::Stopped
+        {
+            continue;
+        }
+        reader.restart.notify_one();
+        restarted += 1;
+    }
+    restarted
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn tracks_failures_and_restarts() {
+        let notified = register("readers_test", "nft_mint", "mainnet");
+        record_failure(
+            "readers_test",
+            "nft_mint",
+            1,
+            "Connection refused".to_string(),
+        );
+        assert_eq!(
+            state("readers_test", "nft_mint"),
+            Some(ReaderState::Backoff)
+        );
+        assert_eq!(restart(Some("readers_test"), None), 1);
+        restarted("readers_test", "nft_mint");
+        stopped("readers_test", "nft_mint");
+        assert_eq!(restart(Some("readers_test"), None), 0);
+
+        let status = list()
+            .into_iter()
+            .find(|reader| reader.source == "readers_test")
+            .unwrap();
+        assert_eq!(status.state, ReaderState::Stopped);
+        assert_eq!(status.restarts, 1);
+        assert_eq!(status.last_error.as_deref(), Some("Connection refused"));
+        // The first restart left a permit
+        assert!(notified.notified().now_or_never().is_some());
+    }
+}
@@ -1,55 +1,482 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
 
-use redis::{aio::ConnectionManager, Value};
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use redis::{aio::ConnectionManager, from_redis_value, Value};
+use tokio::{sync::watch, time::MissedTickBehavior};
+use tracing::Instrument;
+
+use crate::{
+    config::{StartPosition, StreamConfig},
+    metrics,
+    replay::{ReplayStart, StreamId, MAX_REPLAY_EVENTS},
+    unix_time_ms,
+};
+
+/// XREADs that fail or stall in a row before the reader gives up and is restarted
+/// with a new connection.
+const MAX_READ_ERRORS: u32 = 3;
+const READ_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How much longer than `xread_block_ms` an XREAD may take before it counts as stalled.
+const XREAD_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Appended to the checkpoint key for the key of the reader's recent IDs, see
+/// `EventHandler::recent_ids`.
+const RECENT_IDS_SUFFIX: &str = ":recent_ids";
+
+/// Where readers save the last read IDs.
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+    /// Prepended to the stream key.
+    pub prefix: String,
+    /// Start reading at new entries, instead of the saved IDs.
+    pub fresh: bool,
+}
+
+/// Last ID handled by the reader of each source and stream key.
+static LAST_READ_IDS: LazyLock<DashMap<(String, String), StreamId>> = LazyLock::new(DashMap::new);
+
+/// The last ID that the reader of this stream handled, or its checkpoint if it
+/// hasn't read anything since it started.
+pub fn last_read_id(source: &str, stream_key: &str) -> Option<StreamId> {
+    LAST_READ_IDS
+        .get(&(source.to_string(), stream_key.to_string()))
+        .map(|id| *id)
+}
+
+pub fn set_last_read_id(source: &str, stream_key: &str, id: &str) {
+    if let Ok(id) = id.parse() {
+        LAST_READ_IDS.insert((source.to_string(), stream_key.to_string()), id);
+    }
+}
 
 pub async fn create_connection(connection_url: &str) -> ConnectionManager {
-    let redis_client = redis::Client::open(connection_url).expect("Failed to create redis client");
-    ConnectionManager::new(redis_client)
+    connect(connection_url)
         .await
         .expect("Failed to create redis connection")
 }
 
+pub async fn connect(connection_url: &str) -> redis::RedisResult<ConnectionManager> {
+    ConnectionManager::new(redis::Client::open(connection_url)?).await
+}
+
+/// Connections of a stream reader. `read` is held by XREAD BLOCK until it
+/// returns, so checkpoints are saved with `checkpoint`, e.g. the shared connection
+/// of the source when `read` is a dedicated one for `xread_block_ms` 0.
+pub struct ReaderConnections {
+    pub read: ConnectionManager,
+    pub checkpoint: ConnectionManager,
+}
+
+/// Reads the stream until `shutdown` changes, saving the last read ID every
+/// `checkpoint_every_events` events or on a timer every `checkpoint_interval_ms`,
+/// whichever comes first, also while XREAD waits, and once more before returning.
+/// Returns an error when the stream can't be read, e.g. `MAX_READ_ERRORS` XREADs
+/// in a row failed or stalled, or an event couldn't be handled. Called again, it
+/// continues after the last handled event.
 pub async fn stream_events(
+    source: &str,
     stream_key: &str,
-    handler: impl EventHandler,
-    connection: ConnectionManager,
-) {
-    let save_key = &format!("events_api_websocket_last_id_{stream_key}");
-    let mut db = redis_db::RedisDB::new(connection).await;
-    let mut last_id = db.get(save_key).await.unwrap_or("$".to_string());
-    log::info!("Last ID for {stream_key}: {last_id}");
+    handler: &impl EventHandler,
+    connections: ReaderConnections,
+    config: &StreamConfig,
+    checkpoints: &Checkpoints,
+    shutdown: &mut watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let save_key = format!("{}{stream_key}", checkpoints.prefix);
+    let mut db = redis_db::RedisDB::new(connections.read).await;
+    let mut checkpoint = Checkpoint {
+        db: redis_db::RedisDB::new(connections.checkpoint).await,
+        recent_ids_key: format!("{save_key}{RECENT_IDS_SUFFIX}"),
+        key: save_key,
+        stream_key,
+        saved_id: String::new(),
+        unsaved_events: 0,
+    };
+    // Only once per server, the handler keeps them when the reader restarts
+    if last_read_id(source, stream_key).is_none() {
+        if let Some(saved) = db.get(&checkpoint.recent_ids_key).await? {
+            handler.restore_recent_ids(&saved);
+        }
+    }
+    let start = if checkpoints.fresh {
+        StartPosition::Latest
+    } else {
+        config.start
+    };
+    let mut last_id = match (last_read_id(source, stream_key), start) {
+        (Some(id), _) => id.to_string(),
+        (None, StartPosition::Latest) => "$".to_string(),
+        (None, start) => match db.get(&checkpoint.key).await? {
+            Some(id) => id,
+            None => match start {
+                StartPosition::Earliest => "0".to_string(),
+                StartPosition::After(id) => id.to_string(),
+                _ => "$".to_string(),
+            },
+        },
+    };
+    tracing::info!("Last ID for {stream_key}: {last_id}");
+    set_last_read_id(source, stream_key, &last_id);
 
-    'outer: loop {
-        let entries = db
-            .xread(100, stream_key, &last_id) // will fetch up to 100 if running behind, or wait for the next 1 if not
-            .await
-            .expect("Failed to read redis stream");
+    let block = Duration::from_millis(config.xread_block_ms);
+    let mut flush =
+        tokio::time::interval(Duration::from_millis(config.checkpoint_interval_ms.max(1)));
+    flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate
+    flush.tick().await;
+    checkpoint.saved_id = last_id.clone();
+    let mut read_errors = 0;
+    metrics::set_age(
+        metrics::CHECKPOINT_AGE,
+        &[("stream", stream_key)],
+        Instant::now(),
+    );
+    let result = 'outer: loop {
+        let xread_start = Instant::now();
+        // XREAD BLOCK 0 waits for the next entry however long it takes
+        let timeout = if block.is_zero() {
+            Duration::MAX
+        } else {
+            block + XREAD_STALL_TIMEOUT
+        };
+        let read = {
+            // will fetch up to xread_count if running behind, or wait for the next 1 if not
+            let xread = tokio::time::timeout(
+                timeout,
+                db.xread(config.xread_count, stream_key, &last_id, block),
+            );
+            tokio::pin!(xread);
+            // Checkpoints are saved on time while XREAD waits for new entries
+            loop {
+                tokio::select! {
+                    read = &mut xread => break read,
+                    _ = flush.tick() => {
+                        if let Err(err) = checkpoint.save(&last_id, handler).await {
+                            break 'outer Err(
+                                anyhow::Error::from(err).context("Failed to set last ID")
+                            );
+                        }
+                    }
+                    _ = shutdown.changed() => break 'outer Ok(()),
+                }
+            }
+        };
+        let entries = match read {
+            Ok(Ok(entries)) => {
+                read_errors = 0;
+                entries
+            }
+            Ok(Err(err)) => {
+                read_errors += 1;
+                tracing::warn!("Failed to read {stream_key} ({read_errors} in a row): {err}");
+                if read_errors >= MAX_READ_ERRORS {
+                    break 'outer Err(
+                        anyhow::Error::from(err).context("Failed to read redis stream")
+                    );
+                }
+                tokio::time::sleep(READ_RETRY_DELAY).await;
+                continue;
+            }
+            Err(_) => {
+                read_errors += 1;
+                tracing::warn!("XREAD of {stream_key} stalled ({read_errors} in a row)");
+                if read_errors >= MAX_READ_ERRORS {
+                    break 'outer Err(anyhow::anyhow!("XREAD of {stream_key} stalled"));
+                }
+                continue;
+            }
+        };
+        // Every batch is its own trace, the reader span lives as long as the server
+        let batch = if entries.is_empty() {
+            tracing::Span::none()
+        } else {
+            tracing::info_span!(
+                parent: None,
+                "batch",
+                source = %source,
+                stream = %stream_key,
+                size = entries.len(),
+                xread_ms = xread_start.elapsed().as_millis() as u64,
+            )
+        };
+        let read_any = !entries.is_empty();
         for (id, data) in entries {
-            if let Err(err) = handler.handle(data).await {
-                log::error!("Failed to handle event {id}: {err}");
-                log::error!("Stopped reading events from {stream_key}");
-                break 'outer;
+            if let Err(err) = handler.handle(&id, data).instrument(batch.clone()).await {
+                batch.in_scope(|| tracing::error!(%id, "Failed to handle event: {err}"));
+                break 'outer Err(err.context(format!("Failed to handle event {id}")));
             }
 
             last_id = id;
+            checkpoint.unsaved_events += 1;
+        }
+        if read_any {
+            set_last_read_id(source, stream_key, &last_id);
+        }
+        if checkpoint.unsaved_events >= config.checkpoint_every_events {
+            if let Err(err) = checkpoint.save(&last_id, handler).await {
+                break 'outer Err(anyhow::Error::from(err).context("Failed to set last ID"));
+            }
+            // The timer counts from the last save
+            flush.reset();
         }
-        db.set(save_key, &last_id)
+    };
+
+    if last_id != checkpoint.saved_id {
+        match checkpoint.save(&last_id, handler).await {
+            Ok(()) => tracing::info!("Saved last ID for {stream_key}: {last_id}"),
+            Err(err) => tracing::error!("Failed to save last ID for {stream_key}: {err}"),
+        }
+    }
+    result
+}
+
+/// The checkpoint of a stream reader, with the events that were handled since it
+/// was saved.
+struct Checkpoint<'a> {
+    db: redis_db::RedisDB,
+    key: String,
+    recent_ids_key: String,
+    stream_key: &'a str,
+    saved_id: String,
+    unsaved_events: u64,
+}
+
+impl Checkpoint<'_> {
+    /// Saves `last_id` and the recent IDs of the handler, if it changed.
+    async fn save(&mut self, last_id: &str, handler: &impl EventHandler) -> redis::RedisResult<()> {
+        if last_id == self.saved_id {
+            return Ok(());
+        }
+        self.db
+            .save_checkpoint(
+                &self.key,
+                last_id,
+                &self.recent_ids_key,
+                handler.recent_ids(),
+            )
+            .await?;
+        self.saved_id = last_id.to_string();
+        self.unsaved_events = 0;
+        metrics::set_age(
+            metrics::CHECKPOINT_AGE,
+            &[("stream", self.stream_key)],
+            Instant::now(),
+        );
+        Ok(())
+    }
+}
+
+/// Reads the entries that a replay should return, oldest first.
+pub async fn read_range(
+    connection: ConnectionManager,
+    stream_key: &str,
+    start: ReplayStart,
+) -> redis::RedisResult<Vec<(String, HashMap<String, Value>)>> {
+    let mut db = redis_db::RedisDB::new(connection).await;
+    match start {
+        ReplayStart::Last(count) => {
+            let mut entries = db.xrevrange(stream_key, "+", "-", count).await?;
+            entries.reverse();
+            Ok(entries)
+        }
+        ReplayStart::After(id) => {
+            let mut entries = Vec::new();
+            let mut last_id = id.to_string();
+            while entries.len() < MAX_REPLAY_EVENTS {
+                let page_size = (MAX_REPLAY_EVENTS - entries.len()).min(1000);
+                let page = db
+                    .xrange(stream_key, &format!("({last_id}"), "+", page_size)
+                    .await?;
+                let is_last_page = page.len() < page_size;
+                if let Some((id, _)) = page.last() {
+                    last_id = id.clone();
+                }
+                entries.extend(page);
+                if is_last_page {
+                    break;
+                }
+            }
+            Ok(entries)
+        }
+    }
+}
+
+/// Reads up to `count` entries after `after`, or from the start of the stream.
+pub async fn read_page(
+    connection: ConnectionManager,
+    stream_key: &str,
+    after: Option<StreamId>,
+    count: usize,
+) -> redis::RedisResult<Vec<(String, HashMap<String, Value>)>> {
+    let mut db = redis_db::RedisDB::new(connection).await;
+    let start = after.map_or("-".to_string(), |id| format!("({id}"));
+    db.xrange(stream_key, &start, "+", count).await
+}
+
+/// Hands the messages of a pub/sub channel to the handler until `shutdown`
+/// changes. Messages are JSON objects with the fields of a stream entry, as JSON
+/// or strings of JSON, and get IDs like stream entries from the time they arrive.
+/// There's no checkpoint: messages that are published while the reader isn't
+/// subscribed are lost.
+pub async fn channel_events(
+    source: &str,
+    channel: &str,
+    handler: &impl EventHandler,
+    connection_url: &str,
+    shutdown: &mut watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut pubsub = redis::Client::open(connection_url)?
+        .get_async_pubsub()
+        .await?;
+    pubsub.subscribe(channel).await?;
+    tracing::info!("Subscribed to {channel}");
+    let mut messages = pubsub.on_message();
+    let mut ids = EntryIds::default();
+    loop {
+        let message = tokio::select! {
+            message = messages.next() => message,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let Some(message) = message else {
+            anyhow::bail!("Subscription to {channel} ended");
+        };
+        let payload = message.get_payload::<String>()?;
+        let fields =
+            match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&payload) {
+                Ok(fields) => fields,
+                Err(err) => {
+                    tracing::warn!("Skipping a message that isn't a JSON object: {err}");
+                    continue;
+                }
+            };
+        let values = fields
+            .into_iter()
+            .map(|(field, value)| {
+                let json = match value {
+                    serde_json::Value::String(json) => json,
+                    value => value.to_string(),
+                };
+                (field, Value::Data(json.into_bytes()))
+            })
+            .collect();
+        let id = ids.next();
+        handler
+            .handle(&id, values)
             .await
-            .expect("Failed to set last ID");
+            .map_err(|err| err.context(format!("Failed to handle event {id}")))?;
+        set_last_read_id(source, channel, &id);
     }
 }
 
+/// Stream entry IDs for events that don't come from a stream: the time in
+/// milliseconds, and a sequence number for events in the same millisecond.
+#[derive(Default)]
+pub struct EntryIds {
+    last_ms: u128,
+    sequence: u64,
+}
+
+impl EntryIds {
+    pub fn next(&mut self) -> String {
+        let now_ms = unix_time_ms();
+        if now_ms == self.last_ms {
+            self.sequence += 1;
+        } else {
+            (self.last_ms, self.sequence) = (now_ms, 0);
+        }
+        format!("{now_ms}-{}", self.sequence)
+    }
+}
+
+/// Retention of a stream, from `XINFO STREAM`.
+pub struct StreamInfo {
+    pub length: u64,
+    /// ID of the oldest entry that is still retained.
+    pub first_id: Option<StreamId>,
+    /// ID of the newest entry, even if it was deleted.
+    pub last_id: Option<StreamId>,
+}
+
+/// Returns the retention of the stream. Streams that don't exist yet are empty.
+pub async fn stream_info(
+    mut connection: ConnectionManager,
+    stream_key: &str,
+) -> anyhow::Result<StreamInfo> {
+    let reply: redis::RedisResult<HashMap<String, Value>> = redis::cmd("XINFO")
+        .arg("STREAM")
+        .arg(stream_key)
+        .query_async(&mut connection)
+        .await;
+    let info = match reply {
+        Ok(info) => info,
+        Err(err) if err.code() == Some("ERR") && err.to_string().contains("no such key") => {
+            return Ok(StreamInfo {
+                length: 0,
+                first_id: None,
+                last_id: None,
+            })
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let first_id = match info.get("first-entry") {
+        Some(Value::Bulk(entry)) => match entry.first() {
+            Some(id) => Some(from_redis_value::<String>(id)?.parse()?),
+            None => None,
+        },
+        _ => None,
+    };
+    let last_id = match info.get("last-generated-id") {
+        Some(id) => Some(from_redis_value::<String>(id)?.parse::<StreamId>()?),
+        None => None,
+    };
+    Ok(StreamInfo {
+        length: info.get("length").map_or(Ok(0), from_redis_value)?,
+        first_id,
+        // 0-0 until the first entry is added
+        last_id: last_id.filter(|id| *id != StreamId(0, 0)),
+    })
+}
+
+/// Reads an ID saved with `save_id`, like a reader checkpoint.
+pub async fn load_id(
+    mut connection: ConnectionManager,
+    key: &str,
+) -> redis::RedisResult<Option<String>> {
+    redis::cmd("GET")
+        .arg(key)
+        .query_async(&mut connection)
+        .await
+}
+
+pub async fn save_id(connection: ConnectionManager, key: &str, id: &str) -> redis::RedisResult<()> {
+    let mut db = redis_db::RedisDB::new(connection).await;
+    db.set(key, id).await.map(drop)
+}
+
 #[async_trait::async_trait]
 pub trait EventHandler {
-    async fn handle(&self, values: HashMap<String, Value>) -> anyhow::Result<()>;
+    async fn handle(&self, id: &str, values: HashMap<String, Value>) -> anyhow::Result<()>;
+
+    /// The IDs of recently handled events, saved with the checkpoint of
+    /// `stream_events` so that duplicates are still recognized after a restart.
+    fn recent_ids(&self) -> Option<String> {
+        None
+    }
+
+    /// Restores IDs saved from `recent_ids`, before the first event.
+    fn restore_recent_ids(&self, _saved: &str) {}
 }
 
 // Modified version of https://github.com/fastnear/redis-node/blob/4b9eb42f5d22162fac22fa14e90481bc016483fa/src/bin/redis_db/mod.rs
 mod redis_db {
     use std::{collections::HashMap, time::Duration};
 
-    use itertools::Itertools;
-    use redis::{aio::ConnectionManager, from_redis_value, Value};
+    use redis::{aio::ConnectionManager, Value};
 
     use self::stream::*;
 
@@ -72,7 +499,24 @@ mod redis_db {
                 .await
         }
 
-        pub async fn get(&mut self, key: &str) -> redis::RedisResult<String> {
+        /// Sets the checkpoint and the recent IDs of a reader at once, so that they
+        /// always match.
+        pub async fn save_checkpoint(
+            &mut self,
+            key: &str,
+            id: &str,
+            recent_ids_key: &str,
+            recent_ids: Option<String>,
+        ) -> redis::RedisResult<()> {
+            let mut cmd = redis::cmd("MSET");
+            cmd.arg(key).arg(id);
+            if let Some(recent_ids) = recent_ids {
+                cmd.arg(recent_ids_key).arg(recent_ids);
+            }
+            cmd.query_async(&mut self.connection).await
+        }
+
+        pub async fn get(&mut self, key: &str) -> redis::RedisResult<Option<String>> {
             redis::cmd("GET")
                 .arg(key)
                 .query_async(&mut self.connection)
@@ -84,12 +528,13 @@ mod redis_db {
             count: usize,
             key: &str,
             id: &str,
+            block: Duration,
         ) -> redis::RedisResult<Vec<(String, HashMap<String, Value>)>> {
             let streams: Vec<Stream> = redis::cmd("XREAD")
                 .arg("COUNT")
                 .arg(count)
                 .arg("BLOCK")
-                .arg(Duration::from_millis(250).as_millis() as u64)
+                .arg(block.as_millis() as u64)
                 .arg("STREAMS")
                 .arg(key)
                 .arg(id)
@@ -99,21 +544,54 @@ mod redis_db {
                 .into_iter()
                 .filter(|s| s.id::<String>().unwrap() == key)
                 .flat_map(|s| s.entries.into_iter())
-                .map(|entry| {
-                    let id = entry.id().unwrap();
-                    let key_values = entry
-                        .key_values
-                        .into_iter()
-                        .tuples()
-                        .map(|(k, v)| (from_redis_value(&k).unwrap(), v))
-                        .collect();
-                    (id, key_values)
-                })
+                .map(Entry::into_id_and_values)
                 .collect())
         }
+
+        pub async fn xrange(
+            &mut self,
+            key: &str,
+            start: &str,
+            end: &str,
+            count: usize,
+        ) -> redis::RedisResult<Vec<(String, HashMap<String, Value>)>> {
+            self.range("XRANGE", key, start, end, count).await
+        }
+
+        pub async fn xrevrange(
+            &mut self,
+            key: &str,
+            end: &str,
+            start: &str,
+            count: usize,
+        ) -> redis::RedisResult<Vec<(String, HashMap<String, Value>)>> {
+            self.range("XREVRANGE", key, end, start, count).await
+        }
+
+        async fn range(
+            &mut self,
+            command: &str,
+            key: &str,
+            from: &str,
+            to: &str,
+            count: usize,
+        ) -> redis::RedisResult<Vec<(String, HashMap<String, Value>)>> {
+            let entries: Vec<Entry> = redis::cmd(command)
+                .arg(key)
+                .arg(from)
+                .arg(to)
+                .arg("COUNT")
+                .arg(count)
+                .query_async(&mut self.connection)
+                .await?;
+            Ok(entries.into_iter().map(Entry::into_id_and_values).collect())
+        }
     }
 
     mod stream {
+        use std::collections::HashMap;
+
+        use itertools::Itertools;
         use redis::{from_redis_value, FromRedisValue, RedisResult, Value};
 
         pub struct Stream {
@@ -151,6 +629,17 @@ mod redis_db {
             pub fn id<RV: FromRedisValue>(&self) -> RedisResult<RV> {
                 from_redis_value(&self.id)
             }
+
+            pub fn into_id_and_values(self) -> (String, HashMap<String, Value>) {
+                let id = self.id().unwrap();
+                let key_values = self
+                    .key_values
+                    .into_iter()
+                    .tuples()
+                    .map(|(k, v)| (from_redis_value(&k).unwrap(), v))
+                    .collect();
+                (id, key_values)
+            }
         }
     }
 }
@@ -0,0 +1,243 @@
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Event;
+
+/// Maximum number of events that are read from a source for one page of a
+/// replay, and that `replay_last` can ask for.
+pub const MAX_REPLAY_EVENTS: usize = 10_000;
+
+/// ID of a Redis stream entry, `<milliseconds>-<sequence>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct StreamId(pub u64, pub u64);
+
+impl StreamId {
+    /// The ID right before this one, so that a replay `After` it starts with it.
+    pub fn previous(self) -> Self {
+        match self {
+            StreamId(ms, 0) => StreamId(ms.saturating_sub(1), u64::MAX),
+            StreamId(ms, seq) => StreamId(ms, seq - 1),
+        }
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ms, seq) = s.split_once('-').unwrap_or((s, "0"));
+        Ok(StreamId(ms.parse()?, seq.parse()?))
+    }
+}
+
+impl TryFrom<String> for StreamId {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<StreamId> for String {
+    fn from(id: StreamId) -> Self {
+        id.to_string()
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.0, self.1)
+    }
+}
+
+/// Where a replay starts, from the `from_stream_id` or `replay_last` query parameter.
+#[derive(Debug, Clone, Copy)]
+pub enum ReplayStart {
+    /// All events after this ID.
+    After(StreamId),
+    /// The last N events.
+    Last(usize),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayQuery {
+    from_stream_id: Option<StreamId>,
+    replay_last: Option<usize>,
+}
+
+impl ReplayQuery {
+    pub fn start(&self) -> Option<ReplayStart> {
+        if let Some(id) = self.from_stream_id {
+            Some(ReplayStart::After(id))
+        } else {
+            self.replay_last
+                .map(|count| ReplayStart::Last(count.min(MAX_REPLAY_EVENTS)))
+        }
+    }
+}
+
+/// Events of a replay, sorted by ID, and where the next page starts if a source
+/// had more events than are read at once.
+pub struct ReplayPage<E> {
+    pub events: Vec<Arc<Event<E>>>,
+    pub next: Option<StreamId>,
+}
+
+impl<E> ReplayPage<E> {
+    /// Sorts the events that were read from all sources into a page. Sources that
+    /// were `truncated` at `MAX_REPLAY_EVENTS` have more events after the last
+    /// one that was read, so the page ends at the first of their last events, and
+    /// the events of other sources after it are read again with the next page.
+    pub fn new(mut events: Vec<Arc<Event<E>>>, truncated: &[StreamId]) -> Self {
+        // Stable sort keeps each source's events in stream order
+        events.sort_by_key(|event| event.id);
+        let next = truncated.iter().min().copied();
+        if let Some(next) = next {
+            events.retain(|event| event.id <= next);
+        }
+        ReplayPage { events, next }
+    }
+}
+
+/// Removes the buffered live events that a page of a replay returned, so that
+/// they aren't delivered again after the replay.
+pub fn skip_replayed<E>(replayed: &[Arc<Event<E>>], buffered: &mut Vec<Arc<Event<E>>>) {
+    let mut last_replayed = HashMap::new();
+    for event in replayed {
+        last_replayed.insert(Arc::clone(&event.source), event.id);
+    }
+    buffered.retain(|event| {
+        last_replayed
+            .get(&event.source)
+            .is_none_or(|last_id| event.id > *last_id)
+    });
+}
+
+/// Joins replayed events with live events that were buffered while the replay
+/// was being read. Live events that were also returned by the replay are skipped,
+/// so that every event is delivered once and events of one source stay in stream order.
+pub fn handover<E>(
+    replayed: Vec<Arc<Event<E>>>,
+    mut buffered: Vec<Arc<Event<E>>>,
+) -> Vec<Arc<Event<E>>> {
+    skip_replayed(&replayed, &mut buffered);
+    let mut events = replayed;
+    events.extend(buffered);
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(source: &str, id: &str) -> Arc<Event<()>> {
+        Arc::new(Event {
+            source: source.into(),
+            id: id.parse().unwrap(),
+            duplicate: false,
+            event: (),
+            span: tracing::Span::none(),
+            frames: Default::default(),
+            raw: None,
+        })
+    }
+
+    fn ids(events: &[Arc<Event<()>>]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| format!("{}:{}", event.source, event.id))
+            .collect()
+    }
+
+    #[test]
+    fn parses_stream_ids() {
+        assert_eq!("1715-3".parse::<StreamId>().unwrap(), StreamId(1715, 3));
+        assert_eq!("1715".parse::<StreamId>().unwrap(), StreamId(1715, 0));
+        assert!("abc-1".parse::<StreamId>().is_err());
+        assert!(StreamId(10, 0) > StreamId(9, 99));
+        assert!(StreamId(10, 2) > StreamId(10, 1));
+        assert_eq!(StreamId(10, 2).previous(), StreamId(10, 1));
+        assert_eq!(StreamId(10, 0).previous(), StreamId(9, u64::MAX));
+    }
+
+    #[test]
+    fn live_events_that_overlap_replay_are_delivered_once() {
+        let replayed = vec![event("a", "1-0"), event("a", "2-0"), event("a", "3-0")];
+        let buffered = vec![event("a", "3-0"), event("a", "4-0")];
+        assert_eq!(
+            ids(&handover(replayed, buffered)),
+            ["a:1-0", "a:2-0", "a:3-0", "a:4-0"]
+        );
+    }
+
+    #[test]
+    fn live_events_after_replay_keep_order() {
+        let replayed = vec![event("a", "1-0")];
+        let buffered = vec![event("a", "2-0"), event("a", "3-0")];
+        assert_eq!(
+            ids(&handover(replayed, buffered)),
+            ["a:1-0", "a:2-0", "a:3-0"]
+        );
+    }
+
+    #[test]
+    fn empty_replay_delivers_all_buffered_events() {
+        let buffered = vec![event("a", "5-0"), event("a", "6-0")];
+        assert_eq!(ids(&handover(vec![], buffered)), ["a:5-0", "a:6-0"]);
+    }
+
+    #[test]
+    fn pages_end_at_the_first_truncated_source() {
+        let read = vec![
+            event("a", "1-0"),
+            event("b", "2-0"),
+            event("a", "3-0"),
+            event("b", "4-0"),
+            event("b", "5-0"),
+        ];
+        // `a` had more events after 3-0, `b` was read to the end
+        let page = ReplayPage::new(read, &[StreamId(3, 0)]);
+        assert_eq!(ids(&page.events), ["a:1-0", "b:2-0", "a:3-0"]);
+        assert_eq!(page.next, Some(StreamId(3, 0)));
+
+        let page = ReplayPage::new(vec![event("b", "2-0"), event("a", "1-0")], &[]);
+        assert_eq!(ids(&page.events), ["a:1-0", "b:2-0"]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn live_events_buffered_during_a_paged_replay_are_delivered_once() {
+        // Live events arrive while the first page is read, and are in it
+        let mut buffered = vec![event("a", "2-0"), event("b", "3-0")];
+        let first = ReplayPage::new(
+            vec![event("a", "1-0"), event("a", "2-0"), event("b", "3-0")],
+            &[StreamId(2, 0)],
+        );
+        skip_replayed(&first.events, &mut buffered);
+        assert_eq!(ids(&buffered), ["b:3-0"]);
+        // More arrive while the last page is read, which doesn't have `a` anymore
+        buffered.push(event("a", "4-0"));
+        let last = ReplayPage::new(vec![event("b", "3-0")], &[]);
+        assert_eq!(last.next, None);
+        let mut delivered = first.events;
+        delivered.extend(handover(last.events, buffered));
+        assert_eq!(ids(&delivered), ["a:1-0", "a:2-0", "b:3-0", "a:4-0"]);
+    }
+
+    #[test]
+    fn sources_are_handed_over_independently() {
+        let replayed = vec![event("a", "10-0"), event("b", "3-0")];
+        let buffered = vec![
+            event("b", "3-0"),
+            event("a", "9-0"),
+            event("b", "4-0"),
+            event("a", "11-0"),
+        ];
+        assert_eq!(
+            ids(&handover(replayed, buffered)),
+            ["a:10-0", "b:3-0", "b:4-0", "a:11-0"]
+        );
+    }
+}
@@ -0,0 +1,30 @@
+//! Optional error reporting to Sentry with the `sentry` crate, enabled by setting
+//! `SENTRY_DSN`. Every `error` log line is reported by the layer of
+//! `logging::init`, with the fields of its spans (like `stream`, `source` and the
+//! entry `id`) as tags. Panics are logged as errors, so they're reported too.
+
+use sentry::{types::Dsn, ClientInitGuard, ClientOptions};
+
+/// Starts the Sentry client if `SENTRY_DSN` is set, with the environment in
+/// `SENTRY_ENVIRONMENT`. Reports are sent until the guard is dropped.
+pub fn init() -> anyhow::Result<Option<ClientInitGuard>> {
+    let Ok(dsn) = std::env::var("SENTRY_DSN") else {
+        return Ok(None);
+    };
+    let dsn = dsn
+        .parse::<Dsn>()
+        .map_err(|err| anyhow::anyhow!("Invalid SENTRY_DSN: {err}"))?;
+    let mut options = ClientOptions::new();
+    options.dsn = Some(dsn);
+    options.release = sentry::release_name!();
+    Ok(Some(sentry::init(options)))
+}
+
+/// Logs panics as errors, within the spans that were entered when they happened.
+pub fn log_panics() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        tracing::error!(panic = true, "{info}");
+        default_hook(info);
+    }));
+}
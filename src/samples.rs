@@ -0,0 +1,114 @@
+//! Sample payloads at `/v0/.../<endpoint>/sample`: the latest event of the stream,
+//! so integrators can see what events look like and test their parsers without
+//! waiting for one. The latest entry is read from Redis and cached for a while.
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{
+    disabled_response, plans, replay::ReplayStart, FromRedis, Networks, ReadReplay, Server,
+    TaggedEvent, DEFAULT_NETWORK,
+};
+
+/// How long a sample is served before the latest event is read again.
+const SAMPLE_TTL: Duration = Duration::from_secs(10);
+
+/// The JSON of the latest event, `None` if the stream had no events, and when it
+/// was read.
+type Sample = (Instant, Option<String>);
+
+/// Samples by network and stream.
+static SAMPLES: LazyLock<DashMap<(String, &'static str), Sample>> = LazyLock::new(DashMap::new);
+
+fn cached(network: &str, stream: &'static str, now: Instant) -> Option<Option<String>> {
+    SAMPLES
+        .get(&(network.to_string(), stream))
+        .filter(|sample| now.duration_since(sample.0) < SAMPLE_TTL)
+        .map(|sample| sample.1.clone())
+}
+
+pub async fn sample<E: Serialize + FromRedis + Send + Sync + 'static>(
+    req: HttpRequest,
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+) -> HttpResponse {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return HttpResponse::NotFound().body(format!("Unknown network: {network}"));
+    }
+    if let Some(response) = disabled_response::<E>(&req) {
+        return response;
+    }
+    if let Err(err) = plans::check_access(&req, E::STREAM_KEY) {
+        return err.error_response();
+    }
+
+    let sample = match cached(&network, E::STREAM_KEY, Instant::now()) {
+        Some(sample) => sample,
+        None => {
+            let latest = match server
+                .send(ReadReplay::<E> {
+                    network: network.clone(),
+                    start: ReplayStart::Last(1),
+                    _marker: Default::default(),
+                })
+                .await
+            {
+                Ok(Ok(latest)) => latest.events,
+                Ok(Err(err)) => {
+                    tracing::error!("Failed to read a sample of {}: {err}", E::STREAM_KEY);
+                    return HttpResponse::InternalServerError().body("Failed to read events");
+                }
+                Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+            };
+            // The latest event of every source, sorted by stream ID
+            let sample = latest.last().map(|event| {
+                serde_json::to_string(&TaggedEvent {
+                    source: &event.source,
+                    stream_id: event.id,
+                    event: &event.event,
+                })
+                .unwrap()
+            });
+            SAMPLES.insert((network, E::STREAM_KEY), (Instant::now(), sample.clone()));
+            sample
+        }
+    };
+    match sample {
+        Some(sample) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(sample),
+        None => HttpResponse::NotFound().body("The stream has no events yet"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_expire() {
+        let now = Instant::now();
+        SAMPLES.insert(
+            ("samples-test".to_string(), "nft_mint"),
+            (now, Some("{}".to_string())),
+        );
+        assert_eq!(
+            cached("samples-test", "nft_mint", now + Duration::from_secs(1)),
+            Some(Some("{}".to_string()))
+        );
+        assert_eq!(cached("samples-test", "nft_mint", now + SAMPLE_TTL), None);
+        assert_eq!(cached("samples-test", "nft_burn", now), None);
+    }
+}
@@ -0,0 +1,54 @@
+//! `sample_rate` of connections: only a fraction of the matching events is sent,
+//! for consumers that want a cheaper, representative feed.
+
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState};
+
+use crate::replay::StreamId;
+
+/// Checks a `sample_rate` of a client message.
+pub fn validate(rate: f64) -> Result<(), String> {
+    if rate > 0.0 && rate <= 1.0 {
+        Ok(())
+    } else {
+        Err(format!(
+            "sample_rate must be above 0 and at most 1, got {rate}"
+        ))
+    }
+}
+
+/// Whether the event is in the sample. Deterministic samples are the same for
+/// every connection with the same rate, and a sample at a lower rate is a subset
+/// of those at higher rates.
+pub fn sampled(rate: f64, deterministic: bool, id: StreamId) -> bool {
+    let hash = if deterministic {
+        // Keys of zero, so the hash is the same on every server
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        hasher.finish()
+    } else {
+        RandomState::new().build_hasher().finish()
+    };
+    ((hash >> 11) as f64 / (1u64 << 53) as f64) < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_about_the_rate() {
+        let ids = (0..10_000).map(|ms| StreamId(ms, 0)).collect::<Vec<_>>();
+        for deterministic in [false, true] {
+            let count = ids
+                .iter()
+                .filter(|id| sampled(0.1, deterministic, **id))
+                .count();
+            assert!((800..1200).contains(&count), "{count}");
+        }
+        assert!(ids.iter().all(|id| sampled(1.0, false, *id)));
+        assert!(ids
+            .iter()
+            .filter(|id| sampled(0.1, true, **id))
+            .all(|id| sampled(0.5, true, *id)));
+    }
+}
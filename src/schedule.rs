@@ -0,0 +1,112 @@
+//! `active_hours` of connections: outside of them, matching events are dropped
+//! while the connection and its filter stay, e.g. for alerting integrations that
+//! must not page at night.
+
+use serde::{de::Error, Deserialize, Deserializer};
+use time::{OffsetDateTime, Weekday};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActiveHours {
+    /// `HH:MM` in UTC.
+    #[serde(deserialize_with = "minute_of_day")]
+    from: u16,
+    /// `HH:MM` in UTC, excluded. Hours that pass midnight, e.g. from `22:00` to
+    /// `06:00`, are allowed, and the same time as `from` means the whole day.
+    #[serde(deserialize_with = "minute_of_day")]
+    to: u16,
+    /// Days in UTC, e.g. `["mon", "tue"]`, or every day if empty.
+    #[serde(default, deserialize_with = "weekdays")]
+    days: Vec<Weekday>,
+}
+
+impl ActiveHours {
+    /// Whether events are delivered at this time.
+    pub fn contains(&self, time: OffsetDateTime) -> bool {
+        if !self.days.is_empty() && !self.days.contains(&time.weekday()) {
+            return false;
+        }
+        let minute = u16::from(time.hour()) * 60 + u16::from(time.minute());
+        if self.from < self.to {
+            self.from <= minute && minute < self.to
+        } else {
+            self.from <= minute || minute < self.to
+        }
+    }
+}
+
+fn minute_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    let minute = text
+        .split_once(':')
+        .filter(|(hours, minutes)| hours.len() == 2 && minutes.len() == 2)
+        .and_then(|(hours, minutes)| {
+            Some((hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?))
+        })
+        .filter(|&(hours, minutes)| hours < 24 && minutes < 60)
+        .map(|(hours, minutes)| hours * 60 + minutes);
+    minute.ok_or_else(|| D::Error::custom(format!("Invalid time {text:?}, expected HH:MM")))
+}
+
+fn weekdays<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Weekday>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|day| match day.as_str() {
+            "mon" => Ok(Weekday::Monday),
+            "tue" => Ok(Weekday::Tuesday),
+            "wed" => Ok(Weekday::Wednesday),
+            "thu" => Ok(Weekday::Thursday),
+            "fri" => Ok(Weekday::Friday),
+            "sat" => Ok(Weekday::Saturday),
+            "sun" => Ok(Weekday::Sunday),
+            _ => Err(D::Error::custom(format!(
+                "Invalid day {day:?}, expected mon, tue, wed, thu, fri, sat or sun"
+            ))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn at(unix_time: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(unix_time).unwrap()
+    }
+
+    #[test]
+    fn contains_times_and_days() {
+        // Monday 2024-01-01
+        let monday = 1_704_067_200;
+        let hours = serde_json::from_value::<ActiveHours>(
+            json!({ "from": "08:00", "to": "20:00", "days": ["mon", "fri"] }),
+        )
+        .unwrap();
+        assert!(!hours.contains(at(monday + 7 * 3600 + 59 * 60)));
+        assert!(hours.contains(at(monday + 8 * 3600)));
+        assert!(!hours.contains(at(monday + 20 * 3600)));
+        assert!(!hours.contains(at(monday + 86400 + 12 * 3600)));
+
+        let night =
+            serde_json::from_value::<ActiveHours>(json!({ "from": "22:00", "to": "06:00" }))
+                .unwrap();
+        assert!(night.contains(at(monday + 23 * 3600)));
+        assert!(night.contains(at(monday + 5 * 3600)));
+        assert!(!night.contains(at(monday + 12 * 3600)));
+
+        let always =
+            serde_json::from_value::<ActiveHours>(json!({ "from": "00:00", "to": "00:00" }))
+                .unwrap();
+        assert!(always.contains(at(monday + 12 * 3600)));
+
+        for invalid in [
+            json!({ "from": "8:00", "to": "20:00" }),
+            json!({ "from": "24:00", "to": "20:00" }),
+            json!({ "from": "08:00", "to": "20:00", "days": ["monday"] }),
+        ] {
+            assert!(serde_json::from_value::<ActiveHours>(invalid).is_err());
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Session tokens, if `session_secret` is configured: protocol 2 clients get
+//! `{"type": "session", "token": <string>}` with their filter message and the last
+//! event they handled, and connecting with `?session=<token>`, e.g. after a server
+//! restart, applies the filter again and replays the events after it. Tokens are
+//! signed, so clients can't change them, and the state lives in the token, so it
+//! survives restarts without storage.
+
+use base64::prelude::*;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::replay::StreamId;
+
+/// How often clients get a new token if their last event changed.
+pub const SESSION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub struct Sessions {
+    key: hmac::Key,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// Stream key of the endpoint.
+    pub endpoint: String,
+    pub network: String,
+    /// The filter and options, like the message of the client.
+    pub message: String,
+    pub resume_from: Option<StreamId>,
+}
+
+impl Sessions {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    /// `<payload>.<signature>`, both base64url.
+    pub fn issue(&self, session: &Session) -> String {
+        let payload = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(session).unwrap());
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        format!(
+            "{payload}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    /// The session of a token, `None` if it's invalid or signed with another secret.
+    pub fn verify(&self, token: &str) -> Option<Session> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac::verify(&self.key, payload.as_bytes(), &signature).ok()?;
+        serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signed_tokens() {
+        let sessions = Sessions::new("secret");
+        let session = Session {
+            endpoint: "nft_mint".to_string(),
+            network: "mainnet".to_string(),
+            message: r#"{"owner_id":"alice.near"}"#.to_string(),
+            resume_from: Some(StreamId(1715, 3)),
+        };
+        let token = sessions.issue(&session);
+        assert_eq!(sessions.verify(&token), Some(session));
+        assert!(Sessions::new("other").verify(&token).is_none());
+
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = BASE64_URL_SAFE_NO_PAD.encode(
+            String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(payload).unwrap())
+                .unwrap()
+                .replace("alice.near", "bob.near"),
+        );
+        assert!(sessions.verify(&format!("{forged}.{signature}")).is_none());
+        assert!(sessions.verify("garbage").is_none());
+    }
+}
@@ -0,0 +1,60 @@
+//! Signed events for API keys with a `signing_secret`: every event frame ends with
+//! `"signature": <hex>`, the HMAC-SHA256 of the frame before the signature was
+//! added, so that systems that the events are relayed to can verify that they
+//! came from this server. To verify, remove `,"signature":"<hex>"` from the end,
+//! before the closing brace, and sign the rest.
+
+use std::fmt::Write;
+
+use bytestring::ByteString;
+use ring::hmac;
+
+pub fn key(secret: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+/// Appends the signature to a JSON object frame.
+pub fn sign(key: &hmac::Key, frame: &str) -> ByteString {
+    let mut signed = frame.strip_suffix('}').unwrap_or(frame).to_string();
+    write!(
+        signed,
+        r#","signature":"{}"}}"#,
+        hex_signature(key, frame.as_bytes())
+    )
+    .unwrap();
+    signed.into()
+}
+
+/// The HMAC-SHA256 of a message in lowercase hex.
+pub fn hex_signature(key: &hmac::Key, message: &[u8]) -> String {
+    hmac::sign(key, message)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_covers_the_frame_without_it() {
+        let key = key("secret");
+        let frame = r#"{"stream_id":"1-0","event":{"owner_id":"alice.near"}}"#;
+        let signed = sign(&key, frame);
+        let value = serde_json::from_str::<serde_json::Value>(&signed).unwrap();
+        let signature = value["signature"].as_str().unwrap();
+        assert_eq!(signature.len(), 64);
+
+        let unsigned = signed
+            .strip_suffix(&format!(r#","signature":"{signature}"}}"#))
+            .unwrap();
+        assert_eq!(format!("{unsigned}}}"), frame);
+        let bytes = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        assert!(hmac::verify(&key, frame.as_bytes(), &bytes).is_ok());
+    }
+}
@@ -0,0 +1,160 @@
+//! Tiny SMTP client for sending plain text emails, with STARTTLS or TLS and
+//! `AUTH PLAIN`.
+
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{SmtpConfig, SmtpSecurity},
+    http_client::TLS_CONNECTOR,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+pub struct Email<'a> {
+    pub to: &'a [String],
+    pub subject: &'a str,
+    pub body: &'a str,
+}
+
+pub async fn send(config: &SmtpConfig, email: &Email<'_>) -> anyhow::Result<()> {
+    tokio::time::timeout(TIMEOUT, send_inner(config, email)).await?
+}
+
+async fn send_inner(config: &SmtpConfig, email: &Email<'_>) -> anyhow::Result<()> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    let stream: Box<dyn Io> = if config.security == SmtpSecurity::Tls {
+        Box::new(tls(config, tcp).await?)
+    } else {
+        Box::new(tcp)
+    };
+    let mut connection = Connection {
+        stream: BufReader::new(stream),
+    };
+    connection.reply(220).await?;
+    connection.command("EHLO localhost", 250).await?;
+    if config.security == SmtpSecurity::Starttls {
+        connection.command("STARTTLS", 220).await?;
+        let tcp = connection.stream.into_inner();
+        connection = Connection {
+            stream: BufReader::new(Box::new(tls(config, tcp).await?)),
+        };
+        connection.command("EHLO localhost", 250).await?;
+    }
+    if let Some(user) = &config.user {
+        let password = config.password.as_deref().unwrap_or_default();
+        let credentials = base64(format!("\0{user}\0{password}").as_bytes());
+        connection
+            .command(&format!("AUTH PLAIN {credentials}"), 235)
+            .await?;
+    }
+
+    connection
+        .command(&format!("MAIL FROM:<{}>", config.from), 250)
+        .await?;
+    for to in email.to {
+        connection.command(&format!("RCPT TO:<{to}>"), 250).await?;
+    }
+    connection.command("DATA", 354).await?;
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        config.from,
+        email.to.join(", "),
+        email.subject.replace(['\r', '\n'], " "),
+        OffsetDateTime::now_utc().format(&Rfc2822)?,
+    );
+    for line in email.body.lines() {
+        // Lines that start with a dot are escaped by doubling it
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push('.');
+    connection.command(&message, 250).await?;
+    connection.command("QUIT", 221).await?;
+    Ok(())
+}
+
+async fn tls(config: &SmtpConfig, stream: impl Io + 'static) -> anyhow::Result<impl Io> {
+    let server_name = ServerName::try_from(config.host.clone())?;
+    Ok(TLS_CONNECTOR.connect(server_name, stream).await?)
+}
+
+struct Connection {
+    stream: BufReader<Box<dyn Io>>,
+}
+
+impl Connection {
+    async fn command(&mut self, command: &str, expected: u16) -> anyhow::Result<()> {
+        self.stream.get_mut().write_all(command.as_bytes()).await?;
+        self.stream.get_mut().write_all(b"\r\n").await?;
+        self.stream.get_mut().flush().await?;
+        self.reply(expected).await
+    }
+
+    /// Reads a (possibly multiline) reply and checks its code.
+    async fn reply(&mut self, expected: u16) -> anyhow::Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                anyhow::bail!("Connection closed");
+            }
+            // The last line of a reply has a space after the code, others have a dash
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            if code != Some(expected) {
+                anyhow::bail!("Expected {expected}, got {}", line.trim_end());
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"\0user\0pass"), "AHVzZXIAcGFzcw==");
+    }
+}
@@ -0,0 +1,149 @@
+//! Statistics mode of the WebSocket endpoints: with the `stats` option, clients
+//! get aggregates of the matching events every `window_sec` instead of the events,
+//! `{"type": "stats", "window_sec": 10, "count": 124, "sums": {...}, "unique": {...}}`.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsOptions {
+    pub window_sec: u64,
+    /// Fields whose values are summed, e.g. `total_amount`, or `a/b` for nested
+    /// fields. Values are integers or strings of integers, like amounts in yocto.
+    #[serde(default)]
+    pub sum: Vec<String>,
+    /// Fields whose distinct values are counted, e.g. `donor_id`. Every item of
+    /// an array is counted.
+    #[serde(default)]
+    pub unique: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    count: u64,
+    sums: HashMap<String, i128>,
+    unique: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "stats")]
+pub struct StatsFrame {
+    window_sec: u64,
+    count: u64,
+    /// Stringified, since they don't fit into JSON numbers.
+    sums: HashMap<String, String>,
+    unique: HashMap<String, usize>,
+}
+
+impl Stats {
+    pub fn add(&mut self, options: &StatsOptions, event: &Value) {
+        self.count += 1;
+        for name in &options.sum {
+            let amount = match field(event, name) {
+                Some(Value::String(amount)) => amount.parse().ok(),
+                Some(Value::Number(amount)) => amount.as_i64().map(i128::from),
+                _ => None,
+            };
+            let sum = self.sums.entry(name.clone()).or_default();
+            *sum = sum.saturating_add(amount.unwrap_or_default());
+        }
+        for name in &options.unique {
+            let values = self.unique.entry(name.clone()).or_default();
+            match field(event, name) {
+                Some(Value::Array(items)) => values.extend(items.iter().map(to_key)),
+                Some(Value::Null) | None => {}
+                Some(value) => {
+                    values.insert(to_key(value));
+                }
+            }
+        }
+    }
+
+    /// Returns the aggregates of the window and starts the next one.
+    pub fn take(&mut self, options: &StatsOptions) -> StatsFrame {
+        let stats = std::mem::take(self);
+        let mut sums = stats.sums;
+        let mut unique = stats.unique;
+        StatsFrame {
+            window_sec: options.window_sec,
+            count: stats.count,
+            sums: options
+                .sum
+                .iter()
+                .map(|name| {
+                    let sum = sums.remove(name).unwrap_or_default();
+                    (name.clone(), sum.to_string())
+                })
+                .collect(),
+            unique: options
+                .unique
+                .iter()
+                .map(|name| (name.clone(), unique.remove(name).map_or(0, |v| v.len())))
+                .collect(),
+        }
+    }
+}
+
+pub fn field<'a>(event: &'a Value, name: &str) -> Option<&'a Value> {
+    if name.contains('/') {
+        event.pointer(&format!("/{name}"))
+    } else {
+        event.get(name)
+    }
+}
+
+pub fn to_key(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn aggregates_window() {
+        let options = StatsOptions {
+            window_sec: 10,
+            sum: vec!["total_amount".to_string(), "changes/wrap.near".to_string()],
+            unique: vec!["donor_id".to_string(), "token_ids".to_string()],
+        };
+        let mut stats = Stats::default();
+        stats.add(
+            &options,
+            &json!({
+                "total_amount": "1000000000000000000000000",
+                "changes": { "wrap.near": "-5" },
+                "donor_id": "a.near",
+                "token_ids": ["1", "2"],
+            }),
+        );
+        stats.add(
+            &options,
+            &json!({ "total_amount": 5, "changes": {}, "donor_id": "a.near", "token_ids": ["2"] }),
+        );
+        let frame = serde_json::to_value(stats.take(&options)).unwrap();
+        assert_eq!(
+            frame,
+            json!({
+                "type": "stats",
+                "window_sec": 10,
+                "count": 2,
+                "sums": {
+                    "total_amount": "1000000000000000000000005",
+                    "changes/wrap.near": "-5",
+                },
+                "unique": { "donor_id": 1, "token_ids": 2 },
+            })
+        );
+        let frame = serde_json::to_value(stats.take(&options)).unwrap();
+        assert_eq!(frame["count"], 0);
+        assert_eq!(frame["sums"]["total_amount"], "0");
+    }
+}
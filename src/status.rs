@@ -0,0 +1,180 @@
+//! `GET /status`: health of every stream, connected clients, uptime and version,
+//! for public status pages and uptime monitors.
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+use crate::{
+    broadcast::for_each_event_type,
+    connections,
+    drain::Drain,
+    history::StreamSources,
+    readers::{self, ReaderState},
+    redis_reader::{last_read_id, stream_info},
+    replay::StreamId,
+    streams::StreamKeys,
+    version, DisabledStreams, Networks, Server, StreamSource,
+};
+
+/// Streams that are read further behind than this are `lagging`.
+const MAX_HEALTHY_LAG: Duration = Duration::from_secs(60);
+
+static STARTED_AT: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Starts counting the uptime.
+pub fn mark_started() {
+    LazyLock::force(&STARTED_AT);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Health {
+    Ok,
+    Disabled,
+    Draining,
+    Lagging,
+    Down,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    /// The worst health of all streams, or `draining`.
+    status: Health,
+    version: &'static str,
+    commit: Option<&'static str>,
+    uptime_sec: u64,
+    connected_clients: usize,
+    networks: Vec<NetworkStatus>,
+}
+
+#[derive(Serialize)]
+struct NetworkStatus {
+    network: String,
+    connected_clients: usize,
+    streams: Vec<StreamHealth>,
+}
+
+#[derive(Serialize)]
+struct StreamHealth {
+    stream: &'static str,
+    /// `None` for disabled streams.
+    source: Option<String>,
+    status: Health,
+    /// Milliseconds between the last event of the stream and the last event read.
+    lag_ms: Option<u64>,
+    last_read_stream_id: Option<StreamId>,
+    /// `None` for disabled streams.
+    reader: Option<ReaderState>,
+}
+
+pub async fn status(
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+    disabled: web::Data<DisabledStreams>,
+    drain: web::Data<Drain>,
+) -> HttpResponse {
+    let mut stream_keys = StreamKeys::default();
+    for_each_event_type(&mut stream_keys);
+    let connections = connections::list();
+
+    let mut network_names = networks.iter().cloned().collect::<Vec<_>>();
+    network_names.sort();
+    let mut statuses = Vec::new();
+    for network in network_names {
+        let mut streams = Vec::new();
+        for &stream in &stream_keys.0 {
+            if disabled.contains(stream) {
+                streams.push(StreamHealth {
+                    stream,
+                    source: None,
+                    status: Health::Disabled,
+                    lag_ms: None,
+                    last_read_stream_id: None,
+                    reader: None,
+                });
+                continue;
+            }
+            let sources = server
+                .send(StreamSources {
+                    network: network.clone(),
+                    stream_key: stream,
+                })
+                .await
+                .unwrap_or_default();
+            for StreamSource {
+                name: source,
+                stream_key,
+                connection,
+                ..
+            } in sources
+            {
+                let last_read_stream_id = last_read_id(&source, &stream_key);
+                let (status, lag_ms) = match stream_info(connection, &stream_key).await {
+                    Ok(info) => {
+                        let lag_ms = info
+                            .last_id
+                            .zip(last_read_stream_id)
+                            .map(|(last, read)| last.0.saturating_sub(read.0));
+                        let lagging = lag_ms
+                            .is_some_and(|lag_ms| lag_ms > MAX_HEALTHY_LAG.as_millis() as u64);
+                        let status = if lagging { Health::Lagging } else { Health::Ok };
+                        (status, lag_ms)
+                    }
+                    Err(err) => {
+                        tracing::warn!("Failed to read info of {stream_key}: {err}");
+                        (Health::Down, None)
+                    }
+                };
+                let reader = readers::state(&source, &stream_key);
+                // A reader that failed isn't reading, however close it got
+                let status = match reader {
+                    Some(ReaderState::Backoff | ReaderState::Stopped) => Health::Down,
+                    _ => status,
+                };
+                streams.push(StreamHealth {
+                    stream,
+                    source: Some(source.to_string()),
+                    status,
+                    lag_ms,
+                    last_read_stream_id,
+                    reader,
+                });
+            }
+        }
+        statuses.push(NetworkStatus {
+            connected_clients: connections
+                .iter()
+                .filter(|connection| connection.network == network)
+                .count(),
+            network,
+            streams,
+        });
+    }
+
+    let worst = statuses
+        .iter()
+        .flat_map(|network| &network.streams)
+        .map(|stream| stream.status)
+        .filter(|status| *status != Health::Disabled)
+        .max()
+        .unwrap_or(Health::Ok);
+    let status = if drain.is_draining() && worst < Health::Draining {
+        Health::Draining
+    } else {
+        worst
+    };
+    HttpResponse::Ok().json(StatusResponse {
+        status,
+        version: version::VERSION,
+        commit: version::COMMIT,
+        uptime_sec: STARTED_AT.elapsed().as_secs(),
+        connected_clients: connections.len(),
+        networks: statuses,
+    })
+}
@@ -0,0 +1,96 @@
+//! Startup checks of the streams that are read: every stream should exist, and its
+//! latest entry should have the fields that its events are read from. Otherwise the
+//! reader would wait on a stream that the indexer never writes, or fail on the
+//! first event.
+
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    config::StreamConfig,
+    redis_reader::read_range,
+    replay::ReplayStart,
+    EventFilter, FromRedis, RedisSource,
+};
+
+/// Logs the streams that don't exist, or panics with them if `require_streams` is
+/// set. Panics if the latest entry of a stream doesn't have the fields of its events.
+/// Streams that are read from pub/sub aren't checked.
+pub async fn validate(
+    sources: &[RedisSource],
+    configs: &HashMap<String, StreamConfig>,
+    require_streams: bool,
+) {
+    let mut streams = StreamFields(Vec::new());
+    for_each_event_type(&mut streams);
+
+    let mut missing = Vec::new();
+    let mut invalid = Vec::new();
+    for source in sources {
+        for (stream, fields) in &streams.0 {
+            if !source.reads(stream) || configs.get(*stream).is_some_and(|config| config.pubsub) {
+                continue;
+            }
+            let stream_key = source.stream_key(stream);
+            let entries = match read_range(
+                source.connection.clone(),
+                &stream_key,
+                ReplayStart::Last(1),
+            )
+            .await
+            {
+                Ok(entries) => entries,
+                Err(err) => panic!("Failed to read {stream_key} from {}: {err}", source.name),
+            };
+            let Some((_, values)) = entries.first() else {
+                missing.push(format!("{stream_key} ({})", source.name));
+                continue;
+            };
+            let missing_fields = fields
+                .iter()
+                .filter(|field| !values.contains_key(**field))
+                .copied()
+                .collect::<Vec<_>>();
+            if !missing_fields.is_empty() {
+                invalid.push(format!(
+                    "{stream_key} ({}) has no {}",
+                    source.name,
+                    missing_fields.join(", ")
+                ));
+            }
+        }
+    }
+
+    if !invalid.is_empty() {
+        panic!(
+            "Streams with unexpected entries: {}. Check stream_prefix and streams of the Redis sources",
+            invalid.join("; ")
+        );
+    }
+    if !missing.is_empty() {
+        let message = format!(
+            "Streams that don't exist or are empty: {}. Their endpoints won't have events until the indexer writes them",
+            missing.join(", ")
+        );
+        if require_streams {
+            panic!("{message}");
+        }
+        tracing::error!("{message}");
+    }
+}
+
+/// Stream keys and the fields of their entries.
+struct StreamFields(Vec<(&'static str, &'static [&'static str])>);
+
+impl EventTypeVisitor for StreamFields {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        self.0.push((E::STREAM_KEY, E::FIELDS));
+    }
+}
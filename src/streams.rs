@@ -0,0 +1,114 @@
+//! `/v0/streams`: how far back every stream of a network is retained in Redis,
+//! so that clients know what `replay_last`, `from_stream_id` and `/history` can reach.
+
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    broadcast::{for_each_event_type, EventTypeVisitor},
+    history::StreamSources,
+    redis_reader::{last_read_id, stream_info},
+    replay::{StreamId, MAX_REPLAY_EVENTS},
+    EventFilter, FromRedis, Networks, Server, StreamSource, DEFAULT_NETWORK,
+};
+
+#[derive(Serialize)]
+struct StreamsResponse {
+    /// Events that one replay returns at most.
+    max_replay_events: usize,
+    streams: Vec<StreamStatus>,
+}
+
+#[derive(Serialize)]
+struct StreamStatus {
+    stream: &'static str,
+    source: String,
+    /// Entries retained in the stream.
+    length: u64,
+    /// The oldest event that can still be replayed.
+    first_stream_id: Option<StreamId>,
+    last_stream_id: Option<StreamId>,
+    /// The last event that the server has read from the stream.
+    last_read_stream_id: Option<StreamId>,
+    /// Milliseconds between the last event of the stream and the last event read.
+    lag_ms: Option<u64>,
+}
+
+pub async fn streams(
+    req: HttpRequest,
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+) -> HttpResponse {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return HttpResponse::NotFound().body(format!("Unknown network: {network}"));
+    }
+    let mut stream_keys = StreamKeys::default();
+    for_each_event_type(&mut stream_keys);
+
+    let mut streams = Vec::new();
+    for stream in stream_keys.0 {
+        let sources = match server
+            .send(StreamSources {
+                network: network.clone(),
+                stream_key: stream,
+            })
+            .await
+        {
+            Ok(sources) => sources,
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        };
+        for StreamSource {
+            name: source,
+            stream_key,
+            connection,
+            ..
+        } in sources
+        {
+            let info = match stream_info(connection, &stream_key).await {
+                Ok(info) => info,
+                Err(err) => {
+                    tracing::error!("Failed to read info of {stream_key}: {err}");
+                    return HttpResponse::InternalServerError().body("Failed to read streams");
+                }
+            };
+            let last_read_stream_id = last_read_id(&source, &stream_key);
+            streams.push(StreamStatus {
+                stream,
+                source: source.to_string(),
+                length: info.length,
+                first_stream_id: info.first_id,
+                last_stream_id: info.last_id,
+                last_read_stream_id,
+                lag_ms: info
+                    .last_id
+                    .zip(last_read_stream_id)
+                    .map(|(last, read)| last.0.saturating_sub(read.0)),
+            });
+        }
+    }
+    HttpResponse::Ok().json(StreamsResponse {
+        max_replay_events: MAX_REPLAY_EVENTS,
+        streams,
+    })
+}
+
+/// Stream keys of all event types.
+#[derive(Default)]
+pub struct StreamKeys(pub Vec<&'static str>);
+
+impl EventTypeVisitor for StreamKeys {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        self.0.push(E::STREAM_KEY);
+    }
+}
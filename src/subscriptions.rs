@@ -0,0 +1,634 @@
+//! Webhooks that API keys manage themselves at `/v0/subscriptions`, unlike the
+//! `webhooks` of the config file. They're kept in Redis and started again when the
+//! server starts, and the last delivery attempts of each are kept for
+//! `/v0/subscriptions/<id>/attempts`. Keys only see their own subscriptions.
+//!
+//! Every subscription is delivered by one of the instances that share the Redis:
+//! the one that holds its lease, `events_api_subscription_owner_<id>`. Instances
+//! renew their leases and claim the subscriptions without an owner every
+//! `RENEW_INTERVAL`, so the subscriptions of an instance that stopped move to
+//! another one once their leases expire. Changes are published on
+//! `CHANGES_CHANNEL`, so that the owner applies them right away.
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+    api_keys::{self, ApiKey},
+    broadcast::Broadcasts,
+    config::WebhookConfig,
+    connections,
+    http_client::Endpoint,
+    plans,
+    replay::StreamId,
+    unix_time_ms, webhooks,
+};
+
+const SUBSCRIPTIONS_KEY: &str = "events_api_subscriptions";
+/// Pub/sub channel of the IDs of subscriptions that were created, changed or
+/// deleted.
+const CHANGES_CHANNEL: &str = "events_api_subscription_changes";
+/// How long an instance owns a subscription after it renewed the lease.
+const LEASE: Duration = Duration::from_secs(30);
+const RENEW_INTERVAL: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Delivery attempts that are kept per subscription.
+const MAX_ATTEMPTS_KEPT: isize = 100;
+/// Subscriptions that one API key can have.
+const MAX_SUBSCRIPTIONS_PER_KEY: usize = 20;
+
+pub struct Subscriptions {
+    connection: ConnectionManager,
+    broadcasts: Arc<Broadcasts>,
+    /// The owner in the leases of this instance.
+    instance_id: String,
+    /// Delivery tasks of the subscriptions that this instance owns, by ID, with
+    /// the subscription as it was saved when its task started.
+    tasks: DashMap<String, (String, JoinHandle<()>)>,
+    /// Held while a subscription is started or stopped, so that the renewals,
+    /// changes and requests don't start one twice.
+    reconciling: Mutex<()>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Subscription {
+    id: String,
+    /// Name of the API key that created it.
+    api_key: String,
+    #[serde(default)]
+    paused: bool,
+    #[serde(flatten)]
+    webhook: WebhookConfig,
+}
+
+impl Subscription {
+    /// Without the secret, for responses.
+    fn public(mut self) -> Self {
+        self.webhook.secret = None;
+        self
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateRequest {
+    /// Replaces the filter, `{}` for all events.
+    filter: Option<Value>,
+    paused: Option<bool>,
+}
+
+/// Records the delivery attempts of a subscription.
+pub struct Attempts {
+    connection: ConnectionManager,
+    redis_key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Attempt<'a> {
+    time_ms: u128,
+    stream_id: StreamId,
+    /// 1 for the first attempt to deliver the event, more for retries.
+    attempt: u32,
+    /// `null` if the event was delivered.
+    error: Option<&'a str>,
+}
+
+fn attempts_key(id: &str) -> String {
+    format!("events_api_subscription_attempts_{id}")
+}
+
+fn owner_key(id: &str) -> String {
+    format!("events_api_subscription_owner_{id}")
+}
+
+/// Renews the lease `KEYS[1]` if `ARGV[1]` owns it, or takes it if nobody does.
+/// Returns 1 if `ARGV[1]` owns it for `ARGV[2]` milliseconds now.
+const CLAIM_SCRIPT: &str = r"
+local owner = redis.call('GET', KEYS[1])
+if owner == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+if owner then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+return 1
+";
+
+/// Deletes the lease `KEYS[1]` if `ARGV[1]` owns it.
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+impl Attempts {
+    /// Saves the attempt in the background.
+    pub fn record(&self, stream_id: StreamId, attempt: u32, error: Option<&anyhow::Error>) {
+        let error = error.map(|err| err.to_string());
+        let attempt = Attempt {
+            time_ms: unix_time_ms(),
+            stream_id,
+            attempt,
+            error: error.as_deref(),
+        };
+        let mut pipeline = redis::pipe();
+        pipeline
+            .cmd("LPUSH")
+            .arg(&self.redis_key)
+            .arg(serde_json::to_string(&attempt).unwrap())
+            .ignore()
+            .cmd("LTRIM")
+            .arg(&self.redis_key)
+            .arg(0)
+            .arg(MAX_ATTEMPTS_KEPT - 1)
+            .ignore();
+        let mut connection = self.connection.clone();
+        tokio::spawn(async move {
+            if let Err(err) = pipeline.query_async::<_, ()>(&mut connection).await {
+                tracing::warn!("Failed to record a delivery attempt: {err}");
+            }
+        });
+    }
+}
+
+impl Subscriptions {
+    /// Starts claiming the subscriptions that were saved and aren't paused, and
+    /// following the changes of other instances.
+    pub fn spawn(
+        connection: ConnectionManager,
+        redis_url: String,
+        broadcasts: Arc<Broadcasts>,
+    ) -> Arc<Self> {
+        let subscriptions = Arc::new(Self {
+            connection,
+            broadcasts,
+            instance_id: connections::new_connection_id(),
+            tasks: DashMap::new(),
+            reconciling: Mutex::new(()),
+        });
+        tokio::spawn(Arc::clone(&subscriptions).renew_leases());
+        tokio::spawn(Arc::clone(&subscriptions).follow_changes(redis_url));
+        subscriptions
+    }
+
+    async fn renew_leases(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(RENEW_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.reconcile_all().await {
+                tracing::warn!("Failed to renew the leases of subscriptions: {err}");
+            }
+        }
+    }
+
+    /// Claims or renews every subscription, and stops the deleted ones.
+    async fn reconcile_all(&self) -> anyhow::Result<()> {
+        let all = match self.all().await {
+            Ok(all) => all,
+            Err(err) => {
+                // Another instance may deliver them once the leases expire
+                for id in self.running() {
+                    self.stop(&id);
+                }
+                return Err(err);
+            }
+        };
+        for subscription in &all {
+            if let Err(err) = self.reconcile(&subscription.id, Some(subscription)).await {
+                tracing::warn!("Failed to renew subscription {}: {err}", subscription.id);
+            }
+        }
+        for id in self.running() {
+            if all.iter().all(|subscription| subscription.id != id) {
+                self.reconcile(&id, None).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// IDs of the subscriptions that this instance delivers.
+    fn running(&self) -> Vec<String> {
+        self.tasks.iter().map(|task| task.key().clone()).collect()
+    }
+
+    /// Stops delivering the subscription, returns whether it was delivered.
+    fn stop(&self, id: &str) -> bool {
+        match self.tasks.remove(id) {
+            Some((_, (_, task))) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn follow_changes(self: Arc<Self>, redis_url: String) {
+        loop {
+            if let Err(err) = self.receive_changes(&redis_url).await {
+                tracing::warn!("Failed to follow changes of subscriptions: {err}");
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    async fn receive_changes(&self, redis_url: &str) -> anyhow::Result<()> {
+        let mut pubsub = redis::Client::open(redis_url)?.get_async_pubsub().await?;
+        pubsub.subscribe(CHANGES_CHANNEL).await?;
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let id = message.get_payload::<String>()?;
+            let subscription = self.load(&id).await?;
+            self.reconcile(&id, subscription.as_ref()).await?;
+        }
+        anyhow::bail!("Subscription to {CHANGES_CHANNEL} ended")
+    }
+
+    /// Applies a change of a subscription on this instance, and tells the other
+    /// instances about it.
+    async fn changed(&self, id: &str) -> anyhow::Result<()> {
+        let subscription = self.load(id).await?;
+        self.reconcile(id, subscription.as_ref()).await?;
+        redis::cmd("PUBLISH")
+            .arg(CHANGES_CHANNEL)
+            .arg(id)
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// Delivers a subscription if this instance owns it or can claim it, with its
+    /// saved config. Stops delivering it if it's paused, deleted (`None`) or owned
+    /// by another instance.
+    async fn reconcile(&self, id: &str, subscription: Option<&Subscription>) -> anyhow::Result<()> {
+        let _reconciling = self.reconciling.lock().await;
+        let Some(subscription) = subscription.filter(|subscription| !subscription.paused) else {
+            if self.stop(id) {
+                self.release(id).await?;
+            }
+            return Ok(());
+        };
+        let claimed = self.claim(id).await;
+        if !matches!(claimed, Ok(true)) {
+            // Another instance may take it over once the lease expires
+            if self.stop(id) {
+                tracing::warn!("Stopped subscription {id}, its lease was lost");
+            }
+            return claimed.map(drop);
+        }
+        let saved = serde_json::to_string(subscription)?;
+        if self
+            .tasks
+            .get(id)
+            .is_some_and(|task| task.value().0 == saved)
+        {
+            return Ok(());
+        }
+        self.stop(id);
+        match self.start(subscription) {
+            Ok(task) => {
+                self.tasks.insert(id.to_string(), (saved, task));
+            }
+            Err(err) => {
+                tracing::warn!("Failed to start subscription {id}: {err}");
+                self.release(id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this instance owns the lease of the subscription, renewed or new.
+    async fn claim(&self, id: &str) -> anyhow::Result<bool> {
+        let claimed: i64 = redis::Script::new(CLAIM_SCRIPT)
+            .key(owner_key(id))
+            .arg(&self.instance_id)
+            .arg(LEASE.as_millis() as u64)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(claimed == 1)
+    }
+
+    async fn release(&self, id: &str) -> anyhow::Result<()> {
+        redis::Script::new(RELEASE_SCRIPT)
+            .key(owner_key(id))
+            .arg(&self.instance_id)
+            .invoke_async::<_, i64>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Subscription>> {
+        let values: Vec<String> = redis::cmd("HVALS")
+            .arg(SUBSCRIPTIONS_KEY)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(values
+            .iter()
+            .map(|value| serde_json::from_str(value))
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn load(&self, id: &str) -> anyhow::Result<Option<Subscription>> {
+        let value: Option<String> = redis::cmd("HGET")
+            .arg(SUBSCRIPTIONS_KEY)
+            .arg(id)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(value
+            .map(|value| serde_json::from_str::<Subscription>(&value))
+            .transpose()?)
+    }
+
+    /// The subscription with this ID if it belongs to the API key.
+    async fn get(&self, id: &str, api_key: &ApiKey) -> anyhow::Result<Option<Subscription>> {
+        Ok(self
+            .load(id)
+            .await?
+            .filter(|subscription| subscription.api_key == api_key.name))
+    }
+
+    async fn save(&self, subscription: &Subscription) -> anyhow::Result<()> {
+        redis::cmd("HSET")
+            .arg(SUBSCRIPTIONS_KEY)
+            .arg(&subscription.id)
+            .arg(serde_json::to_string(subscription)?)
+            .query_async::<_, ()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// Starts delivering the subscription.
+    fn start(&self, subscription: &Subscription) -> Result<JoinHandle<()>, String> {
+        let attempts = Attempts {
+            connection: self.connection.clone(),
+            redis_key: attempts_key(&subscription.id),
+        };
+        webhooks::start(
+            &subscription.webhook,
+            &self.broadcasts,
+            Some(attempts),
+            true,
+        )
+        .unwrap_or_else(|| Err(format!("Unknown stream: {}", subscription.webhook.stream)))
+    }
+}
+
+pub fn services(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/subscriptions")
+            .route(web::get().to(list))
+            .route(web::post().to(create)),
+    )
+    .service(
+        web::resource("/subscriptions/{id}")
+            .route(web::get().to(get))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    )
+    .service(web::resource("/subscriptions/{id}/attempts").route(web::get().to(attempts)));
+}
+
+fn require_api_key(req: &HttpRequest) -> Result<Result<ApiKey, HttpResponse>, Error> {
+    Ok(api_keys::authenticate(req)?
+        .ok_or_else(|| HttpResponse::Unauthorized().body("Subscriptions need an API key")))
+}
+
+fn internal_error(err: anyhow::Error) -> HttpResponse {
+    tracing::error!("Failed to access subscriptions: {err}");
+    HttpResponse::InternalServerError().body("Failed to access subscriptions")
+}
+
+fn not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("Unknown subscription: {id}"))
+}
+
+/// Resolves the host of a URL, and checks that it's public.
+async fn check_url(url: &str) -> anyhow::Result<()> {
+    Endpoint::parse(url)?.public_only().resolve().await?;
+    Ok(())
+}
+
+async fn create(
+    req: HttpRequest,
+    body: web::Json<WebhookConfig>,
+    subscriptions: web::Data<Subscriptions>,
+) -> Result<HttpResponse, Error> {
+    let api_key = match require_api_key(&req)? {
+        Ok(api_key) => api_key,
+        Err(response) => return Ok(response),
+    };
+    let subscription = Subscription {
+        // Random, so that IDs can't be guessed
+        id: connections::new_connection_id(),
+        api_key: api_key.name.clone(),
+        paused: false,
+        webhook: body.into_inner(),
+    };
+    match webhooks::validate(&subscription.webhook, true) {
+        Some(Ok(())) => {}
+        Some(Err(err)) => return Ok(HttpResponse::BadRequest().body(err)),
+        None => {
+            return Ok(HttpResponse::BadRequest()
+                .body(format!("Unknown stream: {}", subscription.webhook.stream)))
+        }
+    }
+    plans::check_access(&req, &subscription.webhook.stream)?;
+    // Checked again on every delivery, in case the host changes its addresses
+    if let Err(err) = check_url(&subscription.webhook.url).await {
+        return Ok(HttpResponse::BadRequest().body(err.to_string()));
+    }
+    match subscriptions.all().await {
+        Ok(all) => {
+            let count = all
+                .iter()
+                .filter(|subscription| subscription.api_key == api_key.name)
+                .count();
+            if count >= MAX_SUBSCRIPTIONS_PER_KEY {
+                return Ok(HttpResponse::Forbidden().body(format!(
+                    "A key can have at most {MAX_SUBSCRIPTIONS_PER_KEY} subscriptions"
+                )));
+            }
+        }
+        Err(err) => return Ok(internal_error(err)),
+    }
+    if let Err(err) = subscriptions.save(&subscription).await {
+        return Ok(internal_error(err));
+    }
+    if let Err(err) = subscriptions.changed(&subscription.id).await {
+        return Ok(internal_error(err));
+    }
+    Ok(HttpResponse::Created().json(subscription.public()))
+}
+
+async fn list(
+    req: HttpRequest,
+    subscriptions: web::Data<Subscriptions>,
+) -> Result<HttpResponse, Error> {
+    let api_key = match require_api_key(&req)? {
+        Ok(api_key) => api_key,
+        Err(response) => return Ok(response),
+    };
+    Ok(match subscriptions.all().await {
+        Ok(all) => HttpResponse::Ok().json(
+            all.into_iter()
+                .filter(|subscription| subscription.api_key == api_key.name)
+                .map(Subscription::public)
+                .collect::<Vec<_>>(),
+        ),
+        Err(err) => internal_error(err),
+    })
+}
+
+async fn get(
+    req: HttpRequest,
+    id: web::Path<String>,
+    subscriptions: web::Data<Subscriptions>,
+) -> Result<HttpResponse, Error> {
+    let api_key = match require_api_key(&req)? {
+        Ok(api_key) => api_key,
+        Err(response) => return Ok(response),
+    };
+    Ok(match subscriptions.get(&id, &api_key).await {
+        Ok(Some(subscription)) => HttpResponse::Ok().json(subscription.public()),
+        Ok(None) => not_found(&id),
+        Err(err) => internal_error(err),
+    })
+}
+
+async fn update(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<UpdateRequest>,
+    subscriptions: web::Data<Subscriptions>,
+) -> Result<HttpResponse, Error> {
+    let api_key = match require_api_key(&req)? {
+        Ok(api_key) => api_key,
+        Err(response) => return Ok(response),
+    };
+    let mut subscription = match subscriptions.get(&id, &api_key).await {
+        Ok(Some(subscription)) => subscription,
+        Ok(None) => return Ok(not_found(&id)),
+        Err(err) => return Ok(internal_error(err)),
+    };
+    let body = body.into_inner();
+    if let Some(filter) = body.filter {
+        subscription.webhook.filter = Some(filter);
+    }
+    if let Some(paused) = body.paused {
+        subscription.paused = paused;
+    }
+    if let Some(Err(err)) = webhooks::validate(&subscription.webhook, true) {
+        return Ok(HttpResponse::BadRequest().body(err));
+    }
+    // The plan of the key may have changed since the subscription was created
+    plans::check_access(&req, &subscription.webhook.stream)?;
+    if let Err(err) = subscriptions.save(&subscription).await {
+        return Ok(internal_error(err));
+    }
+    if let Err(err) = subscriptions.changed(&subscription.id).await {
+        return Ok(internal_error(err));
+    }
+    Ok(HttpResponse::Ok().json(subscription.public()))
+}
+
+async fn delete(
+    req: HttpRequest,
+    id: web::Path<String>,
+    subscriptions: web::Data<Subscriptions>,
+) -> Result<HttpResponse, Error> {
+    let api_key = match require_api_key(&req)? {
+        Ok(api_key) => api_key,
+        Err(response) => return Ok(response),
+    };
+    match subscriptions.get(&id, &api_key).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(not_found(&id)),
+        Err(err) => return Ok(internal_error(err)),
+    }
+    let mut pipeline = redis::pipe();
+    pipeline
+        .cmd("HDEL")
+        .arg(SUBSCRIPTIONS_KEY)
+        .arg(id.as_str())
+        .ignore()
+        .cmd("DEL")
+        .arg(attempts_key(&id))
+        .ignore();
+    let deleted = pipeline
+        .query_async::<_, ()>(&mut subscriptions.connection.clone())
+        .await;
+    if let Err(err) = deleted {
+        return Ok(internal_error(err.into()));
+    }
+    Ok(match subscriptions.changed(&id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => internal_error(err),
+    })
+}
+
+/// The last delivery attempts of a subscription, newest first.
+async fn attempts(
+    req: HttpRequest,
+    id: web::Path<String>,
+    subscriptions: web::Data<Subscriptions>,
+) -> Result<HttpResponse, Error> {
+    let api_key = match require_api_key(&req)? {
+        Ok(api_key) => api_key,
+        Err(response) => return Ok(response),
+    };
+    match subscriptions.get(&id, &api_key).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(not_found(&id)),
+        Err(err) => return Ok(internal_error(err)),
+    }
+    let result: redis::RedisResult<Vec<String>> = redis::cmd("LRANGE")
+        .arg(attempts_key(&id))
+        .arg(0)
+        .arg(-1)
+        .query_async(&mut subscriptions.connection.clone())
+        .await;
+    Ok(match result {
+        Ok(attempts) => {
+            let attempts = attempts
+                .iter()
+                .filter_map(|attempt| serde_json::from_str::<Value>(attempt).ok())
+                .collect::<Vec<_>>();
+            HttpResponse::Ok().json(attempts)
+        }
+        Err(err) => internal_error(err.into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn keeps_the_secret_out_of_responses() {
+        let subscription = serde_json::from_value::<Subscription>(json!({
+            "id": "1",
+            "api_key": "bot",
+            "url": "https://example.com/hook",
+            "stream": "nft_mint",
+            "filter": { "owner_id": "alice.near" },
+            "secret": "whsec",
+        }))
+        .unwrap();
+        assert!(!subscription.paused);
+        assert_eq!(subscription.webhook.network, "mainnet");
+        let saved = serde_json::to_value(&subscription).unwrap();
+        assert_eq!(saved["secret"], "whsec");
+        let public = serde_json::to_value(subscription.public()).unwrap();
+        assert_eq!(public.get("secret"), None);
+        assert_eq!(public["filter"]["owner_id"], "alice.near");
+    }
+}
@@ -0,0 +1,333 @@
+//! `--synthetic` mode for local development: instead of reading Redis, readers
+//! make up events for every stream, at `--synthetic=<events per second>` per
+//! stream (1 by default), so that frontends can be developed against the full
+//! API without an indexer or mainnet data.
+
+use std::{collections::HashMap, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+use crate::{
+    redis_reader::{EntryIds, EventHandler},
+    unix_time_ms,
+};
+
+const ACCOUNTS: &[&str] = &[
+    "alice.near",
+    "bob.near",
+    "carol.near",
+    "dave.tg",
+    "eve.near",
+    "frank.near",
+    "grace.near",
+    "heidi.near",
+];
+const NFT_CONTRACTS: &[&str] = &[
+    "nft.herewallet.near",
+    "x.paras.near",
+    "tinkerunion_nft.enleap.near",
+];
+const TOKENS: &[&str] = &[
+    "wrap.near",
+    "usdt.tether-token.near",
+    "17208628f84f5d6ad33f0da3bbbeb27ffcb398eac501a31bd6ad2011e36133a1",
+    "token.v2.ref-finance.near",
+];
+const POTS: &[&str] = &[
+    "build.v1.potfactory.potlock.near",
+    "octopus.v1.potfactory.potlock.near",
+];
+const BASE58: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// The rate of `--synthetic`, or `None` without it.
+pub fn rate() -> Option<f64> {
+    std::env::args().find_map(|arg| {
+        if arg == "--synthetic" {
+            return Some(1.0);
+        }
+        let rate = arg.strip_prefix("--synthetic=")?;
+        match rate.parse::<f64>() {
+            Ok(rate) if rate > 0.0 => Some(rate),
+            _ => panic!("Invalid --synthetic rate: {rate}"),
+        }
+    })
+}
+
+/// Hands made-up events of the stream to the handler until `shutdown` changes.
+pub async fn generate(
+    stream_key: &str,
+    rate: f64,
+    handler: &impl EventHandler,
+    shutdown: &mut watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+    let mut rng = Rng::new();
+    let mut ids = EntryIds::default();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+        let values = entry(stream_key, &mut rng, unix_time_ms());
+        handler.handle(&ids.next(), values).await?;
+    }
+}
+
+/// A made-up entry of the stream, for benchmarks.
+pub fn sample(stream_key: &str) -> HashMap<String, redis::Value> {
+    entry(stream_key, &mut Rng::new(), unix_time_ms())
+}
+
+/// A stream entry, like the indexer writes it.
+fn entry(stream_key: &str, rng: &mut Rng, now_ms: u128) -> HashMap<String, redis::Value> {
+    fields(stream_key, rng, now_ms)
+        .into_iter()
+        .map(|(field, value)| {
+            let value = redis::Value::Data(value.to_string().into_bytes());
+            (field.to_string(), value)
+        })
+        .collect()
+}
+
+fn fields(stream_key: &str, rng: &mut Rng, now_ms: u128) -> Vec<(&'static str, Value)> {
+    // About one block per second
+    let block_height = (now_ms / 1000) as u64 - 1_600_000_000;
+    let block_timestamp_nanosec = (now_ms * 1_000_000).to_string();
+    let transaction_id = rng.hash();
+    let receipt_id = rng.hash();
+    let token_ids = (0..rng.below(3) + 1)
+        .map(|_| rng.below(10_000).to_string())
+        .collect::<Vec<_>>();
+    let nft_context = json!({
+        "transaction_id": transaction_id,
+        "receipt_id": receipt_id,
+        "block_height": block_height,
+        "block_timestamp_nanosec": block_timestamp_nanosec,
+        "contract_id": rng.pick(NFT_CONTRACTS),
+    });
+    let potlock_context = json!({
+        "transaction_id": transaction_id,
+        "receipt_id": receipt_id,
+        "block_height": block_height,
+        "block_timestamp_nanosec": block_timestamp_nanosec,
+    });
+    let trade_context = json!({
+        "trader": rng.pick(ACCOUNTS),
+        "block_height": block_height,
+        "block_timestamp_nanosec": block_timestamp_nanosec,
+        "transaction_id": transaction_id,
+        "receipt_id": receipt_id,
+    });
+    let total_amount = rng.amount();
+    let protocol_fee = total_amount / 50;
+    let pool_swap = json!({
+        "pool": format!("REF-{}", rng.below(5000)),
+        "token_in": rng.pick(TOKENS),
+        "token_out": rng.pick(TOKENS),
+        "amount_in": rng.amount().to_string(),
+        "amount_out": rng.amount().to_string(),
+    });
+    let mut balance_changes = serde_json::Map::new();
+    if let (Some(token_in), Some(amount_in)) = (
+        pool_swap["token_in"].as_str(),
+        pool_swap["amount_in"].as_str(),
+    ) {
+        balance_changes.insert(token_in.to_string(), json!(format!("-{amount_in}")));
+    }
+    if let Some(token_out) = pool_swap["token_out"].as_str() {
+        balance_changes.insert(token_out.to_string(), pool_swap["amount_out"].clone());
+    }
+    match stream_key {
+        "nft_mint" => vec![
+            ("context", nft_context),
+            (
+                "mint",
+                json!({ "owner_id": rng.pick(ACCOUNTS), "token_ids": token_ids, "memo": null }),
+            ),
+        ],
+        "nft_transfer" => vec![
+            ("context", nft_context),
+            (
+                "transfer",
+                json!({
+                    "old_owner_id": rng.pick(ACCOUNTS),
+                    "new_owner_id": rng.pick(ACCOUNTS),
+                    "token_prices_near": token_ids
+                        .iter()
+                        .map(|_| Some(rng.amount().to_string()))
+                        .collect::<Vec<_>>(),
+                    "token_ids": token_ids,
+                    "memo": null,
+                }),
+            ),
+        ],
+        "nft_burn" => vec![
+            ("context", nft_context),
+            (
+                "burn",
+                json!({ "owner_id": rng.pick(ACCOUNTS), "token_ids": token_ids, "memo": null }),
+            ),
+        ],
+        "potlock_donation" => vec![
+            ("context", potlock_context),
+            (
+                "donation",
+                json!({
+                    "donation_id": rng.below(1_000_000),
+                    "donor_id": rng.pick(ACCOUNTS),
+                    "total_amount": total_amount.to_string(),
+                    "account_id": rng.pick(ACCOUNTS),
+                    "message": null,
+                    "donated_at": now_ms as u64,
+                    "project_id": rng.pick(ACCOUNTS),
+                    "protocol_fee": protocol_fee.to_string(),
+                    "referrer_id": null,
+                    "referrer_fee": null,
+                }),
+            ),
+        ],
+        "potlock_pot_project_donation" => vec![
+            ("context", potlock_context),
+            (
+                "pot_project_donation",
+                json!({
+                    "donation_id": rng.below(1_000_000),
+                    "pot_id": rng.pick(POTS),
+                    "donor_id": rng.pick(ACCOUNTS),
+                    "total_amount": total_amount.to_string(),
+                    "net_amount": (total_amount - protocol_fee).to_string(),
+                    "message": null,
+                    "donated_at": now_ms as u64,
+                    "project_id": rng.pick(ACCOUNTS),
+                    "referrer_id": null,
+                    "referrer_fee": null,
+                    "protocol_fee": protocol_fee.to_string(),
+                    "chef_id": null,
+                    "chef_fee": null,
+                }),
+            ),
+        ],
+        "potlock_pot_donation" => vec![
+            ("context", potlock_context),
+            (
+                "pot_donation",
+                json!({
+                    "donation_id": rng.below(1_000_000),
+                    "pot_id": rng.pick(POTS),
+                    "donor_id": rng.pick(ACCOUNTS),
+                    "total_amount": total_amount.to_string(),
+                    "net_amount": (total_amount - protocol_fee).to_string(),
+                    "message": null,
+                    "donated_at": now_ms as u64,
+                    "referrer_id": null,
+                    "referrer_fee": null,
+                    "protocol_fee": protocol_fee.to_string(),
+                    "chef_id": null,
+                    "chef_fee": null,
+                }),
+            ),
+        ],
+        "trade_pool" => vec![("context", trade_context), ("swap", pool_swap)],
+        "trade_swap" => vec![
+            ("context", trade_context),
+            (
+                "balance_change",
+                json!({
+                    "balance_changes": balance_changes,
+                    "pool_swaps": [pool_swap],
+                }),
+            ),
+        ],
+        "trade_pool_change" => vec![(
+            "pool_change",
+            json!({
+                "pool_id": pool_swap["pool"],
+                "receipt_id": receipt_id,
+                "block_timestamp_nanosec": block_timestamp_nanosec,
+                "block_height": block_height,
+                "pool": {
+                    "Ref": {
+                        "SimplePool": {
+                            "token_account_ids": [rng.pick(TOKENS), rng.pick(TOKENS)],
+                            "amounts": [rng.amount().to_string(), rng.amount().to_string()],
+                            "total_fee": 30,
+                        },
+                    },
+                },
+            }),
+        )],
+        _ => unreachable!("No synthetic events for {stream_key}"),
+    }
+}
+
+/// xorshift64*, good enough for made-up data.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let mut seed = [0; 8];
+        getrandom::getrandom(&mut seed).expect("Failed to seed synthetic events");
+        Self(u64::from_le_bytes(seed) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn pick(&mut self, items: &[&'static str]) -> &'static str {
+        items[self.below(items.len() as u64) as usize]
+    }
+
+    /// Up to 1000 NEAR in yocto.
+    fn amount(&mut self) -> u128 {
+        u128::from(self.below(1_000_000) + 1) * 10u128.pow(18)
+    }
+
+    /// A base58 transaction or receipt hash.
+    fn hash(&mut self) -> String {
+        (0..44)
+            .map(|_| BASE58[self.below(BASE58.len() as u64) as usize] as char)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use super::*;
+    use crate::{
+        broadcast::{for_each_event_type, EventTypeVisitor},
+        config::Encoding,
+        EventFilter, FromRedis,
+    };
+
+    struct Check(Rng);
+
+    impl EventTypeVisitor for Check {
+        fn visit<
+            E: Serialize + FromRedis + Send + Sync + 'static,
+            F: EventFilter<E> + DeserializeOwned + Send + 'static,
+        >(
+            &mut self,
+        ) {
+            let values = entry(E::STREAM_KEY, &mut self.0, unix_time_ms());
+            if let Err(err) = E::decode(&values, Encoding::Json) {
+                panic!("Invalid synthetic {} event: {err}", E::STREAM_KEY);
+            }
+        }
+    }
+
+    #[test]
+    fn generates_valid_events() {
+        for_each_event_type(&mut Check(Rng::new()));
+    }
+}
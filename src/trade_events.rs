@@ -1,32 +1,43 @@
-use std::{collections::HashMap, marker::PhantomData, time::Instant};
+use std::collections::HashMap;
 
 use actix::prelude::{dev::Message, Addr, Handler};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
-use actix_web_actors::ws::WsResponseBuilder;
-use redis::FromRedisValue;
+use borsh::BorshDeserialize;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    AccountId, Balance, BlockHeight, EventFilter, EventWebSocket, FromRedis, PoolId, ReceiptId,
-    Server, SubscribeToEvents, TransactionId, UnsubscribeFromEvents,
+    common_filter::{in_block_range, CommonFilter},
+    config::Encoding,
+    connect,
+    dead_letters::FromRedisError,
+    fields::{self, FilterFields},
+    heads::Head,
+    pool_metadata::{self, PoolToken},
+    redis_field,
+    types::{
+        AccountId, Balance, BalanceChange, BlockHeight, EventContext, PoolId, ReceiptId,
+        UnknownFields,
+    },
+    EventFilter, FromRedis, Networks, Server, SubscribeToEvents, UnsubscribeFromEvents,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct TradeContext {
     pub trader: AccountId,
-    pub block_height: BlockHeight,
-    pub block_timestamp_nanosec: Balance,
-    pub transaction_id: TransactionId,
-    pub receipt_id: ReceiptId,
+    #[serde(flatten)]
+    pub common: EventContext,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct RawPoolSwap {
     pub pool: PoolId,
     pub token_in: AccountId,
     pub token_out: AccountId,
     pub amount_in: Balance,
     pub amount_out: Balance,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Deserialize, Message)]
@@ -36,12 +47,25 @@ pub struct FullTradePoolEvent {
     pub event: RawPoolSwap,
     #[serde(flatten)]
     pub context: TradeContext,
+    /// The tokens of the pool, if `pool_metadata` is configured and the pool is known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_tokens: Option<Vec<PoolToken>>,
+    /// `amount_in` with the decimals of `token_in`, e.g. `"12.5"`, if
+    /// `decimal_amounts` is configured and they're known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_in_decimal: Option<String>,
+    /// `amount_out` with the decimals of `token_out`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_out_decimal: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct TradeBalanceChangeSwap {
-    pub balance_changes: HashMap<AccountId, Balance>,
+    pub balance_changes: HashMap<AccountId, BalanceChange>,
     pub pool_swaps: Vec<RawPoolSwap>,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Deserialize, Message)]
@@ -51,15 +75,23 @@ pub struct FullTradeSwapEvent {
     pub event: TradeBalanceChangeSwap,
     #[serde(flatten)]
     pub context: TradeContext,
+    /// `balance_changes` with the decimals of the tokens, e.g. `"-12.5"`, if
+    /// `decimal_amounts` is configured and the decimals of all tokens are known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance_changes_decimal: Option<HashMap<AccountId, String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
 pub struct TradePoolChangeEvent {
     pub pool_id: PoolId,
     pub receipt_id: ReceiptId,
     pub block_timestamp_nanosec: String,
     pub block_height: BlockHeight,
+    #[borsh(deserialize_with = "crate::encodings::json_string")]
     pub pool: serde_json::Value,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
 }
 
 #[derive(Debug, Serialize, Deserialize, Message)]
@@ -73,35 +105,38 @@ pub async fn trade_pool(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullTradePoolEvent, TradePoolEventFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
-    )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    connect::<FullTradePoolEvent, TradePoolEventFilter>(req, stream, server, networks).await
 }
 
 impl FromRedis for FullTradePoolEvent {
-    fn from_redis(values: HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<TradeContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<RawPoolSwap>(&String::from_redis_value(
-                values.get("swap").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullTradePoolEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "trade_pool";
+    const FIELDS: &'static [&'static str] = &["context", "swap"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullTradePoolEvent {
+            event: redis_field(values, "swap", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+            pool_tokens: None,
+            amount_in_decimal: None,
+            amount_out_decimal: None,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.common.head()
+    }
+
+    fn enrich(&mut self, network: &str) {
+        self.pool_tokens = pool_metadata::pool_tokens(network, &self.event.pool);
+        self.amount_in_decimal = pool_metadata::decimals(network, &self.event.token_in)
+            .map(|decimals| self.event.amount_in.to_decimal(decimals));
+        self.amount_out_decimal = pool_metadata::decimals(network, &self.event.token_out)
+            .map(|decimals| self.event.amount_out.to_decimal(decimals));
     }
 }
 
@@ -109,10 +144,33 @@ impl FromRedis for FullTradePoolEvent {
 pub struct TradePoolEventFilter {
     pool_id: Option<PoolId>,
     account_id: Option<AccountId>,
+    /// Only swaps where the trader got this token out of the pool.
+    token_bought: Option<AccountId>,
+    /// Only swaps where the trader put this token into the pool.
+    token_sold: Option<AccountId>,
+    #[serde(flatten)]
+    common: CommonFilter,
+}
+
+impl FilterFields for TradePoolEventFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&["pool_id", "account_id", "token_bought", "token_sold"])
+    }
 }
 
 impl EventFilter<FullTradePoolEvent> for TradePoolEventFilter {
     fn matches(&self, event: &FullTradePoolEvent) -> bool {
+        if !self.common.matches(
+            &event.context.common,
+            [
+                &event.context.trader,
+                &event.event.token_in,
+                &event.event.token_out,
+            ],
+        ) {
+            return false;
+        }
+
         if let Some(pool_id) = &self.pool_id {
             if event.event.pool != *pool_id {
                 return false;
@@ -125,6 +183,18 @@ impl EventFilter<FullTradePoolEvent> for TradePoolEventFilter {
             }
         }
 
+        if let Some(token_bought) = &self.token_bought {
+            if event.event.token_out != *token_bought {
+                return false;
+            }
+        }
+
+        if let Some(token_sold) = &self.token_sold {
+            if event.event.token_in != *token_sold {
+                return false;
+            }
+        }
+
         true
     }
 }
@@ -137,7 +207,7 @@ impl Handler<SubscribeToEvents<FullTradePoolEvent, TradePoolEventFilter>> for Se
         msg: SubscribeToEvents<FullTradePoolEvent, TradePoolEventFilter>,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        self.trade_pool_sockets.insert(msg.0);
+        self.network(&msg.1).trade_pool_sockets.insert(msg.0);
     }
 }
 
@@ -149,7 +219,7 @@ impl Handler<UnsubscribeFromEvents<FullTradePoolEvent, TradePoolEventFilter>> fo
         msg: UnsubscribeFromEvents<FullTradePoolEvent, TradePoolEventFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.trade_pool_sockets.remove(&msg.0);
+        self.network(&msg.1).trade_pool_sockets.remove(&msg.0);
     }
 }
 
@@ -157,35 +227,41 @@ pub async fn trade_swap(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullTradeSwapEvent, TradeSwapEventFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
-    )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    connect::<FullTradeSwapEvent, TradeSwapEventFilter>(req, stream, server, networks).await
 }
 
 impl FromRedis for FullTradeSwapEvent {
-    fn from_redis(values: HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match (
-            serde_json::from_str::<TradeContext>(&String::from_redis_value(
-                values.get("context").unwrap(),
-            )?),
-            serde_json::from_str::<TradeBalanceChangeSwap>(&String::from_redis_value(
-                values.get("balance_change").unwrap(),
-            )?),
-        ) {
-            (Ok(context), Ok(event)) => Ok(FullTradeSwapEvent { event, context }),
-            (Err(e), _) | (_, Err(e)) => Err(e.into()),
-        }
+    const STREAM_KEY: &'static str = "trade_swap";
+    const FIELDS: &'static [&'static str] = &["context", "balance_change"];
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullTradeSwapEvent {
+            event: redis_field(values, "balance_change", encoding)?,
+            context: redis_field(values, "context", encoding)?,
+            balance_changes_decimal: None,
+        })
+    }
+
+    fn head(&self) -> Head {
+        self.context.common.head()
+    }
+
+    fn enrich(&mut self, network: &str) {
+        self.balance_changes_decimal = self
+            .event
+            .balance_changes
+            .iter()
+            .map(|(token, change)| {
+                let decimals = pool_metadata::decimals(network, token)?;
+                Some((token.clone(), change.to_decimal(decimals)))
+            })
+            .collect::<Option<HashMap<_, _>>>()
+            .filter(|changes| !changes.is_empty());
     }
 }
 
@@ -193,10 +269,41 @@ impl FromRedis for FullTradeSwapEvent {
 pub struct TradeSwapEventFilter {
     account_id: Option<AccountId>,
     involved_token_account_ids: Option<Vec<AccountId>>,
+    /// Only swaps where the trader bought or sold at least this much of one of the
+    /// tokens, by the absolute value of its balance change. `min_amounts` is the
+    /// old name.
+    #[serde(alias = "min_amounts")]
+    min_balance_changes: Option<HashMap<AccountId, Balance>>,
+    /// Only swaps where the balance of this token of the trader went up.
+    token_bought: Option<AccountId>,
+    /// Only swaps where the balance of this token of the trader went down.
+    token_sold: Option<AccountId>,
+    #[serde(flatten)]
+    common: CommonFilter,
+}
+
+impl FilterFields for TradeSwapEventFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        CommonFilter::with_fields(&[
+            "account_id",
+            "involved_token_account_ids",
+            "min_balance_changes",
+            "min_amounts",
+            "token_bought",
+            "token_sold",
+        ])
+    }
 }
 
 impl EventFilter<FullTradeSwapEvent> for TradeSwapEventFilter {
     fn matches(&self, event: &FullTradeSwapEvent) -> bool {
+        if !self.common.matches(
+            &event.context.common,
+            std::iter::once(&event.context.trader).chain(event.event.balance_changes.keys()),
+        ) {
+            return false;
+        }
+
         if let Some(account_id) = &self.account_id {
             if event.context.trader != *account_id {
                 return false;
@@ -205,14 +312,44 @@ impl EventFilter<FullTradeSwapEvent> for TradeSwapEventFilter {
 
         if let Some(involved_token_account_ids) = &self.involved_token_account_ids {
             for involved_token in involved_token_account_ids {
-                if !event.event.balance_changes.contains_key(involved_token)
-                    || event.event.balance_changes.get(involved_token).unwrap() == "0"
+                if event
+                    .event
+                    .balance_changes
+                    .get(involved_token)
+                    .is_none_or(|amount| amount.0 == 0)
                 {
                     return false;
                 }
             }
         }
 
+        if let Some(min_balance_changes) = &self.min_balance_changes {
+            let large = min_balance_changes.iter().any(|(token, min_amount)| {
+                event
+                    .event
+                    .balance_changes
+                    .get(token)
+                    .is_some_and(|amount| amount.0.unsigned_abs() >= min_amount.0)
+            });
+            if !large {
+                return false;
+            }
+        }
+
+        if let Some(token_bought) = &self.token_bought {
+            let bought = event.event.balance_changes.get(token_bought);
+            if bought.is_none_or(|amount| amount.0 <= 0) {
+                return false;
+            }
+        }
+
+        if let Some(token_sold) = &self.token_sold {
+            let sold = event.event.balance_changes.get(token_sold);
+            if sold.is_none_or(|amount| amount.0 >= 0) {
+                return false;
+            }
+        }
+
         true
     }
 }
@@ -225,7 +362,7 @@ impl Handler<SubscribeToEvents<FullTradeSwapEvent, TradeSwapEventFilter>> for Se
         msg: SubscribeToEvents<FullTradeSwapEvent, TradeSwapEventFilter>,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        self.trade_swap_sockets.insert(msg.0);
+        self.network(&msg.1).trade_swap_sockets.insert(msg.0);
     }
 }
 
@@ -237,7 +374,7 @@ impl Handler<UnsubscribeFromEvents<FullTradeSwapEvent, TradeSwapEventFilter>> fo
         msg: UnsubscribeFromEvents<FullTradeSwapEvent, TradeSwapEventFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.trade_swap_sockets.remove(&msg.0);
+        self.network(&msg.1).trade_swap_sockets.remove(&msg.0);
     }
 }
 
@@ -245,40 +382,74 @@ pub async fn trade_pool_change(
     req: HttpRequest,
     stream: web::Payload,
     server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
 ) -> Result<HttpResponse, Error> {
-    let (addr, res) = WsResponseBuilder::new(
-        EventWebSocket::<FullTradePoolChangeEvent, TradePoolChangeEventFilter> {
-            last_heartbeat: Instant::now(),
-            filter: None,
-            server: server.get_ref().clone(),
-            _marker: PhantomData,
-        },
-        &req,
-        stream,
-    )
-    .start_with_addr()?;
-    server.send(SubscribeToEvents(addr)).await.unwrap();
-    Ok(res)
+    connect::<FullTradePoolChangeEvent, TradePoolChangeEventFilter>(req, stream, server, networks)
+        .await
 }
 
 impl FromRedis for FullTradePoolChangeEvent {
-    fn from_redis(values: HashMap<String, redis::Value>) -> anyhow::Result<Self> {
-        match serde_json::from_str::<TradePoolChangeEvent>(&String::from_redis_value(
-            values.get("pool_change").unwrap(),
-        )?) {
-            Ok(event) => Ok(FullTradePoolChangeEvent { event }),
-            Err(e) => Err(e.into()),
+    const STREAM_KEY: &'static str = "trade_pool_change";
+    const FIELDS: &'static [&'static str] = &["pool_change"];
+    const CONFLATION_KEY: Option<&'static str> = Some("pool_id");
+
+    fn decode(
+        values: &HashMap<String, redis::Value>,
+        encoding: Encoding,
+    ) -> Result<Self, FromRedisError> {
+        Ok(FullTradePoolChangeEvent {
+            event: redis_field(values, "pool_change", encoding)?,
+        })
+    }
+
+    fn head(&self) -> Head {
+        Head {
+            block_height: self.event.block_height,
+            block_timestamp_nanosec: self
+                .event
+                .block_timestamp_nanosec
+                .parse()
+                .unwrap_or_default(),
         }
     }
+
+    fn enrich(&mut self, network: &str) {
+        pool_metadata::record_pool(network, &self.event.pool_id, &self.event.pool);
+    }
 }
 
+/// Pool changes have no transaction and no accounts, so of the fields of
+/// `CommonFilter`, this only has the block range and the receipt.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TradePoolChangeEventFilter {
     pool_id: Option<PoolId>,
+    min_block_height: Option<BlockHeight>,
+    max_block_height: Option<BlockHeight>,
+    receipt_id: Option<ReceiptId>,
+}
+
+impl FilterFields for TradePoolChangeEventFilter {
+    fn filter_fields() -> Vec<&'static str> {
+        fields::field_names::<Self>().to_vec()
+    }
 }
 
 impl EventFilter<FullTradePoolChangeEvent> for TradePoolChangeEventFilter {
     fn matches(&self, event: &FullTradePoolChangeEvent) -> bool {
+        if !in_block_range(
+            self.min_block_height,
+            self.max_block_height,
+            event.head().block_height,
+        ) {
+            return false;
+        }
+
+        if let Some(receipt_id) = &self.receipt_id {
+            if event.event.receipt_id != *receipt_id {
+                return false;
+            }
+        }
+
         if let Some(pool_id) = &self.pool_id {
             if event.event.pool_id != *pool_id {
                 return false;
@@ -297,7 +468,7 @@ impl Handler<SubscribeToEvents<FullTradePoolChangeEvent, TradePoolChangeEventFil
         msg: SubscribeToEvents<FullTradePoolChangeEvent, TradePoolChangeEventFilter>,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        self.trade_pool_change_sockets.insert(msg.0);
+        self.network(&msg.1).trade_pool_change_sockets.insert(msg.0);
     }
 }
 
@@ -311,6 +482,255 @@ impl Handler<UnsubscribeFromEvents<FullTradePoolChangeEvent, TradePoolChangeEven
         msg: UnsubscribeFromEvents<FullTradePoolChangeEvent, TradePoolChangeEventFilter>,
         _ctx: &mut Self::Context,
     ) {
-        self.trade_pool_change_sockets.remove(&msg.0);
+        self.network(&msg.1)
+            .trade_pool_change_sockets
+            .remove(&msg.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+    use proptest::prelude::*;
+    use serde_json::{json, Map, Value};
+
+    use super::*;
+    use crate::filter_properties::{
+        account, account_value, accounts_value, amount, block_height_value, check, check_fields,
+        event, filter, id_value,
+    };
+
+    const TOKENS: &[&str] = &[
+        "wrap.near",
+        "usdt.tether-token.near",
+        "token.v2.ref-finance.near",
+    ];
+    const POOLS: &[&str] = &["REF-1", "REF-2"];
+
+    fn token() -> impl Strategy<Value = String> {
+        prop::sample::select(TOKENS).prop_map(str::to_string)
+    }
+
+    fn pool() -> impl Strategy<Value = String> {
+        prop::sample::select(POOLS).prop_map(str::to_string)
+    }
+
+    fn token_value() -> BoxedStrategy<Value> {
+        token().prop_map(Value::from).boxed()
+    }
+
+    fn pool_value() -> BoxedStrategy<Value> {
+        pool().prop_map(Value::from).boxed()
+    }
+
+    /// Bought (positive), sold (negative) or unchanged amounts.
+    fn balance_change() -> impl Strategy<Value = String> {
+        (amount(), any::<bool>()).prop_map(|(amount, sold)| {
+            if sold && amount != "0" {
+                format!("-{amount}")
+            } else {
+                amount
+            }
+        })
+    }
+
+    fn context(trader: String) -> Value {
+        json!({
+            "trader": trader,
+            "block_height": 1,
+            "block_timestamp_nanosec": "1",
+            "transaction_id": "tx",
+            "receipt_id": "receipt",
+        })
+    }
+
+    fn swap(pool: String, token_in: String, token_out: String) -> Value {
+        json!({
+            "pool": pool,
+            "token_in": token_in,
+            "token_out": token_out,
+            "amount_in": "1",
+            "amount_out": "1",
+        })
+    }
+
+    #[test]
+    fn reads_borsh() {
+        let pool = json!({ "Ref": { "SimplePool": { "total_fee": 30 } } });
+        let payload = borsh::to_vec(&(
+            "REF-1",
+            "receipt",
+            "1700000000000000000",
+            100u64,
+            pool.to_string(),
+        ))
+        .unwrap();
+        let values = HashMap::from([(
+            "pool_change".to_string(),
+            redis::Value::Data(BASE64_STANDARD.encode(payload).into_bytes()),
+        )]);
+        let event = FullTradePoolChangeEvent::decode(&values, Encoding::Borsh).unwrap();
+        assert_eq!(event.event.pool_id, "REF-1");
+        assert_eq!(event.event.block_height, 100);
+        assert_eq!(event.event.pool, pool);
+    }
+
+    #[test]
+    fn passes_unknown_nested_fields_on() {
+        let mut pool_swap = swap(
+            "REF-1".to_string(),
+            TOKENS[0].to_string(),
+            TOKENS[1].to_string(),
+        );
+        pool_swap["fee_bps"] = json!(30);
+        let event = event::<FullTradeSwapEvent>(vec![
+            ("context", context("alice.near".to_string())),
+            (
+                "balance_change",
+                json!({
+                    "balance_changes": {},
+                    "pool_swaps": [pool_swap],
+                    "route": { "hops": 1 },
+                }),
+            ),
+        ]);
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["pool_swaps"][0]["fee_bps"], 30);
+        assert_eq!(json["route"], json!({ "hops": 1 }));
+    }
+
+    #[test]
+    fn lists_filter_fields() {
+        check_fields::<TradePoolEventFilter>();
+        check_fields::<TradeSwapEventFilter>();
+        check_fields::<TradePoolChangeEventFilter>();
+    }
+
+    proptest! {
+        #[test]
+        fn pool_filter_invariants(
+            (trader, pool, token_in, token_out) in (account(), pool(), token(), token()),
+            filter in filter(vec![
+                ("pool_id", pool_value()),
+                ("account_id", account_value()),
+                ("min_block_height", block_height_value()),
+                ("max_block_height", block_height_value()),
+                ("transaction_id", id_value("tx")),
+                ("receipt_id", id_value("receipt")),
+                ("involved_account_ids", accounts_value()),
+                ("token_bought", token_value()),
+                ("token_sold", token_value()),
+            ]),
+        ) {
+            let event = event::<FullTradePoolEvent>(vec![
+                ("context", context(trader)),
+                ("swap", swap(pool, token_in, token_out)),
+            ]);
+            check::<_, TradePoolEventFilter>(&event, &filter)?;
+        }
+
+        #[test]
+        fn swap_filter_invariants(
+            (trader, pool) in (account(), pool()),
+            balance_changes in prop::collection::hash_map(token(), balance_change(), 0..=3),
+            filter in filter(vec![
+                ("account_id", account_value()),
+                (
+                    "involved_token_account_ids",
+                    prop::collection::vec(token(), 1..=2).prop_map(Value::from).boxed(),
+                ),
+                (
+                    "min_balance_changes",
+                    prop::collection::hash_map(token(), amount().prop_map(Value::from), 1..=2)
+                        .prop_map(|amounts| Value::Object(amounts.into_iter().collect::<Map<_, _>>()))
+                        .boxed(),
+                ),
+                ("token_bought", token_value()),
+                ("token_sold", token_value()),
+            ]),
+        ) {
+            let event = event::<FullTradeSwapEvent>(vec![
+                ("context", context(trader)),
+                (
+                    "balance_change",
+                    json!({
+                        "balance_changes": balance_changes,
+                        "pool_swaps": [swap(pool, TOKENS[0].to_string(), TOKENS[1].to_string())],
+                    }),
+                ),
+            ]);
+            check::<_, TradeSwapEventFilter>(&event, &filter)?;
+        }
+
+        #[test]
+        fn pool_change_filter_invariants(
+            pool_id in pool(),
+            filter in filter(vec![("pool_id", pool_value())]),
+        ) {
+            let event = event::<FullTradePoolChangeEvent>(vec![(
+                "pool_change",
+                json!({
+                    "pool_id": pool_id,
+                    "receipt_id": "receipt",
+                    "block_timestamp_nanosec": "1",
+                    "block_height": 1,
+                    "pool": {},
+                }),
+            )]);
+            check::<_, TradePoolChangeEventFilter>(&event, &filter)?;
+        }
+    }
+
+    #[test]
+    fn min_balance_changes_compare_bought_and_sold_amounts() {
+        let event = event::<FullTradeSwapEvent>(vec![
+            ("context", context("alice.near".to_string())),
+            (
+                "balance_change",
+                json!({
+                    "balance_changes": {
+                        "wrap.near": "-2000000000000000000000000",
+                        "usdt.tether-token.near": "5000000",
+                    },
+                    "pool_swaps": [],
+                }),
+            ),
+        ]);
+        let matches = |min_balance_changes| {
+            let filter = json!({ "min_balance_changes": min_balance_changes });
+            crate::fields::parse_filter::<TradeSwapEventFilter>(&filter)
+                .unwrap()
+                .matches(&event)
+        };
+        assert!(matches(json!({ "wrap.near": "2000000000000000000000000" })));
+        assert!(!matches(
+            json!({ "wrap.near": "2000000000000000000000001" })
+        ));
+        assert!(matches(json!({ "usdt.tether-token.near": "5000000" })));
+        assert!(matches(
+            json!({ "wrap.near": "3000000000000000000000000", "usdt.tether-token.near": "1" })
+        ));
+        assert!(!matches(json!({ "token.v2.ref-finance.near": "1" })));
+
+        let old_name = json!({ "min_amounts": { "wrap.near": "2000000000000000000000001" } });
+        let filter = crate::fields::parse_filter::<TradeSwapEventFilter>(&old_name).unwrap();
+        assert!(!filter.matches(&event));
+    }
+
+    #[test]
+    fn requires_a_filter_field() {
+        let presets = HashMap::from([
+            (
+                "whales".to_string(),
+                json!({ "min_balance_changes": { "wrap.near": "1" } }),
+            ),
+            ("everything".to_string(), json!({})),
+        ]);
+        let has_filter = |text| crate::has_filter::<TradeSwapEventFilter>(&presets, text);
+        assert!(has_filter(r#"{"account_id": "alice.near"}"#));
+        assert!(has_filter(r#"{"preset": "whales"}"#));
+        assert!(!has_filter(r#"{"preset": "everything"}"#));
+        assert!(!has_filter(r#"{"account_id": null, "batch_ms": 100}"#));
+        assert!(!has_filter("{}"));
     }
 }
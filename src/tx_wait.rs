@@ -0,0 +1,214 @@
+//! Waiting for a transaction at `/v0/tx/<transaction_id>/wait`, the most common flow
+//! of dApps: submit a transaction, then wait until its events land. Responds with
+//! the events of the transaction on all streams, or waits up to `timeout` seconds
+//! for the first one.
+
+use std::{sync::Arc, time::Duration};
+
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    task::JoinHandle,
+};
+
+use crate::{
+    broadcast::{for_each_event_type, Broadcasts, EventTypeVisitor},
+    config::PlanConfig,
+    plans,
+    replay::{ReplayStart, StreamId},
+    types::TransactionId,
+    unix_time_ms, Event, EventFilter, FromRedis, Networks, ReadReplay, Server, TaggedEvent,
+    DEFAULT_NETWORK,
+};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const MAX_TIMEOUT_SECS: u64 = 60;
+/// The last events of every stream that are searched, in case the transaction
+/// landed before the request.
+const RECENT_EVENTS: usize = 1000;
+/// Events of the transaction on other streams that arrive this soon after the
+/// first one are returned with it.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Deserialize)]
+pub struct WaitQuery {
+    /// Seconds to wait for the first event of the transaction.
+    timeout: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct WaitResponse {
+    transaction_id: TransactionId,
+    events: Vec<TransactionEvent>,
+}
+
+#[derive(Serialize)]
+struct TransactionEvent {
+    stream: &'static str,
+    /// In the same format as the WebSocket endpoints (protocol 1).
+    event: serde_json::Value,
+}
+
+pub async fn wait(
+    req: HttpRequest,
+    path: web::Path<TransactionId>,
+    query: web::Query<WaitQuery>,
+    server: web::Data<Addr<Server>>,
+    networks: web::Data<Networks>,
+    broadcasts: web::Data<Broadcasts>,
+) -> HttpResponse {
+    let network = req
+        .match_info()
+        .get("network")
+        .unwrap_or(DEFAULT_NETWORK)
+        .to_string();
+    if !networks.contains(&network) {
+        return HttpResponse::NotFound().body(format!("Unknown network: {network}"));
+    }
+    let plan = match plans::plan_of(&req) {
+        Ok((_, plan)) => plan,
+        Err(err) => return err.error_response(),
+    };
+    let transaction_id = path.into_inner();
+    let timeout = Duration::from_secs(
+        query
+            .timeout
+            .unwrap_or(DEFAULT_TIMEOUT_SECS)
+            .min(MAX_TIMEOUT_SECS),
+    );
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watchers = Watchers {
+        req: &req,
+        network,
+        transaction_id: transaction_id.clone(),
+        server: server.get_ref().clone(),
+        broadcasts: &broadcasts,
+        sender,
+        tasks: Vec::new(),
+        plan,
+        now_ms: unix_time_ms(),
+    };
+    for_each_event_type(&mut watchers);
+    let _tasks = Tasks(watchers.tasks);
+
+    let mut events = Vec::new();
+    if let Ok(Some(event)) = tokio::time::timeout(timeout, receiver.recv()).await {
+        events.push(event);
+        let settled = tokio::time::Instant::now() + SETTLE_TIME;
+        while let Ok(Some(event)) = tokio::time::timeout_at(settled, receiver.recv()).await {
+            events.push(event);
+        }
+    }
+    HttpResponse::Ok().json(WaitResponse {
+        transaction_id,
+        events,
+    })
+}
+
+/// Starts a task for every enabled stream whose filter has `transaction_id` and
+/// that the plan of the API key includes, that sends the events of the
+/// transaction, first the recent ones within the plan's replay limits, then the
+/// live ones.
+struct Watchers<'a> {
+    req: &'a HttpRequest,
+    network: String,
+    transaction_id: TransactionId,
+    server: Addr<Server>,
+    broadcasts: &'a Broadcasts,
+    sender: mpsc::UnboundedSender<TransactionEvent>,
+    tasks: Vec<JoinHandle<()>>,
+    plan: Option<Arc<PlanConfig>>,
+    now_ms: u128,
+}
+
+impl EventTypeVisitor for Watchers<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        if !F::filter_fields().contains(&"transaction_id")
+            || crate::disabled_response::<E>(self.req).is_some()
+            || self
+                .plan
+                .as_ref()
+                .is_some_and(|plan| !plan.includes(E::STREAM_KEY))
+        {
+            return;
+        }
+        let filter = serde_json::from_value::<F>(serde_json::json!({
+            "transaction_id": self.transaction_id,
+        }))
+        .expect("Invalid transaction filter");
+        // Subscribe before reading the recent events, so that nothing is missed in between
+        let mut live = self.broadcasts.subscribe::<E>(&self.network);
+        let (start, earliest) = match &self.plan {
+            Some(plan) => (
+                plan.limit_replay(ReplayStart::Last(RECENT_EVENTS), self.now_ms)
+                    .expect("Replays of the last events are always allowed"),
+                plan.earliest(self.now_ms),
+            ),
+            None => (ReplayStart::Last(RECENT_EVENTS), None),
+        };
+        let recent = self.server.send(ReadReplay::<E> {
+            network: self.network.clone(),
+            start,
+            _marker: Default::default(),
+        });
+        let sender = self.sender.clone();
+        let send = move |event: &Event<E>| {
+            if filter.matches(&event.event) {
+                let _ = sender.send(TransactionEvent {
+                    stream: E::STREAM_KEY,
+                    event: serde_json::to_value(TaggedEvent {
+                        source: &event.source,
+                        stream_id: event.id,
+                        event: &event.event,
+                    })
+                    .unwrap(),
+                });
+            }
+        };
+        self.tasks.push(tokio::spawn(async move {
+            let mut last_recent = None;
+            match recent.await {
+                Ok(Ok(recent)) => {
+                    for event in recent.events {
+                        last_recent = last_recent.max(Some(event.id));
+                        if earliest.is_none_or(|earliest: StreamId| event.id >= earliest) {
+                            send(&event);
+                        }
+                    }
+                }
+                Ok(Err(err)) => tracing::warn!("Failed to read {}: {err}", E::STREAM_KEY),
+                Err(err) => tracing::warn!("Failed to read {}: {err}", E::STREAM_KEY),
+            }
+            loop {
+                let event = match live.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if last_recent.is_none_or(|last| event.id > last) {
+                    send(&event);
+                }
+            }
+        }));
+    }
+}
+
+/// Stops the tasks of the streams when the request ends, also when the client
+/// goes away before.
+struct Tasks(Vec<JoinHandle<()>>);
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
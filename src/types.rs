@@ -0,0 +1,46 @@
+//! Types that the events of all streams are made of, so that event modules share
+//! them instead of each defining their own.
+
+use borsh::BorshDeserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::heads::Head;
+pub use crate::{
+    account_id::AccountId,
+    balance::{Balance, BalanceChange},
+};
+
+pub type TransactionId = String;
+pub type ReceiptId = String;
+pub type NftTokenId = String;
+pub type BlockHeight = u64;
+pub type DonationId = u64;
+pub type ProjectId = AccountId;
+pub type TimestampMs = u64;
+pub type PoolId = String;
+/// Fields of an event that this version doesn't know yet, passed on as they are.
+/// Flattened into the event structs, and not in borsh.
+pub type UnknownFields = serde_json::Map<String, serde_json::Value>;
+
+/// Where an event happened. Streams with more context, like the contract of NFT
+/// events, flatten this into their own context.
+#[derive(Debug, Serialize, Deserialize, BorshDeserialize)]
+pub struct EventContext {
+    pub transaction_id: TransactionId,
+    pub receipt_id: ReceiptId,
+    pub block_height: BlockHeight,
+    pub block_timestamp_nanosec: String,
+    #[serde(flatten)]
+    #[borsh(skip)]
+    pub extra: UnknownFields,
+}
+
+impl EventContext {
+    /// The block that the event happened in.
+    pub fn head(&self) -> Head {
+        Head {
+            block_height: self.block_height,
+            block_timestamp_nanosec: self.block_timestamp_nanosec.parse().unwrap_or_default(),
+        }
+    }
+}
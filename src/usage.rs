@@ -0,0 +1,298 @@
+//! Usage metering for billing: the events delivered to WebSocket connections of
+//! every API key and their bytes per UTC day. Counts are kept in memory and added
+//! to Redis every `FLUSH_INTERVAL`, so instances that share the Redis add up.
+//! Key holders read theirs at `GET /v0/usage`, admins export all of them at
+//! `GET /admin/usage` as JSON or CSV.
+
+use std::{collections::BTreeMap, sync::LazyLock, time::Duration};
+
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use dashmap::DashMap;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use time::{Date, Month, OffsetDateTime};
+
+use crate::{
+    admin::{self, AdminToken},
+    api_keys,
+};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// Days of usage that are kept in Redis.
+const RETENTION_DAYS: u64 = 400;
+const DEFAULT_DAYS: u16 = 30;
+const MAX_DAYS: u16 = 366;
+
+/// Counts that weren't added to Redis yet, by API key and day.
+static PENDING: LazyLock<DashMap<(String, Date), Counts>> = LazyLock::new(DashMap::new);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    events: u64,
+    bytes: u64,
+}
+
+pub struct Usage {
+    connection: ConnectionManager,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageRow {
+    /// `YYYY-MM-DD`, in UTC.
+    date: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    events: u64,
+    bytes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// First day, `YYYY-MM-DD`, 30 days before `to` by default.
+    from: Option<String>,
+    /// Last day, today by default.
+    to: Option<String>,
+    /// `json` (default) or `csv`, for the admin export.
+    format: Option<String>,
+}
+
+fn redis_key(day: Date) -> String {
+    format!("events_api_usage_{day}")
+}
+
+/// Counts an event frame delivered to an API key.
+pub fn record(api_key: &str, bytes: usize) {
+    let day = OffsetDateTime::now_utc().date();
+    let mut counts = PENDING.entry((api_key.to_string(), day)).or_default();
+    counts.events += 1;
+    counts.bytes += bytes as u64;
+}
+
+impl Usage {
+    /// Starts adding the counts to Redis.
+    pub fn spawn(connection: ConnectionManager) -> Self {
+        let mut flush_connection = connection.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                flush(&mut flush_connection).await;
+            }
+        });
+        Self { connection }
+    }
+
+    /// Counts of the API keys by day, all keys if `api_key` is `None`.
+    async fn load(
+        &self,
+        from: Date,
+        to: Date,
+        api_key: Option<&str>,
+    ) -> redis::RedisResult<Vec<UsageRow>> {
+        let mut rows = Vec::new();
+        let mut day = from;
+        while day <= to {
+            let fields: BTreeMap<String, u64> = redis::cmd("HGETALL")
+                .arg(redis_key(day))
+                .query_async(&mut self.connection.clone())
+                .await?;
+            let mut by_key = BTreeMap::<&str, Counts>::new();
+            for (field, value) in &fields {
+                let Some((key, counter)) = field.rsplit_once('/') else {
+                    continue;
+                };
+                if api_key.is_some_and(|api_key| api_key != key) {
+                    continue;
+                }
+                let counts = by_key.entry(key).or_default();
+                match counter {
+                    "events" => counts.events = *value,
+                    "bytes" => counts.bytes = *value,
+                    _ => {}
+                }
+            }
+            rows.extend(by_key.into_iter().map(|(key, counts)| UsageRow {
+                date: day.to_string(),
+                api_key: api_key.is_none().then(|| key.to_string()),
+                events: counts.events,
+                bytes: counts.bytes,
+            }));
+            let Some(next) = day.next_day() else {
+                break;
+            };
+            day = next;
+        }
+        Ok(rows)
+    }
+}
+
+async fn flush(connection: &mut ConnectionManager) {
+    let keys = PENDING
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+    let pending = keys
+        .into_iter()
+        .filter_map(|key| PENDING.remove(&key))
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        return;
+    }
+    let mut pipeline = redis::pipe();
+    for ((api_key, day), counts) in &pending {
+        let redis_key = redis_key(*day);
+        pipeline
+            .cmd("HINCRBY")
+            .arg(&redis_key)
+            .arg(format!("{api_key}/events"))
+            .arg(counts.events)
+            .ignore()
+            .cmd("HINCRBY")
+            .arg(&redis_key)
+            .arg(format!("{api_key}/bytes"))
+            .arg(counts.bytes)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&redis_key)
+            .arg(RETENTION_DAYS * 24 * 60 * 60)
+            .ignore();
+    }
+    if let Err(err) = pipeline.query_async::<_, ()>(connection).await {
+        tracing::warn!("Failed to save usage: {err}");
+        // Added again with the next flush
+        for (key, counts) in pending {
+            let mut pending = PENDING.entry(key).or_default();
+            pending.events += counts.events;
+            pending.bytes += counts.bytes;
+        }
+    }
+}
+
+/// `YYYY-MM-DD`.
+fn parse_date(text: &str) -> Option<Date> {
+    let mut parts = text.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = Month::try_from(parts.next()?.parse::<u8>().ok()?).ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// The days of a query, or a message for the client.
+fn date_range(query: &UsageQuery) -> Result<(Date, Date), String> {
+    let parse = |date: &str| parse_date(date).ok_or_else(|| format!("Invalid date: {date}"));
+    let to = match &query.to {
+        Some(to) => parse(to)?,
+        None => OffsetDateTime::now_utc().date(),
+    };
+    let from = match &query.from {
+        Some(from) => parse(from)?,
+        None => to.saturating_sub(time::Duration::days(i64::from(DEFAULT_DAYS) - 1)),
+    };
+    if from > to {
+        return Err("from is after to".to_string());
+    }
+    if (to - from).whole_days() >= i64::from(MAX_DAYS) {
+        return Err(format!("At most {MAX_DAYS} days can be requested"));
+    }
+    Ok((from, to))
+}
+
+/// The usage of the API key of the request.
+pub async fn usage(
+    req: HttpRequest,
+    query: web::Query<UsageQuery>,
+    usage: web::Data<Usage>,
+) -> Result<HttpResponse, Error> {
+    let Some(api_key) = api_keys::authenticate(&req)? else {
+        return Ok(HttpResponse::Unauthorized().body("Usage needs an API key"));
+    };
+    let (from, to) = match date_range(&query) {
+        Ok(range) => range,
+        Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+    };
+    Ok(match usage.load(from, to, Some(&api_key.name)).await {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(err) => {
+            tracing::error!("Failed to load usage: {err}");
+            HttpResponse::InternalServerError().body("Failed to load usage")
+        }
+    })
+}
+
+/// The usage of all API keys, for billing.
+pub async fn export(
+    req: HttpRequest,
+    query: web::Query<UsageQuery>,
+    token: web::Data<AdminToken>,
+    usage: web::Data<Usage>,
+) -> HttpResponse {
+    if !admin::authorized(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (from, to) = match date_range(&query) {
+        Ok(range) => range,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    let rows = match usage.load(from, to, None).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            tracing::error!("Failed to load usage: {err}");
+            return HttpResponse::InternalServerError().body("Failed to load usage");
+        }
+    };
+    match query.format.as_deref() {
+        None | Some("json") => HttpResponse::Ok().json(rows),
+        Some("csv") => HttpResponse::Ok().content_type("text/csv").body(csv(&rows)),
+        Some(format) => HttpResponse::BadRequest().body(format!("Unknown format: {format}")),
+    }
+}
+
+fn csv(rows: &[UsageRow]) -> String {
+    let mut csv = "date,api_key,events,bytes\n".to_string();
+    for row in rows {
+        let api_key = row.api_key.as_deref().unwrap_or_default();
+        // Quoted, since key names are free text
+        let api_key = format!("\"{}\"", api_key.replace('"', "\"\""));
+        csv.push_str(&format!(
+            "{},{api_key},{},{}\n",
+            row.date, row.events, row.bytes
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges_and_writes_csv() {
+        let query = |from: Option<&str>, to: Option<&str>| UsageQuery {
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+            format: None,
+        };
+        let (from, to) = date_range(&query(Some("2024-02-28"), Some("2024-03-01"))).unwrap();
+        assert_eq!(
+            (from.to_string(), to.to_string()),
+            ("2024-02-28".to_string(), "2024-03-01".to_string())
+        );
+        let (from, to) = date_range(&query(None, Some("2024-03-30"))).unwrap();
+        assert_eq!(from.to_string(), "2024-03-01");
+        assert_eq!(to.to_string(), "2024-03-30");
+        assert!(date_range(&query(Some("2024-02-30"), None)).is_err());
+        assert!(date_range(&query(Some("2024-03-02"), Some("2024-03-01"))).is_err());
+        assert!(date_range(&query(Some("2020-01-01"), Some("2024-01-01"))).is_err());
+
+        let rows = [UsageRow {
+            date: "2024-03-01".to_string(),
+            api_key: Some("bot \"1\"".to_string()),
+            events: 10,
+            bytes: 2048,
+        }];
+        assert_eq!(
+            csv(&rows),
+            "date,api_key,events,bytes\n2024-03-01,\"bot \"\"1\"\"\",10,2048\n"
+        );
+    }
+}
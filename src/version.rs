@@ -0,0 +1,60 @@
+//! `GET /version`: what this build is and what it supports, so that client SDKs
+//! can adapt and deploys can be verified.
+
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{
+    broadcast::for_each_event_type, protocol::Protocol, streams::StreamKeys, DisabledStreams,
+};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// `GIT_COMMIT` at build time, or the commit that `build.rs` found.
+pub const COMMIT: Option<&str> = option_env!("GIT_COMMIT");
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+/// Optional parts of the server that are enabled, e.g. `grpc` or `kafka`.
+pub struct Features(pub Vec<&'static str>);
+
+#[derive(Serialize)]
+struct VersionResponse<'a> {
+    version: &'static str,
+    commit: Option<&'static str>,
+    build_time: Option<String>,
+    /// Path prefixes of the HTTP API.
+    api_versions: &'static [&'static str],
+    /// Versions of the WebSocket protocol that `/v1` endpoints negotiate.
+    protocols: &'static [u32],
+    latest_protocol: u32,
+    features: &'a [&'static str],
+    /// Streams that can be subscribed to.
+    streams: Vec<&'static str>,
+}
+
+pub async fn version(
+    features: web::Data<Features>,
+    disabled: web::Data<DisabledStreams>,
+) -> HttpResponse {
+    let mut stream_keys = StreamKeys::default();
+    for_each_event_type(&mut stream_keys);
+    let build_time = BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+        .and_then(|time| time.format(&Rfc3339).ok());
+    HttpResponse::Ok().json(VersionResponse {
+        version: VERSION,
+        commit: COMMIT,
+        build_time,
+        api_versions: &["v0", "v1"],
+        protocols: &[Protocol::V1 as u32, Protocol::V2 as u32],
+        latest_protocol: Protocol::LATEST as u32,
+        features: &features.0,
+        streams: stream_keys
+            .0
+            .into_iter()
+            .filter(|stream| !disabled.contains(*stream))
+            .collect(),
+    })
+}
@@ -0,0 +1,326 @@
+//! Posts matching events to HTTP endpoints, configured with `webhooks` in the
+//! config file. Events are posted as JSON, or formatted as Discord webhook
+//! messages or Telegram `sendMessage` requests from a template.
+//!
+//! Templates have `{field}` placeholders for the fields of the event, e.g.
+//! `{contract_id}`, and `{a/b}` for nested fields, e.g. `{balance_changes/wrap.near}`.
+//! `{stream}` is the stream key. Strings are inserted as they are, other values as JSON.
+//!
+//! Every attempt to deliver an event has the same `Idempotency-Key` header, so that
+//! receivers can skip retries that they already handled. Webhooks with a `secret`
+//! are signed like Stripe webhooks, with a `X-Webhook-Signature: t=<unix time>,v1=<hex>`
+//! header, the HMAC-SHA256 of `<unix time>.<body>`, so that receivers can check
+//! that the request came from this server and isn't an old one sent again.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+
+use crate::{
+    broadcast::{for_each_event_type, Broadcasts, EventReceiver, EventTypeVisitor},
+    config::{WebhookConfig, WebhookFormat},
+    fields,
+    http_client::Endpoint,
+    signing,
+    subscriptions::Attempts,
+    EventFilter, FromRedis, TaggedEvent,
+};
+
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+/// Discord rejects embed descriptions that are longer.
+const MAX_DISCORD_DESCRIPTION: usize = 4096;
+/// Telegram rejects messages that are longer.
+const MAX_TELEGRAM_TEXT: usize = 4096;
+
+pub fn spawn(webhooks: Vec<WebhookConfig>, broadcasts: &Broadcasts) {
+    for webhook in &webhooks {
+        match start(webhook, broadcasts, None, false) {
+            Some(Ok(_)) => {}
+            Some(Err(err)) => panic!("Invalid webhook to {}: {err}", webhook.url),
+            None => tracing::warn!(
+                "Webhook to {} has unknown stream {}",
+                webhook.url,
+                webhook.stream
+            ),
+        }
+    }
+}
+
+/// Starts delivering events to a webhook, recording the attempts if `attempts`
+/// is set, and only to public addresses if `public_only`, for webhooks that
+/// clients chose. `None` if its stream doesn't exist, `Err` if the rest is invalid.
+pub fn start(
+    webhook: &WebhookConfig,
+    broadcasts: &Broadcasts,
+    attempts: Option<Attempts>,
+    public_only: bool,
+) -> Option<Result<JoinHandle<()>, String>> {
+    let mut starter = Starter {
+        webhook,
+        broadcasts: Some(broadcasts),
+        attempts,
+        public_only,
+        result: None,
+        task: None,
+    };
+    for_each_event_type(&mut starter);
+    let task = starter.task;
+    starter
+        .result
+        .map(|result| result.map(|()| task.expect("Webhook wasn't started")))
+}
+
+/// Checks a webhook without starting it, like `start`.
+pub fn validate(webhook: &WebhookConfig, public_only: bool) -> Option<Result<(), String>> {
+    let mut starter = Starter {
+        webhook,
+        broadcasts: None,
+        attempts: None,
+        public_only,
+        result: None,
+        task: None,
+    };
+    for_each_event_type(&mut starter);
+    starter.result
+}
+
+struct Starter<'a> {
+    webhook: &'a WebhookConfig,
+    /// `None` to only check the webhook.
+    broadcasts: Option<&'a Broadcasts>,
+    attempts: Option<Attempts>,
+    public_only: bool,
+    /// `None` if the stream doesn't exist.
+    result: Option<Result<(), String>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl EventTypeVisitor for Starter<'_> {
+    fn visit<
+        E: Serialize + FromRedis + Send + Sync + 'static,
+        F: EventFilter<E> + DeserializeOwned + Send + 'static,
+    >(
+        &mut self,
+    ) {
+        let webhook = self.webhook;
+        if webhook.stream != E::STREAM_KEY {
+            return;
+        }
+        let filter = match webhook.filter.as_ref().map(fields::parse_filter::<F>) {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(err)) => {
+                self.result = Some(Err(format!("Invalid filter: {err}")));
+                return;
+            }
+            None => None,
+        };
+        let endpoint = match Endpoint::parse(&webhook.url) {
+            Ok(endpoint) if self.public_only => endpoint.public_only(),
+            Ok(endpoint) => endpoint,
+            Err(err) => {
+                self.result = Some(Err(format!("Invalid URL: {err}")));
+                return;
+            }
+        };
+        if webhook.format == WebhookFormat::Telegram && webhook.chat_id.is_none() {
+            self.result = Some(Err("Telegram webhooks need a chat_id".to_string()));
+            return;
+        }
+        self.result = Some(Ok(()));
+        if let Some(broadcasts) = self.broadcasts {
+            self.task = Some(tokio::spawn(deliver(
+                broadcasts.subscribe::<E>(&webhook.network),
+                filter,
+                endpoint,
+                webhook.clone(),
+                self.attempts.take(),
+            )));
+        }
+    }
+}
+
+async fn deliver<E: Serialize + FromRedis, F: EventFilter<E>>(
+    mut events: EventReceiver<E>,
+    filter: Option<F>,
+    endpoint: Endpoint,
+    webhook: WebhookConfig,
+    attempts: Option<Attempts>,
+) {
+    let key = webhook.secret.as_deref().map(signing::key);
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => {
+                tracing::warn!(
+                    "Webhook to {} fell behind, {count} events were skipped",
+                    webhook.url
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !filter.as_ref().is_none_or(|f| f.matches(&event.event)) {
+            continue;
+        }
+        let event_json = serde_json::to_value(TaggedEvent {
+            source: &event.source,
+            stream_id: event.id,
+            event: &event.event,
+        })
+        .unwrap();
+        let body = payload(&webhook, E::STREAM_KEY, event_json).to_string();
+        let idempotency_key = format!("{}:{}:{}", E::STREAM_KEY, event.source, event.id);
+        for attempt in 1..=MAX_ATTEMPTS {
+            // Signed again for every attempt, so that the timestamp is recent
+            let signature = key.as_ref().map(|key| {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                signature(key, timestamp, &body)
+            });
+            let mut headers = vec![(IDEMPOTENCY_HEADER, idempotency_key.as_str())];
+            if let Some(signature) = &signature {
+                headers.push((SIGNATURE_HEADER, signature));
+            }
+            let result = endpoint.post("", &headers, "application/json", &body).await;
+            if let Some(attempts) = &attempts {
+                attempts.record(event.id, attempt, result.as_ref().err());
+            }
+            match result {
+                Ok(_) => break,
+                Err(err) if attempt == MAX_ATTEMPTS => tracing::warn!(
+                    stream_id = %event.id,
+                    "Failed to deliver webhook to {}: {err}",
+                    webhook.url
+                ),
+                Err(_) => tokio::time::sleep(RETRY_DELAY * attempt).await,
+            }
+        }
+    }
+}
+
+fn payload(webhook: &WebhookConfig, stream_key: &str, event: Value) -> Value {
+    match webhook.format {
+        WebhookFormat::Json => event,
+        WebhookFormat::Discord => {
+            let title = match &webhook.title {
+                Some(title) => render(title, stream_key, &event),
+                None => format!("New {stream_key} event"),
+            };
+            let description = match &webhook.template {
+                Some(template) => render(template, stream_key, &event),
+                None => format!(
+                    "```json\n{}\n```",
+                    serde_json::to_string_pretty(&event).unwrap()
+                ),
+            };
+            json!({
+                "embeds": [{
+                    "title": title,
+                    "description": truncate(description, MAX_DISCORD_DESCRIPTION),
+                }],
+            })
+        }
+        WebhookFormat::Telegram => {
+            let text = match &webhook.template {
+                Some(template) => render(template, stream_key, &event),
+                None => format!(
+                    "New {stream_key} event\n{}",
+                    serde_json::to_string_pretty(&event).unwrap()
+                ),
+            };
+            json!({
+                "chat_id": webhook.chat_id,
+                "text": truncate(text, MAX_TELEGRAM_TEXT),
+            })
+        }
+    }
+}
+
+/// The `X-Webhook-Signature` header of a request.
+fn signature(key: &hmac::Key, timestamp: u64, body: &str) -> String {
+    let signed = format!("{timestamp}.{body}");
+    format!(
+        "t={timestamp},v1={}",
+        signing::hex_signature(key, signed.as_bytes())
+    )
+}
+
+/// Replaces the placeholders of the template with fields of the event.
+pub fn render(template: &str, stream_key: &str, event: &Value) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let name = &rest[start + 1..start + length];
+        let value = if name == "stream" {
+            Some(Value::String(stream_key.to_string()))
+        } else if name.contains('/') {
+            event.pointer(&format!("/{name}")).cloned()
+        } else {
+            event.get(name).cloned()
+        };
+        match value {
+            Some(Value::String(string)) => rendered.push_str(&string),
+            Some(Value::Null) | None => {}
+            Some(value) => rendered.push_str(&value.to_string()),
+        }
+        rest = &rest[start + length + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn truncate(mut text: String, max_chars: usize) -> String {
+    if let Some((index, _)) = text.char_indices().nth(max_chars) {
+        text.truncate(index);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_placeholders() {
+        let event = json!({
+            "contract_id": "nft.near",
+            "token_ids": ["1", "2"],
+            "balance_changes": { "wrap.near": "-100" },
+            "memo": null,
+        });
+        assert_eq!(
+            render(
+                "{stream}: {token_ids} on {contract_id} for {balance_changes/wrap.near}{memo}{missing}",
+                "nft_transfer",
+                &event
+            ),
+            r#"nft_transfer: ["1","2"] on nft.near for -100"#
+        );
+        assert_eq!(
+            render("{contract_id", "nft_transfer", &event),
+            "{contract_id"
+        );
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        let key = signing::key("whsec");
+        let header = signature(&key, 1715000000, r#"{"a":1}"#);
+        let (timestamp, hex) = header.split_once(",v1=").unwrap();
+        assert_eq!(timestamp, "t=1715000000");
+        assert_eq!(hex, signing::hex_signature(&key, br#"1715000000.{"a":1}"#));
+        assert_ne!(signature(&key, 1715000001, r#"{"a":1}"#), header);
+    }
+}
@@ -0,0 +1,281 @@
+//! Tiny WebSocket client for reading text messages from another instance of this
+//! server, over plain TCP or TLS.
+
+use actix_web::http::Uri;
+use base64::prelude::*;
+use rustls::pki_types::ServerName;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::http_client::{check_header, TLS_CONNECTOR};
+
+/// Larger messages are a protocol error, events are far smaller.
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+pub struct WsClient {
+    stream: BufReader<Box<dyn Io>>,
+}
+
+impl WsClient {
+    /// Connects to a `ws://` or `wss://` URL, sending the headers with the handshake.
+    pub async fn connect(url: &str, headers: &[(&str, &str)]) -> anyhow::Result<Self> {
+        let uri = url.parse::<Uri>()?;
+        let tls = match uri.scheme_str() {
+            Some("ws") => false,
+            Some("wss") => true,
+            _ => anyhow::bail!("Only ws:// and wss:// URLs are supported, got {url}"),
+        };
+        let Some(host) = uri.host() else {
+            anyhow::bail!("No host in {url}");
+        };
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        let stream = TcpStream::connect((host, port)).await?;
+        let stream: Box<dyn Io> = if tls {
+            let server_name = ServerName::try_from(host.to_string())?;
+            Box::new(TLS_CONNECTOR.connect(server_name, stream).await?)
+        } else {
+            Box::new(stream)
+        };
+        Self::handshake(stream, &uri, headers).await
+    }
+
+    async fn handshake(
+        stream: Box<dyn Io>,
+        uri: &Uri,
+        headers: &[(&str, &str)],
+    ) -> anyhow::Result<Self> {
+        let mut client = Self {
+            stream: BufReader::new(stream),
+        };
+        let host = uri.host().unwrap_or_default();
+        let mut key = [0; 16];
+        getrandom::getrandom(&mut key).expect("Failed to generate a WebSocket key");
+        let target = uri.path_and_query().map_or("/", |target| target.as_str());
+        let mut request = format!(
+            "GET {target} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+            BASE64_STANDARD.encode(key),
+        );
+        for (name, value) in headers {
+            check_header(name, value)?;
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        client
+            .stream
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await?;
+
+        let mut status_line = String::new();
+        client.stream.read_line(&mut status_line).await?;
+        if status_line.split(' ').nth(1) != Some("101") {
+            anyhow::bail!("Server responded with {:?}", status_line.trim());
+        }
+        // The upstream is configured by the operator, so Sec-WebSocket-Accept isn't checked
+        loop {
+            let mut line = String::new();
+            if client.stream.read_line(&mut line).await? == 0 {
+                anyhow::bail!("Connection closed during the handshake");
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        Ok(client)
+    }
+
+    /// The next text or binary message, answering pings on the way, or `None`
+    /// when the server closes the connection.
+    pub async fn next_message(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut message = Vec::new();
+        loop {
+            let mut head = [0; 2];
+            self.stream.read_exact(&mut head).await?;
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0f;
+            let masked = head[1] & 0x80 != 0;
+            let length = match head[1] & 0x7f {
+                126 => u64::from(self.stream.read_u16().await?),
+                127 => self.stream.read_u64().await?,
+                length => u64::from(length),
+            };
+            // The length can be up to 2^64 - 1
+            let total = (message.len() as u64).checked_add(length);
+            if total.is_none_or(|total| total > MAX_MESSAGE_SIZE) {
+                anyhow::bail!("Message larger than {MAX_MESSAGE_SIZE} bytes");
+            }
+            let mut mask = [0; 4];
+            if masked {
+                self.stream.read_exact(&mut mask).await?;
+            }
+            let mut payload = vec![0; length as usize];
+            self.stream.read_exact(&mut payload).await?;
+            if masked {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+            match opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(Some(message));
+                    }
+                }
+                OPCODE_PING => self.send(OPCODE_PONG, &payload).await?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    // Echoing the status code completes the closing handshake
+                    let _ = self
+                        .send(OPCODE_CLOSE, payload.get(..2).unwrap_or(&[]))
+                        .await;
+                    return Ok(None);
+                }
+                opcode => anyhow::bail!("Unknown opcode {opcode}"),
+            }
+        }
+    }
+
+    /// Sends a frame, masked like every frame from a client.
+    async fn send(&mut self, opcode: u8, payload: &[u8]) -> anyhow::Result<()> {
+        let mut mask = [0; 4];
+        getrandom::getrandom(&mut mask).expect("Failed to generate a WebSocket mask");
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        self.stream.get_mut().write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+
+    /// A client connected to the returned server side, and the request it sent.
+    async fn connect(response: &[u8]) -> (anyhow::Result<WsClient>, DuplexStream, String) {
+        let (client, mut server) = duplex(64 * 1024);
+        server.write_all(response).await.unwrap();
+        let uri = "ws://mirror.example.com:8080/v0/firehose?network=mainnet"
+            .parse::<Uri>()
+            .unwrap();
+        let client =
+            WsClient::handshake(Box::new(client), &uri, &[("Authorization", "Bearer key")]).await;
+        let mut request = vec![0; 1024];
+        let length = server.read(&mut request).await.unwrap();
+        request.truncate(length);
+        (client, server, String::from_utf8(request).unwrap())
+    }
+
+    /// Unmasks a frame from the client with a payload of up to 125 bytes.
+    async fn read_frame(server: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 6];
+        server.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[1] & 0x80, 0x80, "Frames from clients are masked");
+        let mut payload = vec![0; usize::from(head[1] & 0x7f)];
+        server.read_exact(&mut payload).await.unwrap();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= head[2 + i % 4];
+        }
+        (head[0], payload)
+    }
+
+    #[tokio::test]
+    async fn sends_the_handshake() {
+        let (client, _server, request) = connect(RESPONSE).await;
+        client.unwrap();
+        let lines = request.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines[0], "GET /v0/firehose?network=mainnet HTTP/1.1");
+        for line in [
+            "Host: mirror.example.com",
+            "Upgrade: websocket",
+            "Connection: Upgrade",
+            "Sec-WebSocket-Version: 13",
+            "Authorization: Bearer key",
+        ] {
+            assert!(lines.contains(&line), "{line} is missing in {request}");
+        }
+        let key = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        assert_eq!(BASE64_STANDARD.decode(key).unwrap().len(), 16);
+        assert!(request.ends_with("\r\n\r\n"));
+
+        let (client, _, _) = connect(b"HTTP/1.1 403 Forbidden\r\n\r\n").await;
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_messages() {
+        let (client, mut server, _) = connect(RESPONSE).await;
+        let mut client = client.unwrap();
+        // `Hello` in two fragments with a ping in between, like in RFC 6455 5.7,
+        // then a message with a 16-bit length and a close frame with code 1000
+        server
+            .write_all(&[
+                0x01, 0x03, b'H', b'e', b'l', 0x89, 0x02, b'h', b'i', 0x80, 0x02, b'l', b'o',
+            ])
+            .await
+            .unwrap();
+        server.write_all(&[0x82, 126, 0x01, 0x00]).await.unwrap();
+        server.write_all(&[7; 256]).await.unwrap();
+        server.write_all(&[0x88, 0x02, 0x03, 0xe8]).await.unwrap();
+
+        assert_eq!(client.next_message().await.unwrap().unwrap(), b"Hello");
+        assert_eq!(read_frame(&mut server).await, (0x8a, b"hi".to_vec()));
+        assert_eq!(client.next_message().await.unwrap().unwrap(), [7; 256]);
+        assert_eq!(client.next_message().await.unwrap(), None);
+        assert_eq!(read_frame(&mut server).await, (0x88, vec![0x03, 0xe8]));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames() {
+        for frames in [
+            // 2^64 - 1 bytes after a fragment, which overflows the length
+            &[
+                0x01, 0x01, b'a', 0x80, 127, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ][..],
+            // 16 MiB and one byte
+            &[0x81, 127, 0, 0, 0, 0, 0x01, 0, 0, 0x01],
+        ] {
+            let (client, mut server, _) = connect(RESPONSE).await;
+            let mut client = client.unwrap();
+            server.write_all(frames).await.unwrap();
+            let err = client.next_message().await.unwrap_err();
+            assert!(err.to_string().starts_with("Message larger than"), "{err}");
+        }
+    }
+}